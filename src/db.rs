@@ -1,4 +1,11 @@
-use postgres::{types::ToSql, NoTls, Row};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use postgres::{
+    error::SqlState,
+    types::{ToSql, Type},
+    NoTls, Row,
+};
 
 pub trait Conn {
     fn run(&mut self, query: &str) -> anyhow::Result<()>;
@@ -8,7 +15,17 @@ pub trait Conn {
         query: &str,
         params: &[&(dyn ToSql + Sync)],
     ) -> anyhow::Result<Vec<Row>>;
-    fn transaction(&mut self) -> anyhow::Result<Transaction>;
+    fn transaction(&mut self) -> anyhow::Result<Transaction<'_>>;
+
+    // Whether statements are recorded rather than run
+    fn is_recording(&self) -> bool {
+        false
+    }
+
+    // Whether statements are run as part of a larger transaction
+    fn in_transaction(&self) -> bool {
+        false
+    }
 }
 
 pub struct DbConn {
@@ -20,6 +37,25 @@ impl DbConn {
         let client = config.connect(NoTls)?;
         Ok(DbConn { client })
     }
+
+    // Start recording with changes also being applied in a transaction,
+    // which is rolled back when the recording ends. Applying changes takes the same locks
+    // as running them would, so they give up after `lock_timeout` rather than queueing
+    // behind other queries and blocking everything queued after them.
+    pub fn recording_with_changes(
+        &mut self,
+        lock_timeout: Duration,
+    ) -> anyhow::Result<RecordingConn<'_>> {
+        self.client.batch_execute(&format!(
+            "BEGIN; SET LOCAL lock_timeout = {}",
+            lock_timeout.as_millis()
+        ))?;
+        Ok(RecordingConn {
+            client: &mut self.client,
+            statements: vec![],
+            apply_changes: true,
+        })
+    }
 }
 
 impl Conn for DbConn {
@@ -42,32 +78,44 @@ impl Conn for DbConn {
         Ok(rows)
     }
 
-    fn transaction(&mut self) -> anyhow::Result<Transaction> {
+    fn transaction(&mut self) -> anyhow::Result<Transaction<'_>> {
         let transaction = self.client.transaction()?;
-        Ok(Transaction { transaction })
+        Ok(Transaction::Postgres(transaction))
     }
 }
 
-pub struct Transaction<'a> {
-    transaction: postgres::Transaction<'a>,
+pub enum Transaction<'a> {
+    Postgres(postgres::Transaction<'a>),
+
+    // A transaction on a RecordingConn, which records BEGIN and COMMIT
+    // rather than starting a real transaction
+    Recording(&'a mut dyn Conn),
 }
 
 impl Transaction<'_> {
     pub fn commit(self) -> anyhow::Result<()> {
-        self.transaction.commit()?;
+        match self {
+            Transaction::Postgres(transaction) => transaction.commit()?,
+            Transaction::Recording(conn) => conn.run("COMMIT")?,
+        }
         Ok(())
     }
 }
 
 impl Conn for Transaction<'_> {
     fn run(&mut self, query: &str) -> anyhow::Result<()> {
-        self.transaction.batch_execute(query)?;
+        match self {
+            Transaction::Postgres(transaction) => transaction.batch_execute(query)?,
+            Transaction::Recording(conn) => conn.run(query)?,
+        }
         Ok(())
     }
 
     fn query(&mut self, query: &str) -> anyhow::Result<Vec<Row>> {
-        let rows = self.transaction.query(query, &[])?;
-        Ok(rows)
+        match self {
+            Transaction::Postgres(transaction) => Ok(transaction.query(query, &[])?),
+            Transaction::Recording(conn) => conn.query(query),
+        }
     }
 
     fn query_with_params(
@@ -75,12 +123,264 @@ impl Conn for Transaction<'_> {
         query: &str,
         params: &[&(dyn ToSql + Sync)],
     ) -> anyhow::Result<Vec<Row>> {
-        let rows = self.transaction.query(query, params)?;
-        Ok(rows)
+        match self {
+            Transaction::Postgres(transaction) => Ok(transaction.query(query, params)?),
+            Transaction::Recording(conn) => conn.query_with_params(query, params),
+        }
+    }
+
+    fn transaction(&mut self) -> anyhow::Result<Transaction<'_>> {
+        match self {
+            Transaction::Postgres(transaction) => {
+                Ok(Transaction::Postgres(transaction.transaction()?))
+            }
+            Transaction::Recording(conn) => conn.transaction(),
+        }
+    }
+
+    fn is_recording(&self) -> bool {
+        matches!(self, Transaction::Recording(_))
+    }
+
+    fn in_transaction(&self) -> bool {
+        true
+    }
+}
+
+// A connection which records every statement that would change the database instead of
+// running it. Everything runs inside a transaction which is rolled back once recording ends.
+// Queries which only read, like introspection of the catalogs, are run read-only and are
+// never recorded. Session settings such as the search path are both applied and recorded,
+// so later queries see the same settings.
+//
+// Until `stop_applying_changes` is called, schema changes made with `run` are also applied,
+// so later introspection sees for example columns added by earlier statements. Writes to
+// rows are only recorded.
+pub struct RecordingConn<'a> {
+    client: &'a mut postgres::Client,
+    statements: Vec<String>,
+    apply_changes: bool,
+}
+
+impl RecordingConn<'_> {
+    // Take the statements recorded since the last call
+    pub fn take_statements(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.statements)
+    }
+
+    // Keep recording without applying any further changes. Changes applied so far
+    // remain visible until the recording ends.
+    pub fn stop_applying_changes(&mut self) {
+        self.apply_changes = false;
+    }
+
+    fn apply(&mut self, query: &str) -> anyhow::Result<()> {
+        self.client.batch_execute("SAVEPOINT reshape_apply")?;
+        match self.client.batch_execute(query) {
+            Ok(()) => self
+                .client
+                .batch_execute("RELEASE SAVEPOINT reshape_apply")?,
+            // Some statements, like creating an index concurrently, can't run in a transaction
+            // and are left out. Introspection doesn't rely on their results.
+            Err(err) if err.code() == Some(&SqlState::ACTIVE_SQL_TRANSACTION) => self
+                .client
+                .batch_execute("ROLLBACK TO SAVEPOINT reshape_apply")?,
+            // Roll back the failed statement so the transaction can still be used
+            Err(err) => {
+                self.client
+                    .batch_execute("ROLLBACK TO SAVEPOINT reshape_apply")?;
+                return Err(err.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn read(
+        &mut self,
+        query: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> anyhow::Result<Result<Vec<Row>, postgres::Error>> {
+        self.client
+            .batch_execute("SAVEPOINT reshape_read; SET LOCAL transaction_read_only = on")?;
+        let result = self.client.query(query, params);
+        self.client
+            .batch_execute("ROLLBACK TO SAVEPOINT reshape_read")?;
+        Ok(result)
+    }
+
+    fn record(&mut self, query: &str, params: &[&(dyn ToSql + Sync)]) -> anyhow::Result<()> {
+        let query = self.inline_params(query, params)?;
+        self.statements.push(query);
+        Ok(())
+    }
+
+    // Recorded statements must be runnable on their own, so parameters are replaced
+    // with literals
+    fn inline_params(
+        &mut self,
+        query: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> anyhow::Result<String> {
+        if params.is_empty() {
+            return Ok(query.to_string());
+        }
+
+        let literals = params
+            .iter()
+            .map(|param| self.quote_literal(*param))
+            .collect::<anyhow::Result<Vec<String>>>()?;
+
+        // Placeholders are replaced in a single pass so that inserted literals are left
+        // alone, skipping over quoted strings and identifiers
+        let mut result = String::with_capacity(query.len());
+        let mut quote: Option<char> = None;
+        let mut chars = query.chars().peekable();
+        while let Some(c) = chars.next() {
+            match quote {
+                Some(q) if c == q => quote = None,
+                None if c == '\'' || c == '"' => quote = Some(c),
+                _ => {}
+            }
+
+            let mut digits = String::new();
+            if c == '$' && quote.is_none() {
+                while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                    digits.push(digit);
+                }
+            }
+
+            let index = digits.parse::<usize>().ok().and_then(|n| n.checked_sub(1));
+            match index.and_then(|index| literals.get(index)) {
+                Some(literal) => result.push_str(literal),
+                None if digits.is_empty() => result.push(c),
+                None => return Err(anyhow!("no parameter for placeholder ${}", digits)),
+            }
+        }
+
+        Ok(result)
+    }
+
+    // Parameters can't be formatted as SQL on the client, so Postgres formats them instead.
+    // The type of the parameter isn't known, so every supported type is tried until one
+    // is accepted.
+    fn quote_literal(&mut self, param: &(dyn ToSql + Sync)) -> anyhow::Result<String> {
+        let types = [
+            Type::TEXT,
+            Type::INT4,
+            Type::INT8,
+            Type::INT2,
+            Type::BOOL,
+            Type::FLOAT8,
+            Type::FLOAT4,
+            Type::JSONB,
+            Type::TEXT_ARRAY,
+            Type::INT4_ARRAY,
+            Type::INT8_ARRAY,
+        ];
+        for data_type in types {
+            let query = format!(
+                "SELECT quote_nullable($1::{data_type}) || '::{data_type}' AS literal",
+                data_type = data_type.name(),
+            );
+            if let Ok(rows) = self.read(&query, &[param])? {
+                return Ok(rows[0].get("literal"));
+            }
+        }
+
+        Err(anyhow!(
+            "failed to record parameter {:?} as a literal",
+            param
+        ))
+    }
+}
+
+// Changes and settings were applied while recording, so roll them back
+impl Drop for RecordingConn<'_> {
+    fn drop(&mut self) {
+        let _ = self.client.batch_execute("ROLLBACK");
+    }
+}
+
+fn is_session_setting(query: &str) -> bool {
+    let query = query.trim_start().to_uppercase();
+    query.starts_with("SET ") || query.starts_with("RESET ")
+}
+
+// Whether a query only reads, which is the case for selects without any data-modifying
+// statements in a WITH clause
+fn is_read(query: &str) -> bool {
+    let query = query.trim_start().to_uppercase();
+    let writes = ["INSERT ", "UPDATE ", "DELETE "];
+    query.starts_with("SELECT")
+        || (query.starts_with("WITH") && !writes.iter().any(|write| query.contains(write)))
+}
+
+fn is_transaction_control(query: &str) -> bool {
+    matches!(query.trim().to_uppercase().as_str(), "BEGIN" | "COMMIT")
+}
+
+impl Conn for RecordingConn<'_> {
+    fn run(&mut self, query: &str) -> anyhow::Result<()> {
+        if is_session_setting(query) {
+            self.client.batch_execute(query)?;
+        } else if self.apply_changes && !is_transaction_control(query) {
+            self.apply(query)?;
+        }
+
+        self.record(query, &[])
+    }
+
+    fn query(&mut self, query: &str) -> anyhow::Result<Vec<Row>> {
+        self.query_with_params(query, &[])
+    }
+
+    fn query_with_params(
+        &mut self,
+        query: &str,
+        params: &[&(dyn ToSql + Sync)],
+    ) -> anyhow::Result<Vec<Row>> {
+        if is_session_setting(query) {
+            self.client.batch_execute(query)?;
+            self.record(query, params)?;
+            return Ok(vec![]);
+        }
+
+        let result = self.read(query, params)?;
+
+        // Statements which write are recorded, as are writes referring to objects which don't
+        // exist yet as they are created by statements recorded earlier. Reads, like queries
+        // of the catalogs, are never part of the recorded statements.
+        let undefined_codes = [
+            SqlState::UNDEFINED_COLUMN,
+            SqlState::UNDEFINED_TABLE,
+            SqlState::UNDEFINED_FUNCTION,
+            SqlState::UNDEFINED_OBJECT,
+        ];
+        match result {
+            Ok(rows) => Ok(rows),
+            Err(err) if err.code() == Some(&SqlState::READ_ONLY_SQL_TRANSACTION) => {
+                self.record(query, params)?;
+                Ok(vec![])
+            }
+            Err(err)
+                if err
+                    .code()
+                    .is_some_and(|code| undefined_codes.contains(code))
+                    && !is_read(query) =>
+            {
+                self.record(query, params)?;
+                Ok(vec![])
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn transaction(&mut self) -> anyhow::Result<Transaction<'_>> {
+        self.run("BEGIN")?;
+        Ok(Transaction::Recording(self))
     }
 
-    fn transaction(&mut self) -> anyhow::Result<Transaction> {
-        let transaction = self.transaction.transaction()?;
-        Ok(Transaction { transaction })
+    fn is_recording(&self) -> bool {
+        true
     }
 }
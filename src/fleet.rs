@@ -0,0 +1,172 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use anyhow::anyhow;
+use colored::*;
+use postgres::{config::Host, Config};
+
+use crate::{Reshape, Status};
+
+// A set of databases sharing the same logical schema, for example one per region or shard.
+// Commands are run against every database using the regular `Reshape` state machine,
+// with at most `concurrency` databases being worked on at the same time.
+pub struct Fleet {
+    urls: Vec<String>,
+    concurrency: usize,
+}
+
+// The outcome of running a command against a single database in a fleet
+pub struct FleetResult {
+    pub database: String,
+    pub status: Option<Status>,
+    pub error: Option<anyhow::Error>,
+}
+
+impl Fleet {
+    pub fn new(urls: Vec<String>, concurrency: usize) -> Fleet {
+        Fleet {
+            urls,
+            concurrency: concurrency.max(1),
+        }
+    }
+
+    // Run a command against every database. Failures are collected rather than stopping
+    // the other databases, and each failed database is left in a state that can be cleaned
+    // up with `reshape abort`. The results are returned in the same order as the URLs.
+    pub fn run<F>(&self, command: F) -> Vec<FleetResult>
+    where
+        F: Fn(&mut Reshape) -> anyhow::Result<()> + Sync,
+    {
+        let next_index = AtomicUsize::new(0);
+        let results: Mutex<Vec<Option<FleetResult>>> =
+            Mutex::new(self.urls.iter().map(|_| None).collect());
+
+        thread::scope(|scope| {
+            for _ in 0..self.concurrency.min(self.urls.len()) {
+                scope.spawn(|| loop {
+                    let index = next_index.fetch_add(1, Ordering::SeqCst);
+                    let Some(url) = self.urls.get(index) else {
+                        break;
+                    };
+
+                    let result = run_for_database(url, &command);
+                    results.lock().unwrap()[index] = Some(result);
+                });
+            }
+        });
+
+        results
+            .into_inner()
+            .unwrap()
+            .into_iter()
+            .map(|result| result.unwrap())
+            .collect()
+    }
+}
+
+fn run_for_database<F>(url: &str, command: &F) -> FleetResult
+where
+    F: Fn(&mut Reshape) -> anyhow::Result<()>,
+{
+    let database = database_name(url);
+    let mut reshape = match Reshape::new(url) {
+        Ok(reshape) => reshape,
+        Err(err) => {
+            return FleetResult {
+                database,
+                status: None,
+                error: Some(err.context("failed to connect")),
+            }
+        }
+    };
+
+    // Loading and saving state panics on database errors, which shouldn't take down
+    // the rest of the fleet
+    let result = panic::catch_unwind(AssertUnwindSafe(|| command(&mut reshape)))
+        .unwrap_or_else(|_| Err(anyhow!("unexpected panic")));
+
+    FleetResult {
+        database,
+        status: Some(reshape.state.status.clone()),
+        error: result.err(),
+    }
+}
+
+// Describe a database by its host, port and name, leaving out any credentials
+fn database_name(url: &str) -> String {
+    let config: Config = match url.parse() {
+        Ok(config) => config,
+        Err(_) => return url.to_string(),
+    };
+
+    let host = match config.get_hosts().first() {
+        Some(Host::Tcp(host)) => host.to_string(),
+        #[cfg(unix)]
+        Some(Host::Unix(path)) => path.display().to_string(),
+        None => "localhost".to_string(),
+    };
+    let port = config.get_ports().first().copied().unwrap_or(5432);
+    let dbname = config.get_dbname().unwrap_or("postgres");
+
+    format!("{}:{}/{}", host, port, dbname)
+}
+
+// Format the results as a table with one row per database
+pub fn status_table(results: &[FleetResult]) -> String {
+    let rows: Vec<(String, String, Option<String>)> = results
+        .iter()
+        .map(|result| {
+            let status = result
+                .status
+                .as_ref()
+                .map(|status| status.name())
+                .unwrap_or("unknown")
+                .to_string();
+            let error = result.error.as_ref().map(|err| format!("{:#}", err));
+            (result.database.to_string(), status, error)
+        })
+        .collect();
+
+    let database_width = rows
+        .iter()
+        .map(|(database, _, _)| database.len())
+        .chain(std::iter::once("DATABASE".len()))
+        .max()
+        .unwrap();
+    let status_width = rows
+        .iter()
+        .map(|(_, status, _)| status.len())
+        .chain(std::iter::once("STATUS".len()))
+        .max()
+        .unwrap();
+
+    let mut lines = vec![format!(
+        "{:database_width$}  {:status_width$}  RESULT",
+        "DATABASE",
+        "STATUS",
+        database_width = database_width,
+        status_width = status_width,
+    )];
+    for (database, status, error) in rows {
+        let result = match error {
+            Some(error) => format!("{} {}", "failed:".red(), error),
+            None => "ok".green().to_string(),
+        };
+        lines.push(format!(
+            "{:database_width$}  {:status_width$}  {}",
+            database,
+            status,
+            result,
+            database_width = database_width,
+            status_width = status_width,
+        ));
+    }
+
+    lines.join("\n")
+}
@@ -3,10 +3,19 @@ use anyhow::Context;
 use crate::db::Conn;
 
 pub fn set_up_helpers(db: &mut dyn Conn, current_migration: &Option<String>) -> anyhow::Result<()> {
+    // The old schema is in use if any of its view schemas are on the search path,
+    // there is one for `public` and one for each other Postgres schema with tables
     let predicate = if let Some(current_migration) = current_migration {
         format!(
-            "current_setting('search_path') = 'migration_{}' OR setting_bool",
-            current_migration
+            r#"
+            EXISTS (
+                SELECT 1
+                FROM unnest(string_to_array(current_setting('search_path'), ',')) AS path
+                WHERE trim(both ' "' from path) = 'migration_{migration}'
+                OR trim(both ' "' from path) LIKE 'migration_{migration}\_\_%'
+            ) OR setting_bool
+            "#,
+            migration = current_migration
         )
     } else {
         "setting_bool".to_string()
@@ -1,5 +1,5 @@
 use crate::{
-    migrations::{Migration, MigrationContext},
+    migrations::{Action, BackfillPolicy, LockRetryPolicy, Migration, MigrationContext},
     schema::Schema,
 };
 
@@ -8,18 +8,46 @@ use colored::*;
 use db::{Conn, DbConn};
 use postgres::Config;
 use schema::Table;
+use std::time::Duration;
 
 mod db;
+mod fleet;
 mod helpers;
+mod lock;
 pub mod migrations;
+mod plan;
 mod schema;
 mod state;
+mod tenants;
 
+pub use crate::fleet::{status_table, Fleet, FleetResult};
+pub use crate::lock::LOCK_KEY;
+pub use crate::plan::{ActionPlan, Plan, SqlScript};
 pub use crate::state::{State, Status};
+pub use crate::tenants::TenantSelector;
+
+// Postgres schema used by actions which don't specify one
+const DEFAULT_SCHEMA: &str = "public";
+
+// The Postgres schemas an action is applied to. Actions which don't specify a schema
+// are applied to every tenant schema when migrating tenants.
+fn schemas_for_action<'a>(action: &'a dyn Action, tenants: &'a [String]) -> Vec<&'a str> {
+    match action.schema() {
+        Some(schema) => vec![schema],
+        None if tenants.is_empty() => vec![DEFAULT_SCHEMA],
+        None => tenants.iter().map(String::as_str).collect(),
+    }
+}
+
+// Default time to wait for another Reshape process to release the advisory lock
+const DEFAULT_LOCK_WAIT_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub struct Reshape {
     pub state: State,
     db: DbConn,
+    lock_wait_timeout: Duration,
+    lock_retry_policy: LockRetryPolicy,
+    backfill_policy: BackfillPolicy,
 }
 
 impl Reshape {
@@ -45,13 +73,89 @@ impl Reshape {
     }
 
     fn new_with_config(config: &Config) -> anyhow::Result<Reshape> {
-        let mut db = DbConn::connect(config)?;
+        // Identify ourselves so other processes can tell who holds the advisory lock
+        let mut config = config.clone();
+        if config.get_application_name().is_none() {
+            config.application_name("reshape");
+        }
+
+        let mut db = DbConn::connect(&config)?;
         let state = State::load(&mut db);
 
-        Ok(Reshape { db, state })
+        Ok(Reshape {
+            db,
+            state,
+            lock_wait_timeout: DEFAULT_LOCK_WAIT_TIMEOUT,
+            lock_retry_policy: LockRetryPolicy::default(),
+            backfill_policy: BackfillPolicy::default(),
+        })
+    }
+
+    // Set how long to wait for another Reshape process working on the same database
+    // to finish before giving up
+    pub fn set_lock_wait_timeout(&mut self, timeout: Duration) {
+        self.lock_wait_timeout = timeout;
+    }
+
+    // Set how long DDL may wait for locks on a table and how it's retried when timing out
+    pub fn set_lock_retry_policy(&mut self, policy: LockRetryPolicy) {
+        self.lock_retry_policy = policy;
+    }
+
+    // Set how existing rows are backfilled in batches when starting a migration
+    pub fn set_backfill_policy(&mut self, policy: BackfillPolicy) {
+        self.backfill_policy = policy;
+    }
+
+    fn migration_context(&self, migration_index: usize, action_index: usize) -> MigrationContext {
+        MigrationContext::new(migration_index, action_index)
+            .with_lock_retry_policy(self.lock_retry_policy)
+            .with_backfill_policy(self.backfill_policy)
+    }
+
+    // Run an operation which changes the state while holding the advisory lock, so only
+    // one Reshape process works on a database at a time. The state is reloaded once the
+    // lock is taken as another process might have changed it while we were waiting.
+    fn with_lock<T>(
+        &mut self,
+        operation: impl FnOnce(&mut Self) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        lock::acquire(&mut self.db, self.lock_wait_timeout)?;
+        self.state = State::load(&mut self.db);
+
+        let result = operation(self);
+        let released = lock::release(&mut self.db);
+
+        let value = result?;
+        released?;
+        Ok(value)
     }
 
     pub fn migrate<T>(&mut self, migrations: T) -> anyhow::Result<()>
+    where
+        T: IntoIterator<Item = Migration>,
+    {
+        self.with_lock(|reshape| reshape.apply_migrations(migrations, None))
+    }
+
+    // Apply migrations to every tenant schema picked by the selector. Actions which
+    // specify a schema are only applied to that schema.
+    pub fn migrate_tenants<T>(
+        &mut self,
+        migrations: T,
+        tenants: &TenantSelector,
+    ) -> anyhow::Result<()>
+    where
+        T: IntoIterator<Item = Migration>,
+    {
+        self.with_lock(|reshape| reshape.apply_migrations(migrations, Some(tenants)))
+    }
+
+    fn apply_migrations<T>(
+        &mut self,
+        migrations: T,
+        tenant_selector: Option<&TenantSelector>,
+    ) -> anyhow::Result<()>
     where
         T: IntoIterator<Item = Migration>,
     {
@@ -82,9 +186,14 @@ impl Reshape {
         }
 
         // If we have already started applying some migrations we need to ensure that
-        // they are the same ones we want to apply now
-        if let state::Status::Applying {
+        // they are the same ones we want to apply now. In that case we resume from the
+        // migration, action and tenant where the previous run was interrupted, using the
+        // same tenants as the previous run.
+        let resume_from = if let state::Status::Applying {
             migrations: existing_migrations,
+            current_migration_index,
+            current_action_index,
+            current_tenant_index,
         } = &self.state.status
         {
             if existing_migrations != &remaining_migrations {
@@ -92,16 +201,50 @@ impl Reshape {
                     "a previous migration seems to have failed without cleaning up. Please run `reshape abort` and then run migrate again."
                 ));
             }
-        }
+
+            (
+                *current_migration_index,
+                *current_action_index,
+                *current_tenant_index,
+            )
+        } else {
+            self.state.tenants = match tenant_selector {
+                Some(selector) => selector
+                    .resolve(&mut self.db)
+                    .context("failed to select tenant schemas")?,
+                None => vec![],
+            };
+
+            // Backfill cursors are only kept while resuming the same migrations
+            State::clear_backfill_cursors(&mut self.db)
+                .context("failed to clear backfill cursors")?;
+
+            (0, 0, 0)
+        };
+        let tenants = self.state.tenants.clone();
 
         // Move to the "Applying" state which is necessary as we can't run the migrations
         // and state update as a single transaction. If a migration unexpectedly fails without
         // automatically aborting, this state saves us from dangling migrations. It forces the user
         // to either run migrate again (which works as all migrations are idempotent) or abort.
-        self.state.applying(remaining_migrations.clone());
+        let (migration_index, action_index, tenant_index) = resume_from;
+        self.state.applying(
+            remaining_migrations.clone(),
+            migration_index,
+            action_index,
+            tenant_index,
+        );
         self.state.save(&mut self.db)?;
 
-        println!("Applying {} migrations\n", remaining_migrations.len());
+        if tenants.is_empty() {
+            println!("Applying {} migrations\n", remaining_migrations.len());
+        } else {
+            println!(
+                "Applying {} migrations to {} tenants\n",
+                remaining_migrations.len(),
+                tenants.len()
+            );
+        }
 
         helpers::set_up_helpers(&mut self.db, current_migration)
             .context("failed to set up helpers")?;
@@ -109,9 +252,10 @@ impl Reshape {
         let mut new_schema = Schema::new();
         let mut last_migration_index = usize::MAX;
         let mut last_action_index = usize::MAX;
+        let mut last_tenant_index = usize::MAX;
         let mut result: anyhow::Result<()> = Ok(());
 
-        for (migration_index, migration) in remaining_migrations.iter().enumerate() {
+        'migrations: for (migration_index, migration) in remaining_migrations.iter().enumerate() {
             println!("Migrating '{}':", migration.name);
             last_migration_index = migration_index;
 
@@ -121,23 +265,52 @@ impl Reshape {
                 let description = action.describe();
                 print!("  + {} ", description);
 
-                let ctx = MigrationContext::new(migration_index, action_index);
-                result = action
-                    .run(&ctx, &mut self.db, &new_schema)
-                    .with_context(|| format!("failed to {}", description));
+                let ctx = self.migration_context(migration_index, action_index);
+                let action_schemas = schemas_for_action(action.as_ref(), &tenants);
+
+                for (tenant_index, action_schema) in action_schemas.into_iter().enumerate() {
+                    new_schema.set_current_schema(action_schema);
+
+                    // Skip tenants which were already applied before the previous run was interrupted
+                    if (migration_index, action_index, tenant_index) < resume_from {
+                        action.update_schema(&ctx, &mut new_schema);
+                        continue;
+                    }
+
+                    last_tenant_index = tenant_index;
+                    self.state.applying(
+                        remaining_migrations.clone(),
+                        migration_index,
+                        action_index,
+                        tenant_index,
+                    );
+
+                    result = self
+                        .state
+                        .save(&mut self.db)
+                        .context("failed to save state")
+                        .and_then(|_| set_search_path(&mut self.db, action_schema, true))
+                        .and_then(|_| action.run(&ctx, &mut self.db, &new_schema))
+                        .with_context(|| format!("failed to {}", description));
+
+                    if result.is_err() {
+                        println!("{}", "failed".red());
+                        break 'migrations;
+                    }
 
-                if result.is_ok() {
                     action.update_schema(&ctx, &mut new_schema);
-                    println!("{}", "done".green());
-                } else {
-                    println!("{}", "failed".red());
-                    break;
                 }
+
+                println!("{}", "done".green());
             }
 
             println!();
         }
 
+        self.db
+            .run("RESET search_path")
+            .context("failed to reset search path")?;
+
         // If a migration failed, we abort all the migrations that were applied
         if let Err(err) = result {
             println!("A migration failed, aborting migrations that have already been applied");
@@ -150,27 +323,51 @@ impl Reshape {
                 remaining_migrations.clone(),
                 last_migration_index + 1,
                 last_action_index + 1,
+                last_tenant_index + 1,
             );
 
             // Abort will only
-            self.abort()?;
+            self.abort_locked()?;
 
             return Err(err);
         }
 
-        // Create schema and views for migration
+        // Create schemas and views for migration
         let target_migration = remaining_migrations.last().unwrap().name.to_string();
-        self.create_schema_for_migration(&target_migration, &new_schema)
-            .with_context(|| {
-                format!("failed to create schema for migration {}", target_migration)
-            })?;
-
-        // Update state once migrations have been performed
+        let mut schemas = schemas_for_migrations(
+            self.state
+                .migrations
+                .iter()
+                .chain(remaining_migrations.iter()),
+        );
+        for tenant in &tenants {
+            if !schemas.contains(tenant) {
+                schemas.push(tenant.to_string());
+            }
+        }
+        Self::create_schema_for_migration(
+            &mut self.db,
+            &target_migration,
+            &schemas,
+            &mut new_schema,
+        )
+        .with_context(|| format!("failed to create schema for migration {}", target_migration))?;
+
+        // Update state once migrations have been performed. All backfills have finished,
+        // so their cursors aren't needed anymore.
+        State::clear_backfill_cursors(&mut self.db).context("failed to clear backfill cursors")?;
         self.state.in_progress(remaining_migrations);
         self.state
             .save(&mut self.db)
             .context("failed to save in-progress state")?;
 
+        // Every tenant uses its own schema of views
+        let schema_query = if tenants.is_empty() {
+            schema_query_for_migration_with_schemas(&target_migration, &schemas)
+        } else {
+            schema_query_for_migration_with_schemas(&target_migration, &["<tenant>".to_string()])
+        };
+
         // If we started from a blank slate, we can finish the migration immediately
         if current_migration.is_none() {
             println!("Automatically completing migrations\n");
@@ -180,13 +377,13 @@ impl Reshape {
             println!("Migrations complete:");
             println!(
                 "  - Run '{}' from your application to use the latest schema",
-                schema_query_for_migration(&target_migration)
+                schema_query
             );
         } else {
             println!("Migrations have been applied and the new schema is ready for use:");
             println!(
                 "  - Run '{}' from your application to use the latest schema",
-                schema_query_for_migration(&target_migration)
+                schema_query
             );
             println!(
                 "  - Run 'reshape complete' once your application has been updated and the previous schema is no longer in use"
@@ -196,22 +393,271 @@ impl Reshape {
         Ok(())
     }
 
+    // Record the SQL which starting, completing and aborting the remaining migrations would run,
+    // without changing the database. Starting the migrations is applied in a transaction which
+    // is rolled back at the end, so completing and aborting are planned against the started
+    // migrations, like when exporting.
+    pub fn plan<T>(&mut self, migrations: T) -> anyhow::Result<Plan>
+    where
+        T: IntoIterator<Item = Migration>,
+    {
+        self.state = State::load(&mut self.db);
+
+        let current_migration = self.state.current_migration.clone();
+        let remaining_migrations = self.state.get_remaining_migrations(migrations)?;
+        let schemas = schemas_for_migrations(
+            self.state
+                .migrations
+                .iter()
+                .chain(remaining_migrations.iter()),
+        );
+
+        let mut db = self
+            .db
+            .recording_with_changes(self.lock_retry_policy.lock_timeout)?;
+        let result = helpers::set_up_helpers(&mut db, &current_migration);
+        let setup = plan::take_statements(&mut db, result);
+
+        let mut new_schema = Schema::new();
+        let mut starts = Vec::new();
+
+        for (migration_index, migration) in remaining_migrations.iter().enumerate() {
+            for (action_index, action) in migration.actions.iter().enumerate() {
+                let ctx = MigrationContext::new(migration_index, action_index);
+                let action_schema = action.schema().unwrap_or(DEFAULT_SCHEMA);
+                new_schema.set_current_schema(action_schema);
+
+                let result = set_search_path(&mut db, action_schema, true)
+                    .and_then(|_| action.run(&ctx, &mut db, &new_schema));
+                starts.push(plan::take_statements(&mut db, result));
+                action.update_schema(&ctx, &mut new_schema);
+            }
+        }
+
+        let views = match remaining_migrations.last() {
+            Some(target_migration) => {
+                let result = Self::create_schema_for_migration(
+                    &mut db,
+                    &target_migration.name,
+                    &schemas,
+                    &mut new_schema,
+                );
+                plan::take_statements(&mut db, result)
+            }
+            None => vec![],
+        };
+
+        db.stop_applying_changes();
+
+        let mut actions = Vec::new();
+        let mut starts = starts.into_iter();
+
+        for (migration_index, migration) in remaining_migrations.iter().enumerate() {
+            for (action_index, action) in migration.actions.iter().enumerate() {
+                let ctx = MigrationContext::new(migration_index, action_index);
+                let action_schema = action.schema().unwrap_or(DEFAULT_SCHEMA);
+
+                let result = set_search_path(&mut db, action_schema, false).and_then(|_| {
+                    if let Some(transaction) = action.complete(&ctx, &mut db)? {
+                        transaction.commit()?;
+                    }
+                    Ok(())
+                });
+                let complete = plan::take_statements(&mut db, result);
+
+                let result = set_search_path(&mut db, action_schema, false)
+                    .and_then(|_| action.abort(&ctx, &mut db));
+                let abort = plan::take_statements(&mut db, result);
+
+                actions.push(ActionPlan {
+                    migration: migration.name.to_string(),
+                    description: action.describe(),
+                    start: starts.next().unwrap_or_default(),
+                    complete,
+                    abort,
+                });
+            }
+        }
+
+        Ok(Plan {
+            setup,
+            actions,
+            views,
+        })
+    }
+
+    // Export the remaining migrations as SQL scripts which can be run without Reshape: one to
+    // start the migrations, and one each to complete or abort them. The scripts include the
+    // state updates Reshape would make, so a database migrated using the scripts ends up in
+    // the same state as one migrated by Reshape.
+    pub fn export_sql<T>(&mut self, migrations: T) -> anyhow::Result<Vec<SqlScript>>
+    where
+        T: IntoIterator<Item = Migration>,
+    {
+        self.state = State::load(&mut self.db);
+
+        if !matches!(self.state.status, Status::Idle) {
+            return Err(anyhow!(
+                "a migration is already in progress, please complete or abort it before exporting"
+            ));
+        }
+
+        let current_migration = self.state.current_migration.clone();
+        let remaining_migrations = self.state.get_remaining_migrations(migrations)?;
+        let target_migration = match remaining_migrations.last() {
+            Some(migration) => migration.name.to_string(),
+            None => return Ok(vec![]),
+        };
+        let schemas = schemas_for_migrations(
+            self.state
+                .migrations
+                .iter()
+                .chain(remaining_migrations.iter()),
+        );
+
+        let mut state = self.state.clone();
+        state.tenants = vec![];
+        let idle_state = state.clone();
+
+        // Schema changes made when starting the migrations are applied in a transaction
+        // which is rolled back at the end, so the views are created from the new columns and
+        // tables. Completing and aborting are recorded against the started migrations.
+        // Locks are only waited for as long as the lock timeout, so exporting fails rather
+        // than blocking queries on busy tables.
+        let mut db = self
+            .db
+            .recording_with_changes(self.lock_retry_policy.lock_timeout)?;
+
+        // Start migrations
+        state.applying(remaining_migrations.clone(), 0, 0, 0);
+        db.run(&state.save_query()?)?;
+        helpers::set_up_helpers(&mut db, &current_migration).context("failed to set up helpers")?;
+
+        let mut new_schema = Schema::new();
+        for (migration_index, migration) in remaining_migrations.iter().enumerate() {
+            for (action_index, action) in migration.actions.iter().enumerate() {
+                let ctx = MigrationContext::new(migration_index, action_index);
+                let action_schema = action.schema().unwrap_or(DEFAULT_SCHEMA);
+                new_schema.set_current_schema(action_schema);
+
+                set_search_path(&mut db, action_schema, true)?;
+                action
+                    .run(&ctx, &mut db, &new_schema)
+                    .with_context(|| format!("failed to export {}", action.describe()))?;
+                action.update_schema(&ctx, &mut new_schema);
+            }
+        }
+
+        Self::create_schema_for_migration(&mut db, &target_migration, &schemas, &mut new_schema)
+            .with_context(|| {
+                format!("failed to export schema for migration {}", target_migration)
+            })?;
+
+        state.in_progress(remaining_migrations.clone());
+        db.run(&state.save_query()?)?;
+        let start = db.take_statements();
+        db.stop_applying_changes();
+
+        // Complete migrations
+        state.completing(remaining_migrations.clone(), 0, 0, 0);
+        db.run(&state.save_query()?)?;
+
+        if let Some(current_migration) = &current_migration {
+            drop_schemas_for_migration(&mut db, current_migration)?;
+        }
+
+        for (migration_index, migration) in remaining_migrations.iter().enumerate() {
+            for (action_index, action) in migration.actions.iter().enumerate() {
+                let ctx = MigrationContext::new(migration_index, action_index);
+                set_search_path(&mut db, action.schema().unwrap_or(DEFAULT_SCHEMA), false)?;
+
+                if let Some(transaction) = action
+                    .complete(&ctx, &mut db)
+                    .with_context(|| format!("failed to export {}", action.describe()))?
+                {
+                    transaction.commit()?;
+                }
+            }
+        }
+
+        db.run("RESET search_path")?;
+        helpers::tear_down_helpers(&mut db).context("failed to tear down helpers")?;
+        state.complete()?;
+        db.run(&state.save_query()?)?;
+        let complete = db.take_statements();
+
+        // Abort migrations. The views for the new migration don't exist yet,
+        // so they are dropped by name rather than looked up.
+        let mut state = idle_state;
+        state.aborting(
+            remaining_migrations.clone(),
+            usize::MAX,
+            usize::MAX,
+            usize::MAX,
+        );
+        db.run(&state.save_query()?)?;
+
+        for table_schema in &schemas {
+            db.run(&format!(
+                "DROP SCHEMA IF EXISTS {} CASCADE",
+                schema_name_for_migration_and_schema(&target_migration, table_schema)
+            ))?;
+        }
+
+        for (migration_index, migration) in remaining_migrations.iter().enumerate().rev() {
+            for (action_index, action) in migration.actions.iter().enumerate().rev() {
+                let ctx = MigrationContext::new(migration_index, action_index);
+                set_search_path(&mut db, action.schema().unwrap_or(DEFAULT_SCHEMA), false)?;
+                action
+                    .abort(&ctx, &mut db)
+                    .with_context(|| format!("failed to export {}", action.describe()))?;
+            }
+        }
+
+        db.run("RESET search_path")?;
+        helpers::tear_down_helpers(&mut db).context("failed to tear down helpers")?;
+        state.status = Status::Idle;
+        db.run(&state.save_query()?)?;
+        let abort = db.take_statements();
+
+        Ok(vec![
+            SqlScript {
+                name: format!("1_{}_start.sql", target_migration),
+                statements: start,
+            },
+            SqlScript {
+                name: format!("2_{}_complete.sql", target_migration),
+                statements: complete,
+            },
+            SqlScript {
+                // Not numbered as it's run instead of the complete script, not after it
+                name: format!("{}_abort.sql", target_migration),
+                statements: abort,
+            },
+        ])
+    }
+
     pub fn complete_migration(&mut self) -> anyhow::Result<()> {
+        self.with_lock(Self::complete_locked)
+    }
+
+    fn complete_locked(&mut self) -> anyhow::Result<()> {
         // Make sure a migration is in progress
-        let (remaining_migrations, starting_migration_index, starting_action_index) = match self.state.status.clone() {
+        let (remaining_migrations, resume_from) = match self.state.status.clone() {
             state::Status::InProgress { migrations } => {
                 // Move into the Completing state. Once in this state,
                 // the migration can't be aborted and must be completed.
-                self.state.completing(migrations.clone(), 0, 0);
+                self.state.completing(migrations.clone(), 0, 0, 0);
                 self.state.save(&mut self.db).context("failed to save state")?;
 
-                (migrations, 0, 0)
+                (migrations, (0, 0, 0))
             },
             state::Status::Completing {
                 migrations,
                 current_migration_index,
-                current_action_index
-            } => (migrations, current_migration_index, current_action_index),
+                current_action_index,
+                current_tenant_index,
+            } => (migrations, (current_migration_index, current_action_index, current_tenant_index)),
             state::Status::Aborting { .. } => {
                 return Err(anyhow!("migration been aborted and can't be completed. Please finish using `reshape abort`."))
             }
@@ -224,19 +670,17 @@ impl Reshape {
             }
         };
 
-        // Remove previous migration's schema
+        // Remove previous migration's schemas
         if let Some(current_migration) = &self.state.current_migration {
-            self.db
-                .run(&format!(
-                    "DROP SCHEMA IF EXISTS {} CASCADE",
-                    schema_name_for_migration(current_migration)
-                ))
+            drop_schemas_for_migration(&mut self.db, current_migration)
                 .context("failed to remove previous migration's schema")?;
         }
 
+        let tenants = self.state.tenants.clone();
+
         for (migration_index, migration) in remaining_migrations.iter().enumerate() {
             // Skip all the migrations which have already been completed
-            if migration_index < starting_migration_index {
+            if migration_index < resume_from.0 {
                 continue;
             }
 
@@ -244,80 +688,94 @@ impl Reshape {
 
             for (action_index, action) in migration.actions.iter().enumerate() {
                 // Skip all actions which have already been completed
-                if migration_index == starting_migration_index
-                    && action_index < starting_action_index
-                {
+                if (migration_index, action_index) < (resume_from.0, resume_from.1) {
                     continue;
                 }
 
                 let description = action.describe();
                 print!("  + {} ", description);
 
-                let ctx = MigrationContext::new(migration_index, action_index);
+                let ctx = self.migration_context(migration_index, action_index);
+                let action_schemas = schemas_for_action(action.as_ref(), &tenants);
 
-                // Update state to indicate that this action has been completed.
-                // We won't save this new state until after the action has completed.
-                self.state.completing(
-                    remaining_migrations.clone(),
-                    migration_index + 1,
-                    action_index + 1,
-                );
+                for (tenant_index, action_schema) in action_schemas.into_iter().enumerate() {
+                    // Skip all tenants which have already been completed
+                    if (migration_index, action_index, tenant_index) < resume_from {
+                        continue;
+                    }
 
-                // This did_save check is necessary because of the borrow checker.
-                // The Transaction which might be returned from action.complete
-                // contains a mutable reference to self.db. We need the Transaction
-                // to be dropped before we can save the state using self.db instead,
-                // which we achieve here by limiting the lifetime of the Transaction
-                // with a new block.
-                let did_save = {
-                    let result = action
-                        .complete(&ctx, &mut self.db)
-                        .with_context(|| format!("failed to complete migration {}", migration.name))
-                        .with_context(|| format!("failed to complete action: {}", description));
-
-                    let maybe_transaction = match result {
-                        Ok(maybe_transaction) => {
-                            println!("{}", "done".green());
-                            maybe_transaction
-                        }
-                        Err(e) => {
-                            println!("{}", "failed".red());
-                            return Err(e);
+                    set_search_path(&mut self.db, action_schema, false)?;
+
+                    // Update state to indicate that this action has been completed for the tenant.
+                    // We won't save this new state until after the action has completed.
+                    self.state.completing(
+                        remaining_migrations.clone(),
+                        migration_index,
+                        action_index,
+                        tenant_index + 1,
+                    );
+
+                    // This did_save check is necessary because of the borrow checker.
+                    // The Transaction which might be returned from action.complete
+                    // contains a mutable reference to self.db. We need the Transaction
+                    // to be dropped before we can save the state using self.db instead,
+                    // which we achieve here by limiting the lifetime of the Transaction
+                    // with a new block.
+                    let did_save = {
+                        let result = action
+                            .complete(&ctx, &mut self.db)
+                            .with_context(|| {
+                                format!("failed to complete migration {}", migration.name)
+                            })
+                            .with_context(|| format!("failed to complete action: {}", description));
+
+                        let maybe_transaction = match result {
+                            Ok(maybe_transaction) => maybe_transaction,
+                            Err(e) => {
+                                println!("{}", "failed".red());
+                                return Err(e);
+                            }
+                        };
+
+                        // Update state with which migrations and actions have been completed.
+                        // Each action can create and return a transaction if they need atomicity.
+                        // We use this transaction to update the state to ensure the action only completes.
+                        // once.
+                        // We want to use a single transaction for each action to keep the length of
+                        // the transaction as short as possible. Wherever possible, we don't want to
+                        // use a transaction at all.
+                        if let Some(mut transaction) = maybe_transaction {
+                            self.state
+                                .save(&mut transaction)
+                                .context("failed to save state after completing action")?;
+                            transaction
+                                .commit()
+                                .context("failed to commit transaction")?;
+
+                            true
+                        } else {
+                            false
                         }
                     };
 
-                    // Update state with which migrations and actions have been completed.
-                    // Each action can create and return a transaction if they need atomicity.
-                    // We use this transaction to update the state to ensure the action only completes.
-                    // once.
-                    // We want to use a single transaction for each action to keep the length of
-                    // the transaction as short as possible. Wherever possible, we don't want to
-                    // use a transaction at all.
-                    if let Some(mut transaction) = maybe_transaction {
+                    // If the action didn't return a transaction we save the state normally instead
+                    if !did_save {
                         self.state
-                            .save(&mut transaction)
+                            .save(&mut self.db)
                             .context("failed to save state after completing action")?;
-                        transaction
-                            .commit()
-                            .context("failed to commit transaction")?;
-
-                        true
-                    } else {
-                        false
                     }
-                };
-
-                // If the action didn't return a transaction we save the state normally instead
-                if !did_save {
-                    self.state
-                        .save(&mut self.db)
-                        .context("failed to save state after completing action")?;
                 }
+
+                println!("{}", "done".green());
             }
 
             println!();
         }
 
+        self.db
+            .run("RESET search_path")
+            .context("failed to reset search path")?;
+
         // Remove helpers which are no longer in use
         helpers::tear_down_helpers(&mut self.db).context("failed to tear down helpers")?;
 
@@ -332,33 +790,54 @@ impl Reshape {
     }
 
     fn create_schema_for_migration(
-        &mut self,
+        db: &mut impl Conn,
         migration_name: &str,
-        schema: &Schema,
+        schemas: &[String],
+        schema: &mut Schema,
     ) -> anyhow::Result<()> {
-        // Create schema for migration
-        let schema_name = schema_name_for_migration(migration_name);
-        self.db
-            .run(&format!("CREATE SCHEMA IF NOT EXISTS {}", schema_name))
-            .with_context(|| {
-                format!(
-                    "failed to create schema {} for migration {}",
-                    schema_name, migration_name
-                )
-            })?;
+        // Each Postgres schema with tables managed by Reshape gets its own schema of views
+        for table_schema in schemas {
+            let schema_name = schema_name_for_migration_and_schema(migration_name, table_schema);
+            db.run(&format!("CREATE SCHEMA IF NOT EXISTS {}", schema_name))
+                .with_context(|| {
+                    format!(
+                        "failed to create schema {} for migration {}",
+                        schema_name, migration_name
+                    )
+                })?;
+
+            // Defaults on the views are resolved against the schema of the tables
+            set_search_path(db, table_schema, false)?;
+            schema.set_current_schema(table_schema);
+
+            // Create views inside schema
+            for table in schema.get_tables(db)? {
+                Self::create_view_for_table(db, &table, &schema_name)?;
+            }
 
-        // Create views inside schema
-        for table in schema.get_tables(&mut self.db)? {
-            Self::create_view_for_table(&mut self.db, &table, &schema_name)?;
+            // Replaced types are exposed under their original name through a domain
+            for (name, real_name) in schema.get_types() {
+                db.run(&format!(
+                    r#"CREATE DOMAIN {schema_name}."{name}" AS "{table_schema}"."{real_name}""#,
+                    schema_name = schema_name,
+                    name = name,
+                    table_schema = table_schema,
+                    real_name = real_name,
+                ))
+                .with_context(|| format!("failed to create type {} in {}", name, schema_name))?;
+            }
         }
 
+        db.run("RESET search_path")
+            .context("failed to reset search path")?;
+
         Ok(())
     }
 
     fn create_view_for_table(
         db: &mut impl Conn,
         table: &Table,
-        schema: &str,
+        view_schema: &str,
     ) -> anyhow::Result<()> {
         let select_columns: Vec<String> = table
             .columns
@@ -376,46 +855,106 @@ impl Reshape {
 
         db.run(&format!(
             r#"
-            CREATE OR REPLACE VIEW {schema}."{view_name}" AS
+            CREATE OR REPLACE VIEW {view_schema}."{view_name}" AS
                 SELECT {columns}
-                FROM "{table_name}"
+                FROM "{table_schema}"."{table_name}"
             "#,
-            schema = schema,
+            view_schema = view_schema,
+            table_schema = table.schema,
             table_name = table.real_name,
             view_name = table.name,
             columns = select_columns.join(","),
         ))
         .with_context(|| format!("failed to create view for table {}", table.name))?;
 
+        // Inserts through a view use the view's defaults rather than the table's.
+        // This lets each schema have its own defaults while a migration is in progress.
+        for column in &table.columns {
+            if let Some(default) = &column.default {
+                db.run(&format!(
+                    r#"
+                    ALTER VIEW {view_schema}."{view_name}" ALTER COLUMN "{column}" SET DEFAULT {default}
+                    "#,
+                    view_schema = view_schema,
+                    view_name = table.name,
+                    column = column.name,
+                    default = default,
+                ))
+                .with_context(|| {
+                    format!(
+                        "failed to set default for column {} on view {}",
+                        column.name, table.name
+                    )
+                })?;
+            }
+        }
+
         Ok(())
     }
 
     pub fn remove(&mut self) -> anyhow::Result<()> {
+        self.with_lock(Self::remove_locked)
+    }
+
+    fn remove_locked(&mut self) -> anyhow::Result<()> {
         // Remove migration schemas and views
         if let Some(current_migration) = &self.state.current_migration {
-            self.db.run(&format!(
-                "DROP SCHEMA IF EXISTS {} CASCADE",
-                schema_name_for_migration(current_migration)
-            ))?;
+            drop_schemas_for_migration(&mut self.db, current_migration)?;
         }
 
-        if let Status::InProgress { migrations } = &self.state.status {
-            let target_migration = migrations.last().unwrap().name.to_string();
-            self.db.run(&format!(
-                "DROP SCHEMA IF EXISTS {} CASCADE",
-                schema_name_for_migration(&target_migration)
-            ))?;
+        let mut migrations = self.state.migrations.clone();
+        if let Status::InProgress {
+            migrations: in_progress_migrations,
+        } = &self.state.status
+        {
+            let target_migration = in_progress_migrations.last().unwrap().name.to_string();
+            drop_schemas_for_migration(&mut self.db, &target_migration)?;
+            migrations.extend(in_progress_migrations.iter().cloned());
         }
 
-        // Remove all tables
-        let schema = Schema::new();
-        for table in schema.get_tables(&mut self.db)? {
-            self.db.run(&format!(
-                r#"
-                DROP TABLE IF EXISTS "{}" CASCADE
-                "#,
-                table.real_name
-            ))?;
+        let mut schemas = schemas_for_migrations(&migrations);
+        for tenant in &self.state.tenants {
+            if !schemas.contains(tenant) {
+                schemas.push(tenant.to_string());
+            }
+        }
+
+        for table_schema in schemas {
+            // Remove all tables
+            let mut schema = Schema::new();
+            schema.set_current_schema(&table_schema);
+            for table in schema.get_tables(&mut self.db)? {
+                self.db.run(&format!(
+                    r#"
+                    DROP TABLE IF EXISTS "{}"."{}" CASCADE
+                    "#,
+                    table_schema, table.real_name
+                ))?;
+            }
+
+            // Remove all enums
+            let enums: Vec<String> = self
+                .db
+                .query_with_params(
+                    "
+                    SELECT pg_type.typname
+                    FROM pg_catalog.pg_type
+                    JOIN pg_catalog.pg_namespace ON pg_namespace.oid = pg_type.typnamespace
+                    WHERE pg_type.typtype = 'e' AND pg_namespace.nspname = $1
+                    ",
+                    &[&table_schema],
+                )?
+                .iter()
+                .map(|row| row.get("typname"))
+                .collect();
+            for enum_name in enums {
+                self.db.run(&format!(
+                    r#"
+                    DROP TYPE IF EXISTS "{}"."{}" CASCADE
+                    "#,
+                    table_schema, enum_name
+                ))?;
+            }
         }
 
         // Reset state
@@ -427,24 +966,57 @@ impl Reshape {
     }
 
     pub fn abort(&mut self) -> anyhow::Result<()> {
-        let (remaining_migrations, last_migration_index, last_action_index) = match self
-            .state
-            .status
-            .clone()
-        {
-            Status::InProgress { migrations } | Status::Applying { migrations } => {
+        self.with_lock(Self::abort_locked)
+    }
+
+    fn abort_locked(&mut self) -> anyhow::Result<()> {
+        let (remaining_migrations, upper_bound) = match self.state.status.clone() {
+            Status::InProgress { migrations } => {
                 // Set to the Aborting state. Once this is done, the migration has to
                 // be fully aborted and can't be completed.
-                self.state.aborting(migrations.clone(), 0, 0);
+                let upper_bound = (usize::MAX, usize::MAX, usize::MAX);
+                self.state.aborting(
+                    migrations.clone(),
+                    upper_bound.0,
+                    upper_bound.1,
+                    upper_bound.2,
+                );
                 self.state.save(&mut self.db)?;
 
-                (migrations, usize::MAX, usize::MAX)
+                (migrations, upper_bound)
+            }
+            Status::Applying {
+                migrations,
+                current_migration_index,
+                current_action_index,
+                current_tenant_index,
+            } => {
+                // Only the tenants which were applied before the run was interrupted,
+                // including the one in progress, have to be aborted
+                let upper_bound = (
+                    current_migration_index + 1,
+                    current_action_index + 1,
+                    current_tenant_index + 1,
+                );
+                self.state.aborting(
+                    migrations.clone(),
+                    upper_bound.0,
+                    upper_bound.1,
+                    upper_bound.2,
+                );
+                self.state.save(&mut self.db)?;
+
+                (migrations, upper_bound)
             }
             Status::Aborting {
                 migrations,
                 last_migration_index,
                 last_action_index,
-            } => (migrations, last_migration_index, last_action_index),
+                last_tenant_index,
+            } => (
+                migrations,
+                (last_migration_index, last_action_index, last_tenant_index),
+            ),
             Status::Completing { .. } => {
                 return Err(anyhow!("Migration completion has already been started. Please run `reshape complete` again to finish it."));
             }
@@ -454,21 +1026,21 @@ impl Reshape {
             }
         };
 
-        // Remove new migration's schema
+        // Remove new migration's schemas
         let target_migration = remaining_migrations.last().unwrap().name.to_string();
-        let schema_name = schema_name_for_migration(&target_migration);
-        self.db
-            .run(&format!("DROP SCHEMA IF EXISTS {} CASCADE", schema_name,))
-            .with_context(|| format!("failed to drop schema {}", schema_name))?;
+        drop_schemas_for_migration(&mut self.db, &target_migration).with_context(|| {
+            format!("failed to drop schemas for migration {}", target_migration)
+        })?;
 
         // Abort all pending migrations
-        self.abort_migrations(
-            &remaining_migrations,
-            last_migration_index,
-            last_action_index,
-        )?;
+        self.abort_migrations(&remaining_migrations, upper_bound)?;
+
+        self.db
+            .run("RESET search_path")
+            .context("failed to reset search path")?;
 
         helpers::tear_down_helpers(&mut self.db).context("failed to tear down helpers")?;
+        State::clear_backfill_cursors(&mut self.db).context("failed to clear backfill cursors")?;
 
         self.state.status = state::Status::Idle;
         self.state
@@ -481,9 +1053,11 @@ impl Reshape {
     fn abort_migrations(
         &mut self,
         migrations: &[Migration],
-        upper_migration_index: usize,
-        upper_action_index: usize,
+        upper_bound: (usize, usize, usize),
     ) -> anyhow::Result<()> {
+        let (upper_migration_index, upper_action_index, upper_tenant_index) = upper_bound;
+        let tenants = self.state.tenants.clone();
+
         // Abort all migrations in reverse order
         for (migration_index, migration) in migrations.iter().enumerate().rev() {
             // Skip migrations which shouldn't be aborted
@@ -505,19 +1079,38 @@ impl Reshape {
                     continue;
                 }
 
-                let ctx = MigrationContext::new(migration_index, action_index);
-                action
-                    .abort(&ctx, &mut self.db)
-                    .with_context(|| format!("failed to abort migration {}", migration.name))
-                    .with_context(|| format!("failed to abort action: {}", action.describe()))?;
-
-                // Update state with which migrations and actions have been aborted.
-                // We don't need to run this in a transaction as aborts are idempotent.
-                self.state
-                    .aborting(migrations.to_vec(), migration_index, action_index);
-                self.state
-                    .save(&mut self.db)
-                    .context("failed to save state")?;
+                let ctx = self.migration_context(migration_index, action_index);
+                let action_schemas = schemas_for_action(action.as_ref(), &tenants);
+
+                for (tenant_index, action_schema) in action_schemas.into_iter().enumerate().rev() {
+                    // Skip tenants which the last action was never applied to
+                    if migration_index == upper_migration_index - 1
+                        && action_index == upper_action_index - 1
+                        && tenant_index >= upper_tenant_index
+                    {
+                        continue;
+                    }
+
+                    set_search_path(&mut self.db, action_schema, false)?;
+                    action
+                        .abort(&ctx, &mut self.db)
+                        .with_context(|| format!("failed to abort migration {}", migration.name))
+                        .with_context(|| {
+                            format!("failed to abort action: {}", action.describe())
+                        })?;
+
+                    // Update state with which migrations, actions and tenants have been aborted.
+                    // We don't need to run this in a transaction as aborts are idempotent.
+                    self.state.aborting(
+                        migrations.to_vec(),
+                        migration_index + 1,
+                        action_index + 1,
+                        tenant_index,
+                    );
+                    self.state
+                        .save(&mut self.db)
+                        .context("failed to save state")?;
+                }
             }
 
             println!("{}", "done".green());
@@ -537,6 +1130,87 @@ pub fn schema_query_for_migration(migration_name: &str) -> String {
     format!("SET search_path TO {}", schema_name)
 }
 
+// Build the search path query for a migration which manages tables in several Postgres schemas.
+// The views for every schema are put on the search path, starting with `public`.
+pub fn schema_query_for_migration_with_schemas(migration_name: &str, schemas: &[String]) -> String {
+    let schema_names: Vec<String> = schemas
+        .iter()
+        .map(|schema| schema_name_for_migration_and_schema(migration_name, schema))
+        .collect();
+    format!("SET search_path TO {}", schema_names.join(", "))
+}
+
+// All Postgres schemas which actions in the migrations apply to. `public` always comes first
+// followed by the other schemas in the order they are first used.
+pub fn schemas_for_migrations<'a>(
+    migrations: impl IntoIterator<Item = &'a Migration>,
+) -> Vec<String> {
+    let mut schemas = vec![DEFAULT_SCHEMA.to_string()];
+    for migration in migrations {
+        for action in &migration.actions {
+            if let Some(schema) = action.schema() {
+                if !schemas.iter().any(|existing| existing == schema) {
+                    schemas.push(schema.to_string());
+                }
+            }
+        }
+    }
+    schemas
+}
+
 fn schema_name_for_migration(migration_name: &str) -> String {
     format!("migration_{}", migration_name)
 }
+
+// Views for tables in `public` live in `migration_{name}` and views for tables
+// in any other schema live in `migration_{name}__{schema}`.
+fn schema_name_for_migration_and_schema(migration_name: &str, schema: &str) -> String {
+    if schema == DEFAULT_SCHEMA {
+        schema_name_for_migration(migration_name)
+    } else {
+        format!("migration_{}__{}", migration_name, schema)
+    }
+}
+
+fn drop_schemas_for_migration(db: &mut impl Conn, migration_name: &str) -> anyhow::Result<()> {
+    let schema_name = schema_name_for_migration(migration_name);
+    let schemas: Vec<String> = db
+        .query_with_params(
+            r#"
+            SELECT nspname
+            FROM pg_catalog.pg_namespace
+            WHERE nspname = $1 OR nspname LIKE $1 || '\_\_%'
+            "#,
+            &[&schema_name],
+        )?
+        .iter()
+        .map(|row| row.get("nspname"))
+        .collect();
+
+    for schema in schemas {
+        db.run(&format!("DROP SCHEMA IF EXISTS \"{}\" CASCADE", schema))
+            .with_context(|| format!("failed to drop schema {}", schema))?;
+    }
+
+    Ok(())
+}
+
+// Point the search path at the Postgres schema an action applies to, so unqualified
+// names in the action resolve to it. The schema is created if it doesn't exist yet.
+fn set_search_path(db: &mut impl Conn, schema: &str, create: bool) -> anyhow::Result<()> {
+    if create {
+        let exists = !db
+            .query_with_params(
+                "SELECT 1 FROM pg_catalog.pg_namespace WHERE nspname = $1",
+                &[&schema],
+            )?
+            .is_empty();
+        if !exists {
+            db.run(&format!("CREATE SCHEMA \"{}\"", schema))
+                .with_context(|| format!("failed to create schema {}", schema))?;
+        }
+    }
+
+    db.run(&format!("SET search_path TO \"{}\"", schema))
+        .with_context(|| format!("failed to set search path to {}", schema))
+}
@@ -0,0 +1,82 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::db::Conn;
+
+// Key of the session-level advisory lock held while Reshape changes a database,
+// the bytes of "resh". Other tools can take the same lock to keep Reshape out.
+pub const LOCK_KEY: i64 = 0x7265_7368;
+
+// How long to wait between attempts to take the lock
+const RETRY_INTERVAL: Duration = Duration::from_millis(200);
+
+// Take the advisory lock, waiting up to `timeout` for another process to release it.
+// Advisory locks are reentrant so taking it again on the same connection succeeds.
+pub(crate) fn acquire(db: &mut impl Conn, timeout: Duration) -> anyhow::Result<()> {
+    let started_at = Instant::now();
+
+    loop {
+        let locked: bool = db
+            .query_with_params("SELECT pg_try_advisory_lock($1)", &[&LOCK_KEY])
+            .context("failed to take advisory lock")?
+            .first()
+            .map(|row| row.get(0))
+            .unwrap_or(false);
+        if locked {
+            return Ok(());
+        }
+
+        if started_at.elapsed() >= timeout {
+            return Err(anyhow!(
+                "another Reshape process is running against this database ({}), gave up waiting after {:?}",
+                describe_holder(db)?,
+                timeout
+            ));
+        }
+
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+pub(crate) fn release(db: &mut impl Conn) -> anyhow::Result<()> {
+    db.query_with_params("SELECT pg_advisory_unlock($1)", &[&LOCK_KEY])
+        .context("failed to release advisory lock")?;
+    Ok(())
+}
+
+// Describe the session holding the lock using its PID and application name.
+// A bigint advisory lock key is split into `classid` and `objid` in pg_locks.
+fn describe_holder(db: &mut impl Conn) -> anyhow::Result<String> {
+    let holder = db
+        .query_with_params(
+            r#"
+            SELECT locks.pid, activity.application_name
+            FROM pg_catalog.pg_locks AS locks
+            LEFT JOIN pg_catalog.pg_stat_activity AS activity ON activity.pid = locks.pid
+            WHERE locks.locktype = 'advisory'
+            AND locks.granted
+            AND locks.database = (SELECT oid FROM pg_catalog.pg_database WHERE datname = current_database())
+            AND locks.classid::BIGINT = $1::BIGINT >> 32
+            AND locks.objid::BIGINT = $1::BIGINT & x'FFFFFFFF'::BIGINT
+            AND locks.objsubid = 1
+            "#,
+            &[&LOCK_KEY],
+        )
+        .context("failed to look up advisory lock holder")?
+        .first()
+        .map(|row| {
+            let pid: i32 = row.get("pid");
+            let application_name: Option<String> = row.get("application_name");
+            match application_name.filter(|name| !name.is_empty()) {
+                Some(name) => format!("held by PID {} using application '{}'", pid, name),
+                None => format!("held by PID {}", pid),
+            }
+        });
+
+    // The lock might have been released since we last tried to take it
+    Ok(holder.unwrap_or_else(|| "lock holder has since disconnected".to_string()))
+}
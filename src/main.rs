@@ -2,12 +2,14 @@ use std::{
     fs::{self, File},
     io::Read,
     path::Path,
+    time::Duration,
 };
 
-use clap::{Args, Parser};
+use anyhow::{anyhow, Context};
+use clap::{Args, Parser, Subcommand};
 use reshape::{
-    migrations::{Action, Migration},
-    Reshape,
+    migrations::{Action, BackfillPolicy, LockRetryPolicy, Migration},
+    Fleet, FleetResult, Reshape, TenantSelector,
 };
 use serde::{Deserialize, Serialize};
 
@@ -20,16 +22,79 @@ struct Opts {
 #[derive(Parser)]
 enum Command {
     Migrate(MigrateOptions),
+    Plan(PlanOptions),
+    ExportSql(ExportSqlOptions),
     Complete(ConnectionOptions),
     Remove(ConnectionOptions),
     Abort(ConnectionOptions),
-    GenerateSchemaQuery(FindMigrationsOptions),
+    GenerateSchemaQuery(GenerateSchemaQueryOptions),
+    #[clap(subcommand)]
+    Fleet(FleetCommand),
+}
+
+// Run commands against every database in a fleet
+#[derive(Subcommand)]
+enum FleetCommand {
+    Migrate(FleetMigrateOptions),
+    Complete(FleetOptions),
+    Abort(FleetOptions),
+}
+
+#[derive(Args)]
+struct FleetMigrateOptions {
+    #[clap(long, short)]
+    complete: bool,
+    #[clap(flatten)]
+    fleet_options: FleetOptions,
+    #[clap(flatten)]
+    backfill_options: BackfillOptions,
+    #[clap(flatten)]
+    find_migrations_options: FindMigrationsOptions,
+}
+
+#[derive(Args)]
+struct FleetOptions {
+    // File with one connection URL per line, blank lines and lines starting with # are ignored
+    #[clap(long)]
+    urls_file: String,
+    // Maximum number of databases to work on at the same time
+    #[clap(long, default_value = "4")]
+    concurrency: usize,
+    #[clap(flatten)]
+    lock_options: LockOptions,
 }
 
 #[derive(Args)]
 struct MigrateOptions {
     #[clap(long, short)]
     complete: bool,
+    // Apply the migrations to every tenant schema matching a LIKE pattern
+    #[clap(long, conflicts_with = "tenants-query")]
+    tenants_pattern: Option<String>,
+    // Apply the migrations to every tenant schema returned by a query
+    #[clap(long)]
+    tenants_query: Option<String>,
+    #[clap(flatten)]
+    backfill_options: BackfillOptions,
+    #[clap(flatten)]
+    connection_options: ConnectionOptions,
+    #[clap(flatten)]
+    find_migrations_options: FindMigrationsOptions,
+}
+
+#[derive(Args)]
+struct PlanOptions {
+    #[clap(flatten)]
+    connection_options: ConnectionOptions,
+    #[clap(flatten)]
+    find_migrations_options: FindMigrationsOptions,
+}
+
+#[derive(Args)]
+struct ExportSqlOptions {
+    // Directory to write the SQL scripts to
+    #[clap(long, default_value = "sql")]
+    output_dir: String,
     #[clap(flatten)]
     connection_options: ConnectionOptions,
     #[clap(flatten)]
@@ -50,6 +115,79 @@ struct ConnectionOptions {
     username: String,
     #[clap(long, short, default_value = "postgres")]
     password: String,
+    #[clap(flatten)]
+    lock_options: LockOptions,
+}
+
+#[derive(Args)]
+struct LockOptions {
+    // Seconds to wait for another Reshape process working on the database to finish
+    #[clap(long, default_value = "30")]
+    lock_wait_timeout: u64,
+    // Milliseconds DDL may wait for a lock on a table before giving up and retrying
+    #[clap(long, default_value = "2000")]
+    lock_timeout: u64,
+    // Milliseconds DDL may run for before being cancelled, no limit by default
+    #[clap(long)]
+    statement_timeout: Option<u64>,
+    // Number of times to retry DDL which timed out waiting for a lock
+    #[clap(long, default_value = "5")]
+    lock_retries: u32,
+    // Milliseconds to wait before the first retry, doubling on every retry
+    #[clap(long, default_value = "1000")]
+    lock_retry_backoff: u64,
+    // Maximum milliseconds to wait between retries
+    #[clap(long, default_value = "30000")]
+    lock_retry_max_backoff: u64,
+}
+
+impl LockOptions {
+    fn apply(&self, reshape: &mut Reshape) {
+        reshape.set_lock_wait_timeout(Duration::from_secs(self.lock_wait_timeout));
+        reshape.set_lock_retry_policy(LockRetryPolicy {
+            lock_timeout: Duration::from_millis(self.lock_timeout),
+            statement_timeout: self.statement_timeout.map(Duration::from_millis),
+            max_retries: self.lock_retries,
+            initial_backoff: Duration::from_millis(self.lock_retry_backoff),
+            max_backoff: Duration::from_millis(self.lock_retry_max_backoff),
+        });
+    }
+}
+
+#[derive(Args)]
+struct BackfillOptions {
+    // Rows per backfill batch, or the size of the first batch with --target-batch-duration
+    #[clap(long, default_value = "1000")]
+    batch_size: u32,
+    // Adapt the batch size so that each batch takes about this many milliseconds
+    #[clap(long)]
+    target_batch_duration: Option<u64>,
+    // Largest batch size when adapting the batch size
+    #[clap(long, default_value = "100000")]
+    max_batch_size: u32,
+    // Milliseconds to pause between batches
+    #[clap(long, default_value = "0")]
+    batch_sleep: u64,
+}
+
+impl BackfillOptions {
+    fn apply(&self, reshape: &mut Reshape) {
+        reshape.set_backfill_policy(BackfillPolicy {
+            batch_size: self.batch_size,
+            target_batch_duration: self.target_batch_duration.map(Duration::from_millis),
+            max_batch_size: self.max_batch_size,
+            sleep_between_batches: Duration::from_millis(self.batch_sleep),
+        });
+    }
+}
+
+#[derive(Args)]
+struct GenerateSchemaQueryOptions {
+    // Generate the query for a single tenant schema
+    #[clap(long)]
+    tenant: Option<String>,
+    #[clap(flatten)]
+    find_migrations_options: FindMigrationsOptions,
 }
 
 #[derive(Parser)]
@@ -67,8 +205,18 @@ fn run(opts: Opts) -> anyhow::Result<()> {
     match opts.cmd {
         Command::Migrate(opts) => {
             let mut reshape = reshape_from_connection_options(&opts.connection_options)?;
+            opts.backfill_options.apply(&mut reshape);
             let migrations = find_migrations(&opts.find_migrations_options)?;
-            reshape.migrate(migrations)?;
+
+            let tenants = match (opts.tenants_pattern, opts.tenants_query) {
+                (Some(pattern), _) => Some(TenantSelector::Pattern(pattern)),
+                (_, Some(query)) => Some(TenantSelector::Query(query)),
+                _ => None,
+            };
+            match tenants {
+                Some(tenants) => reshape.migrate_tenants(migrations, &tenants)?,
+                None => reshape.migrate(migrations)?,
+            }
 
             // Automatically complete migration if --complete flag is set
             if opts.complete {
@@ -77,6 +225,40 @@ fn run(opts: Opts) -> anyhow::Result<()> {
 
             Ok(())
         }
+        Command::Plan(opts) => {
+            let mut reshape = reshape_from_connection_options(&opts.connection_options)?;
+            let migrations = find_migrations(&opts.find_migrations_options)?;
+            let plan = reshape.plan(migrations)?;
+
+            if plan.is_empty() {
+                println!("No migrations left to apply");
+            } else {
+                print!("{}", plan);
+            }
+
+            Ok(())
+        }
+        Command::ExportSql(opts) => {
+            let mut reshape = reshape_from_connection_options(&opts.connection_options)?;
+            let migrations = find_migrations(&opts.find_migrations_options)?;
+            let scripts = reshape.export_sql(migrations)?;
+
+            if scripts.is_empty() {
+                println!("No migrations left to apply");
+                return Ok(());
+            }
+
+            fs::create_dir_all(&opts.output_dir)
+                .with_context(|| format!("failed to create directory {}", opts.output_dir))?;
+            for script in scripts {
+                let path = Path::new(&opts.output_dir).join(&script.name);
+                fs::write(&path, script.to_string())
+                    .with_context(|| format!("failed to write {}", path.display()))?;
+                println!("Wrote {}", path.display());
+            }
+
+            Ok(())
+        }
         Command::Complete(opts) => {
             let mut reshape = reshape_from_connection_options(&opts)?;
             reshape.complete_migration()
@@ -89,26 +271,103 @@ fn run(opts: Opts) -> anyhow::Result<()> {
             let mut reshape = reshape_from_connection_options(&opts)?;
             reshape.abort()
         }
-        Command::GenerateSchemaQuery(find_migrations_options) => {
-            let migrations = find_migrations(&find_migrations_options)?;
-            let query = migrations
-                .last()
-                .map(|migration| reshape::schema_query_for_migration(&migration.name));
+        Command::GenerateSchemaQuery(opts) => {
+            let migrations = find_migrations(&opts.find_migrations_options)?;
+            let schemas = match opts.tenant {
+                Some(tenant) => vec![tenant],
+                None => reshape::schemas_for_migrations(&migrations),
+            };
+            let query = migrations.last().map(|migration| {
+                reshape::schema_query_for_migration_with_schemas(&migration.name, &schemas)
+            });
             println!("{}", query.unwrap_or_else(|| "".to_string()));
 
             Ok(())
         }
+        Command::Fleet(FleetCommand::Migrate(opts)) => {
+            let fleet = fleet_from_options(&opts.fleet_options)?;
+            let migrations = find_migrations(&opts.find_migrations_options)?;
+            let results = fleet.run(|reshape| {
+                opts.fleet_options.lock_options.apply(reshape);
+                opts.backfill_options.apply(reshape);
+                reshape.migrate(migrations.clone())?;
+
+                // Automatically complete migration if --complete flag is set
+                if opts.complete {
+                    reshape.complete_migration()?;
+                }
+
+                Ok(())
+            });
+            report_fleet_results(&results, "migrate")
+        }
+        Command::Fleet(FleetCommand::Complete(opts)) => {
+            let fleet = fleet_from_options(&opts)?;
+            let results = fleet.run(|reshape| {
+                opts.lock_options.apply(reshape);
+                reshape.complete_migration()
+            });
+            report_fleet_results(&results, "complete")
+        }
+        Command::Fleet(FleetCommand::Abort(opts)) => {
+            let fleet = fleet_from_options(&opts)?;
+            let results = fleet.run(|reshape| {
+                opts.lock_options.apply(reshape);
+                reshape.abort()
+            });
+            report_fleet_results(&results, "abort")
+        }
     }
 }
 
+fn fleet_from_options(opts: &FleetOptions) -> anyhow::Result<Fleet> {
+    let contents = fs::read_to_string(&opts.urls_file)
+        .with_context(|| format!("failed to read {}", opts.urls_file))?;
+    let urls: Vec<String> = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+
+    if urls.is_empty() {
+        return Err(anyhow!("no connection URLs found in {}", opts.urls_file));
+    }
+
+    Ok(Fleet::new(urls, opts.concurrency))
+}
+
+fn report_fleet_results(results: &[FleetResult], command: &str) -> anyhow::Result<()> {
+    println!("\n{}", reshape::status_table(results));
+
+    let failed = results
+        .iter()
+        .filter(|result| result.error.is_some())
+        .count();
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} of {} databases failed to {}. Run `reshape fleet abort` to roll back, or fix the failing databases and run `reshape fleet {}` again",
+            failed,
+            results.len(),
+            command,
+            command,
+        ));
+    }
+
+    Ok(())
+}
+
 fn reshape_from_connection_options(opts: &ConnectionOptions) -> anyhow::Result<Reshape> {
     let env_url = std::env::var("POSTGRES_URL").ok();
-    let url = env_url.as_ref().or_else(|| opts.url.as_ref());
+    let url = env_url.as_ref().or(opts.url.as_ref());
 
-    match url {
-        Some(url) => Reshape::new(url),
-        None => Reshape::new_with_options(&opts.host, opts.port, &opts.username, &opts.password),
-    }
+    let mut reshape = match url {
+        Some(url) => Reshape::new(url)?,
+        None => Reshape::new_with_options(&opts.host, opts.port, &opts.username, &opts.password)?,
+    };
+    opts.lock_options.apply(&mut reshape);
+
+    Ok(reshape)
 }
 
 fn find_migrations(opts: &FindMigrationsOptions) -> anyhow::Result<Vec<Migration>> {
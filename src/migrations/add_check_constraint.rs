@@ -0,0 +1,122 @@
+use super::{common, Action, MigrationContext};
+use crate::{
+    db::{Conn, Transaction},
+    schema::Schema,
+};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AddCheckConstraint {
+    pub table: String,
+    pub name: String,
+    pub check: String,
+
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+impl AddCheckConstraint {
+    fn temp_constraint_name(&self, ctx: &MigrationContext) -> String {
+        format!("{}_temp_check", ctx.prefix())
+    }
+}
+
+#[typetag::serde(name = "add_check_constraint")]
+impl Action for AddCheckConstraint {
+    fn describe(&self) -> String {
+        format!(
+            "Adding check constraint \"{}\" to \"{}\"",
+            self.name, self.table
+        )
+    }
+
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    fn run(
+        &self,
+        ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        schema: &Schema,
+    ) -> anyhow::Result<()> {
+        let table = schema.get_table(db, &self.table)?;
+
+        // The check is written using the column names of the new schema,
+        // which might be backed by differently named columns during the migration
+        let check = common::rewrite_column_references(&self.check, |name| {
+            table
+                .columns
+                .iter()
+                .find(|column| column.name == name)
+                .map(|column| column.real_name.to_string())
+        });
+
+        // Add the check as NOT VALID so that existing rows aren't scanned under
+        // an exclusive lock. New rows will be checked immediately and the
+        // existing rows are validated when the migration is completed.
+        let query = format!(
+            r#"
+            ALTER TABLE "{table}"
+            DROP CONSTRAINT IF EXISTS "{constraint_name}";
+
+            ALTER TABLE "{table}"
+            ADD CONSTRAINT "{constraint_name}"
+            CHECK ({check}) NOT VALID
+            "#,
+            table = table.real_name,
+            constraint_name = self.temp_constraint_name(ctx),
+            check = check,
+        );
+        db.run(&query).context("failed to add check constraint")?;
+
+        Ok(())
+    }
+
+    fn complete<'a>(
+        &self,
+        ctx: &MigrationContext,
+        db: &'a mut dyn Conn,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        let mut transaction = db.transaction().context("failed to create transaction")?;
+
+        // Validate the constraint for all existing rows.
+        // This performs a sequential scan but does not take an exclusive lock.
+        let query = format!(
+            r#"
+            ALTER TABLE "{table}"
+            VALIDATE CONSTRAINT "{constraint_name}"
+            "#,
+            table = self.table,
+            constraint_name = self.temp_constraint_name(ctx),
+        );
+        transaction
+            .run(&query)
+            .context("failed to validate check constraint")?;
+
+        // Rename the constraint to its final name
+        let query = format!(
+            r#"
+            ALTER TABLE "{table}"
+            RENAME CONSTRAINT "{temp_constraint_name}" TO "{constraint_name}"
+            "#,
+            table = self.table,
+            temp_constraint_name = self.temp_constraint_name(ctx),
+            constraint_name = self.name,
+        );
+        transaction
+            .run(&query)
+            .context("failed to rename check constraint")?;
+
+        Ok(Some(transaction))
+    }
+
+    fn update_schema(&self, _ctx: &MigrationContext, _schema: &mut Schema) {}
+
+    fn abort(&self, ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
+        common::drop_temporary_constraint(db, &self.temp_constraint_name(ctx))
+            .context("failed to drop check constraint")?;
+        Ok(())
+    }
+}
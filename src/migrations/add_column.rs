@@ -1,4 +1,4 @@
-use super::{common, Action, Column, MigrationContext};
+use super::{backfill, common, Action, Column, Dependency, MigrationContext};
 use crate::{
     db::{Conn, Transaction},
     schema::Schema,
@@ -11,10 +11,16 @@ pub struct AddColumn {
     pub table: String,
     pub up: Option<String>,
     pub column: Column,
+
+    #[serde(default)]
+    pub dependencies: Vec<Dependency>,
+
+    #[serde(default)]
+    pub schema: Option<String>,
 }
 
 impl AddColumn {
-    fn temp_column_name(&self, ctx: &MigrationContext) -> String {
+    pub(super) fn temp_column_name(&self, ctx: &MigrationContext) -> String {
         format!(
             "{}_temp_column_{}_{}",
             ctx.prefix(),
@@ -51,6 +57,10 @@ impl Action for AddColumn {
         )
     }
 
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
     fn run(
         &self,
         ctx: &MigrationContext,
@@ -84,7 +94,7 @@ impl Action for AddColumn {
             table = self.table,
             definition = definition_parts.join(" "),
         );
-        db.run(&query).context("failed to add column")?;
+        ctx.run_ddl(db, &query).context("failed to add column")?;
 
         if let Some(up) = &self.up {
             let table = schema.get_table(db, &self.table)?;
@@ -94,7 +104,8 @@ impl Action for AddColumn {
                 .iter()
                 .map(|column| {
                     format!(
-                        "{alias} public.{table}.{real_name}%TYPE := NEW.{real_name};",
+                        "{alias} \"{schema}\".{table}.{real_name}%TYPE := NEW.{real_name};",
+                        schema = table.schema,
                         table = table.real_name,
                         alias = column.name,
                         real_name = column.real_name,
@@ -107,6 +118,7 @@ impl Action for AddColumn {
                 r#"
                 CREATE OR REPLACE FUNCTION {trigger_name}()
                 RETURNS TRIGGER AS $$
+                #variable_conflict use_column
                 BEGIN
                     IF reshape.is_old_schema() THEN
                         DECLARE
@@ -129,11 +141,25 @@ impl Action for AddColumn {
                 declarations = declarations.join("\n"),
             );
             db.run(&query).context("failed to create up trigger")?;
+
+            // Rows are touched when the tables read by `up` change, so that the up trigger
+            // can re-evaluate the value. These are added before the backfill so that
+            // changes made while it's running aren't missed.
+            common::create_dependency_triggers(
+                db,
+                schema,
+                &self.trigger_name(ctx),
+                &table,
+                &temp_column_name,
+                &self.dependencies,
+                true,
+            )
+            .context("failed to create dependency triggers")?;
         }
 
         // Backfill values in batches
         if self.up.is_some() {
-            common::batch_touch_rows(db, &table.real_name, &temp_column_name)
+            backfill::touch_rows(ctx, db, &table.real_name, &temp_column_name)
                 .context("failed to batch update existing rows")?;
         }
 
@@ -152,7 +178,7 @@ impl Action for AddColumn {
                 constraint_name = self.not_null_constraint_name(ctx),
                 column = temp_column_name,
             );
-            db.run(&query)
+            ctx.run_ddl(db, &query)
                 .context("failed to add NOT NULL constraint")?;
         }
 
@@ -166,68 +192,75 @@ impl Action for AddColumn {
     ) -> anyhow::Result<Option<Transaction<'a>>> {
         let mut transaction = db.transaction().context("failed to create transaction")?;
 
-        // Remove triggers and procedures
-        let query = format!(
-            r#"
-            DROP TRIGGER IF EXISTS "{trigger_name}" ON "{table}";
-            DROP FUNCTION IF EXISTS "{trigger_name}";
-            "#,
-            table = self.table,
-            trigger_name = self.trigger_name(ctx),
-        );
-        transaction
-            .run(&query)
-            .context("failed to drop up trigger")?;
+        // The statements are retried together if any lock can't be taken in time. They run in a
+        // savepoint, which releases the locks it took when rolled back.
+        ctx.run_ddl_group(&mut transaction, |transaction| {
+            common::drop_dependency_triggers(
+                transaction,
+                &self.trigger_name(ctx),
+                &self.dependencies,
+            )
+            .context("failed to drop dependency triggers")?;
 
-        // Update column to be NOT NULL if necessary
-        if !self.column.nullable {
-            // Validate the temporary constraint (should always be valid).
-            // This performs a sequential scan but does not take an exclusive lock.
+            // Remove triggers and procedures
             let query = format!(
                 r#"
-                ALTER TABLE "{table}"
-                VALIDATE CONSTRAINT "{constraint_name}"
+                DROP TRIGGER IF EXISTS "{trigger_name}" ON "{table}";
+                DROP FUNCTION IF EXISTS "{trigger_name}";
                 "#,
                 table = self.table,
-                constraint_name = self.not_null_constraint_name(ctx),
+                trigger_name = self.trigger_name(ctx),
             );
             transaction
                 .run(&query)
-                .context("failed to validate NOT NULL constraint")?;
+                .context("failed to drop up trigger")?;
 
-            // Update the column to be NOT NULL.
-            // This requires an exclusive lock but since PG 12 it can check
-            // the existing constraint for correctness which makes the lock short-lived.
-            // Source: https://dba.stackexchange.com/a/268128
-            let query = format!(
-                r#"
-                ALTER TABLE "{table}"
-                ALTER COLUMN "{column}" SET NOT NULL
-                "#,
-                table = self.table,
-                column = self.temp_column_name(ctx),
-            );
-            transaction
-                .run(&query)
-                .context("failed to set column as NOT NULL")?;
+            // Update column to be NOT NULL if necessary
+            if !self.column.nullable {
+                // Validate the temporary constraint (should always be valid).
+                // This performs a sequential scan but does not take an exclusive lock.
+                let query = format!(
+                    r#"
+                    ALTER TABLE "{table}"
+                    VALIDATE CONSTRAINT "{constraint_name}"
+                    "#,
+                    table = self.table,
+                    constraint_name = self.not_null_constraint_name(ctx),
+                );
+                transaction
+                    .run(&query)
+                    .context("failed to validate NOT NULL constraint")?;
 
-            // Drop the temporary constraint
-            let query = format!(
-                r#"
-                ALTER TABLE "{table}"
-                DROP CONSTRAINT "{constraint_name}"
-                "#,
-                table = self.table,
-                constraint_name = self.not_null_constraint_name(ctx),
-            );
-            transaction
-                .run(&query)
-                .context("failed to drop NOT NULL constraint")?;
-        }
+                // Update the column to be NOT NULL.
+                // This requires an exclusive lock but since PG 12 it can check
+                // the existing constraint for correctness which makes the lock short-lived.
+                // Source: https://dba.stackexchange.com/a/268128
+                let query = format!(
+                    r#"
+                    ALTER TABLE "{table}"
+                    ALTER COLUMN "{column}" SET NOT NULL
+                    "#,
+                    table = self.table,
+                    column = self.temp_column_name(ctx),
+                );
+                ctx.run_ddl(transaction, &query)
+                    .context("failed to set column as NOT NULL")?;
 
-        // Rename the temporary column to its real name
-        transaction
-            .run(&format!(
+                // Drop the temporary constraint
+                let query = format!(
+                    r#"
+                    ALTER TABLE "{table}"
+                    DROP CONSTRAINT "{constraint_name}"
+                    "#,
+                    table = self.table,
+                    constraint_name = self.not_null_constraint_name(ctx),
+                );
+                ctx.run_ddl(transaction, &query)
+                    .context("failed to drop NOT NULL constraint")?;
+            }
+
+            // Rename the temporary column to its real name
+            let query = format!(
                 r#"
                 ALTER TABLE "{table}"
                 RENAME COLUMN "{temp_column_name}" TO "{column_name}"
@@ -235,8 +268,12 @@ impl Action for AddColumn {
                 table = self.table,
                 temp_column_name = self.temp_column_name(ctx),
                 column_name = self.column.name,
-            ))
-            .context("failed to rename column to final name")?;
+            );
+            ctx.run_ddl(transaction, &query)
+                .context("failed to rename column to final name")?;
+
+            Ok(())
+        })?;
 
         Ok(Some(transaction))
     }
@@ -250,28 +287,35 @@ impl Action for AddColumn {
     }
 
     fn abort(&self, ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
-        // Remove column
-        let query = format!(
-            r#"
-            ALTER TABLE "{table}"
-            DROP COLUMN IF EXISTS "{column}"
-            "#,
-            table = self.table,
-            column = self.temp_column_name(ctx),
-        );
-        db.run(&query).context("failed to drop column")?;
+        // The column and triggers are dropped together, retrying all of them if any lock
+        // can't be taken in time
+        ctx.run_ddl_group(db, |db| {
+            // Remove column
+            let query = format!(
+                r#"
+                ALTER TABLE "{table}"
+                DROP COLUMN IF EXISTS "{column}"
+                "#,
+                table = self.table,
+                column = self.temp_column_name(ctx),
+            );
+            ctx.run_ddl(db, &query).context("failed to drop column")?;
 
-        // Remove triggers and procedures
-        let query = format!(
-            r#"
-            DROP TRIGGER IF EXISTS "{trigger_name}" ON "{table}";
-            DROP FUNCTION IF EXISTS "{trigger_name}";
-            "#,
-            table = self.table,
-            trigger_name = self.trigger_name(ctx),
-        );
-        db.run(&query).context("failed to drop up trigger")?;
+            // Remove triggers and procedures
+            let query = format!(
+                r#"
+                DROP TRIGGER IF EXISTS "{trigger_name}" ON "{table}";
+                DROP FUNCTION IF EXISTS "{trigger_name}";
+                "#,
+                table = self.table,
+                trigger_name = self.trigger_name(ctx),
+            );
+            db.run(&query).context("failed to drop up trigger")?;
 
-        Ok(())
+            common::drop_dependency_triggers(db, &self.trigger_name(ctx), &self.dependencies)
+                .context("failed to drop dependency triggers")?;
+
+            Ok(())
+        })
     }
 }
@@ -0,0 +1,80 @@
+use super::{Action, MigrationContext};
+use crate::{
+    db::{Conn, Transaction},
+    schema::Schema,
+};
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AddEnumValue {
+    #[serde(rename = "enum")]
+    pub enum_name: String,
+    pub value: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+#[typetag::serde(name = "add_enum_value")]
+impl Action for AddEnumValue {
+    fn describe(&self) -> String {
+        format!(
+            "Adding value \"{}\" to enum \"{}\"",
+            self.value, self.enum_name
+        )
+    }
+
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    fn run(
+        &self,
+        _ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        _schema: &Schema,
+    ) -> anyhow::Result<()> {
+        let position = match (&self.before, &self.after) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow!(
+                    "only one of before and after can be set when adding an enum value"
+                ))
+            }
+            (Some(before), None) => format!("BEFORE '{}'", before.replace('\'', "''")),
+            (None, Some(after)) => format!("AFTER '{}'", after.replace('\'', "''")),
+            (None, None) => "".to_string(),
+        };
+
+        // Adding a value doesn't affect any existing values so it's safe to
+        // make it available to both schemas right away
+        let query = format!(
+            r#"
+            ALTER TYPE "{name}" ADD VALUE IF NOT EXISTS '{value}' {position}
+            "#,
+            name = self.enum_name,
+            value = self.value.replace('\'', "''"),
+            position = position,
+        );
+        db.run(&query).context("failed to add enum value")?;
+
+        Ok(())
+    }
+
+    fn complete<'a>(
+        &self,
+        _ctx: &MigrationContext,
+        _db: &'a mut dyn Conn,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        Ok(None)
+    }
+
+    fn update_schema(&self, _ctx: &MigrationContext, _schema: &mut Schema) {}
+
+    fn abort(&self, _ctx: &MigrationContext, _db: &mut dyn Conn) -> anyhow::Result<()> {
+        // Postgres doesn't support removing values from an enum so the value is kept
+        Ok(())
+    }
+}
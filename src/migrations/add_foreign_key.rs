@@ -0,0 +1,160 @@
+use super::{common, Action, ForeignKey, MigrationContext};
+use crate::{
+    db::{Conn, Transaction},
+    schema::Schema,
+};
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AddForeignKey {
+    pub table: String,
+    pub foreign_key: ForeignKey,
+
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+impl AddForeignKey {
+    fn temp_constraint_name(&self, ctx: &MigrationContext) -> String {
+        format!("{}_temp_fkey", ctx.prefix())
+    }
+
+    fn final_constraint_name(&self) -> String {
+        format!(
+            "{table}_{columns}_fkey",
+            table = self.table,
+            columns = self.foreign_key.columns.join("_")
+        )
+    }
+}
+
+#[typetag::serde(name = "add_foreign_key")]
+impl Action for AddForeignKey {
+    fn describe(&self) -> String {
+        format!(
+            "Adding foreign key from table \"{}\" to \"{}\"",
+            self.table, self.foreign_key.referenced_table
+        )
+    }
+
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    fn run(
+        &self,
+        ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        schema: &Schema,
+    ) -> anyhow::Result<()> {
+        let table = schema.get_table(db, &self.table)?;
+        let referenced_table = schema.get_table(db, &self.foreign_key.referenced_table)?;
+
+        // Resolve the columns on both sides to their real names, as the columns
+        // might have been renamed or replaced earlier in the same migration
+        let columns = self
+            .foreign_key
+            .columns
+            .iter()
+            .map(|name| {
+                table
+                    .columns
+                    .iter()
+                    .find(|column| &column.name == name)
+                    .map(|column| format!("\"{}\"", column.real_name))
+                    .ok_or_else(|| anyhow!("no such column {} exists on {}", name, self.table))
+            })
+            .collect::<anyhow::Result<Vec<String>>>()?;
+
+        let referenced_columns = self
+            .foreign_key
+            .referenced_columns
+            .iter()
+            .map(|name| {
+                referenced_table
+                    .columns
+                    .iter()
+                    .find(|column| &column.name == name)
+                    .map(|column| format!("\"{}\"", column.real_name))
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "no such column {} exists on {}",
+                            name,
+                            self.foreign_key.referenced_table
+                        )
+                    })
+            })
+            .collect::<anyhow::Result<Vec<String>>>()?;
+
+        // Add the foreign key as NOT VALID so that existing rows aren't checked
+        // under a lock. New rows will still be checked immediately and the existing
+        // rows are validated when the migration is completed.
+        let query = format!(
+            r#"
+            ALTER TABLE "{table}"
+            DROP CONSTRAINT IF EXISTS "{constraint_name}";
+
+            ALTER TABLE "{table}"
+            ADD CONSTRAINT "{constraint_name}"
+            FOREIGN KEY ({columns})
+            REFERENCES "{referenced_table}" ({referenced_columns})
+            NOT VALID
+            "#,
+            table = table.real_name,
+            constraint_name = self.temp_constraint_name(ctx),
+            columns = columns.join(", "),
+            referenced_table = referenced_table.real_name,
+            referenced_columns = referenced_columns.join(", "),
+        );
+        db.run(&query).context("failed to add foreign key")?;
+
+        Ok(())
+    }
+
+    fn complete<'a>(
+        &self,
+        ctx: &MigrationContext,
+        db: &'a mut dyn Conn,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        let mut transaction = db.transaction().context("failed to create transaction")?;
+
+        // Validate the foreign key for all existing rows.
+        // This performs a sequential scan but does not take an exclusive lock.
+        let query = format!(
+            r#"
+            ALTER TABLE "{table}"
+            VALIDATE CONSTRAINT "{constraint_name}"
+            "#,
+            table = self.table,
+            constraint_name = self.temp_constraint_name(ctx),
+        );
+        transaction
+            .run(&query)
+            .context("failed to validate foreign key")?;
+
+        // Rename the constraint to its final name
+        let query = format!(
+            r#"
+            ALTER TABLE "{table}"
+            RENAME CONSTRAINT "{temp_constraint_name}" TO "{constraint_name}"
+            "#,
+            table = self.table,
+            temp_constraint_name = self.temp_constraint_name(ctx),
+            constraint_name = self.final_constraint_name(),
+        );
+        transaction
+            .run(&query)
+            .context("failed to rename foreign key")?;
+
+        Ok(Some(transaction))
+    }
+
+    fn update_schema(&self, _ctx: &MigrationContext, _schema: &mut Schema) {}
+
+    fn abort(&self, ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
+        common::drop_temporary_constraint(db, &self.temp_constraint_name(ctx))
+            .context("failed to drop foreign key")?;
+        Ok(())
+    }
+}
@@ -1,24 +1,47 @@
-use super::{Action, MigrationContext};
+use super::{common, Action, MigrationContext};
 use crate::{
     db::{Conn, Transaction},
     schema::Schema,
 };
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AddIndex {
     pub table: String,
     pub name: String,
+
+    // Every entry is either a column name or an expression, optionally
+    // followed by a sort order, for example "LOWER(name) DESC NULLS LAST"
     pub columns: Vec<String>,
+
+    #[serde(default)]
+    pub unique: bool,
+
+    pub method: Option<String>,
+
+    #[serde(rename = "where")]
+    pub predicate: Option<String>,
+
+    #[serde(default)]
+    pub include: Vec<String>,
+
+    #[serde(default)]
+    pub schema: Option<String>,
 }
 
+const INDEX_METHODS: [&str; 6] = ["btree", "hash", "gist", "spgist", "gin", "brin"];
+
 #[typetag::serde(name = "add_index")]
 impl Action for AddIndex {
     fn describe(&self) -> String {
         format!("Adding index \"{}\" to table \"{}\"", self.name, self.table)
     }
 
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
     fn run(
         &self,
         _ctx: &MigrationContext,
@@ -27,20 +50,89 @@ impl Action for AddIndex {
     ) -> anyhow::Result<()> {
         let table = schema.get_table(db, &self.table)?;
 
-        let column_real_names: Vec<String> = table
+        // Column references in expressions and predicates use the names of the new
+        // schema, which might be backed by differently named columns during the migration
+        let rewrite = |expression: &str| {
+            common::rewrite_column_references(expression, |name| {
+                table
+                    .columns
+                    .iter()
+                    .find(|column| column.name == name)
+                    .map(|column| column.real_name.to_string())
+            })
+        };
+
+        let keys: Vec<String> = self
             .columns
             .iter()
-            .filter(|column| self.columns.contains(&column.name))
-            .map(|column| format!("\"{}\"", column.real_name))
+            .map(|key| {
+                // Plain column names are matched exactly to allow names with upper case letters
+                table
+                    .columns
+                    .iter()
+                    .find(|column| &column.name == key)
+                    .map(|column| format!("\"{}\"", column.real_name))
+                    .unwrap_or_else(|| rewrite(key))
+            })
             .collect();
 
+        let include_columns = self
+            .include
+            .iter()
+            .map(|name| {
+                table
+                    .columns
+                    .iter()
+                    .find(|column| &column.name == name)
+                    .map(|column| format!("\"{}\"", column.real_name))
+                    .ok_or_else(|| anyhow!("no such column {} exists on {}", name, self.table))
+            })
+            .collect::<anyhow::Result<Vec<String>>>()?;
+
+        let mut definition_parts = vec![format!("({})", keys.join(", "))];
+
+        if let Some(method) = &self.method {
+            let method = method.to_lowercase();
+            if !INDEX_METHODS.contains(&method.as_str()) {
+                return Err(anyhow!(
+                    "unsupported index method {}, expected one of: {}",
+                    method,
+                    INDEX_METHODS.join(", ")
+                ));
+            }
+            definition_parts.insert(0, format!("USING {}", method));
+        }
+
+        if !include_columns.is_empty() {
+            definition_parts.push(format!("INCLUDE ({})", include_columns.join(", ")));
+        }
+
+        if let Some(predicate) = &self.predicate {
+            definition_parts.push(format!("WHERE {}", rewrite(predicate)));
+        }
+
+        let definition = definition_parts.join(" ");
+
+        // Indices can't be built concurrently on partitioned tables, so instead we build
+        // the index concurrently on each partition and attach them to the parent index
+        if common::is_partitioned_table(db, &table.real_name)
+            .context("failed to check if table is partitioned")?
+        {
+            return self.create_partitioned_index(db, &table.real_name, &self.name, &definition);
+        }
+
+        // A previous attempt at building the index might have failed and left
+        // an invalid index behind, which we need to get rid of before trying again
+        common::drop_invalid_index(db, &self.name).context("failed to drop invalid index")?;
+
         db.run(&format!(
             r#"
-			CREATE INDEX CONCURRENTLY "{name}" ON "{table}" ({columns})
+			CREATE {unique} INDEX CONCURRENTLY IF NOT EXISTS "{name}" ON "{table}" {definition}
 			"#,
+            unique = if self.unique { "UNIQUE" } else { "" },
             name = self.name,
-            table = self.table,
-            columns = column_real_names.join(", "),
+            table = table.real_name,
+            definition = definition,
         ))
         .context("failed to create index")?;
         Ok(())
@@ -57,13 +149,83 @@ impl Action for AddIndex {
     fn update_schema(&self, _ctx: &MigrationContext, _schema: &mut Schema) {}
 
     fn abort(&self, _ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
+        let is_partitioned = common::is_partitioned_index(db, &self.name)
+            .context("failed to check if index is partitioned")?;
+
         db.run(&format!(
             r#"
-			DROP INDEX CONCURRENTLY IF EXISTS "{name}"
+			DROP INDEX {concurrently} IF EXISTS "{name}"
 			"#,
+            concurrently = if is_partitioned { "" } else { "CONCURRENTLY" },
             name = self.name,
         ))
         .context("failed to drop index")?;
         Ok(())
     }
 }
+
+impl AddIndex {
+    // Creates an index on a partitioned table without blocking writes. The index is first
+    // created on the parent table only, which leaves it invalid, and then built for each
+    // partition separately before being attached. Once all partitions have been attached,
+    // the parent index becomes valid.
+    fn create_partitioned_index(
+        &self,
+        db: &mut dyn Conn,
+        table: &str,
+        name: &str,
+        definition: &str,
+    ) -> anyhow::Result<()> {
+        db.run(&format!(
+            r#"
+            CREATE {unique} INDEX IF NOT EXISTS "{name}" ON ONLY "{table}" {definition}
+            "#,
+            unique = if self.unique { "UNIQUE" } else { "" },
+            name = name,
+            table = table,
+            definition = definition,
+        ))
+        .context("failed to create index on partitioned table")?;
+
+        let partitions =
+            common::get_partitions(db, table).context("failed to get partitions of table")?;
+        for partition in partitions {
+            let partition_index_name = format!("{}_{}", name, partition.name);
+
+            if partition.is_partitioned {
+                self.create_partitioned_index(
+                    db,
+                    &partition.name,
+                    &partition_index_name,
+                    definition,
+                )?;
+            } else {
+                common::drop_invalid_index(db, &partition_index_name)
+                    .context("failed to drop invalid index")?;
+                db.run(&format!(
+                    r#"
+                    CREATE {unique} INDEX CONCURRENTLY IF NOT EXISTS "{name}" ON "{table}" {definition}
+                    "#,
+                    unique = if self.unique { "UNIQUE" } else { "" },
+                    name = partition_index_name,
+                    table = partition.name,
+                    definition = definition,
+                ))
+                .with_context(|| {
+                    format!("failed to create index on partition {}", partition.name)
+                })?;
+            }
+
+            db.run(&format!(
+                r#"
+                ALTER INDEX "{name}" ATTACH PARTITION "{partition_index}"
+                "#,
+                name = name,
+                partition_index = partition_index_name,
+            ))
+            .with_context(|| format!("failed to attach index for partition {}", partition.name))?;
+        }
+
+        Ok(())
+    }
+}
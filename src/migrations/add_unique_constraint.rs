@@ -0,0 +1,118 @@
+use super::{common, Action, MigrationContext};
+use crate::{
+    db::{Conn, Transaction},
+    schema::Schema,
+};
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AddUniqueConstraint {
+    pub table: String,
+    pub name: String,
+    pub columns: Vec<String>,
+
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+impl AddUniqueConstraint {
+    fn temp_index_name(&self, ctx: &MigrationContext) -> String {
+        format!("{}_temp_unique_index", ctx.prefix())
+    }
+}
+
+#[typetag::serde(name = "add_unique_constraint")]
+impl Action for AddUniqueConstraint {
+    fn describe(&self) -> String {
+        format!(
+            "Adding unique constraint \"{}\" to \"{}\"",
+            self.name, self.table
+        )
+    }
+
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    fn run(
+        &self,
+        ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        schema: &Schema,
+    ) -> anyhow::Result<()> {
+        let table = schema.get_table(db, &self.table)?;
+
+        let column_real_names = self
+            .columns
+            .iter()
+            .map(|name| {
+                table
+                    .columns
+                    .iter()
+                    .find(|column| &column.name == name)
+                    .map(|column| format!("\"{}\"", column.real_name))
+                    .ok_or_else(|| anyhow!("no such column {} exists on {}", name, self.table))
+            })
+            .collect::<anyhow::Result<Vec<String>>>()?;
+
+        // A previous attempt at building the index might have failed and left
+        // an invalid index behind, which we need to get rid of before trying again
+        common::drop_invalid_index(db, &self.temp_index_name(ctx))
+            .context("failed to drop invalid index")?;
+
+        // Build the unique index without blocking writes. The index will
+        // be turned into a constraint when the migration is completed.
+        db.run(&format!(
+            r#"
+            CREATE UNIQUE INDEX CONCURRENTLY IF NOT EXISTS "{index}" ON "{table}" ({columns})
+            "#,
+            index = self.temp_index_name(ctx),
+            table = table.real_name,
+            columns = column_real_names.join(", "),
+        ))
+        .context("failed to create unique index")?;
+
+        Ok(())
+    }
+
+    fn complete<'a>(
+        &self,
+        ctx: &MigrationContext,
+        db: &'a mut dyn Conn,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        let mut transaction = db.transaction().context("failed to create transaction")?;
+
+        // Turn the index into a constraint. This is fast as the index already
+        // exists, and the index will be renamed to match the constraint.
+        let query = format!(
+            r#"
+            ALTER TABLE "{table}"
+            ADD CONSTRAINT "{constraint_name}" UNIQUE USING INDEX "{index}"
+            "#,
+            table = self.table,
+            constraint_name = self.name,
+            index = self.temp_index_name(ctx),
+        );
+        transaction
+            .run(&query)
+            .context("failed to add unique constraint")?;
+
+        Ok(Some(transaction))
+    }
+
+    fn update_schema(&self, _ctx: &MigrationContext, _schema: &mut Schema) {}
+
+    fn abort(&self, ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
+        // This will also remove the index if it was left invalid by a failed build
+        db.run(&format!(
+            r#"
+            DROP INDEX CONCURRENTLY IF EXISTS "{index}"
+            "#,
+            index = self.temp_index_name(ctx),
+        ))
+        .context("failed to drop unique index")?;
+
+        Ok(())
+    }
+}
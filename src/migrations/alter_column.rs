@@ -1,8 +1,8 @@
-use super::{Action, MigrationContext};
+use super::{Action, Dependency, MigrationContext};
 use crate::{
     db::{Conn, Transaction},
-    migrations::common,
-    schema::Schema,
+    migrations::{backfill, common},
+    schema::{Column, Schema, Table},
 };
 use anyhow::{anyhow, Context};
 use serde::{Deserialize, Serialize};
@@ -14,6 +14,16 @@ pub struct AlterColumn {
     pub up: Option<String>,
     pub down: Option<String>,
     pub changes: ColumnChanges,
+
+    // Tables read by `up` and `down` respectively
+    #[serde(default)]
+    pub dependencies: Vec<Dependency>,
+
+    #[serde(default)]
+    pub down_dependencies: Vec<Dependency>,
+
+    #[serde(default)]
+    pub schema: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -31,19 +41,16 @@ impl Action for AlterColumn {
         format!("Altering column \"{}\" on \"{}\"", self.column, self.table)
     }
 
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
     fn run(
         &self,
         ctx: &MigrationContext,
         db: &mut dyn Conn,
         schema: &Schema,
     ) -> anyhow::Result<()> {
-        // If we are only changing the name of a column, we don't have to do anything at this stage
-        // We'll set the new schema to point to the old column. When the migration is completed,
-        // we rename the actual column.
-        if self.can_short_circuit() {
-            return Ok(());
-        }
-
         let table = schema.get_table(db, &self.table)?;
 
         let column = table
@@ -52,29 +59,49 @@ impl Action for AlterColumn {
             .find(|column| column.name == self.column)
             .ok_or_else(|| anyhow!("no such column {} exists", self.column))?;
 
+        // If the values of the column don't change, the column can be altered in place
+        // without a temporary column and backfill
+        if self.can_alter_in_place() {
+            return self.run_in_place(ctx, db, &table.real_name, column);
+        }
+
+        // Views on the column are dropped together with it, so make sure they can be recreated
+        self.check_dependent_views(db, &table.real_name, &column.real_name)?;
+
         let temporary_column_name = self.temporary_column_name(ctx);
         let temporary_column_type = self.changes.data_type.as_ref().unwrap_or(&column.data_type);
 
         // Add temporary, nullable column
-        let mut temp_column_definition_parts: Vec<&str> =
-            vec![&temporary_column_name, &temporary_column_type];
-
-        // Use either new default value or existing one if one exists
-        let default_value = self.changes.default.as_ref().or(column.default.as_ref());
-        if let Some(default) = default_value {
-            temp_column_definition_parts.push("DEFAULT");
-            temp_column_definition_parts.push(default);
-        }
-
         let query = format!(
             r#"
-			ALTER TABLE "{table}"
-            ADD COLUMN IF NOT EXISTS {temp_column_definition}
-			"#,
+            ALTER TABLE "{table}"
+            ADD COLUMN IF NOT EXISTS "{temp_column}" {temp_column_type}
+            "#,
             table = self.table,
-            temp_column_definition = temp_column_definition_parts.join(" "),
+            temp_column = temporary_column_name,
+            temp_column_type = temporary_column_type,
         );
-        db.run(&query).context("failed to add temporary column")?;
+        ctx.run_ddl(db, &query)
+            .context("failed to add temporary column")?;
+
+        // Use either new default value or existing one if one exists.
+        // The default is set separately from adding the column so that existing rows aren't
+        // affected, which would force a rewrite of the table for volatile defaults like nextval.
+        // Existing rows will get their values when backfilling instead.
+        let default_value = self.changes.default.as_ref().or(column.default.as_ref());
+        if let Some(default) = default_value {
+            let query = format!(
+                r#"
+                ALTER TABLE "{table}"
+                ALTER COLUMN "{temp_column}" SET DEFAULT {default}
+                "#,
+                table = self.table,
+                temp_column = temporary_column_name,
+                default = default,
+            );
+            ctx.run_ddl(db, &query)
+                .context("failed to set default for temporary column")?;
+        }
 
         // If up or down wasn't provided, we default to simply moving the value over.
         // This is the correct behaviour for example when only changing the default value.
@@ -87,7 +114,8 @@ impl Action for AlterColumn {
             .filter(|column| column.name != self.column)
             .map(|column| {
                 format!(
-                    "{alias} public.{table}.{real_name}%TYPE := NEW.{real_name};",
+                    "{alias} \"{schema}\".{table}.{real_name}%TYPE := NEW.{real_name};",
+                    schema = table.schema,
                     table = table.real_name,
                     alias = column.name,
                     real_name = column.real_name,
@@ -99,11 +127,12 @@ impl Action for AlterColumn {
             r#"
                 CREATE OR REPLACE FUNCTION {up_trigger}()
                 RETURNS TRIGGER AS $$
+                #variable_conflict use_column
                 BEGIN
                     IF reshape.is_old_schema() THEN
                         DECLARE
                             {declarations}
-                            {existing_column} public.{table}.{existing_column_real}%TYPE := NEW.{existing_column_real};
+                            {existing_column} "{schema}".{table}.{existing_column_real}%TYPE := NEW.{existing_column_real};
                         BEGIN
                             NEW.{temp_column} = {up};
                         END;
@@ -117,11 +146,12 @@ impl Action for AlterColumn {
 
                 CREATE OR REPLACE FUNCTION {down_trigger}()
                 RETURNS TRIGGER AS $$
+                #variable_conflict use_column
                 BEGIN
                     IF NOT reshape.is_old_schema() THEN
                         DECLARE
                             {declarations}
-                            {existing_column} public.{table}.{temp_column}%TYPE := NEW.{temp_column};
+                            {existing_column} "{schema}".{table}.{temp_column}%TYPE := NEW.{temp_column};
                         BEGIN
                             NEW.{existing_column_real} = {down};
                         END;
@@ -133,6 +163,7 @@ impl Action for AlterColumn {
                 DROP TRIGGER IF EXISTS "{down_trigger}" ON "{table}";
                 CREATE TRIGGER "{down_trigger}" BEFORE INSERT OR UPDATE ON "{table}" FOR EACH ROW EXECUTE PROCEDURE {down_trigger}();
                 "#,
+            schema = table.schema,
             existing_column = &self.column,
             existing_column_real = column.real_name,
             temp_column = self.temporary_column_name(ctx),
@@ -146,27 +177,72 @@ impl Action for AlterColumn {
         db.run(&query)
             .context("failed to create up and down triggers")?;
 
-        // Backfill values in batches by touching the previous column
-        common::batch_touch_rows(db, &table.real_name, &column.real_name)
+        // Rows are touched when the tables read by `up` or `down` change, so that the up or down
+        // trigger can re-evaluate the value. These are added before the backfill so that
+        // changes made while it's running aren't missed.
+        common::create_dependency_triggers(
+            db,
+            schema,
+            &self.up_trigger_name(ctx),
+            &table,
+            &temporary_column_name,
+            &self.dependencies,
+            true,
+        )
+        .context("failed to create dependency triggers")?;
+        common::create_dependency_triggers(
+            db,
+            schema,
+            &self.down_trigger_name(ctx),
+            &self.new_table(&table, &temporary_column_name),
+            &temporary_column_name,
+            &self.down_dependencies,
+            false,
+        )
+        .context("failed to create dependency triggers for down")?;
+
+        // Backfill values in batches by touching the temporary column. The up trigger will
+        // overwrite the value, and unlike the previous column, the temporary column is never
+        // part of the primary key or an identity which can't be updated.
+        backfill::touch_rows(ctx, db, &table.real_name, &temporary_column_name)
             .context("failed to batch update existing rows")?;
 
         // Add a temporary NOT NULL constraint if the column shouldn't be nullable.
         // This constraint is set as NOT VALID so it doesn't apply to existing rows and
         // the existing rows don't need to be scanned under an exclusive lock.
         // Thanks to this, we can set the full column as NOT NULL later with minimal locking.
-        if !column.nullable {
-            let query = format!(
+        if !self.changes.nullable.unwrap_or(column.nullable) {
+            self.add_not_null_constraint(ctx, db, &temporary_column_name)?;
+        }
+
+        // Build copies of all indices on the existing column for the temporary column.
+        // These are built concurrently now so that they can take the place of the
+        // existing indices when the old column is dropped on completion.
+        let indices = common::get_dependent_indices(db, &table.real_name, &column.real_name)
+            .context("failed to get indices for column")?;
+        for index in indices {
+            // Exclusion constraints can't be added using an existing index,
+            // so they are recreated from scratch on completion instead
+            if index.is_exclusion_constraint() {
+                continue;
+            }
+
+            let (create, definition) =
+                common::rename_column_in_index(&index, &column.real_name, &temporary_column_name)
+                    .ok_or_else(|| anyhow!("failed to parse definition of index {}", index.name))?;
+            let temporary_index_name = self.temporary_index_name(ctx, index.oid);
+
+            common::drop_invalid_index(db, &temporary_index_name)
+                .context("failed to drop invalid index")?;
+            db.run(&format!(
                 r#"
-                ALTER TABLE "{table}"
-                ADD CONSTRAINT "{constraint_name}"
-                CHECK ("{column}" IS NOT NULL) NOT VALID
+                {create} CONCURRENTLY IF NOT EXISTS "{name}" {definition}
                 "#,
-                table = self.table,
-                constraint_name = self.not_null_constraint_name(ctx),
-                column = self.temporary_column_name(ctx),
-            );
-            db.run(&query)
-                .context("failed to add NOT NULL constraint")?;
+                create = create,
+                name = temporary_index_name,
+                definition = definition,
+            ))
+            .with_context(|| format!("failed to create copy of index {}", index.name))?;
         }
 
         Ok(())
@@ -177,97 +253,54 @@ impl Action for AlterColumn {
         ctx: &MigrationContext,
         db: &'a mut dyn Conn,
     ) -> anyhow::Result<Option<Transaction<'a>>> {
-        if self.can_short_circuit() {
-            if let Some(new_name) = &self.changes.name {
-                let query = format!(
-                    r#"
-			        ALTER TABLE "{table}"
-			        RENAME COLUMN "{existing_name}" TO "{new_name}"
-			        "#,
-                    table = self.table,
-                    existing_name = self.column,
-                    new_name = new_name,
-                );
-                db.run(&query).context("failed to rename column")?;
-            }
-            return Ok(None);
-        }
+        self.complete_with(ctx, db, |_| Ok(()), |_| Ok(()))
+    }
 
-        // Update column to be NOT NULL if necessary
-        let has_not_null_constraint = !db
-            .query_with_params(
-                "
-                SELECT constraint_name
-                FROM information_schema.constraint_column_usage
-                WHERE constraint_name = $1
-                ",
-                &[&self.not_null_constraint_name(ctx)],
-            )
-            .context("failed to get any NOT NULL constraint")?
-            .is_empty();
-        if has_not_null_constraint {
-            // Validate the temporary constraint (should always be valid).
-            // This performs a sequential scan but does not take an exclusive lock.
-            let query = format!(
-                r#"
-                ALTER TABLE "{table}"
-                VALIDATE CONSTRAINT "{constraint_name}"
-                "#,
-                table = self.table,
-                constraint_name = self.not_null_constraint_name(ctx),
-            );
-            db.run(&query)
-                .context("failed to validate NOT NULL constraint")?;
+    fn update_schema(&self, ctx: &MigrationContext, schema: &mut Schema) {
+        // If the column is altered in place, we haven't created a temporary column.
+        // Instead, we rename the schema column but point it to the old column and
+        // track the new default so that it can be applied to the new schema.
+        if self.can_alter_in_place() {
+            schema.change_table(&self.table, |table_changes| {
+                table_changes.change_column(&self.column, |column_changes| {
+                    if let Some(new_name) = &self.changes.name {
+                        column_changes.set_name(new_name);
+                    }
 
-            // Update the column to be NOT NULL.
-            // This requires an exclusive lock but since PG 12 it can check
-            // the existing constraint for correctness which makes the lock short-lived.
-            // Source: https://dba.stackexchange.com/a/268128
-            let query = format!(
-                r#"
-                ALTER TABLE "{table}"
-                ALTER COLUMN "{column}" SET NOT NULL
-                "#,
-                table = self.table,
-                column = self.temporary_column_name(ctx),
-            );
-            db.run(&query).context("failed to set column as NOT NULL")?;
+                    if let Some(default) = &self.changes.default {
+                        column_changes.set_default(default);
+                    }
+                });
+            });
 
-            // Drop the temporary constraint
-            let query = format!(
-                r#"
-                ALTER TABLE "{table}"
-                DROP CONSTRAINT "{constraint_name}"
-                "#,
-                table = self.table,
-                constraint_name = self.not_null_constraint_name(ctx),
-            );
-            db.run(&query)
-                .context("failed to drop NOT NULL constraint")?;
+            return;
         }
 
-        // Remove old column
-        let query = format!(
-            r#"
-            ALTER TABLE "{table}" DROP COLUMN "{column}" CASCADE
-			"#,
-            table = self.table,
-            column = self.column,
-        );
-        db.run(&query).context("failed to drop old column")?;
+        schema.change_table(&self.table, |table_changes| {
+            table_changes.change_column(&self.column, |column_changes| {
+                column_changes.set_column(&self.temporary_column_name(ctx));
+            });
+        });
+    }
 
-        // Rename temporary column
-        let column_name = self.changes.name.as_deref().unwrap_or(&self.column);
+    fn abort(&self, ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
+        if self.can_alter_in_place() {
+            return self.abort_in_place(ctx, db);
+        }
+
+        self.drop_dependency_triggers(ctx, db)?;
+
+        // Drop temporary column
         let query = format!(
             r#"
-            ALTER TABLE "{table}" RENAME COLUMN "{temp_column}" TO "{name}"
-			"#,
+            ALTER TABLE "{table}"
+            DROP COLUMN IF EXISTS "{temp_column}";
+            "#,
             table = self.table,
             temp_column = self.temporary_column_name(ctx),
-            name = column_name,
         );
-        db.run(&query)
-            .context("failed to rename temporary column")?;
+        ctx.run_ddl(db, &query)
+            .context("failed to drop temporary column")?;
 
         // Remove triggers and procedures
         let query = format!(
@@ -285,42 +318,205 @@ impl Action for AlterColumn {
         db.run(&query)
             .context("failed to drop up and down triggers")?;
 
-        Ok(None)
+        Ok(())
     }
+}
 
-    fn update_schema(&self, ctx: &MigrationContext, schema: &mut Schema) {
-        // If we are only changing the name of a column, we haven't created a temporary column
-        // Instead, we rename the schema column but point it to the old column
-        if self.can_short_circuit() {
-            if let Some(new_name) = &self.changes.name {
-                schema.change_table(&self.table, |table_changes| {
-                    table_changes.change_column(&self.column, |column_changes| {
-                        column_changes.set_name(new_name);
-                    });
-                });
+impl AlterColumn {
+    // Completes the migration like `complete`, running `start_swap` at the start and
+    // `finish_swap` at the end of the transaction which swaps the columns. Changes which must
+    // never be missing from the new column, like its identity, can be made there while the
+    // table is still locked. Both are run again if the swap is retried.
+    pub(crate) fn complete_with<'a>(
+        &self,
+        ctx: &MigrationContext,
+        db: &'a mut dyn Conn,
+        start_swap: impl Fn(&mut dyn Conn) -> anyhow::Result<()>,
+        finish_swap: impl Fn(&mut dyn Conn) -> anyhow::Result<()>,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        if self.can_alter_in_place() {
+            self.complete_in_place(ctx, db)?;
+            return Ok(None);
+        }
+
+        // The dependency triggers touch the temporary column which is about to be renamed
+        self.drop_dependency_triggers(ctx, db)?;
+
+        // Update column to be NOT NULL if necessary
+        self.complete_not_null_constraint(ctx, db, &self.temporary_column_name(ctx))?;
+
+        // Find all indices, constraints and views which depend on the old column. These will
+        // be dropped together with the column and must be recreated for the new column.
+        let indices = common::get_dependent_indices(db, &self.table, &self.column)
+            .context("failed to get indices for column")?;
+        let constraints = common::get_dependent_constraints(db, &self.table, &self.column)
+            .context("failed to get constraints for column")?;
+        self.check_dependent_views(db, &self.table, &self.column)?;
+        let views = common::get_dependent_views(db, &self.table, Some(&self.column))
+            .context("failed to get views for column")?;
+
+        // If the copy of an index is missing, for example because it covered another column
+        // which has since been replaced, it's built now before any exclusive lock is taken
+        let temporary_column_name = self.temporary_column_name(ctx);
+        for index in &indices {
+            if index.is_exclusion_constraint() {
+                continue;
             }
 
-            return;
+            let temporary_index_name = self.temporary_index_name(ctx, index.oid);
+            let has_temporary_index = !db
+                .query_with_params(
+                    "
+                    SELECT relname
+                    FROM pg_catalog.pg_class
+                    WHERE relname = $1 AND relkind = 'i'
+                    ",
+                    &[&temporary_index_name],
+                )
+                .context("failed to get index")?
+                .is_empty();
+            if has_temporary_index {
+                continue;
+            }
+
+            let (create, definition) =
+                common::rename_column_in_index(index, &self.column, &temporary_column_name)
+                    .ok_or_else(|| anyhow!("failed to parse definition of index {}", index.name))?;
+            common::drop_invalid_index(db, &temporary_index_name)
+                .context("failed to drop invalid index")?;
+            db.run(&format!(
+                r#"
+                {create} CONCURRENTLY IF NOT EXISTS "{name}" {definition}
+                "#,
+                create = create,
+                name = temporary_index_name,
+                definition = definition,
+            ))
+            .with_context(|| format!("failed to create copy of index {}", index.name))?;
         }
 
-        schema.change_table(&self.table, |table_changes| {
-            table_changes.change_column(&self.column, |column_changes| {
-                column_changes.set_column(&self.temporary_column_name(ctx));
-            });
-        });
-    }
+        // Swap the columns and restore indices and constraints in a single transaction
+        // to ensure the new column is never left without them. If any lock can't be taken
+        // in time, the whole swap is rolled back and retried.
+        let column_name = self.changes.name.as_deref().unwrap_or(&self.column);
+        let mut constraints_to_validate: Vec<&common::DependentConstraint> = Vec::new();
+        ctx.run_ddl_group(db, |transaction| {
+            constraints_to_validate.clear();
+            start_swap(transaction)?;
 
-    fn abort(&self, ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
-        // Drop temporary column
-        let query = format!(
-            r#"
-			ALTER TABLE "{table}"
-            DROP COLUMN IF EXISTS "{temp_column}";
-			"#,
-            table = self.table,
-            temp_column = self.temporary_column_name(ctx),
-        );
-        db.run(&query).context("failed to drop temporary column")?;
+            // Remove old column
+            let query = format!(
+                r#"
+                ALTER TABLE "{table}" DROP COLUMN "{column}" CASCADE
+                "#,
+                table = self.table,
+                column = self.column,
+            );
+            ctx.run_ddl(transaction, &query)
+                .context("failed to drop old column")?;
+
+            // Rename temporary column
+            let query = format!(
+                r#"
+                ALTER TABLE "{table}" RENAME COLUMN "{temp_column}" TO "{name}"
+                "#,
+                table = self.table,
+                temp_column = self.temporary_column_name(ctx),
+                name = column_name,
+            );
+            ctx.run_ddl(transaction, &query)
+                .context("failed to rename temporary column")?;
+
+            // Replace the dropped indices with the ones built for the new column
+            for index in &indices {
+                if index.is_exclusion_constraint() {
+                    continue;
+                }
+
+                let temporary_index_name = self.temporary_index_name(ctx, index.oid);
+                let query = match &index.constraint {
+                    Some((constraint_name, constraint_type)) if constraint_type == "p" => format!(
+                        r#"
+                        ALTER TABLE "{table}" ADD CONSTRAINT "{constraint_name}" PRIMARY KEY USING INDEX "{index}"
+                        "#,
+                        table = self.table,
+                        constraint_name = constraint_name,
+                        index = temporary_index_name,
+                    ),
+                    Some((constraint_name, constraint_type)) if constraint_type == "u" => format!(
+                        r#"
+                        ALTER TABLE "{table}" ADD CONSTRAINT "{constraint_name}" UNIQUE USING INDEX "{index}"
+                        "#,
+                        table = self.table,
+                        constraint_name = constraint_name,
+                        index = temporary_index_name,
+                    ),
+                    _ => format!(
+                        r#"
+                        ALTER INDEX "{index}" RENAME TO "{name}"
+                        "#,
+                        index = temporary_index_name,
+                        name = index.name,
+                    ),
+                };
+                ctx.run_ddl(transaction, &query)
+                    .with_context(|| format!("failed to restore index {}", index.name))?;
+            }
+
+            // Recreate all constraints which were dropped together with the column. Check constraints
+            // and foreign keys are added as NOT VALID and validated after the transaction has been
+            // committed, to avoid scanning the table while holding an exclusive lock.
+            for constraint in &constraints {
+                let definition =
+                    common::rename_column_in_constraint(constraint, &self.column, column_name);
+
+                // Constraints which weren't validated before should stay that way
+                let (definition, was_valid) = match definition.strip_suffix(" NOT VALID") {
+                    Some(definition) => (definition.to_string(), false),
+                    None => (definition, true),
+                };
+
+                let can_skip_validation = constraint.constraint_type != "x";
+                if was_valid && can_skip_validation {
+                    constraints_to_validate.push(constraint);
+                }
+
+                let query = format!(
+                    r#"
+                    ALTER TABLE "{table}" ADD CONSTRAINT "{name}" {definition} {not_valid}
+                    "#,
+                    table = constraint.table,
+                    name = constraint.name,
+                    definition = definition,
+                    not_valid = if can_skip_validation { "NOT VALID" } else { "" },
+                );
+                ctx.run_ddl(transaction, &query)
+                    .with_context(|| format!("failed to restore constraint {}", constraint.name))?;
+            }
+
+            // Recreate the dropped views, which now refer to the new column by the same name
+            for view in &views {
+                for statement in view.create_statements() {
+                    transaction.run(&statement).with_context(|| {
+                        format!("failed to restore view {}.{}", view.schema, view.name)
+                    })?;
+                }
+            }
+
+            finish_swap(transaction)
+        })
+        .context("failed to swap columns")?;
+
+        for constraint in constraints_to_validate {
+            db.run(&format!(
+                r#"
+                ALTER TABLE "{table}" VALIDATE CONSTRAINT "{name}"
+                "#,
+                table = constraint.table,
+                name = constraint.name,
+            ))
+            .with_context(|| format!("failed to validate constraint {}", constraint.name))?;
+        }
 
         // Remove triggers and procedures
         let query = format!(
@@ -338,11 +534,54 @@ impl Action for AlterColumn {
         db.run(&query)
             .context("failed to drop up and down triggers")?;
 
-        Ok(())
+        Ok(None)
+    }
+
+    fn drop_dependency_triggers(
+        &self,
+        ctx: &MigrationContext,
+        db: &mut dyn Conn,
+    ) -> anyhow::Result<()> {
+        common::drop_dependency_triggers(db, &self.up_trigger_name(ctx), &self.dependencies)
+            .context("failed to drop dependency triggers")?;
+        common::drop_dependency_triggers(db, &self.down_trigger_name(ctx), &self.down_dependencies)
+            .context("failed to drop dependency triggers for down")
+    }
+
+    // The table as seen from the new schema, where the column is backed by the temporary column.
+    // Conditions for the dependencies of `down` refer to the columns of this table.
+    fn new_table(&self, table: &Table, temporary_column_name: &str) -> Table {
+        let columns = table
+            .columns
+            .iter()
+            .map(|column| {
+                let (name, real_name) = if column.name == self.column {
+                    (
+                        self.changes.name.as_ref().unwrap_or(&column.name),
+                        temporary_column_name,
+                    )
+                } else {
+                    (&column.name, column.real_name.as_str())
+                };
+
+                Column {
+                    name: name.to_string(),
+                    real_name: real_name.to_string(),
+                    data_type: column.data_type.to_string(),
+                    nullable: column.nullable,
+                    default: column.default.clone(),
+                }
+            })
+            .collect();
+
+        Table {
+            schema: table.schema.to_string(),
+            name: table.name.to_string(),
+            real_name: table.real_name.to_string(),
+            columns,
+        }
     }
-}
 
-impl AlterColumn {
     fn temporary_column_name(&self, ctx: &MigrationContext) -> String {
         format!("{}_new_{}", ctx.prefix(), self.column)
     }
@@ -359,10 +598,283 @@ impl AlterColumn {
         format!("{}_alter_column_temporary", ctx.prefix())
     }
 
-    fn can_short_circuit(&self) -> bool {
-        self.changes.name.is_some()
-            && self.changes.data_type.is_none()
-            && self.changes.nullable.is_none()
-            && self.changes.default.is_none()
+    fn temporary_index_name(&self, ctx: &MigrationContext, index_oid: i64) -> String {
+        format!("{}_index_{}", ctx.prefix(), index_oid)
+    }
+
+    // Views which depend on the column are recreated from their definitions when the old
+    // column is dropped. This only works for regular views, and only if the column keeps its
+    // name. Views in Reshape's own migration schemas are replaced separately.
+    fn check_dependent_views(
+        &self,
+        db: &mut dyn Conn,
+        table: &str,
+        column: &str,
+    ) -> anyhow::Result<()> {
+        let views = common::get_dependent_views(db, table, Some(column))
+            .context("failed to get views for column")?;
+        let is_renamed = self
+            .changes
+            .name
+            .as_ref()
+            .is_some_and(|name| name != &self.column);
+
+        for view in views
+            .iter()
+            .filter(|view| !view.schema.starts_with("migration_"))
+        {
+            if view.is_materialized {
+                return Err(anyhow!(
+                    "materialized view {}.{} depends on column {} and can't be recreated when the column is replaced, please drop it first",
+                    view.schema,
+                    view.name,
+                    self.column
+                ));
+            }
+
+            if is_renamed {
+                return Err(anyhow!(
+                    "view {}.{} depends on column {} and can't be recreated when the column is renamed, please drop it first",
+                    view.schema,
+                    view.name,
+                    self.column
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn can_alter_in_place(&self) -> bool {
+        self.up.is_none() && self.down.is_none() && self.changes.data_type.is_none()
+    }
+
+    fn run_in_place(
+        &self,
+        ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        table: &str,
+        column: &Column,
+    ) -> anyhow::Result<()> {
+        match self.changes.nullable {
+            // Add a temporary NOT NULL constraint which is validated and replaced
+            // by a proper NOT NULL when the migration is completed
+            Some(false) if column.nullable => {
+                self.add_not_null_constraint(ctx, db, &column.real_name)?;
+            }
+            // The new schema must be able to insert NULL values right away, so we
+            // drop NOT NULL now. A temporary constraint keeps the old schema from
+            // inserting NULL values and tells abort to restore NOT NULL.
+            Some(true) if !column.nullable => {
+                let query = format!(
+                    r#"
+                    ALTER TABLE "{table}"
+                    DROP CONSTRAINT IF EXISTS "{constraint_name}";
+
+                    ALTER TABLE "{table}"
+                    ADD CONSTRAINT "{constraint_name}"
+                    CHECK ("{column}" IS NOT NULL OR NOT reshape.is_old_schema()) NOT VALID;
+
+                    ALTER TABLE "{table}"
+                    ALTER COLUMN "{column}" DROP NOT NULL;
+                    "#,
+                    table = table,
+                    constraint_name = self.not_null_constraint_name(ctx),
+                    column = column.real_name,
+                );
+                ctx.run_ddl(db, &query).context("failed to drop NOT NULL")?;
+            }
+            _ => {}
+        }
+
+        // Changes to the default value are applied to the new schema's view and
+        // are only applied to the table when the migration is completed
+        Ok(())
+    }
+
+    fn complete_in_place(&self, ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
+        match self.changes.nullable {
+            Some(false) => self.complete_not_null_constraint(ctx, db, &self.column)?,
+            Some(true) => {
+                let query = format!(
+                    r#"
+                    ALTER TABLE "{table}"
+                    DROP CONSTRAINT IF EXISTS "{constraint_name}"
+                    "#,
+                    table = self.table,
+                    constraint_name = self.not_null_constraint_name(ctx),
+                );
+                ctx.run_ddl(db, &query)
+                    .context("failed to drop temporary NOT NULL constraint")?;
+            }
+            None => {}
+        }
+
+        if let Some(default) = &self.changes.default {
+            let query = format!(
+                r#"
+                ALTER TABLE "{table}"
+                ALTER COLUMN "{column}" SET DEFAULT {default}
+                "#,
+                table = self.table,
+                column = self.column,
+                default = default,
+            );
+            ctx.run_ddl(db, &query).context("failed to set default")?;
+        }
+
+        if let Some(new_name) = &self.changes.name {
+            let query = format!(
+                r#"
+                ALTER TABLE "{table}"
+                RENAME COLUMN "{existing_name}" TO "{new_name}"
+                "#,
+                table = self.table,
+                existing_name = self.column,
+                new_name = new_name,
+            );
+            ctx.run_ddl(db, &query).context("failed to rename column")?;
+        }
+
+        Ok(())
+    }
+
+    fn abort_in_place(&self, ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
+        let has_not_null_constraint = !db
+            .query_with_params(
+                "
+                SELECT conname
+                FROM pg_catalog.pg_constraint
+                WHERE conname = $1
+                ",
+                &[&self.not_null_constraint_name(ctx)],
+            )
+            .context("failed to get any NOT NULL constraint")?
+            .is_empty();
+        if !has_not_null_constraint {
+            return Ok(());
+        }
+
+        // If NOT NULL was dropped when the migration started, it must be restored.
+        // This will fail if the new schema has inserted any NULL values.
+        if self.changes.nullable == Some(true) {
+            let query = format!(
+                r#"
+                ALTER TABLE "{table}"
+                ALTER COLUMN "{column}" SET NOT NULL
+                "#,
+                table = self.table,
+                column = self.column,
+            );
+            ctx.run_ddl(db, &query)
+                .context("failed to restore NOT NULL")?;
+        }
+
+        let query = format!(
+            r#"
+            ALTER TABLE "{table}"
+            DROP CONSTRAINT IF EXISTS "{constraint_name}"
+            "#,
+            table = self.table,
+            constraint_name = self.not_null_constraint_name(ctx),
+        );
+        ctx.run_ddl(db, &query)
+            .context("failed to drop temporary NOT NULL constraint")?;
+
+        Ok(())
+    }
+
+    // Add a temporary NOT NULL constraint to a column.
+    // This constraint is set as NOT VALID so it doesn't apply to existing rows and
+    // the existing rows don't need to be scanned under an exclusive lock.
+    // Thanks to this, we can set the full column as NOT NULL later with minimal locking.
+    fn add_not_null_constraint(
+        &self,
+        ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        column: &str,
+    ) -> anyhow::Result<()> {
+        let query = format!(
+            r#"
+            ALTER TABLE "{table}"
+            DROP CONSTRAINT IF EXISTS "{constraint_name}";
+
+            ALTER TABLE "{table}"
+            ADD CONSTRAINT "{constraint_name}"
+            CHECK ("{column}" IS NOT NULL) NOT VALID
+            "#,
+            table = self.table,
+            constraint_name = self.not_null_constraint_name(ctx),
+            column = column,
+        );
+        ctx.run_ddl(db, &query)
+            .context("failed to add NOT NULL constraint")?;
+
+        Ok(())
+    }
+
+    // Replace the temporary NOT NULL constraint, if one exists, with a proper NOT NULL
+    fn complete_not_null_constraint(
+        &self,
+        ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        column: &str,
+    ) -> anyhow::Result<()> {
+        let has_not_null_constraint = !db
+            .query_with_params(
+                "
+                SELECT constraint_name
+                FROM information_schema.constraint_column_usage
+                WHERE constraint_name = $1
+                ",
+                &[&self.not_null_constraint_name(ctx)],
+            )
+            .context("failed to get any NOT NULL constraint")?
+            .is_empty();
+        if !has_not_null_constraint {
+            return Ok(());
+        }
+
+        // Validate the temporary constraint (should always be valid).
+        // This performs a sequential scan but does not take an exclusive lock.
+        let query = format!(
+            r#"
+            ALTER TABLE "{table}"
+            VALIDATE CONSTRAINT "{constraint_name}"
+            "#,
+            table = self.table,
+            constraint_name = self.not_null_constraint_name(ctx),
+        );
+        db.run(&query)
+            .context("failed to validate NOT NULL constraint")?;
+
+        // Update the column to be NOT NULL.
+        // This requires an exclusive lock but since PG 12 it can check
+        // the existing constraint for correctness which makes the lock short-lived.
+        // Source: https://dba.stackexchange.com/a/268128
+        let query = format!(
+            r#"
+            ALTER TABLE "{table}"
+            ALTER COLUMN "{column}" SET NOT NULL
+            "#,
+            table = self.table,
+            column = column,
+        );
+        ctx.run_ddl(db, &query)
+            .context("failed to set column as NOT NULL")?;
+
+        // Drop the temporary constraint
+        let query = format!(
+            r#"
+            ALTER TABLE "{table}"
+            DROP CONSTRAINT "{constraint_name}"
+            "#,
+            table = self.table,
+            constraint_name = self.not_null_constraint_name(ctx),
+        );
+        ctx.run_ddl(db, &query)
+            .context("failed to drop NOT NULL constraint")?;
+
+        Ok(())
     }
 }
@@ -0,0 +1,129 @@
+use super::{Action, MigrationContext};
+use crate::{
+    db::{Conn, Transaction},
+    schema::Schema,
+};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AttachPartition {
+    pub table: String,
+    pub partition: String,
+
+    // Partition bound, for example "FOR VALUES FROM ('2024-01-01') TO ('2024-02-01')" or "DEFAULT"
+    pub bound: String,
+
+    #[serde(default)]
+    pub create: bool,
+
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+#[typetag::serde(name = "attach_partition")]
+impl Action for AttachPartition {
+    fn describe(&self) -> String {
+        format!(
+            "Attaching partition \"{}\" to \"{}\"",
+            self.partition, self.table
+        )
+    }
+
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    fn run(
+        &self,
+        _ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        schema: &Schema,
+    ) -> anyhow::Result<()> {
+        let table = schema.get_table(db, &self.table)?;
+
+        // The partition is created as a standalone table and then attached, as attaching
+        // only takes a SHARE UPDATE EXCLUSIVE lock on the parent table, unlike creating
+        // the table with PARTITION OF which blocks all reads and writes
+        if self.create {
+            db.run(&format!(
+                r#"
+                CREATE TABLE IF NOT EXISTS "{partition}" (
+                    LIKE "{table}" INCLUDING DEFAULTS INCLUDING CONSTRAINTS
+                )
+                "#,
+                partition = self.partition,
+                table = table.real_name,
+            ))
+            .context("failed to create partition")?;
+        }
+
+        if is_attached(db, &table.real_name, &self.partition)? {
+            return Ok(());
+        }
+
+        db.run(&format!(
+            r#"
+            ALTER TABLE "{table}" ATTACH PARTITION "{partition}" {bound}
+            "#,
+            table = table.real_name,
+            partition = self.partition,
+            bound = self.bound,
+        ))
+        .context("failed to attach partition")?;
+
+        Ok(())
+    }
+
+    fn complete<'a>(
+        &self,
+        _ctx: &MigrationContext,
+        _db: &'a mut dyn Conn,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        Ok(None)
+    }
+
+    fn update_schema(&self, _ctx: &MigrationContext, _schema: &mut Schema) {}
+
+    fn abort(&self, _ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
+        if is_attached(db, &self.table, &self.partition)? {
+            db.run(&format!(
+                r#"
+                ALTER TABLE "{table}" DETACH PARTITION "{partition}"
+                "#,
+                table = self.table,
+                partition = self.partition,
+            ))
+            .context("failed to detach partition")?;
+        }
+
+        if self.create {
+            db.run(&format!(
+                r#"
+                DROP TABLE IF EXISTS "{partition}"
+                "#,
+                partition = self.partition,
+            ))
+            .context("failed to drop partition")?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(super) fn is_attached(db: &mut dyn Conn, table: &str, partition: &str) -> anyhow::Result<bool> {
+    let is_attached = !db
+        .query_with_params(
+            "
+            SELECT pg_inherits.inhrelid
+            FROM pg_catalog.pg_inherits
+            WHERE pg_inherits.inhparent = to_regclass(format('%I.%I', current_schema(), $1::TEXT))
+            AND pg_inherits.inhrelid = to_regclass(format('%I.%I', current_schema(), $2::TEXT))
+            ",
+            &[&table, &partition],
+        )
+        .context("failed to check if partition is attached")?
+        .is_empty();
+
+    Ok(is_attached)
+}
@@ -0,0 +1,734 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context};
+use postgres::{
+    types::{FromSql, ToSql},
+    Row,
+};
+
+use super::{common, MigrationContext};
+use crate::{db::Conn, State};
+
+// How the batches of a backfill are sized and paced. By default batches have a fixed size,
+// and with a target duration the size is adapted so each batch takes about that long.
+#[derive(Clone, Copy, Debug)]
+pub struct BackfillPolicy {
+    // Rows per batch, or the size of the first batch when adapting
+    pub batch_size: u32,
+    pub target_batch_duration: Option<Duration>,
+    pub max_batch_size: u32,
+
+    // Pause between batches, for example to let replicas catch up
+    pub sleep_between_batches: Duration,
+}
+
+impl Default for BackfillPolicy {
+    fn default() -> Self {
+        BackfillPolicy {
+            batch_size: 1000,
+            target_batch_duration: None,
+            max_batch_size: 100_000,
+            sleep_between_batches: Duration::ZERO,
+        }
+    }
+}
+
+impl BackfillPolicy {
+    // Size of the next batch given how long the last one took. The size changes by at most
+    // a factor of two between batches to smooth out noisy timings.
+    fn next_batch_size(&self, batch_size: u32, duration: Duration) -> u32 {
+        let Some(target) = self.target_batch_duration else {
+            return batch_size;
+        };
+
+        let ratio = (target.as_secs_f64() / duration.as_secs_f64().max(0.001)).clamp(0.5, 2.0);
+        ((batch_size as f64 * ratio) as u32).clamp(1, self.max_batch_size.max(1))
+    }
+}
+
+// Touches all rows of a table in batches, which makes any row triggers on the table run.
+// Partitioned tables are touched one partition at a time.
+pub fn touch_rows(
+    ctx: &MigrationContext,
+    db: &mut dyn Conn,
+    table: &str,
+    column: &str,
+) -> anyhow::Result<()> {
+    let partitions = common::get_leaf_partitions(db, table)?;
+    let mut progress = Progress::start(db, &partitions)?;
+    let schema = current_schema(db)?;
+
+    for partition in &partitions {
+        let key = BatchKey::for_table(db, partition)?;
+        let (select, key_where) = match &key {
+            BatchKey::Columns(columns) => {
+                let key_where = columns
+                    .iter()
+                    .map(|column| {
+                        format!(
+                            r#""{table}"."{column}" = rows."{column}""#,
+                            table = partition,
+                            column = column,
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join(" AND ");
+                (quote_columns(columns), key_where)
+            }
+            BatchKey::Pages => (
+                "ctid".to_string(),
+                format!(
+                    r#""{table}".ctid = ANY(ARRAY(SELECT ctid FROM rows))"#,
+                    table = partition,
+                ),
+            ),
+        };
+
+        let change = format!(
+            r#"
+            UPDATE "{table}"
+            SET "{column}" = "{table}"."{column}"
+            {from}
+            WHERE {key_where}
+            "#,
+            table = partition,
+            column = column,
+            from = if matches!(key, BatchKey::Columns(_)) {
+                "FROM rows"
+            } else {
+                ""
+            },
+            key_where = key_where,
+        );
+        let batches = Batches {
+            name: format!("{}:{}.{}.{}", ctx.prefix(), schema, partition, column),
+            table: partition,
+            select,
+            key,
+            change,
+            lock_rows: false,
+        };
+        batches.run(ctx, db, &mut progress)?;
+    }
+
+    Ok(())
+}
+
+// Copies all rows from one table to another in batches, split using the batch key of the source
+// table. `columns` maps each column of the target table to an expression over the source row.
+// Inside the expressions, the source columns are referenced using the aliases in `source_columns`.
+// Only rows matching `condition`, if given, are copied. Rows which already exist in the target
+// table are left untouched. The source rows are locked while copied, so a row deleted
+// concurrently is either skipped or deleted after the copy, letting triggers remove it again.
+pub fn copy_rows(
+    ctx: &MigrationContext,
+    db: &mut dyn Conn,
+    table: &str,
+    source_columns: &[(String, String)],
+    target_table: &str,
+    columns: &[(String, String)],
+    condition: Option<&str>,
+) -> anyhow::Result<()> {
+    let mut progress = Progress::start(db, &[table.to_string()])?;
+    let key = BatchKey::for_table(db, table)?;
+
+    let source_select = source_columns
+        .iter()
+        .map(|(real_name, alias)| format!("\"{}\" AS \"{}\"", real_name, alias))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let target_columns = columns
+        .iter()
+        .map(|(column, _)| format!("\"{}\"", column))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let target_values = columns
+        .iter()
+        .map(|(_, expression)| expression.to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    let change = format!(
+        r#"
+        INSERT INTO "{target_table}" ({target_columns})
+        SELECT {target_values}
+        FROM (SELECT {source_select} FROM rows) AS row
+        {condition}
+        ON CONFLICT DO NOTHING
+        "#,
+        target_table = target_table,
+        target_columns = target_columns,
+        target_values = target_values,
+        source_select = source_select,
+        condition = condition
+            .map(|condition| format!("WHERE {}", condition))
+            .unwrap_or_default(),
+    );
+    let batches = Batches {
+        name: format!(
+            "{}:{}.{}->{}",
+            ctx.prefix(),
+            current_schema(db)?,
+            table,
+            target_table
+        ),
+        table,
+        key,
+        select: "*".to_string(),
+        change,
+        lock_rows: true,
+    };
+    batches.run(ctx, db, &mut progress)
+}
+
+fn current_schema(db: &mut dyn Conn) -> anyhow::Result<String> {
+    let schema = db
+        .query("SELECT current_schema()")
+        .context("failed to get current schema")?
+        .first()
+        .map(|row| row.get(0))
+        .ok_or_else(|| anyhow!("no schema is on the search path"))?;
+    Ok(schema)
+}
+
+// How a table is split into batches. Rows are ordered by the primary key, or by a unique index
+// over NOT NULL columns for tables without one. Tables with neither are split into ranges of
+// pages using the physical location of rows, `ctid`.
+enum BatchKey {
+    Columns(Vec<String>),
+    Pages,
+}
+
+impl BatchKey {
+    fn for_table(db: &mut dyn Conn, table: &str) -> anyhow::Result<BatchKey> {
+        let primary_key = common::get_primary_key_columns_for_table(db, &format!("\"{}\"", table))?;
+        if !primary_key.is_empty() {
+            return Ok(BatchKey::Columns(primary_key));
+        }
+
+        // A unique index only identifies rows when none of its columns can be NULL. Indexes
+        // over expressions, partial indexes and included columns can't be used either.
+        let unique_key: Option<Vec<String>> = db
+            .query(&format!(
+                r#"
+                SELECT array_agg(a.attname::TEXT ORDER BY key.position) AS columns
+                FROM pg_catalog.pg_index AS i
+                JOIN pg_catalog.pg_class AS c ON c.oid = i.indexrelid
+                CROSS JOIN LATERAL unnest(i.indkey::INT2[]) WITH ORDINALITY AS key(attnum, position)
+                JOIN pg_catalog.pg_attribute AS a ON a.attrelid = i.indrelid AND a.attnum = key.attnum
+                WHERE i.indrelid = '"{table}"'::regclass
+                AND i.indisunique
+                AND i.indisvalid
+                AND i.indexprs IS NULL
+                AND i.indpred IS NULL
+                AND key.position <= i.indnkeyatts
+                GROUP BY c.relname
+                HAVING bool_and(a.attnotnull)
+                ORDER BY count(*), c.relname
+                LIMIT 1
+                "#,
+                table = table,
+            ))
+            .context("failed to get unique indices")?
+            .first()
+            .map(|row| row.get("columns"));
+        if let Some(columns) = unique_key {
+            return Ok(BatchKey::Columns(columns));
+        }
+
+        // Only regular tables stored in the heap have a ctid which maps to pages
+        let is_heap: bool = db
+            .query(&format!(
+                r#"
+                SELECT c.relkind = 'r' AND am.amname IS NOT DISTINCT FROM 'heap' AS is_heap
+                FROM pg_catalog.pg_class AS c
+                LEFT JOIN pg_catalog.pg_am AS am ON am.oid = c.relam
+                WHERE c.oid = '"{table}"'::regclass
+                "#,
+                table = table,
+            ))
+            .context("failed to get table storage")?
+            .first()
+            .map(|row| row.get("is_heap"))
+            .unwrap_or(false);
+        if is_heap {
+            // Ranges of ctids are only scanned efficiently from Postgres 14, older versions
+            // would scan the whole table for every batch
+            let server_version: i32 = db
+                .query("SELECT current_setting('server_version_num')::INTEGER AS server_version")
+                .context("failed to get server version")?
+                .first()
+                .map(|row| row.get("server_version"))
+                .ok_or_else(|| anyhow!("failed to get server version"))?;
+            if server_version < 140000 {
+                return Err(anyhow!(
+                    "table {} has no primary key or unique index over NOT NULL columns, and can only be backfilled by ctid on Postgres 14 or later",
+                    table
+                ));
+            }
+
+            return Ok(BatchKey::Pages);
+        }
+
+        Err(anyhow!(
+            "table {} has no primary key or unique index over NOT NULL columns, and can't be backfilled by ctid as it's not stored in the heap",
+            table
+        ))
+    }
+}
+
+fn quote_columns(columns: &[String]) -> String {
+    columns
+        .iter()
+        .map(|column| format!("\"{}\"", column))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+// Runs `change` for every row of a table, one batch at a time in order of `key`. The change
+// reads the rows of the current batch, holding the `select` columns, from a CTE named `rows`.
+// Every batch is run in its own short transaction to avoid holding locks on the rows
+// for longer than needed. The cursor is saved under `name` together with each batch, so
+// a backfill which was interrupted continues after the last batch that was committed.
+// With `lock_rows`, the rows of the batch are locked with `FOR SHARE` when read.
+struct Batches<'a> {
+    name: String,
+    table: &'a str,
+    key: BatchKey,
+    select: String,
+    change: String,
+    lock_rows: bool,
+}
+
+impl Batches<'_> {
+    fn lock(&self) -> &'static str {
+        if self.lock_rows {
+            "FOR SHARE"
+        } else {
+            ""
+        }
+    }
+
+    fn run(
+        &self,
+        ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        progress: &mut Progress,
+    ) -> anyhow::Result<()> {
+        // Recorded statements, for example when exporting SQL, aren't run in batches.
+        // Instead a single statement covers every row, so there is nothing to resume either.
+        let recording = db.is_recording();
+        let cursor = if recording {
+            None
+        } else {
+            State::load_backfill_cursor(db, &self.name).context("failed to load backfill cursor")?
+        };
+        if cursor.is_some() {
+            println!();
+            println!(
+                "    Resuming backfill of {} from where it was interrupted",
+                self.table
+            );
+        }
+
+        match &self.key {
+            BatchKey::Columns(columns) => {
+                let cursor = cursor
+                    .map(|values| {
+                        values
+                            .iter()
+                            .map(|value| PostgresRawValue::decode(value))
+                            .collect::<anyhow::Result<Vec<PostgresRawValue>>>()
+                    })
+                    .transpose()?;
+                self.run_by_columns(ctx, db, progress, columns, cursor)
+            }
+            BatchKey::Pages => {
+                let cursor = cursor
+                    .and_then(|values| values.first().cloned())
+                    .map(|page| {
+                        page.parse::<u64>()
+                            .with_context(|| format!("invalid backfill cursor page {}", page))
+                    })
+                    .transpose()?;
+                self.run_by_pages(ctx, db, progress, cursor)
+            }
+        }
+    }
+
+    fn run_by_columns(
+        &self,
+        ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        progress: &mut Progress,
+        key: &[String],
+        mut cursor: Option<Vec<PostgresRawValue>>,
+    ) -> anyhow::Result<()> {
+        let policy = &ctx.backfill_policy;
+        let recording = db.is_recording();
+        let key_columns = quote_columns(key);
+        let key_order = key
+            .iter()
+            .map(|column| format!("\"{}\" DESC", column))
+            .collect::<Vec<String>>()
+            .join(", ");
+
+        let mut batch_size = policy.batch_size.max(1);
+        loop {
+            let (cursor_where, params) = cursor_condition(&key_columns, &cursor);
+            let limit = if recording {
+                String::new()
+            } else {
+                format!("LIMIT {}", batch_size)
+            };
+
+            let query = format!(
+                r#"
+                WITH rows AS (
+                    SELECT {select}
+                    FROM "{table}"
+                    {cursor_where}
+                    ORDER BY {key_columns}
+                    {limit}
+                    {lock}
+                ), changed AS (
+                    {change}
+                )
+                SELECT {key_columns}, (SELECT COUNT(*) FROM rows) AS batch_rows
+                FROM rows
+                ORDER BY {key_order}
+                LIMIT 1
+                "#,
+                select = self.select,
+                table = self.table,
+                cursor_where = cursor_where,
+                key_columns = key_columns,
+                limit = limit,
+                lock = self.lock(),
+                change = self.change,
+                key_order = key_order,
+            );
+
+            let started_at = Instant::now();
+            let rows = self.run_batch(db, &query, &params, |rows| {
+                rows.first().map(|row| {
+                    (0..key.len())
+                        .map(|i| row.get::<_, PostgresRawValue>(i).encode())
+                        .collect()
+                })
+            })?;
+
+            let Some(last_row) = rows.first() else {
+                break;
+            };
+            let batch_rows: i64 = last_row.get(key.len());
+            progress.add(batch_rows as u64);
+
+            cursor = Some((0..key.len()).map(|i| last_row.get(i)).collect());
+            batch_size = policy.next_batch_size(batch_size, started_at.elapsed());
+
+            if !policy.sleep_between_batches.is_zero() {
+                thread::sleep(policy.sleep_between_batches);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Batches cover a range of pages, sized from the number of rows seen per page so far.
+    // The range is bounded by the size of the table when the backfill starts, as rows moved
+    // to new pages after that have been updated since and already ran the triggers.
+    fn run_by_pages(
+        &self,
+        ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        progress: &mut Progress,
+        cursor: Option<u64>,
+    ) -> anyhow::Result<()> {
+        let policy = &ctx.backfill_policy;
+        let batch_query = |range_where: &str| {
+            format!(
+                r#"
+                WITH rows AS (
+                    SELECT {select}
+                    FROM "{table}"
+                    {range_where}
+                    {lock}
+                ), changed AS (
+                    {change}
+                )
+                SELECT COUNT(*) AS batch_rows
+                FROM rows
+                "#,
+                select = self.select,
+                table = self.table,
+                range_where = range_where,
+                lock = self.lock(),
+                change = self.change,
+            )
+        };
+
+        if db.is_recording() {
+            self.run_batch(db, &batch_query(""), &[], |_| None)?;
+            return Ok(());
+        }
+
+        let stats = db
+            .query(&format!(
+                r#"
+                SELECT
+                    pg_relation_size(oid) / current_setting('block_size')::BIGINT AS pages,
+                    reltuples::FLOAT8 / NULLIF(relpages, 0) AS rows_per_page
+                FROM pg_catalog.pg_class
+                WHERE oid = '"{table}"'::regclass
+                "#,
+                table = self.table,
+            ))
+            .context("failed to get table size")?;
+        let Some(stats) = stats.first() else {
+            return Ok(());
+        };
+        let pages: i64 = stats.get("pages");
+        let pages = pages as u64;
+
+        // Without statistics the first batch is a single page
+        let mut rows_per_page = stats
+            .get::<_, Option<f64>>("rows_per_page")
+            .filter(|rows| *rows > 0.0)
+            .unwrap_or(policy.batch_size as f64);
+
+        let mut page = cursor.unwrap_or(0);
+        let mut batch_size = policy.batch_size.max(1);
+        while page < pages {
+            let page_count = ((batch_size as f64 / rows_per_page).ceil() as u64).max(1);
+            let end = page + page_count;
+            let range_where = format!(
+                "WHERE ctid >= '({},0)'::tid AND ctid < '({},0)'::tid",
+                page, end
+            );
+
+            let started_at = Instant::now();
+            let rows = self.run_batch(db, &batch_query(&range_where), &[], |_| {
+                Some(vec![end.to_string()])
+            })?;
+            let batch_rows: i64 = rows.first().map(|row| row.get("batch_rows")).unwrap_or(0);
+            progress.add(batch_rows as u64);
+
+            if batch_rows > 0 {
+                rows_per_page = batch_rows as f64 / page_count as f64;
+            }
+            page = end;
+            batch_size = policy.next_batch_size(batch_size, started_at.elapsed());
+
+            if !policy.sleep_between_batches.is_zero() {
+                thread::sleep(policy.sleep_between_batches);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Runs a single batch in its own transaction. The cursor to continue from after the batch
+    // is saved in the same transaction, so it's only stored if the batch is committed.
+    fn run_batch(
+        &self,
+        db: &mut dyn Conn,
+        query: &str,
+        params: &[&(dyn ToSql + Sync)],
+        next_cursor: impl FnOnce(&[Row]) -> Option<Vec<String>>,
+    ) -> anyhow::Result<Vec<Row>> {
+        let recording = db.is_recording();
+        let mut transaction = db.transaction().context("failed to create transaction")?;
+
+        // Writes made by the backfill should be treated like they were made from the old schema
+        transaction.run("SET LOCAL reshape.is_old_schema = 'YES'")?;
+        let rows = transaction.query_with_params(query, params)?;
+
+        if let (Some(cursor), false) = (next_cursor(&rows), recording) {
+            State::save_backfill_cursor(&mut transaction, &self.name, &cursor)
+                .context("failed to save backfill cursor")?;
+        }
+        transaction
+            .commit()
+            .context("failed to commit transaction")?;
+
+        Ok(rows)
+    }
+}
+
+// Builds the condition for selecting the rows after the cursor, which holds the key
+// values of the last row in the previous batch
+fn cursor_condition<'a>(
+    key_columns: &str,
+    cursor: &'a Option<Vec<PostgresRawValue>>,
+) -> (String, Vec<&'a (dyn ToSql + Sync)>) {
+    match cursor {
+        Some(values) => {
+            let placeholders: Vec<String> = (1..=values.len()).map(|i| format!("${}", i)).collect();
+            let params = values
+                .iter()
+                .map(|value| value as &(dyn ToSql + Sync))
+                .collect();
+
+            (
+                format!("WHERE ({}) > ({})", key_columns, placeholders.join(", ")),
+                params,
+            )
+        }
+        None => ("".to_string(), Vec::new()),
+    }
+}
+
+// How often progress is reported for long backfills
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+// Tracks the rows backfilled so far. The total is estimated from the planner statistics,
+// which are missing for tables that haven't been analyzed yet.
+struct Progress {
+    estimated_total: Option<u64>,
+    rows: u64,
+    started_at: Instant,
+    last_reported_at: Instant,
+    has_reported: bool,
+}
+
+impl Progress {
+    fn start(db: &mut dyn Conn, tables: &[String]) -> anyhow::Result<Progress> {
+        let mut estimated_total = Some(0);
+        for table in tables {
+            let estimate: f32 = db
+                .query(&format!(
+                    r#"
+                    SELECT reltuples
+                    FROM pg_catalog.pg_class
+                    WHERE oid = '"{table}"'::regclass
+                    "#,
+                    table = table,
+                ))
+                .context("failed to get estimated row count")?
+                .first()
+                .map(|row| row.get("reltuples"))
+                .unwrap_or(-1.0);
+
+            estimated_total = match estimated_total {
+                Some(total) if estimate >= 0.0 => Some(total + estimate as u64),
+                _ => None,
+            };
+        }
+
+        let now = Instant::now();
+        Ok(Progress {
+            estimated_total,
+            rows: 0,
+            started_at: now,
+            last_reported_at: now,
+            has_reported: false,
+        })
+    }
+
+    fn add(&mut self, rows: u64) {
+        self.rows += rows;
+        if self.last_reported_at.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+        self.last_reported_at = Instant::now();
+
+        // Progress is reported on its own lines below the action being run
+        if !self.has_reported {
+            println!();
+            self.has_reported = true;
+        }
+
+        match self.estimated_total.filter(|total| *total > self.rows) {
+            Some(total) => {
+                let remaining = self
+                    .started_at
+                    .elapsed()
+                    .mul_f64((total - self.rows) as f64 / self.rows as f64);
+                println!(
+                    "    Backfilled {} of about {} rows ({}%), about {} left",
+                    self.rows,
+                    total,
+                    self.rows * 100 / total,
+                    format_duration(remaining),
+                );
+            }
+            None => println!("    Backfilled {} rows", self.rows),
+        }
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match (seconds / 3600, seconds % 3600 / 60, seconds % 60) {
+        (0, 0, seconds) => format!("{}s", seconds),
+        (0, minutes, seconds) => format!("{}m {}s", minutes, seconds),
+        (hours, minutes, _) => format!("{}h {}m", hours, minutes),
+    }
+}
+
+#[derive(Debug)]
+struct PostgresRawValue {
+    bytes: Vec<u8>,
+}
+
+// Values are saved as hex in their binary format, which is only read back
+// as parameters for the same column
+impl PostgresRawValue {
+    fn encode(&self) -> String {
+        self.bytes
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    fn decode(hex: &str) -> anyhow::Result<Self> {
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2).unwrap_or_default(), 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .with_context(|| format!("invalid backfill cursor value {}", hex))?;
+        Ok(PostgresRawValue { bytes })
+    }
+}
+
+impl<'a> FromSql<'a> for PostgresRawValue {
+    fn from_sql(
+        _ty: &postgres::types::Type,
+        raw: &'a [u8],
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        Ok(PostgresRawValue {
+            bytes: raw.to_vec(),
+        })
+    }
+
+    fn accepts(_ty: &postgres::types::Type) -> bool {
+        true
+    }
+}
+
+impl ToSql for PostgresRawValue {
+    fn to_sql(
+        &self,
+        _ty: &postgres::types::Type,
+        out: &mut postgres::types::private::BytesMut,
+    ) -> Result<postgres::types::IsNull, Box<dyn std::error::Error + Sync + Send>>
+    where
+        Self: Sized,
+    {
+        out.extend_from_slice(&self.bytes);
+        Ok(postgres::types::IsNull::No)
+    }
+
+    fn accepts(_ty: &postgres::types::Type) -> bool
+    where
+        Self: Sized,
+    {
+        true
+    }
+
+    postgres::types::to_sql_checked!();
+}
@@ -1,8 +1,10 @@
 use derive_builder::Builder;
-use postgres::types::{FromSql, ToSql};
 use serde::{Deserialize, Serialize};
 
-use crate::db::Conn;
+use crate::{
+    db::Conn,
+    schema::{Schema, Table},
+};
 
 #[derive(Serialize, Deserialize, Builder, Clone, Debug)]
 #[builder(setter(into))]
@@ -27,156 +29,856 @@ fn nullable_default() -> bool {
     true
 }
 
+// Another table which is read by an `up` or `down` expression. `on` is a condition joining the
+// two tables, where both are referenced by their names, for example
+// `customers.id = orders.customer_id`. Tables read by `up` and `down` are listed separately
+// as they are re-evaluated from different sides of the migration.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Dependency {
+    pub table: String,
+    pub on: String,
+}
+
+// Gets the names of all tables holding the rows of a table. For a partitioned table, these are
+// all partitions which aren't partitioned themselves, and for other tables only the table itself.
+pub fn get_leaf_partitions(db: &mut dyn Conn, table: &str) -> anyhow::Result<Vec<String>> {
+    if !is_partitioned_table(db, table)? {
+        return Ok(vec![table.to_string()]);
+    }
+
+    let partitions = db
+        .query_with_params(
+            "
+            SELECT pg_class.relname
+            FROM pg_partition_tree(format('%I.%I', current_schema(), $1::TEXT)::regclass) AS tree
+            JOIN pg_catalog.pg_class ON pg_class.oid = tree.relid
+            WHERE tree.isleaf
+            ORDER BY tree.level, pg_class.relname
+            ",
+            &[&table],
+        )?
+        .iter()
+        .map(|row| row.get("relname"))
+        .collect();
+
+    Ok(partitions)
+}
+
+// A partition directly attached to a partitioned table
 #[derive(Debug)]
-struct PostgresRawValue {
-    bytes: Vec<u8>,
+pub struct Partition {
+    pub name: String,
+    pub is_partitioned: bool,
 }
 
-impl<'a> FromSql<'a> for PostgresRawValue {
-    fn from_sql(
-        _ty: &postgres::types::Type,
-        raw: &'a [u8],
-    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
-        Ok(PostgresRawValue {
-            bytes: raw.to_vec(),
+pub fn get_partitions(db: &mut dyn Conn, table: &str) -> anyhow::Result<Vec<Partition>> {
+    let partitions = db
+        .query_with_params(
+            "
+            SELECT pg_class.relname, pg_class.relkind = 'p' AS is_partitioned
+            FROM pg_catalog.pg_inherits
+            JOIN pg_catalog.pg_class ON pg_class.oid = pg_inherits.inhrelid
+            WHERE pg_inherits.inhparent = format('%I.%I', current_schema(), $1::TEXT)::regclass
+            ORDER BY pg_class.relname
+            ",
+            &[&table],
+        )?
+        .iter()
+        .map(|row| Partition {
+            name: row.get("relname"),
+            is_partitioned: row.get("is_partitioned"),
         })
+        .collect();
+
+    Ok(partitions)
+}
+
+pub fn is_partitioned_table(db: &mut dyn Conn, table: &str) -> anyhow::Result<bool> {
+    let is_partitioned = !db
+        .query_with_params(
+            "
+            SELECT pg_class.relname
+            FROM pg_catalog.pg_class
+            JOIN pg_catalog.pg_namespace ON pg_namespace.oid = pg_class.relnamespace
+            WHERE pg_namespace.nspname = current_schema()
+            AND pg_class.relname = $1
+            AND pg_class.relkind = 'p'
+            ",
+            &[&table],
+        )?
+        .is_empty();
+
+    Ok(is_partitioned)
+}
+
+// Indices on partitioned tables can't be created or dropped concurrently
+pub fn is_partitioned_index(db: &mut dyn Conn, index: &str) -> anyhow::Result<bool> {
+    let is_partitioned = !db
+        .query_with_params(
+            "
+            SELECT pg_class.relname
+            FROM pg_catalog.pg_class
+            JOIN pg_catalog.pg_namespace ON pg_namespace.oid = pg_class.relnamespace
+            WHERE pg_namespace.nspname = current_schema()
+            AND pg_class.relname = $1
+            AND pg_class.relkind = 'I'
+            ",
+            &[&index],
+        )?
+        .is_empty();
+
+    Ok(is_partitioned)
+}
+
+pub fn get_primary_key_columns_for_table(
+    db: &mut dyn Conn,
+    table: &str,
+) -> anyhow::Result<Vec<String>> {
+    // Query from https://wiki.postgresql.org/wiki/Retrieve_primary_key_columns
+    let primary_key_columns: Vec<String> = db
+        .query(&format!(
+            "
+            SELECT a.attname AS column_name
+            FROM   pg_index i
+            JOIN   pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = ANY(i.indkey)
+            WHERE  i.indrelid = '{table}'::regclass
+            AND    i.indisprimary;
+            ",
+            table = table
+        ))?
+        .iter()
+        .map(|row| row.get("column_name"))
+        .collect();
+
+    Ok(primary_key_columns)
+}
+
+pub fn get_constraint_type(
+    db: &mut dyn Conn,
+    table: &str,
+    constraint: &str,
+) -> anyhow::Result<Option<String>> {
+    let constraint_type = db
+        .query_with_params(
+            "
+            SELECT pg_constraint.contype::TEXT AS constraint_type
+            FROM pg_catalog.pg_constraint
+            JOIN pg_catalog.pg_class ON pg_constraint.conrelid = pg_class.oid
+            JOIN pg_catalog.pg_namespace ON pg_class.relnamespace = pg_namespace.oid
+            WHERE pg_namespace.nspname = current_schema()
+            AND pg_class.relname = $1
+            AND pg_constraint.conname = $2
+            ",
+            &[&table, &constraint],
+        )?
+        .first()
+        .map(|row| row.get("constraint_type"));
+
+    Ok(constraint_type)
+}
+
+// Drops a temporary constraint from whichever table it was added to. The table
+// can't be referred to by name as it might have been renamed earlier in the migration.
+pub fn drop_temporary_constraint(db: &mut dyn Conn, constraint: &str) -> anyhow::Result<()> {
+    let tables: Vec<String> = db
+        .query_with_params(
+            "
+            SELECT pg_class.relname AS table_name
+            FROM pg_catalog.pg_constraint
+            JOIN pg_catalog.pg_class ON pg_constraint.conrelid = pg_class.oid
+            WHERE pg_constraint.conname = $1
+            ",
+            &[&constraint],
+        )?
+        .iter()
+        .map(|row| row.get("table_name"))
+        .collect();
+
+    for table in tables {
+        db.run(&format!(
+            r#"
+            ALTER TABLE "{table}"
+            DROP CONSTRAINT IF EXISTS "{constraint}"
+            "#,
+            table = table,
+            constraint = constraint,
+        ))?;
     }
 
-    fn accepts(_ty: &postgres::types::Type) -> bool {
-        true
+    Ok(())
+}
+
+// Rewrites all column references in an SQL expression using `rename`, which is
+// passed every unqualified identifier and returns the real name to use instead.
+// String literals, function names, type names and qualified references are
+// left untouched. This is used to let users write expressions using the column
+// names of the new schema, which might not match the real columns yet.
+pub fn rewrite_column_references(
+    expression: &str,
+    rename: impl Fn(&str) -> Option<String>,
+) -> String {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut result = String::with_capacity(expression.len());
+    let mut index = 0;
+
+    let is_identifier_start = |c: char| c.is_alphabetic() || c == '_';
+    let is_identifier_part = |c: char| c.is_alphanumeric() || c == '_' || c == '$';
+
+    // Returns the next non-whitespace character from a position
+    let next_significant = |from: usize| chars[from..].iter().find(|c| !c.is_whitespace());
+
+    // Returns the previous non-whitespace characters before a position
+    let previous_significant = |to: usize| -> String {
+        chars[..to]
+            .iter()
+            .rev()
+            .skip_while(|c| c.is_whitespace())
+            .take(2)
+            .collect()
+    };
+
+    while index < chars.len() {
+        let c = chars[index];
+
+        // String literals are copied verbatim
+        if c == '\'' {
+            let start = index;
+            index += 1;
+            while index < chars.len() {
+                if chars[index] == '\'' {
+                    if chars.get(index + 1) == Some(&'\'') {
+                        index += 2;
+                        continue;
+                    }
+                    break;
+                }
+                index += 1;
+            }
+            index = (index + 1).min(chars.len());
+            result.extend(&chars[start..index]);
+            continue;
+        }
+
+        // Dollar-quoted strings are copied verbatim
+        if c == '$' && !chars.get(index + 1).is_some_and(|c| c.is_ascii_digit()) {
+            let tag_end = chars[index + 1..]
+                .iter()
+                .position(|c| *c == '$')
+                .map(|position| index + 1 + position);
+
+            if let Some(tag_end) = tag_end {
+                let tag: String = chars[index..=tag_end].iter().collect();
+                let rest: String = chars[tag_end + 1..].iter().collect();
+                let end = rest
+                    .find(&tag)
+                    .map(|position| tag_end + 1 + rest[..position].chars().count() + tag.len())
+                    .unwrap_or(chars.len());
+                result.extend(&chars[index..end]);
+                index = end;
+                continue;
+            }
+        }
+
+        // Numbers can contain letters (for example exponents) and shouldn't be treated as identifiers
+        if c.is_ascii_digit() {
+            let start = index;
+            while index < chars.len() && (is_identifier_part(chars[index]) || chars[index] == '.') {
+                index += 1;
+            }
+            result.extend(&chars[start..index]);
+            continue;
+        }
+
+        let (identifier, start, end) = if c == '"' {
+            let start = index;
+            let mut identifier = String::new();
+            index += 1;
+            while index < chars.len() {
+                if chars[index] == '"' {
+                    if chars.get(index + 1) == Some(&'"') {
+                        identifier.push('"');
+                        index += 2;
+                        continue;
+                    }
+                    break;
+                }
+                identifier.push(chars[index]);
+                index += 1;
+            }
+            index = (index + 1).min(chars.len());
+            (identifier, start, index)
+        } else if is_identifier_start(c) {
+            let start = index;
+            while index < chars.len() && is_identifier_part(chars[index]) {
+                index += 1;
+            }
+
+            // Unquoted identifiers are folded to lower case by Postgres
+            let identifier: String = chars[start..index].iter().collect();
+            (identifier.to_lowercase(), start, index)
+        } else {
+            result.push(c);
+            index += 1;
+            continue;
+        };
+
+        let previous = previous_significant(start);
+        let next = next_significant(end);
+
+        let is_qualified = previous.starts_with('.') || next == Some(&'.');
+        let is_function = next == Some(&'(');
+        let is_type = previous == "::";
+        let is_string_prefix = chars.get(end) == Some(&'\'');
+
+        match rename(&identifier) {
+            Some(real_name) if !is_qualified && !is_function && !is_type && !is_string_prefix => {
+                result.push_str(&format!("\"{}\"", real_name.replace('"', "\"\"")));
+            }
+            _ => result.extend(&chars[start..end]),
+        }
+    }
+
+    result
+}
+
+// Drops an index if it was left invalid, which happens when building an index
+// concurrently fails or is interrupted. Invalid indices aren't used for queries
+// but still have to be maintained on writes.
+pub fn drop_invalid_index(db: &mut dyn Conn, index: &str) -> anyhow::Result<()> {
+    let is_invalid = !db
+        .query_with_params(
+            "
+            SELECT pg_class.relname
+            FROM pg_catalog.pg_index
+            JOIN pg_catalog.pg_class ON pg_index.indexrelid = pg_class.oid
+            WHERE pg_class.relname = $1 AND NOT pg_index.indisvalid
+            ",
+            &[&index],
+        )?
+        .is_empty();
+
+    if is_invalid {
+        db.run(&format!(
+            r#"
+            DROP INDEX CONCURRENTLY IF EXISTS "{index}"
+            "#,
+            index = index,
+        ))?;
+    }
+
+    Ok(())
+}
+
+// An index which depends on a column, either through its key columns,
+// expressions or predicate
+#[derive(Debug)]
+pub struct DependentIndex {
+    pub oid: i64,
+    pub name: String,
+    pub definition: String,
+    pub constraint: Option<(String, String)>,
+}
+
+impl DependentIndex {
+    pub fn is_exclusion_constraint(&self) -> bool {
+        matches!(&self.constraint, Some((_, constraint_type)) if constraint_type == "x")
     }
 }
 
-impl ToSql for PostgresRawValue {
-    fn to_sql(
-        &self,
-        _ty: &postgres::types::Type,
-        out: &mut postgres::types::private::BytesMut,
-    ) -> Result<postgres::types::IsNull, Box<dyn std::error::Error + Sync + Send>>
-    where
-        Self: Sized,
-    {
-        out.extend_from_slice(&self.bytes);
-        Ok(postgres::types::IsNull::No)
+// A check, exclusion or foreign key constraint which depends on a column. Foreign
+// keys can depend on the column either from the referencing or referenced side.
+#[derive(Debug)]
+pub struct DependentConstraint {
+    pub table: String,
+    pub name: String,
+    pub constraint_type: String,
+    pub definition: String,
+    pub is_local: bool,
+    pub is_referenced: bool,
+}
+
+pub fn get_dependent_indices(
+    db: &mut dyn Conn,
+    table: &str,
+    column: &str,
+) -> anyhow::Result<Vec<DependentIndex>> {
+    let indices = db
+        .query_with_params(
+            "
+            SELECT DISTINCT
+                index_class.oid::INT8 AS oid,
+                index_class.relname AS name,
+                pg_get_indexdef(index_class.oid) AS definition,
+                pg_constraint.conname AS constraint_name,
+                pg_constraint.contype::TEXT AS constraint_type
+            FROM pg_catalog.pg_index
+            JOIN pg_catalog.pg_class index_class ON index_class.oid = pg_index.indexrelid
+            JOIN pg_catalog.pg_class table_class ON table_class.oid = pg_index.indrelid
+            JOIN pg_catalog.pg_attribute ON pg_attribute.attrelid = table_class.oid
+            LEFT JOIN pg_catalog.pg_constraint ON pg_constraint.conindid = index_class.oid
+                AND pg_constraint.conrelid = table_class.oid
+            WHERE table_class.relname = $1
+            AND table_class.relnamespace = current_schema()::regnamespace
+            AND pg_attribute.attname = $2
+            AND (
+                pg_attribute.attnum = ANY(pg_index.indkey)
+                OR EXISTS (
+                    SELECT 1
+                    FROM pg_catalog.pg_depend
+                    WHERE pg_depend.classid = 'pg_class'::regclass
+                    AND pg_depend.objid = index_class.oid
+                    AND pg_depend.refclassid = 'pg_class'::regclass
+                    AND pg_depend.refobjid = table_class.oid
+                    AND pg_depend.refobjsubid = pg_attribute.attnum
+                )
+            )
+            ",
+            &[&table, &column],
+        )?
+        .iter()
+        .map(|row| {
+            let constraint_name: Option<String> = row.get("constraint_name");
+            let constraint_type: Option<String> = row.get("constraint_type");
+
+            DependentIndex {
+                oid: row.get("oid"),
+                name: row.get("name"),
+                definition: row.get("definition"),
+                constraint: constraint_name.zip(constraint_type),
+            }
+        })
+        .collect();
+
+    Ok(indices)
+}
+
+pub fn get_dependent_constraints(
+    db: &mut dyn Conn,
+    table: &str,
+    column: &str,
+) -> anyhow::Result<Vec<DependentConstraint>> {
+    let constraints = db
+        .query_with_params(
+            "
+            SELECT
+                constraint_table.relname AS table_name,
+                pg_constraint.conname AS name,
+                pg_constraint.contype::TEXT AS constraint_type,
+                pg_get_constraintdef(pg_constraint.oid) AS definition,
+                COALESCE(
+                    pg_constraint.conrelid = table_class.oid
+                        AND pg_attribute.attnum = ANY(pg_constraint.conkey),
+                    FALSE
+                ) AS is_local,
+                COALESCE(
+                    pg_constraint.confrelid = table_class.oid
+                        AND pg_attribute.attnum = ANY(pg_constraint.confkey),
+                    FALSE
+                ) AS is_referenced
+            FROM pg_catalog.pg_constraint
+            JOIN pg_catalog.pg_class constraint_table ON constraint_table.oid = pg_constraint.conrelid
+            JOIN pg_catalog.pg_class table_class ON table_class.relname = $1
+                AND table_class.relnamespace = current_schema()::regnamespace
+            JOIN pg_catalog.pg_attribute ON pg_attribute.attrelid = table_class.oid
+                AND pg_attribute.attname = $2
+            WHERE (
+                pg_constraint.conrelid = table_class.oid
+                AND pg_constraint.contype IN ('c', 'f', 'x')
+                AND pg_attribute.attnum = ANY(pg_constraint.conkey)
+            ) OR (
+                pg_constraint.confrelid = table_class.oid
+                AND pg_constraint.contype = 'f'
+                AND pg_attribute.attnum = ANY(pg_constraint.confkey)
+            )
+            ",
+            &[&table, &column],
+        )?
+        .iter()
+        .map(|row| DependentConstraint {
+            table: row.get("table_name"),
+            name: row.get("name"),
+            constraint_type: row.get("constraint_type"),
+            definition: row.get("definition"),
+            is_local: row.get("is_local"),
+            is_referenced: row.get("is_referenced"),
+        })
+        .collect();
+
+    Ok(constraints)
+}
+
+// A view which depends on a column, or any column of a table, either directly or through other
+// views. Dropping the column or table with CASCADE drops all of them, so they are listed in the
+// order they can be recreated.
+#[derive(Debug)]
+pub struct DependentView {
+    pub schema: String,
+    pub name: String,
+    pub is_materialized: bool,
+    pub definition: String,
+    pub options: Option<String>,
+    pub owner: String,
+    pub grants: Vec<String>,
+}
+
+impl DependentView {
+    // Statements which recreate the view together with its options, owner and privileges
+    pub fn create_statements(&self) -> Vec<String> {
+        let name = format!("\"{}\".\"{}\"", self.schema, self.name);
+        let options = self
+            .options
+            .as_ref()
+            .map(|options| format!("WITH ({})", options))
+            .unwrap_or_default();
+
+        let mut statements = vec![
+            format!("CREATE VIEW {} {} AS {}", name, options, self.definition),
+            format!("ALTER VIEW {} OWNER TO \"{}\"", name, self.owner),
+        ];
+        statements.extend(self.grants.iter().cloned());
+        statements
     }
+}
 
-    fn accepts(_ty: &postgres::types::Type) -> bool
-    where
-        Self: Sized,
-    {
-        true
+pub fn get_dependent_views(
+    db: &mut dyn Conn,
+    table: &str,
+    column: Option<&str>,
+) -> anyhow::Result<Vec<DependentView>> {
+    let views = db
+        .query_with_params(
+            "
+            WITH RECURSIVE views AS (
+                SELECT pg_rewrite.ev_class AS oid, 1 AS depth
+                FROM pg_catalog.pg_depend
+                JOIN pg_catalog.pg_rewrite ON pg_rewrite.oid = pg_depend.objid
+                JOIN pg_catalog.pg_class table_class ON table_class.oid = pg_depend.refobjid
+                LEFT JOIN pg_catalog.pg_attribute ON pg_attribute.attrelid = table_class.oid
+                    AND pg_attribute.attnum = pg_depend.refobjsubid
+                WHERE pg_depend.classid = 'pg_rewrite'::regclass
+                AND pg_depend.refclassid = 'pg_class'::regclass
+                AND table_class.relname = $1
+                AND table_class.relnamespace = current_schema()::regnamespace
+                AND ($2::TEXT IS NULL OR pg_attribute.attname = $2)
+                AND pg_rewrite.ev_class <> table_class.oid
+                UNION
+                SELECT pg_rewrite.ev_class, views.depth + 1
+                FROM views
+                JOIN pg_catalog.pg_depend ON pg_depend.refobjid = views.oid
+                    AND pg_depend.refclassid = 'pg_class'::regclass
+                    AND pg_depend.classid = 'pg_rewrite'::regclass
+                JOIN pg_catalog.pg_rewrite ON pg_rewrite.oid = pg_depend.objid
+                WHERE pg_rewrite.ev_class <> views.oid
+            )
+            SELECT
+                pg_namespace.nspname AS schema,
+                view_class.relname AS name,
+                view_class.relkind = 'm' AS is_materialized,
+                pg_get_viewdef(view_class.oid) AS definition,
+                array_to_string(view_class.reloptions, ', ') AS options,
+                pg_get_userbyid(view_class.relowner)::TEXT AS owner,
+                ARRAY(
+                    SELECT format(
+                        'GRANT %s ON %I.%I TO %s%s',
+                        acl.privilege_type,
+                        pg_namespace.nspname,
+                        view_class.relname,
+                        CASE WHEN acl.grantee = 0 THEN 'PUBLIC' ELSE quote_ident(pg_get_userbyid(acl.grantee)) END,
+                        CASE WHEN acl.is_grantable THEN ' WITH GRANT OPTION' ELSE '' END
+                    )
+                    FROM aclexplode(view_class.relacl) AS acl
+                    WHERE acl.grantee <> view_class.relowner
+                ) AS grants
+            FROM views
+            JOIN pg_catalog.pg_class view_class ON view_class.oid = views.oid
+            JOIN pg_catalog.pg_namespace ON pg_namespace.oid = view_class.relnamespace
+            GROUP BY view_class.oid, pg_namespace.nspname
+            ORDER BY max(views.depth), view_class.oid
+            ",
+            &[&table, &column],
+        )?
+        .iter()
+        .map(|row| DependentView {
+            schema: row.get("schema"),
+            name: row.get("name"),
+            is_materialized: row.get("is_materialized"),
+            definition: row.get("definition"),
+            options: row.get("options"),
+            owner: row.get("owner"),
+            grants: row.get("grants"),
+        })
+        .collect();
+
+    Ok(views)
+}
+
+// Rewrites a definition from `pg_get_constraintdef` to refer to a renamed column.
+// For foreign keys, only the side of the constraint which refers to the column is changed.
+pub fn rename_column_in_constraint(
+    constraint: &DependentConstraint,
+    old_column: &str,
+    new_column: &str,
+) -> String {
+    let rename = |name: &str| (name == old_column).then(|| new_column.to_string());
+
+    match constraint.definition.split_once(" REFERENCES ") {
+        Some((local, referenced)) => {
+            let local = if constraint.is_local {
+                rewrite_column_references(local, rename)
+            } else {
+                local.to_string()
+            };
+
+            let referenced = if constraint.is_referenced {
+                // Skip the referenced table name to only rewrite the list of columns
+                match referenced.split_once('(') {
+                    Some((table, rest)) => {
+                        format!("{}({}", table, rewrite_column_references(rest, rename))
+                    }
+                    None => referenced.to_string(),
+                }
+            } else {
+                referenced.to_string()
+            };
+
+            format!("{} REFERENCES {}", local, referenced)
+        }
+        None => rewrite_column_references(&constraint.definition, rename),
     }
+}
+
+// Rewrites a definition from `pg_get_indexdef` to create a new index with a different
+// name and a renamed column. Returns the parts before and after the index name so that
+// options like CONCURRENTLY can be added.
+pub fn rename_column_in_index(
+    index: &DependentIndex,
+    old_column: &str,
+    new_column: &str,
+) -> Option<(String, String)> {
+    let (create, rest) = index.definition.split_once(" INDEX ")?;
+    let (_, rest) = rest.split_once(" ON ")?;
 
-    postgres::types::to_sql_checked!();
+    // Only rewrite from the column list onwards to avoid touching the table and method names
+    let column_list_start = rest.find('(')?;
+    let (table, columns) = rest.split_at(column_list_start);
+
+    let columns = rewrite_column_references(columns, |name| {
+        (name == old_column).then(|| new_column.to_string())
+    });
+
+    Some((
+        format!("{} INDEX", create),
+        format!("ON {}{}", table, columns),
+    ))
 }
 
-pub fn batch_touch_rows(db: &mut dyn Conn, table: &str, column: &str) -> anyhow::Result<()> {
-    const BATCH_SIZE: u16 = 1000;
+// Get the sequence used to generate values for a column, either through
+// a serial default or an identity
+pub fn get_sequence(
+    db: &mut dyn Conn,
+    table: &str,
+    column: &str,
+) -> anyhow::Result<Option<String>> {
+    let sequence = db
+        .query_with_params(
+            "SELECT pg_get_serial_sequence($1, $2) AS sequence",
+            &[&format!("\"{}\"", table), &column],
+        )?
+        .first()
+        .and_then(|row| row.get("sequence"));
+
+    Ok(sequence)
+}
+
+// Get the identity kind of a column, "a" for ALWAYS and "d" for BY DEFAULT,
+// or None if the column isn't an identity
+pub fn get_identity(
+    db: &mut dyn Conn,
+    table: &str,
+    column: &str,
+) -> anyhow::Result<Option<String>> {
+    let identity = db
+        .query_with_params(
+            "
+            SELECT pg_attribute.attidentity::TEXT AS identity
+            FROM pg_catalog.pg_attribute
+            JOIN pg_catalog.pg_class ON pg_class.oid = pg_attribute.attrelid
+            JOIN pg_catalog.pg_namespace ON pg_namespace.oid = pg_class.relnamespace
+            WHERE pg_namespace.nspname = current_schema()
+            AND pg_class.relname = $1
+            AND pg_attribute.attname = $2
+            AND pg_attribute.attidentity != ''
+            ",
+            &[&table, &column],
+        )?
+        .first()
+        .map(|row| row.get("identity"));
 
-    db.query("SET reshape.is_old_schema = 'YES'")?;
+    Ok(identity)
+}
 
-    let mut cursor: Option<PostgresRawValue> = None;
+// Get the value which will be returned by the next call to nextval for a sequence
+pub fn get_next_sequence_value(db: &mut dyn Conn, sequence: &str) -> anyhow::Result<i64> {
+    let rows = db.query(&format!(
+        "SELECT last_value, is_called FROM {sequence}",
+        sequence = sequence,
+    ))?;
+    let row = rows
+        .first()
+        .ok_or_else(|| anyhow::anyhow!("failed to get current value of sequence {}", sequence))?;
 
-    loop {
-        let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+    let last_value: i64 = row.get("last_value");
+    let is_called: bool = row.get("is_called");
 
-        let primary_key = get_primary_key_columns_for_table(db, table)?;
-        let primary_key_columns = primary_key.join(", ");
+    Ok(if is_called {
+        last_value + 1
+    } else {
+        last_value
+    })
+}
 
-        let primary_key_where = primary_key
-            .iter()
-            .map(|column| {
-                format!(
-                    r#"
-                    "{table}"."{column}" = rows."{column}"
-                    "#,
-                    table = table,
-                    column = column,
-                )
-            })
-            .collect::<Vec<String>>()
-            .join(" AND ");
+// Builds a subquery exposing a single row of a table under the table's name and the schema names
+// of its columns, for example `(SELECT NEW."real_name" AS "name") AS "table"`
+pub fn row_subquery(table: &Table, row: &str, alias: &str) -> String {
+    let columns: Vec<String> = table
+        .columns
+        .iter()
+        .map(|column| format!("{}.\"{}\" AS \"{}\"", row, column.real_name, column.name))
+        .collect();
 
-        let returning_columns = primary_key
+    format!(
+        "(SELECT {columns}) AS \"{alias}\"",
+        columns = columns.join(", "),
+        alias = alias,
+    )
+}
+
+// Builds a subquery exposing all rows of a table under the table's name and the schema names
+// of its columns, along with any `extra_columns`
+pub fn table_subquery(table: &Table, alias: &str, extra_columns: &[&str]) -> String {
+    let mut columns: Vec<String> = extra_columns
+        .iter()
+        .map(|column| column.to_string())
+        .collect();
+    columns.extend(
+        table
+            .columns
             .iter()
-            .map(|column| format!("rows.\"{}\"", column))
-            .collect::<Vec<String>>()
-            .join(", ");
+            .map(|column| format!("\"{}\" AS \"{}\"", column.real_name, column.name)),
+    );
 
-        let cursor_where = if let Some(cursor) = &cursor {
-            params.push(cursor);
+    format!(
+        "(SELECT {columns} FROM \"{schema}\".\"{table}\") AS \"{alias}\"",
+        columns = columns.join(", "),
+        schema = table.schema,
+        table = table.real_name,
+        alias = alias,
+    )
+}
+
+fn dependency_trigger_name(trigger_name: &str, index: usize) -> String {
+    format!("{}_dependency_{}", trigger_name, index)
+}
 
+// Adds triggers to the tables in `dependencies` which touch `column` for every row of `table`
+// joined to a changed row. This makes the row triggers on `table` evaluate their expressions
+// again, so that values derived from other tables stay fresh. The rows are touched as if written
+// through the old schema, which re-evaluates `up` expressions, or if `old_schema` is false, as if
+// written through the new schema, which re-evaluates `down` expressions. Both the old and new
+// versions of a changed row are used, so rows which are no longer joined are updated too.
+pub fn create_dependency_triggers(
+    db: &mut dyn Conn,
+    schema: &Schema,
+    trigger_name: &str,
+    table: &Table,
+    column: &str,
+    dependencies: &[Dependency],
+    old_schema: bool,
+) -> anyhow::Result<()> {
+    for (index, dependency) in dependencies.iter().enumerate() {
+        let dependency_table = schema.get_table(db, &dependency.table)?;
+        if dependency_table.real_name == table.real_name {
+            return Err(anyhow::anyhow!(
+                "table {} can't depend on itself",
+                dependency.table
+            ));
+        }
+
+        let touch_query = |row: &str| {
             format!(
-                "WHERE ({primary_key_columns}) > $1",
-                primary_key_columns = primary_key_columns
+                r#"
+                UPDATE "{schema}"."{table}" AS __reshape_target
+                SET "{column}" = __reshape_target."{column}"
+                FROM {table_rows}, {dependency_row}
+                WHERE __reshape_target.ctid = "{alias}"."__reshape_ctid" AND ({on});
+                "#,
+                schema = table.schema,
+                table = table.real_name,
+                column = column,
+                table_rows = table_subquery(table, &table.name, &["ctid AS __reshape_ctid"]),
+                dependency_row = row_subquery(&dependency_table, row, &dependency.table),
+                alias = table.name,
+                on = dependency.on,
             )
+        };
+
+        // The old schema is also detected from the search path, so it's replaced with the
+        // schema of the table when touching rows as the new schema
+        let (setting, search_path) = if old_schema {
+            ("YES", "previous_search_path".to_string())
         } else {
-            "".to_string()
+            ("", format!("'\"{}\"'", table.schema))
         };
 
         let query = format!(
             r#"
-            WITH rows AS (
-                SELECT {primary_key_columns}
-                FROM public."{table}"
-                {cursor_where}
-                ORDER BY {primary_key_columns}
-                LIMIT {batch_size}
-            ), update AS (
-                UPDATE public."{table}"
-                SET "{column}" = "{column}"
-                FROM rows
-                WHERE {primary_key_where}
-                RETURNING {returning_columns}
-            )
-            SELECT LAST_VALUE(({primary_key_columns})) OVER () AS last_value
-            FROM update
-            LIMIT 1
+            CREATE OR REPLACE FUNCTION {trigger_name}()
+            RETURNS TRIGGER AS $$
+            DECLARE
+                previous_setting TEXT := current_setting('reshape.is_old_schema', TRUE);
+                previous_search_path TEXT := current_setting('search_path');
+            BEGIN
+                PERFORM set_config('reshape.is_old_schema', '{setting}', TRUE);
+                PERFORM set_config('search_path', {search_path}, TRUE);
+                IF TG_OP = 'UPDATE' OR TG_OP = 'DELETE' THEN
+                    {touch_old}
+                END IF;
+                IF TG_OP = 'UPDATE' OR TG_OP = 'INSERT' THEN
+                    {touch_new}
+                END IF;
+                PERFORM set_config('reshape.is_old_schema', COALESCE(previous_setting, ''), TRUE);
+                PERFORM set_config('search_path', previous_search_path, TRUE);
+                RETURN NULL;
+            END
+            $$ language 'plpgsql';
+
+            DROP TRIGGER IF EXISTS "{trigger_name}" ON "{dependency_table}";
+            CREATE TRIGGER "{trigger_name}" AFTER INSERT OR UPDATE OR DELETE ON "{dependency_table}" FOR EACH ROW EXECUTE PROCEDURE {trigger_name}();
             "#,
-            table = table,
-            primary_key_columns = primary_key_columns,
-            cursor_where = cursor_where,
-            batch_size = BATCH_SIZE,
-            column = column,
-            primary_key_where = primary_key_where,
-            returning_columns = returning_columns,
+            trigger_name = dependency_trigger_name(trigger_name, index),
+            setting = setting,
+            search_path = search_path,
+            touch_old = touch_query("OLD"),
+            touch_new = touch_query("NEW"),
+            dependency_table = dependency_table.real_name,
         );
-        let last_value = db
-            .query_with_params(&query, &params)?
-            .first()
-            .and_then(|row| row.get("last_value"));
-
-        if last_value.is_none() {
-            break;
-        }
-
-        cursor = last_value
+        db.run(&query)?;
     }
 
-    db.query("SET reshape.is_old_schema = ''")?;
-
     Ok(())
 }
 
-fn get_primary_key_columns_for_table(
+pub fn drop_dependency_triggers(
     db: &mut dyn Conn,
-    table: &str,
-) -> anyhow::Result<Vec<String>> {
-    // Query from https://wiki.postgresql.org/wiki/Retrieve_primary_key_columns
-    let primary_key_columns: Vec<String> = db
-        .query(&format!(
-            "
-            SELECT a.attname AS column_name
-            FROM   pg_index i
-            JOIN   pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = ANY(i.indkey)
-            WHERE  i.indrelid = '{table}'::regclass
-            AND    i.indisprimary;
-            ",
-            table = table
-        ))?
-        .iter()
-        .map(|row| row.get("column_name"))
-        .collect();
+    trigger_name: &str,
+    dependencies: &[Dependency],
+) -> anyhow::Result<()> {
+    for index in 0..dependencies.len() {
+        db.run(&format!(
+            r#"DROP FUNCTION IF EXISTS "{}" CASCADE"#,
+            dependency_trigger_name(trigger_name, index),
+        ))?;
+    }
 
-    Ok(primary_key_columns)
+    Ok(())
 }
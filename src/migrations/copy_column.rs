@@ -0,0 +1,172 @@
+use super::{backfill, common, Action, AddColumn, Column, Dependency, MigrationContext};
+use crate::{
+    db::{Conn, Transaction},
+    schema::Schema,
+};
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CopyColumn {
+    pub table: String,
+    pub column: Column,
+    pub from: String,
+    pub on: String,
+    pub up: String,
+
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+impl CopyColumn {
+    // The column itself is added using a regular `AddColumn` without an `up` expression,
+    // which takes care of adding, validating and renaming the temporary column
+    fn add_column(&self) -> AddColumn {
+        AddColumn {
+            table: self.table.to_string(),
+            up: None,
+            column: self.column.clone(),
+            dependencies: vec![],
+            schema: self.schema.clone(),
+        }
+    }
+
+    fn dependencies(&self) -> Vec<Dependency> {
+        vec![Dependency {
+            table: self.from.to_string(),
+            on: self.on.to_string(),
+        }]
+    }
+
+    fn trigger_name(&self, ctx: &MigrationContext) -> String {
+        format!(
+            "{}_copy_column_{}_{}",
+            ctx.prefix(),
+            self.table,
+            self.column.name
+        )
+    }
+}
+
+#[typetag::serde(name = "copy_column")]
+impl Action for CopyColumn {
+    fn describe(&self) -> String {
+        format!(
+            "Copying column \"{}\" from \"{}\" to \"{}\"",
+            self.column.name, self.from, self.table
+        )
+    }
+
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    fn run(
+        &self,
+        ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        schema: &Schema,
+    ) -> anyhow::Result<()> {
+        if self.table == self.from {
+            return Err(anyhow!(
+                "can't copy column {} from table {} to itself",
+                self.column.name,
+                self.table
+            ));
+        }
+
+        let table = schema.get_table(db, &self.table)?;
+        let from_table = schema.get_table(db, &self.from)?;
+
+        let add_column = self.add_column();
+        add_column.run(ctx, db, schema)?;
+        let temp_column_name = add_column.temp_column_name(ctx);
+
+        // Both tables are exposed to `on` and `up` under their schema names so that they
+        // can be referenced just like in a regular query joining the two tables.
+        // The value is always computed, regardless of which schema the write came from,
+        // as the copy should stay correct for both.
+        let query = format!(
+            r#"
+            CREATE OR REPLACE FUNCTION {trigger_name}()
+            RETURNS TRIGGER AS $$
+            BEGIN
+                NEW."{temp_column_name}" = (
+                    SELECT {up}
+                    FROM {row}, {from_table}
+                    WHERE {on}
+                    LIMIT 1
+                );
+                RETURN NEW;
+            END
+            $$ language 'plpgsql';
+
+            DROP TRIGGER IF EXISTS "{trigger_name}" ON "{table}";
+            CREATE TRIGGER "{trigger_name}" BEFORE UPDATE OR INSERT ON "{table}" FOR EACH ROW EXECUTE PROCEDURE {trigger_name}();
+            "#,
+            trigger_name = self.trigger_name(ctx),
+            temp_column_name = temp_column_name,
+            up = self.up,
+            on = self.on,
+            row = common::row_subquery(&table, "NEW", &self.table),
+            from_table = common::table_subquery(&from_table, &self.from, &[]),
+            table = table.real_name,
+        );
+        db.run(&query).context("failed to create copy trigger")?;
+
+        // Changes to the other table touch all rows which are joined to the changed row,
+        // which causes the trigger above to update the copy
+        common::create_dependency_triggers(
+            db,
+            schema,
+            &self.trigger_name(ctx),
+            &table,
+            &temp_column_name,
+            &self.dependencies(),
+            true,
+        )
+        .context("failed to create trigger on referenced table")?;
+
+        // Backfill values in batches
+        backfill::touch_rows(ctx, db, &table.real_name, &temp_column_name)
+            .context("failed to batch update existing rows")?;
+
+        Ok(())
+    }
+
+    fn complete<'a>(
+        &self,
+        ctx: &MigrationContext,
+        db: &'a mut dyn Conn,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        let query = format!(
+            r#"
+            DROP FUNCTION IF EXISTS "{trigger_name}" CASCADE;
+            "#,
+            trigger_name = self.trigger_name(ctx),
+        );
+        db.run(&query).context("failed to drop copy trigger")?;
+        common::drop_dependency_triggers(db, &self.trigger_name(ctx), &self.dependencies())
+            .context("failed to drop trigger on referenced table")?;
+
+        self.add_column().complete(ctx, db)
+    }
+
+    fn update_schema(&self, ctx: &MigrationContext, schema: &mut Schema) {
+        self.add_column().update_schema(ctx, schema);
+    }
+
+    fn abort(&self, ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
+        let query = format!(
+            r#"
+            DROP FUNCTION IF EXISTS "{trigger_name}" CASCADE;
+            "#,
+            trigger_name = self.trigger_name(ctx),
+        );
+        db.run(&query).context("failed to drop copy trigger")?;
+        common::drop_dependency_triggers(db, &self.trigger_name(ctx), &self.dependencies())
+            .context("failed to drop trigger on referenced table")?;
+
+        self.add_column().abort(ctx, db)
+    }
+}
@@ -0,0 +1,76 @@
+use super::{Action, MigrationContext};
+use crate::{
+    db::{Conn, Transaction},
+    schema::Schema,
+};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreateEnum {
+    pub name: String,
+    pub values: Vec<String>,
+
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+#[typetag::serde(name = "create_enum")]
+impl Action for CreateEnum {
+    fn describe(&self) -> String {
+        format!("Creating enum \"{}\"", self.name)
+    }
+
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    fn run(
+        &self,
+        _ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        _schema: &Schema,
+    ) -> anyhow::Result<()> {
+        let query = format!(
+            r#"
+            CREATE TYPE "{name}" AS ENUM ({values})
+            "#,
+            name = self.name,
+            values = enum_values(&self.values),
+        );
+        db.run(&query).context("failed to create enum")?;
+
+        Ok(())
+    }
+
+    fn complete<'a>(
+        &self,
+        _ctx: &MigrationContext,
+        _db: &'a mut dyn Conn,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        Ok(None)
+    }
+
+    fn update_schema(&self, _ctx: &MigrationContext, _schema: &mut Schema) {}
+
+    fn abort(&self, _ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
+        let query = format!(
+            r#"
+            DROP TYPE IF EXISTS "{name}"
+            "#,
+            name = self.name,
+        );
+        db.run(&query).context("failed to drop enum")?;
+
+        Ok(())
+    }
+}
+
+// Format a list of enum values as quoted SQL literals
+pub(super) fn enum_values(values: &[String]) -> String {
+    values
+        .iter()
+        .map(|value| format!("'{}'", value.replace('\'', "''")))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
@@ -0,0 +1,81 @@
+use super::{create_table::table_definition, Action, Column, ForeignKey, MigrationContext};
+use crate::{
+    db::{Conn, Transaction},
+    schema::Schema,
+};
+use anyhow::Context;
+use derive_builder::Builder;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Builder, Debug)]
+#[builder(setter(into))]
+pub struct CreatePartitionedTable {
+    pub name: String,
+    pub columns: Vec<Column>,
+    pub primary_key: Vec<String>,
+
+    #[serde(default)]
+    #[builder(default)]
+    pub foreign_keys: Vec<ForeignKey>,
+
+    // Partitioning method and key, for example "RANGE (created_at)"
+    pub partition_by: String,
+
+    #[serde(default)]
+    #[builder(setter(into, strip_option), default)]
+    pub schema: Option<String>,
+}
+
+#[typetag::serde(name = "create_partitioned_table")]
+impl Action for CreatePartitionedTable {
+    fn describe(&self) -> String {
+        format!("Creating partitioned table \"{}\"", self.name)
+    }
+
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    fn run(
+        &self,
+        _ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        _schema: &Schema,
+    ) -> anyhow::Result<()> {
+        db.run(&format!(
+            r#"
+            CREATE TABLE "{name}" (
+                {definition}
+            ) PARTITION BY {partition_by}
+            "#,
+            name = self.name,
+            definition = table_definition(&self.columns, &self.primary_key, &self.foreign_keys),
+            partition_by = self.partition_by,
+        ))
+        .context("failed to create partitioned table")?;
+        Ok(())
+    }
+
+    fn complete<'a>(
+        &self,
+        _ctx: &MigrationContext,
+        _db: &'a mut dyn Conn,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        Ok(None)
+    }
+
+    fn update_schema(&self, _ctx: &MigrationContext, _schema: &mut Schema) {}
+
+    fn abort(&self, _ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
+        // Partitions are dropped together with the table
+        db.run(&format!(
+            r#"
+            DROP TABLE IF EXISTS "{name}"
+            "#,
+            name = self.name,
+        ))
+        .context("failed to drop partitioned table")?;
+
+        Ok(())
+    }
+}
@@ -17,6 +17,10 @@ pub struct CreateTable {
     #[serde(default)]
     #[builder(default)]
     pub foreign_keys: Vec<ForeignKey>,
+
+    #[serde(default)]
+    #[builder(setter(into, strip_option), default)]
+    pub schema: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -32,68 +36,16 @@ impl Action for CreateTable {
         format!("Creating table \"{}\"", self.name)
     }
 
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
     fn run(
         &self,
         _ctx: &MigrationContext,
         db: &mut dyn Conn,
         _schema: &Schema,
     ) -> anyhow::Result<()> {
-        let mut definition_rows: Vec<String> = self
-            .columns
-            .iter()
-            .map(|column| {
-                let mut parts = vec![format!("\"{}\"", column.name), column.data_type.to_string()];
-
-                if let Some(default) = &column.default {
-                    parts.push("DEFAULT".to_string());
-                    parts.push(default.to_string());
-                }
-
-                if !column.nullable {
-                    parts.push("NOT NULL".to_string());
-                }
-
-                if let Some(generated) = &column.generated {
-                    parts.push("GENERATED".to_string());
-                    parts.push(generated.to_string());
-                }
-
-                parts.join(" ")
-            })
-            .collect();
-
-        let primary_key_columns = self
-            .primary_key
-            .iter()
-            // Add quotes around all column names
-            .map(|col| format!("\"{}\"", col))
-            .collect::<Vec<String>>()
-            .join(", ");
-        definition_rows.push(format!("PRIMARY KEY ({})", primary_key_columns));
-
-        for foreign_key in &self.foreign_keys {
-            // Add quotes around all column names
-            let columns: Vec<String> = foreign_key
-                .columns
-                .iter()
-                .map(|col| format!("\"{}\"", col))
-                .collect();
-            let referenced_columns: Vec<String> = foreign_key
-                .referenced_columns
-                .iter()
-                .map(|col| format!("\"{}\"", col))
-                .collect();
-
-            definition_rows.push(format!(
-                r#"
-                FOREIGN KEY ({columns}) REFERENCES "{table}" ({referenced_columns})
-                "#,
-                columns = columns.join(", "),
-                table = foreign_key.referenced_table,
-                referenced_columns = referenced_columns.join(", "),
-            ));
-        }
-
         db.run(&format!(
             r#"
             CREATE TABLE "{name}" (
@@ -101,7 +53,7 @@ impl Action for CreateTable {
             )
             "#,
             name = self.name,
-            definition = definition_rows.join(",\n"),
+            definition = table_definition(&self.columns, &self.primary_key, &self.foreign_keys),
         ))
         .context("failed to create table")?;
         Ok(())
@@ -130,3 +82,66 @@ impl Action for CreateTable {
         Ok(())
     }
 }
+
+// Build the column, primary key and foreign key definitions of a table
+pub(super) fn table_definition(
+    columns: &[Column],
+    primary_key: &[String],
+    foreign_keys: &[ForeignKey],
+) -> String {
+    let mut definition_rows: Vec<String> = columns
+        .iter()
+        .map(|column| {
+            let mut parts = vec![format!("\"{}\"", column.name), column.data_type.to_string()];
+
+            if let Some(default) = &column.default {
+                parts.push("DEFAULT".to_string());
+                parts.push(default.to_string());
+            }
+
+            if !column.nullable {
+                parts.push("NOT NULL".to_string());
+            }
+
+            if let Some(generated) = &column.generated {
+                parts.push("GENERATED".to_string());
+                parts.push(generated.to_string());
+            }
+
+            parts.join(" ")
+        })
+        .collect();
+
+    let primary_key_columns = primary_key
+        .iter()
+        // Add quotes around all column names
+        .map(|col| format!("\"{}\"", col))
+        .collect::<Vec<String>>()
+        .join(", ");
+    definition_rows.push(format!("PRIMARY KEY ({})", primary_key_columns));
+
+    for foreign_key in foreign_keys {
+        // Add quotes around all column names
+        let columns: Vec<String> = foreign_key
+            .columns
+            .iter()
+            .map(|col| format!("\"{}\"", col))
+            .collect();
+        let referenced_columns: Vec<String> = foreign_key
+            .referenced_columns
+            .iter()
+            .map(|col| format!("\"{}\"", col))
+            .collect();
+
+        definition_rows.push(format!(
+            r#"
+            FOREIGN KEY ({columns}) REFERENCES "{table}" ({referenced_columns})
+            "#,
+            columns = columns.join(", "),
+            table = foreign_key.referenced_table,
+            referenced_columns = referenced_columns.join(", "),
+        ));
+    }
+
+    definition_rows.join(",\n")
+}
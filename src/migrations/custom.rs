@@ -0,0 +1,80 @@
+use super::{Action, MigrationContext};
+use crate::{
+    db::{Conn, Transaction},
+    schema::Schema,
+};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Custom {
+    pub start: Option<String>,
+    pub complete: Option<String>,
+    pub abort: Option<String>,
+
+    // Run the complete query in the same transaction as the state update
+    // which marks the action as completed, ensuring it's only applied once
+    #[serde(default)]
+    pub complete_in_transaction: bool,
+
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+#[typetag::serde(name = "custom")]
+impl Action for Custom {
+    fn describe(&self) -> String {
+        "Running custom migration".to_string()
+    }
+
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    fn run(
+        &self,
+        _ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        _schema: &Schema,
+    ) -> anyhow::Result<()> {
+        if let Some(start_query) = &self.start {
+            db.run(start_query).context("failed to run start query")?;
+        }
+
+        Ok(())
+    }
+
+    fn complete<'a>(
+        &self,
+        _ctx: &MigrationContext,
+        db: &'a mut dyn Conn,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        let complete_query = match &self.complete {
+            Some(complete_query) => complete_query,
+            None => return Ok(None),
+        };
+
+        if self.complete_in_transaction {
+            let mut transaction = db.transaction().context("failed to create transaction")?;
+            transaction
+                .run(complete_query)
+                .context("failed to run complete query")?;
+            return Ok(Some(transaction));
+        }
+
+        db.run(complete_query)
+            .context("failed to run complete query")?;
+
+        Ok(None)
+    }
+
+    fn update_schema(&self, _ctx: &MigrationContext, _schema: &mut Schema) {}
+
+    fn abort(&self, _ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
+        if let Some(abort_query) = &self.abort {
+            db.run(abort_query).context("failed to run abort query")?;
+        }
+
+        Ok(())
+    }
+}
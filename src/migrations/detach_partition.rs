@@ -0,0 +1,139 @@
+use super::{attach_partition::is_attached, Action, MigrationContext};
+use crate::{
+    db::{Conn, Transaction},
+    schema::Schema,
+};
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DetachPartition {
+    pub table: String,
+    pub partition: String,
+
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+#[typetag::serde(name = "detach_partition")]
+impl Action for DetachPartition {
+    fn describe(&self) -> String {
+        format!(
+            "Detaching partition \"{}\" from \"{}\"",
+            self.partition, self.table
+        )
+    }
+
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    fn run(
+        &self,
+        _ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        schema: &Schema,
+    ) -> anyhow::Result<()> {
+        // The partition is kept attached until the migration is completed,
+        // as the rows in it should still be visible to the old schema
+        let table = schema.get_table(db, &self.table)?;
+        if !is_attached(db, &table.real_name, &self.partition)? {
+            return Err(anyhow!(
+                "{} is not a partition of {}",
+                self.partition,
+                self.table
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn complete<'a>(
+        &self,
+        _ctx: &MigrationContext,
+        db: &'a mut dyn Conn,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        // Concurrent detaches were added in Postgres 14, older versions always use a regular detach
+        let server_version: i32 = db
+            .query("SELECT current_setting('server_version_num')::INTEGER AS server_version")
+            .context("failed to get server version")?
+            .first()
+            .map(|row| row.get("server_version"))
+            .ok_or_else(|| anyhow!("failed to get server version"))?;
+        if server_version < 140000 {
+            if is_attached(db, &self.table, &self.partition)? {
+                db.run(&format!(
+                    r#"
+                    ALTER TABLE "{table}" DETACH PARTITION "{partition}"
+                    "#,
+                    table = self.table,
+                    partition = self.partition,
+                ))
+                .context("failed to detach partition")?;
+            }
+
+            return Ok(None);
+        }
+
+        let detach_pending: Option<bool> = db
+            .query_with_params(
+                "
+                SELECT pg_inherits.inhdetachpending
+                FROM pg_catalog.pg_inherits
+                WHERE pg_inherits.inhparent = to_regclass(format('%I.%I', current_schema(), $1::TEXT))
+                AND pg_inherits.inhrelid = to_regclass(format('%I.%I', current_schema(), $2::TEXT))
+                ",
+                &[&self.table, &self.partition],
+            )
+            .context("failed to get partition")?
+            .first()
+            .map(|row| row.get("inhdetachpending"));
+
+        let mode = match detach_pending {
+            // The partition has already been detached
+            None => return Ok(None),
+            // A previous concurrent detach was interrupted and must be finalized
+            Some(true) => "FINALIZE",
+            Some(false) => {
+                // Detaching concurrently isn't possible when there is a default partition
+                let has_default_partition = !db
+                    .query_with_params(
+                        "
+                        SELECT partdefid
+                        FROM pg_catalog.pg_partitioned_table
+                        WHERE partrelid = format('%I.%I', current_schema(), $1::TEXT)::regclass
+                        AND partdefid <> 0
+                        ",
+                        &[&self.table],
+                    )
+                    .context("failed to get default partition")?
+                    .is_empty();
+
+                if has_default_partition {
+                    ""
+                } else {
+                    "CONCURRENTLY"
+                }
+            }
+        };
+
+        // This can't run in a transaction when detaching concurrently
+        db.run(&format!(
+            r#"
+            ALTER TABLE "{table}" DETACH PARTITION "{partition}" {mode}
+            "#,
+            table = self.table,
+            partition = self.partition,
+            mode = mode,
+        ))
+        .context("failed to detach partition")?;
+
+        Ok(None)
+    }
+
+    fn update_schema(&self, _ctx: &MigrationContext, _schema: &mut Schema) {}
+
+    fn abort(&self, _ctx: &MigrationContext, _db: &mut dyn Conn) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    thread,
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use postgres::error::SqlState;
+
+use crate::db::Conn;
+
+// Limits how long DDL may wait for locks on a table. An `ALTER TABLE` waiting behind a
+// long-running query blocks every other query on the table for as long as it waits, so
+// instead it gives up after `lock_timeout` and is retried after a backoff, giving queued
+// queries a chance to run in between.
+#[derive(Clone, Copy, Debug)]
+pub struct LockRetryPolicy {
+    pub lock_timeout: Duration,
+    pub statement_timeout: Option<Duration>,
+
+    // Number of retries after the first attempt before giving up
+    pub max_retries: u32,
+
+    // The backoff doubles on every retry up to `max_backoff`, with random jitter so that
+    // concurrent processes don't retry in lockstep
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for LockRetryPolicy {
+    fn default() -> Self {
+        LockRetryPolicy {
+            lock_timeout: Duration::from_secs(2),
+            statement_timeout: None,
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+impl LockRetryPolicy {
+    // Backoff before the retry with the given number, starting at 1. Picked uniformly
+    // between half and all of the exponential backoff.
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry - 1))
+            .min(self.max_backoff);
+
+        let jitter = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        backoff.mul_f64(0.5 + jitter / 2.0)
+    }
+}
+
+// Run a DDL statement with the timeouts of the policy, retrying when the lock can't be
+// taken in time. Every attempt runs in its own transaction so a failed attempt can be
+// rolled back and the timeouts don't leak into later statements. Statements run as part
+// of a larger transaction are never retried on their own, as the transaction would keep
+// holding its other locks while waiting. The lock error is instead returned so that the
+// whole transaction can be retried with `run_with_retry`.
+pub(crate) fn run_ddl(
+    policy: &LockRetryPolicy,
+    db: &mut dyn Conn,
+    query: &str,
+) -> anyhow::Result<()> {
+    let max_retries = if db.in_transaction() {
+        0
+    } else {
+        policy.max_retries
+    };
+    retry(policy, max_retries, db, |transaction| {
+        transaction.run(query)
+    })
+}
+
+// Run a group of statements in a transaction, or savepoint if already in a transaction,
+// retrying all of them when a lock can't be taken in time. Rolling back a failed attempt
+// releases the locks it took, which means the connection must not have taken any locks
+// on the same tables before.
+pub(crate) fn run_with_retry(
+    policy: &LockRetryPolicy,
+    db: &mut dyn Conn,
+    f: impl FnMut(&mut dyn Conn) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    retry(policy, policy.max_retries, db, f)
+}
+
+fn retry(
+    policy: &LockRetryPolicy,
+    max_retries: u32,
+    db: &mut dyn Conn,
+    mut f: impl FnMut(&mut dyn Conn) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    // Plans and exported scripts are never retried
+    if db.is_recording() {
+        return f(db);
+    }
+
+    // Timeouts set in a savepoint stay in effect for the rest of the enclosing transaction,
+    // so the previous values are restored afterwards rather than reset to the defaults
+    let (previous_lock_timeout, previous_statement_timeout): (String, String) = db
+        .query(
+            "
+            SELECT
+                current_setting('lock_timeout') AS lock_timeout,
+                current_setting('statement_timeout') AS statement_timeout
+            ",
+        )?
+        .first()
+        .map(|row| (row.get("lock_timeout"), row.get("statement_timeout")))
+        .ok_or_else(|| anyhow!("failed to get current timeouts"))?;
+
+    let mut retry = 0;
+    loop {
+        let mut transaction = db.transaction()?;
+        transaction.run(&format!(
+            "SET LOCAL lock_timeout = {}",
+            policy.lock_timeout.as_millis()
+        ))?;
+        if let Some(statement_timeout) = policy.statement_timeout {
+            transaction.run(&format!(
+                "SET LOCAL statement_timeout = {}",
+                statement_timeout.as_millis()
+            ))?;
+        }
+
+        match f(&mut transaction) {
+            Ok(()) => {
+                transaction.run(&format!(
+                    "SET LOCAL lock_timeout = '{}'; SET LOCAL statement_timeout = '{}'",
+                    previous_lock_timeout, previous_statement_timeout
+                ))?;
+                transaction.commit()?;
+                return Ok(());
+            }
+            Err(err) if is_lock_not_available(&err) && retry < max_retries => {
+                // Dropping the transaction rolls back the failed attempt
+                drop(transaction);
+
+                retry += 1;
+                let backoff = policy.backoff(retry);
+                // Retries are logged on their own lines below the action being run
+                if retry == 1 {
+                    println!();
+                }
+                println!(
+                    "    Timed out waiting for lock, retrying in {:.1?} ({} of {} retries)",
+                    backoff, retry, max_retries
+                );
+                thread::sleep(backoff);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// The lock error may be wrapped in context added by the statements of a group
+fn is_lock_not_available(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        err.downcast_ref::<postgres::Error>()
+            .and_then(postgres::Error::code)
+            == Some(&SqlState::LOCK_NOT_AVAILABLE)
+    })
+}
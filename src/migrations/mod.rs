@@ -7,7 +7,13 @@ use serde::{Deserialize, Serialize};
 
 // Re-export migration types
 mod common;
-pub use common::{Column, ColumnBuilder};
+pub use common::{Column, ColumnBuilder, Dependency};
+
+mod lock_retry;
+pub use lock_retry::LockRetryPolicy;
+
+mod backfill;
+pub use backfill::BackfillPolicy;
 
 mod create_table;
 pub use create_table::{CreateTable, CreateTableBuilder, ForeignKey};
@@ -33,6 +39,60 @@ pub use remove_table::RemoveTable;
 mod rename_table;
 pub use rename_table::RenameTable;
 
+mod add_foreign_key;
+pub use add_foreign_key::AddForeignKey;
+
+mod remove_constraint;
+pub use remove_constraint::RemoveConstraint;
+
+mod remove_foreign_key;
+pub use remove_foreign_key::RemoveForeignKey;
+
+mod add_check_constraint;
+pub use add_check_constraint::AddCheckConstraint;
+
+mod add_unique_constraint;
+pub use add_unique_constraint::AddUniqueConstraint;
+
+mod create_enum;
+pub use create_enum::CreateEnum;
+
+mod remove_enum;
+pub use remove_enum::RemoveEnum;
+
+mod add_enum_value;
+pub use add_enum_value::AddEnumValue;
+
+mod replace_enum;
+pub use replace_enum::{EnumColumn, ReplaceEnum};
+
+mod rename_enum_value;
+pub use rename_enum_value::RenameEnumValue;
+
+mod custom;
+pub use custom::Custom;
+
+mod widen_primary_key;
+pub use widen_primary_key::WidenPrimaryKey;
+
+mod rewrite_table;
+pub use rewrite_table::RewriteTable;
+
+mod split_table;
+pub use split_table::SplitTable;
+
+mod copy_column;
+pub use copy_column::CopyColumn;
+
+mod create_partitioned_table;
+pub use create_partitioned_table::{CreatePartitionedTable, CreatePartitionedTableBuilder};
+
+mod attach_partition;
+pub use attach_partition::AttachPartition;
+
+mod detach_partition;
+pub use detach_partition::DetachPartition;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Migration {
     pub name: String,
@@ -73,6 +133,9 @@ impl Clone for Migration {
 pub struct MigrationContext {
     migration_index: usize,
     action_index: usize,
+    sub_action_index: Option<usize>,
+    lock_retry_policy: LockRetryPolicy,
+    backfill_policy: BackfillPolicy,
 }
 
 impl MigrationContext {
@@ -80,28 +143,87 @@ impl MigrationContext {
         MigrationContext {
             migration_index,
             action_index,
+            sub_action_index: None,
+            lock_retry_policy: LockRetryPolicy::default(),
+            backfill_policy: BackfillPolicy::default(),
+        }
+    }
+
+    pub fn with_lock_retry_policy(mut self, lock_retry_policy: LockRetryPolicy) -> Self {
+        self.lock_retry_policy = lock_retry_policy;
+        self
+    }
+
+    pub fn with_backfill_policy(mut self, backfill_policy: BackfillPolicy) -> Self {
+        self.backfill_policy = backfill_policy;
+        self
+    }
+
+    // Run DDL which takes strong locks on a table, like most forms of `ALTER TABLE`,
+    // giving up and retrying if the locks can't be taken quickly
+    fn run_ddl(&self, db: &mut dyn Conn, query: &str) -> anyhow::Result<()> {
+        lock_retry::run_ddl(&self.lock_retry_policy, db, query)
+    }
+
+    // Run a group of DDL statements together, retrying all of them if any lock can't be
+    // taken quickly. See `lock_retry::run_with_retry`.
+    fn run_ddl_group(
+        &self,
+        db: &mut dyn Conn,
+        f: impl FnMut(&mut dyn Conn) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        lock_retry::run_with_retry(&self.lock_retry_policy, db, f)
+    }
+
+    // Create a context for an action which is run as part of another action.
+    // This gives every sub-action its own prefix so that their temporary
+    // objects don't collide.
+    fn for_sub_action(&self, sub_action_index: usize) -> Self {
+        MigrationContext {
+            migration_index: self.migration_index,
+            action_index: self.action_index,
+            sub_action_index: Some(sub_action_index),
+            lock_retry_policy: self.lock_retry_policy,
+            backfill_policy: self.backfill_policy,
         }
     }
 
     fn prefix(&self) -> String {
-        format!(
-            "__reshape_{:0>4}_{:0>4}",
-            self.migration_index, self.action_index
-        )
+        match self.sub_action_index {
+            Some(sub_action_index) => format!(
+                "__reshape_{:0>4}_{:0>4}_{:0>4}",
+                self.migration_index, self.action_index, sub_action_index
+            ),
+            None => format!(
+                "__reshape_{:0>4}_{:0>4}",
+                self.migration_index, self.action_index
+            ),
+        }
     }
 
     fn prefix_inverse(&self) -> String {
-        format!(
-            "__reshape_{:0>4}_{:0>4}",
-            1000 - self.migration_index,
-            1000 - self.action_index
-        )
+        match self.sub_action_index {
+            Some(sub_action_index) => format!(
+                "__reshape_{:0>4}_{:0>4}_{:0>4}",
+                1000 - self.migration_index,
+                1000 - self.action_index,
+                1000 - sub_action_index
+            ),
+            None => format!(
+                "__reshape_{:0>4}_{:0>4}",
+                1000 - self.migration_index,
+                1000 - self.action_index
+            ),
+        }
     }
 }
 
 #[typetag::serde(tag = "type")]
-pub trait Action: Debug {
+pub trait Action: Debug + Send + Sync {
     fn describe(&self) -> String;
+
+    // The Postgres schema holding the tables and types the action applies to, defaults to `public`
+    fn schema(&self) -> Option<&str>;
     fn run(&self, ctx: &MigrationContext, db: &mut dyn Conn, schema: &Schema)
         -> anyhow::Result<()>;
     fn complete<'a>(
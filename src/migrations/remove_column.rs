@@ -11,6 +11,9 @@ pub struct RemoveColumn {
     pub table: String,
     pub column: String,
     pub down: Option<String>,
+
+    #[serde(default)]
+    pub schema: Option<String>,
 }
 
 impl RemoveColumn {
@@ -33,6 +36,10 @@ impl Action for RemoveColumn {
         )
     }
 
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
     fn run(
         &self,
         ctx: &MigrationContext,
@@ -48,7 +55,8 @@ impl Action for RemoveColumn {
                 .iter()
                 .map(|column| {
                     format!(
-                        "{alias} public.{table}.{real_name}%TYPE := NEW.{real_name};",
+                        "{alias} \"{schema}\".{table}.{real_name}%TYPE := NEW.{real_name};",
+                        schema = table.schema,
                         table = table.real_name,
                         alias = column.name,
                         real_name = column.real_name,
@@ -105,7 +113,7 @@ impl Action for RemoveColumn {
             column = self.column,
             trigger_name = self.trigger_name(ctx),
         );
-        db.run(&query)
+        ctx.run_ddl(db, &query)
             .context("failed to drop column and down trigger")?;
 
         Ok(None)
@@ -0,0 +1,82 @@
+use super::{common, Action, MigrationContext};
+use crate::{
+    db::{Conn, Transaction},
+    schema::Schema,
+};
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemoveConstraint {
+    pub table: String,
+    pub constraint: String,
+
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+#[typetag::serde(name = "remove_constraint")]
+impl Action for RemoveConstraint {
+    fn describe(&self) -> String {
+        format!(
+            "Removing constraint \"{}\" from \"{}\"",
+            self.constraint, self.table
+        )
+    }
+
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    fn run(
+        &self,
+        _ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        schema: &Schema,
+    ) -> anyhow::Result<()> {
+        // Make sure the constraint exists, the constraint isn't removed until completion
+        let table = schema.get_table(db, &self.table)?;
+        common::get_constraint_type(db, &table.real_name, &self.constraint)?.ok_or_else(|| {
+            anyhow!(
+                "no such constraint {} exists on {}",
+                self.constraint,
+                self.table
+            )
+        })?;
+
+        Ok(())
+    }
+
+    fn complete<'a>(
+        &self,
+        _ctx: &MigrationContext,
+        db: &'a mut dyn Conn,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        drop_constraint(db, &self.table, &self.constraint)?;
+        Ok(None)
+    }
+
+    fn update_schema(&self, _ctx: &MigrationContext, _schema: &mut Schema) {}
+
+    fn abort(&self, _ctx: &MigrationContext, _db: &mut dyn Conn) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+pub(super) fn drop_constraint(
+    db: &mut dyn Conn,
+    table: &str,
+    constraint: &str,
+) -> anyhow::Result<()> {
+    db.run(&format!(
+        r#"
+        ALTER TABLE "{table}"
+        DROP CONSTRAINT IF EXISTS "{constraint}"
+        "#,
+        table = table,
+        constraint = constraint,
+    ))
+    .context("failed to drop constraint")?;
+
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use super::{common, remove_constraint, Action, MigrationContext};
+use crate::{
+    db::{Conn, Transaction},
+    schema::Schema,
+};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RemoveForeignKey {
+    pub table: String,
+    pub foreign_key: String,
+
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+#[typetag::serde(name = "remove_foreign_key")]
+impl Action for RemoveForeignKey {
+    fn describe(&self) -> String {
+        format!(
+            "Removing foreign key \"{}\" from \"{}\"",
+            self.foreign_key, self.table
+        )
+    }
+
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    fn run(
+        &self,
+        _ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        schema: &Schema,
+    ) -> anyhow::Result<()> {
+        // Make sure the foreign key exists, it isn't removed until completion
+        let table = schema.get_table(db, &self.table)?;
+        let constraint_type = common::get_constraint_type(db, &table.real_name, &self.foreign_key)?
+            .ok_or_else(|| {
+                anyhow!(
+                    "no such foreign key {} exists on {}",
+                    self.foreign_key,
+                    self.table
+                )
+            })?;
+
+        if constraint_type != "f" {
+            return Err(anyhow!(
+                "constraint {} on {} is not a foreign key",
+                self.foreign_key,
+                self.table
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn complete<'a>(
+        &self,
+        _ctx: &MigrationContext,
+        db: &'a mut dyn Conn,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        remove_constraint::drop_constraint(db, &self.table, &self.foreign_key)?;
+        Ok(None)
+    }
+
+    fn update_schema(&self, _ctx: &MigrationContext, _schema: &mut Schema) {}
+
+    fn abort(&self, _ctx: &MigrationContext, _db: &mut dyn Conn) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use super::{Action, MigrationContext};
+use super::{common, Action, MigrationContext};
 use crate::{
     db::{Conn, Transaction},
     schema::Schema,
@@ -9,6 +9,9 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RemoveIndex {
     pub index: String,
+
+    #[serde(default)]
+    pub schema: Option<String>,
 }
 
 #[typetag::serde(name = "remove_index")]
@@ -17,6 +20,10 @@ impl Action for RemoveIndex {
         format!("Removing index \"{}\"", self.index)
     }
 
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
     fn run(
         &self,
         _ctx: &MigrationContext,
@@ -32,10 +39,14 @@ impl Action for RemoveIndex {
         _ctx: &MigrationContext,
         db: &'a mut dyn Conn,
     ) -> anyhow::Result<Option<Transaction<'a>>> {
+        let is_partitioned = common::is_partitioned_index(db, &self.index)
+            .context("failed to check if index is partitioned")?;
+
         db.run(&format!(
             r#"
-            DROP INDEX CONCURRENTLY IF EXISTS "{name}"
+            DROP INDEX {concurrently} IF EXISTS "{name}"
             "#,
+            concurrently = if is_partitioned { "" } else { "CONCURRENTLY" },
             name = self.index
         ))
         .context("failed to drop index")?;
@@ -9,6 +9,9 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RemoveTable {
     pub table: String,
+
+    #[serde(default)]
+    pub schema: Option<String>,
 }
 
 #[typetag::serde(name = "remove_table")]
@@ -17,6 +20,10 @@ impl Action for RemoveTable {
         format!("Removing table \"{}\"", self.table)
     }
 
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
     fn run(
         &self,
         _ctx: &MigrationContext,
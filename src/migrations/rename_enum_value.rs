@@ -0,0 +1,109 @@
+use super::{Action, EnumColumn, MigrationContext, ReplaceEnum};
+use crate::{
+    db::{Conn, Transaction},
+    schema::Schema,
+};
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RenameEnumValue {
+    #[serde(rename = "enum")]
+    pub enum_name: String,
+    pub from: String,
+    pub to: String,
+    pub columns: Vec<EnumColumn>,
+
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+impl RenameEnumValue {
+    // Renaming a value is performed by replacing the enum with a copy where the value
+    // has been renamed. This lets the old schema keep using the old value.
+    fn replace_enum(&self, values: Vec<String>) -> ReplaceEnum {
+        ReplaceEnum {
+            enum_name: self.enum_name.to_string(),
+            values,
+            columns: self.columns.clone(),
+            renames: HashMap::from([(self.from.to_string(), self.to.to_string())]),
+            schema: self.schema.clone(),
+        }
+    }
+}
+
+#[typetag::serde(name = "rename_enum_value")]
+impl Action for RenameEnumValue {
+    fn describe(&self) -> String {
+        format!(
+            "Renaming value \"{}\" to \"{}\" in enum \"{}\"",
+            self.from, self.to, self.enum_name
+        )
+    }
+
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    fn run(
+        &self,
+        ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        schema: &Schema,
+    ) -> anyhow::Result<()> {
+        let values: Vec<String> = db
+            .query_with_params(
+                "
+                SELECT pg_enum.enumlabel
+                FROM pg_catalog.pg_enum
+                JOIN pg_catalog.pg_type ON pg_type.oid = pg_enum.enumtypid
+                JOIN pg_catalog.pg_namespace ON pg_namespace.oid = pg_type.typnamespace
+                WHERE pg_type.typname = $1 AND pg_namespace.nspname = current_schema()
+                ORDER BY pg_enum.enumsortorder
+                ",
+                &[&self.enum_name],
+            )
+            .context("failed to get enum values")?
+            .iter()
+            .map(|row| row.get("enumlabel"))
+            .collect();
+
+        if !values.contains(&self.from) {
+            return Err(anyhow!(
+                "no value {} exists in enum {}",
+                self.from,
+                self.enum_name
+            ));
+        }
+
+        let values = values
+            .into_iter()
+            .map(|value| {
+                if value == self.from {
+                    self.to.to_string()
+                } else {
+                    value
+                }
+            })
+            .collect();
+
+        self.replace_enum(values).run(ctx, db, schema)
+    }
+
+    fn complete<'a>(
+        &self,
+        ctx: &MigrationContext,
+        db: &'a mut dyn Conn,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        self.replace_enum(vec![]).complete(ctx, db)
+    }
+
+    fn update_schema(&self, ctx: &MigrationContext, schema: &mut Schema) {
+        self.replace_enum(vec![]).update_schema(ctx, schema);
+    }
+
+    fn abort(&self, ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
+        self.replace_enum(vec![]).abort(ctx, db)
+    }
+}
@@ -10,6 +10,9 @@ use serde::{Deserialize, Serialize};
 pub struct RenameTable {
     pub table: String,
     pub new_name: String,
+
+    #[serde(default)]
+    pub schema: Option<String>,
 }
 
 #[typetag::serde(name = "rename_table")]
@@ -18,6 +21,10 @@ impl Action for RenameTable {
         format!("Renaming table \"{}\" to \"{}\"", self.table, self.new_name)
     }
 
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
     fn run(
         &self,
         _ctx: &MigrationContext,
@@ -0,0 +1,286 @@
+use super::{create_enum::enum_values, Action, AlterColumn, ColumnChanges, MigrationContext};
+use crate::{
+    db::{Conn, Transaction},
+    schema::Schema,
+};
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReplaceEnum {
+    #[serde(rename = "enum")]
+    pub enum_name: String,
+    pub values: Vec<String>,
+    pub columns: Vec<EnumColumn>,
+
+    #[serde(default)]
+    pub renames: HashMap<String, String>,
+
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EnumColumn {
+    pub table: String,
+    pub column: String,
+}
+
+impl ReplaceEnum {
+    fn new_enum_name(&self, ctx: &MigrationContext) -> String {
+        format!("{}_{}", ctx.prefix(), self.enum_name)
+    }
+
+    // Every column using the enum is changed to use the new enum through an `AlterColumn`.
+    // The values are converted through text, applying any renames along the way.
+    fn alter_columns(&self, ctx: &MigrationContext) -> Vec<AlterColumn> {
+        let new_enum_name = self.new_enum_name(ctx);
+        let schema = self.schema.as_deref().unwrap_or("public");
+        let reverse_renames: HashMap<String, String> = self
+            .renames
+            .iter()
+            .map(|(from, to)| (to.to_string(), from.to_string()))
+            .collect();
+
+        self.columns
+            .iter()
+            .map(|column| AlterColumn {
+                table: column.table.to_string(),
+                column: column.column.to_string(),
+                up: Some(convert_enum_value(
+                    &column.column,
+                    &self.renames,
+                    schema,
+                    &new_enum_name,
+                )),
+                down: Some(convert_enum_value(
+                    &column.column,
+                    &reverse_renames,
+                    schema,
+                    &self.enum_name,
+                )),
+                changes: ColumnChanges {
+                    name: None,
+                    data_type: Some(format!("\"{}\"", new_enum_name)),
+                    nullable: None,
+                    default: None,
+                },
+                dependencies: vec![],
+                down_dependencies: vec![],
+                schema: self.schema.clone(),
+            })
+            .collect()
+    }
+}
+
+#[typetag::serde(name = "replace_enum")]
+impl Action for ReplaceEnum {
+    fn describe(&self) -> String {
+        format!("Replacing enum \"{}\"", self.enum_name)
+    }
+
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    fn run(
+        &self,
+        ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        schema: &Schema,
+    ) -> anyhow::Result<()> {
+        // All columns using the enum must be changed, otherwise the old enum can't be removed
+        let mut listed_columns: Vec<(String, String)> = Vec::new();
+        for enum_column in &self.columns {
+            let table = schema.get_table(db, &enum_column.table)?;
+            let column = table
+                .columns
+                .iter()
+                .find(|column| column.name == enum_column.column)
+                .ok_or_else(|| anyhow!("no such column {} exists", enum_column.column))?;
+            listed_columns.push((table.real_name.to_string(), column.real_name.to_string()));
+        }
+
+        let columns_using_enum = db
+            .query_with_params(
+                "
+                SELECT pg_class.relname AS table_name, pg_attribute.attname AS column_name
+                FROM pg_catalog.pg_attribute
+                JOIN pg_catalog.pg_class ON pg_class.oid = pg_attribute.attrelid
+                JOIN pg_catalog.pg_namespace ON pg_namespace.oid = pg_class.relnamespace
+                JOIN pg_catalog.pg_type ON pg_type.oid = pg_attribute.atttypid
+                WHERE pg_type.typname = $1
+                AND pg_namespace.nspname = current_schema()
+                AND pg_class.relkind IN ('r', 'p')
+                AND NOT pg_attribute.attisdropped
+                ",
+                &[&self.enum_name],
+            )
+            .context("failed to get columns using enum")?;
+        for row in columns_using_enum {
+            let table: String = row.get("table_name");
+            let column: String = row.get("column_name");
+
+            if !listed_columns.contains(&(table.to_string(), column.to_string())) {
+                return Err(anyhow!(
+                    "column {}.{} uses enum {} but is not included in columns",
+                    table,
+                    column,
+                    self.enum_name
+                ));
+            }
+        }
+
+        let query = format!(
+            r#"
+            DROP TYPE IF EXISTS "{new_name}";
+            CREATE TYPE "{new_name}" AS ENUM ({values});
+            "#,
+            new_name = self.new_enum_name(ctx),
+            values = enum_values(&self.values),
+        );
+        db.run(&query).context("failed to create new enum")?;
+
+        for (index, mut alter_column) in self.alter_columns(ctx).into_iter().enumerate() {
+            // The default value is bound to the old enum and has to be converted to the new one
+            let table = schema.get_table(db, &alter_column.table)?;
+            let default = table
+                .columns
+                .iter()
+                .find(|column| column.name == alter_column.column)
+                .and_then(|column| column.default.as_ref());
+            if let Some(default) = default {
+                let value = parse_enum_default(default).ok_or_else(|| {
+                    anyhow!(
+                        "unsupported default {} for column {}",
+                        default,
+                        alter_column.column
+                    )
+                })?;
+                let value = self.renames.get(&value).unwrap_or(&value);
+                alter_column.changes.default = Some(format!(
+                    "'{}'::\"{}\"",
+                    value.replace('\'', "''"),
+                    self.new_enum_name(ctx)
+                ));
+            }
+
+            alter_column.run(&ctx.for_sub_action(index), db, schema)?;
+        }
+
+        Ok(())
+    }
+
+    fn complete<'a>(
+        &self,
+        ctx: &MigrationContext,
+        db: &'a mut dyn Conn,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        for (index, alter_column) in self.alter_columns(ctx).iter().enumerate() {
+            if let Some(transaction) = alter_column.complete(&ctx.for_sub_action(index), db)? {
+                transaction
+                    .commit()
+                    .context("failed to commit transaction")?;
+            }
+        }
+
+        // Once no columns use the old enum anymore, the new one can take its place
+        let mut transaction = db.transaction().context("failed to create transaction")?;
+        let query = format!(
+            r#"
+            DROP TYPE "{name}";
+            ALTER TYPE "{new_name}" RENAME TO "{name}";
+            "#,
+            name = self.enum_name,
+            new_name = self.new_enum_name(ctx),
+        );
+        transaction
+            .run(&query)
+            .context("failed to replace old enum")?;
+
+        Ok(Some(transaction))
+    }
+
+    fn update_schema(&self, ctx: &MigrationContext, schema: &mut Schema) {
+        for (index, alter_column) in self.alter_columns(ctx).iter().enumerate() {
+            alter_column.update_schema(&ctx.for_sub_action(index), schema);
+        }
+
+        // The new schema refers to the new enum by the name of the enum it replaces
+        schema.set_type(&self.enum_name, &self.new_enum_name(ctx));
+    }
+
+    fn abort(&self, ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
+        for (index, alter_column) in self.alter_columns(ctx).iter().enumerate().rev() {
+            alter_column.abort(&ctx.for_sub_action(index), db)?;
+        }
+
+        let query = format!(
+            r#"
+            DROP TYPE IF EXISTS "{new_name}"
+            "#,
+            new_name = self.new_enum_name(ctx),
+        );
+        db.run(&query).context("failed to drop new enum")?;
+
+        Ok(())
+    }
+}
+
+// Build an expression which converts a column value to another enum by going
+// through text, for example: CASE status::TEXT WHEN 'a' THEN 'b' ELSE status::TEXT END::public.new_enum.
+// The enum is qualified as the expression is evaluated with the search path of the client.
+fn convert_enum_value(
+    column: &str,
+    renames: &HashMap<String, String>,
+    schema: &str,
+    to_enum: &str,
+) -> String {
+    if renames.is_empty() {
+        return format!("{}::TEXT::\"{}\".\"{}\"", column, schema, to_enum);
+    }
+
+    let cases: Vec<String> = renames
+        .iter()
+        .map(|(from, to)| {
+            format!(
+                "WHEN '{}' THEN '{}'",
+                from.replace('\'', "''"),
+                to.replace('\'', "''")
+            )
+        })
+        .collect();
+
+    format!(
+        "CASE {column}::TEXT {cases} ELSE {column}::TEXT END::\"{schema}\".\"{to_enum}\"",
+        column = column,
+        cases = cases.join(" "),
+        schema = schema,
+        to_enum = to_enum,
+    )
+}
+
+// Extract the value from an enum default such as 'active'::status
+fn parse_enum_default(default: &str) -> Option<String> {
+    let rest = default.strip_prefix('\'')?;
+
+    let mut value = String::new();
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\'' {
+            if chars.peek() == Some(&'\'') {
+                chars.next();
+                value.push('\'');
+                continue;
+            }
+
+            let rest: String = chars.collect();
+            return rest.starts_with("::").then_some(value);
+        }
+
+        value.push(c);
+    }
+
+    None
+}
@@ -0,0 +1,984 @@
+use super::{
+    backfill, common, create_table::table_definition, Action, Column, ForeignKey, MigrationContext,
+};
+use crate::{
+    db::{Conn, Transaction},
+    schema::Schema,
+};
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RewriteTable {
+    pub table: String,
+    pub columns: Vec<Column>,
+    pub primary_key: Vec<String>,
+
+    #[serde(default)]
+    pub foreign_keys: Vec<ForeignKey>,
+
+    pub partition_by: Option<String>,
+
+    // Expressions for the new columns, referencing the columns of the old table
+    #[serde(default)]
+    pub up: HashMap<String, String>,
+
+    // Expressions for the old columns, referencing the columns of the new table
+    #[serde(default)]
+    pub down: HashMap<String, String>,
+
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+impl RewriteTable {
+    fn shadow_table_name(&self, ctx: &MigrationContext) -> String {
+        format!("{}_{}", ctx.prefix(), self.table)
+    }
+
+    fn old_trigger_name(&self, ctx: &MigrationContext) -> String {
+        format!("{}_rewrite_old_trigger", ctx.prefix())
+    }
+
+    fn new_trigger_name(&self, ctx: &MigrationContext) -> String {
+        format!("{}_rewrite_new_trigger", ctx.prefix())
+    }
+
+    fn shadow_index_name(&self, ctx: &MigrationContext, index_oid: i64) -> String {
+        format!("{}_rewrite_index_{}", ctx.prefix(), index_oid)
+    }
+
+    // Build copies of the secondary indices of the old table on the shadow table, unless
+    // they already exist. `renames` maps the real names of the old columns to the names of
+    // the new columns.
+    fn copy_indices(
+        &self,
+        ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        indices: &[SecondaryIndex],
+        renames: &HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        let shadow_table = self.shadow_table_name(ctx);
+
+        for index in indices {
+            let name = self.shadow_index_name(ctx, index.oid);
+            let has_copy = !db
+                .query_with_params(
+                    "
+                    SELECT relname
+                    FROM pg_catalog.pg_class
+                    WHERE relname = $1 AND relkind = 'i'
+                    AND relnamespace = current_schema()::regnamespace
+                    ",
+                    &[&name],
+                )
+                .context("failed to get index")?
+                .is_empty();
+            if has_copy {
+                continue;
+            }
+
+            let (create, definition) = index.shadow_definition(&shadow_table, renames)?;
+            common::drop_invalid_index(db, &name).context("failed to drop invalid index")?;
+            db.run(&format!(
+                r#"
+                {create} CONCURRENTLY IF NOT EXISTS "{name}" {definition}
+                "#,
+                create = create,
+                name = name,
+                definition = definition,
+            ))
+            .with_context(|| format!("failed to create copy of index {}", index.name))?;
+        }
+
+        Ok(())
+    }
+
+    // Columns in the new table which should keep getting their values from the sequence
+    // of the column with the same name in the old table, for example a serial primary key
+    fn inherits_sequence(&self, column: &Column) -> bool {
+        column.default.is_none()
+            && column.generated.is_none()
+            && !self.up.contains_key(&column.name)
+    }
+}
+
+#[typetag::serde(name = "rewrite_table")]
+impl Action for RewriteTable {
+    fn describe(&self) -> String {
+        format!("Rewriting table \"{}\"", self.table)
+    }
+
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    fn run(
+        &self,
+        ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        schema: &Schema,
+    ) -> anyhow::Result<()> {
+        let table = schema.get_table(db, &self.table)?;
+        let shadow_table = self.shadow_table_name(ctx);
+
+        // Secondary indices and check constraints would be dropped together with the old table,
+        // so they are recreated on the shadow table. That can't be done online for partitioned
+        // tables or exclusion constraints.
+        let indices = get_secondary_indices(db, &table.real_name)
+            .context("failed to get indices for table")?;
+        let check_constraints = get_check_constraints(db, &table.real_name)
+            .context("failed to get check constraints for table")?;
+        if let Some(index) = indices.iter().find(|index| index.is_exclusion_constraint()) {
+            return Err(anyhow!(
+                "exclusion constraint {} on table {} can't be recreated on the new table, please drop it first",
+                index.name,
+                self.table
+            ));
+        }
+        if self.partition_by.is_some() {
+            let names: Vec<&str> = indices
+                .iter()
+                .map(|index| index.name.as_str())
+                .chain(
+                    check_constraints
+                        .iter()
+                        .map(|constraint| constraint.name.as_str()),
+                )
+                .collect();
+            if !names.is_empty() {
+                return Err(anyhow!(
+                    "table {} has indices or constraints which can't be recreated on a partitioned table ({}), please drop them first",
+                    self.table,
+                    names.join(", ")
+                ));
+            }
+        }
+
+        // Create the shadow table, which will replace the existing table once the migration is completed
+        let partition_by = match &self.partition_by {
+            Some(partition_by) => format!("PARTITION BY {}", partition_by),
+            None => "".to_string(),
+        };
+        let query = format!(
+            r#"
+            DROP TABLE IF EXISTS "{shadow_table}" CASCADE;
+            CREATE TABLE "{shadow_table}" (
+                {definition}
+            ) {partition_by};
+            "#,
+            shadow_table = shadow_table,
+            definition = table_definition(&self.columns, &self.primary_key, &self.foreign_keys),
+            partition_by = partition_by,
+        );
+        db.run(&query).context("failed to create shadow table")?;
+
+        // A partitioned table needs somewhere to store rows before any partitions have been attached
+        if self.partition_by.is_some() {
+            let query = format!(
+                r#"
+                CREATE TABLE "{shadow_table}_default" PARTITION OF "{shadow_table}" DEFAULT
+                "#,
+                shadow_table = shadow_table,
+            );
+            db.run(&query)
+                .context("failed to create default partition")?;
+        }
+
+        for column in self
+            .columns
+            .iter()
+            .filter(|column| self.inherits_sequence(column))
+        {
+            let old_column = match table.columns.iter().find(|old| old.name == column.name) {
+                Some(old_column) => old_column,
+                None => continue,
+            };
+
+            let sequence = common::get_sequence(db, &table.real_name, &old_column.real_name)
+                .context("failed to get sequence for column")?;
+            if let Some(sequence) = sequence {
+                let query = format!(
+                    r#"
+                    ALTER TABLE "{shadow_table}"
+                    ALTER COLUMN "{column}" SET DEFAULT nextval('{sequence}'::regclass)
+                    "#,
+                    shadow_table = shadow_table,
+                    column = column.name,
+                    sequence = sequence,
+                );
+                db.run(&query)
+                    .context("failed to set default for shadow table column")?;
+            }
+        }
+
+        // Map every column of the new table to an expression over the old columns and the
+        // other way around. Columns without an expression or a column with the same name
+        // in the other table are left out and will get their default value.
+        let up_columns: Vec<(String, String)> = self
+            .columns
+            .iter()
+            .filter_map(|column| {
+                let expression = self.up.get(&column.name).cloned().or_else(|| {
+                    table
+                        .columns
+                        .iter()
+                        .find(|old| old.name == column.name)
+                        .map(|old| format!("\"{}\"", old.name))
+                })?;
+                Some((column.name.to_string(), expression))
+            })
+            .collect();
+        let down_columns: Vec<(String, String)> = table
+            .columns
+            .iter()
+            .filter_map(|old| {
+                let expression = self.down.get(&old.name).cloned().or_else(|| {
+                    self.columns
+                        .iter()
+                        .find(|column| column.name == old.name)
+                        .map(|column| format!("\"{}\"", column.name))
+                })?;
+                Some((old.real_name.to_string(), expression))
+            })
+            .collect();
+
+        let old_primary_key = common::get_primary_key_columns_for_table(
+            db,
+            &format!("\"{}\".\"{}\"", table.schema, table.real_name),
+        )
+        .context("failed to get primary key")?;
+
+        let old_columns: Vec<(String, String)> = table
+            .columns
+            .iter()
+            .map(|column| (column.real_name.to_string(), column.name.to_string()))
+            .collect();
+        let new_columns: Vec<(String, String)> = self
+            .columns
+            .iter()
+            .map(|column| (column.name.to_string(), column.name.to_string()))
+            .collect();
+
+        let old_to_new = sync_function(
+            &self.old_trigger_name(ctx),
+            "reshape.is_old_schema()",
+            &old_columns,
+            &format!("\"{}\".\"{}\"", table.schema, shadow_table),
+            &self.primary_key,
+            &up_columns,
+        )?;
+        let new_to_old = sync_function(
+            &self.new_trigger_name(ctx),
+            "NOT reshape.is_old_schema()",
+            &new_columns,
+            &format!("\"{}\".\"{}\"", table.schema, table.real_name),
+            &old_primary_key,
+            &down_columns,
+        )?;
+
+        // Keep the tables in sync in both directions. Writes from the old schema
+        // are copied to the shadow table and writes from the new schema are copied
+        // to the old table. The triggers check which schema is in use to avoid loops.
+        let query = format!(
+            r#"
+            {old_to_new}
+
+            DROP TRIGGER IF EXISTS "{old_trigger}" ON "{table}";
+            CREATE TRIGGER "{old_trigger}" AFTER INSERT OR UPDATE OR DELETE ON "{table}" FOR EACH ROW EXECUTE PROCEDURE {old_trigger}();
+
+            {new_to_old}
+
+            DROP TRIGGER IF EXISTS "{new_trigger}" ON "{shadow_table}";
+            CREATE TRIGGER "{new_trigger}" AFTER INSERT OR UPDATE OR DELETE ON "{shadow_table}" FOR EACH ROW EXECUTE PROCEDURE {new_trigger}();
+            "#,
+            old_to_new = old_to_new,
+            new_to_old = new_to_old,
+            table = table.real_name,
+            shadow_table = shadow_table,
+            old_trigger = self.old_trigger_name(ctx),
+            new_trigger = self.new_trigger_name(ctx),
+        );
+        db.run(&query).context("failed to create sync triggers")?;
+
+        // Copy all existing rows to the shadow table
+        backfill::copy_rows(
+            ctx,
+            db,
+            &table.real_name,
+            &old_columns,
+            &shadow_table,
+            &up_columns,
+            None,
+        )
+        .context("failed to copy existing rows")?;
+
+        // Indices and constraints on the old table can only be recreated if the new table
+        // still has all the columns they use
+        let renames: HashMap<String, String> = table
+            .columns
+            .iter()
+            .filter(|old| self.columns.iter().any(|column| column.name == old.name))
+            .map(|old| (old.real_name.to_string(), old.name.to_string()))
+            .collect();
+
+        self.copy_indices(ctx, db, &indices, &renames)?;
+
+        // Check constraints are added as NOT VALID and validated separately to avoid blocking
+        // writes to the shadow table, and thereby the old table, while the rows are scanned
+        for constraint in &check_constraints {
+            let (definition, was_valid) = constraint.shadow_definition(&renames)?;
+            let query = format!(
+                r#"
+                ALTER TABLE "{shadow_table}" DROP CONSTRAINT IF EXISTS "{name}";
+                ALTER TABLE "{shadow_table}" ADD CONSTRAINT "{name}" {definition} NOT VALID;
+                "#,
+                shadow_table = shadow_table,
+                name = constraint.name,
+                definition = definition,
+            );
+            db.run(&query)
+                .with_context(|| format!("failed to copy constraint {}", constraint.name))?;
+
+            if was_valid {
+                let query = format!(
+                    r#"
+                    ALTER TABLE "{shadow_table}" VALIDATE CONSTRAINT "{name}"
+                    "#,
+                    shadow_table = shadow_table,
+                    name = constraint.name,
+                );
+                db.run(&query).with_context(|| {
+                    format!("failed to validate constraint {}", constraint.name)
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn complete<'a>(
+        &self,
+        ctx: &MigrationContext,
+        db: &'a mut dyn Conn,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        let shadow_table = self.shadow_table_name(ctx);
+
+        // Sequences shared with the old table must be kept when it's dropped. Identity
+        // sequences always belong to their column, so those are recreated for the new table.
+        let mut sequences: Vec<InheritedSequence> = Vec::new();
+        for column in self
+            .columns
+            .iter()
+            .filter(|column| self.inherits_sequence(column))
+        {
+            let sequence = common::get_sequence(db, &self.table, &column.name)
+                .context("failed to get sequence for column")?;
+            let sequence = match sequence {
+                Some(sequence) => sequence,
+                None => continue,
+            };
+
+            let identity = common::get_identity(db, &self.table, &column.name)
+                .context("failed to get identity of column")?;
+
+            sequences.push(InheritedSequence {
+                column: column.name.to_string(),
+                sequence,
+                identity,
+            });
+        }
+
+        // Foreign keys referencing the old table will be dropped together with it
+        let foreign_keys: Vec<(String, String, String)> = db
+            .query_with_params(
+                "
+                SELECT
+                    pg_constraint.conname AS name,
+                    referencing_table.relname AS table_name,
+                    pg_get_constraintdef(pg_constraint.oid) AS definition
+                FROM pg_catalog.pg_constraint
+                JOIN pg_catalog.pg_class AS referenced_table ON referenced_table.oid = pg_constraint.confrelid
+                JOIN pg_catalog.pg_class AS referencing_table ON referencing_table.oid = pg_constraint.conrelid
+                JOIN pg_catalog.pg_namespace ON pg_namespace.oid = referenced_table.relnamespace
+                WHERE pg_constraint.contype = 'f'
+                AND pg_namespace.nspname = current_schema()
+                AND referenced_table.relname = $1
+                AND referencing_table.oid != referenced_table.oid
+                ",
+                &[&self.table],
+            )
+            .context("failed to get foreign keys referencing table")?
+            .iter()
+            .map(|row| (row.get("name"), row.get("table_name"), row.get("definition")))
+            .collect();
+
+        // Indices added to the old table since the migration started are copied now,
+        // before any exclusive lock is taken
+        let indices =
+            get_secondary_indices(db, &self.table).context("failed to get indices for table")?;
+        let renames: HashMap<String, String> = self
+            .columns
+            .iter()
+            .map(|column| (column.name.to_string(), column.name.to_string()))
+            .collect();
+        self.copy_indices(ctx, db, &indices, &renames)?;
+
+        // Views which depend on the old table are dropped together with it and must be
+        // recreated. Materialized views would have to be refreshed while the table is locked.
+        let views = common::get_dependent_views(db, &self.table, None)
+            .context("failed to get views for table")?;
+        if let Some(view) = views.iter().find(|view| view.is_materialized) {
+            return Err(anyhow!(
+                "materialized view {}.{} depends on table {} and can't be recreated when the table is replaced, please drop it first",
+                view.schema,
+                view.name,
+                self.table
+            ));
+        }
+
+        // The swap is retried as a whole if any lock can't be taken in time
+        ctx.run_ddl_group(db, |transaction| {
+            // Lock both tables before reading where the identity sequences are at, so that no rows
+            // can be inserted using later values until the identities have been recreated
+            transaction
+                .run(&format!(
+                    r#"
+                    LOCK TABLE "{table}", "{shadow_table}" IN ACCESS EXCLUSIVE MODE
+                    "#,
+                    table = self.table,
+                    shadow_table = shadow_table,
+                ))
+                .context("failed to lock tables")?;
+            let mut next_identity_values: HashMap<&str, i64> = HashMap::new();
+            for sequence in sequences
+                .iter()
+                .filter(|sequence| sequence.identity.is_some())
+            {
+                let next_value = common::get_next_sequence_value(transaction, &sequence.sequence)
+                    .context("failed to get current value of sequence")?;
+                next_identity_values.insert(&sequence.column, next_value);
+            }
+
+            for InheritedSequence {
+                sequence, identity, ..
+            } in &sequences
+            {
+                if identity.is_none() {
+                    transaction
+                        .run(&format!(
+                            "ALTER SEQUENCE {sequence} OWNED BY NONE",
+                            sequence = sequence,
+                        ))
+                        .context("failed to detach sequence from old table")?;
+                }
+            }
+
+            // Swap the tables
+            let query = format!(
+                r#"
+                DROP FUNCTION IF EXISTS "{new_trigger}" CASCADE;
+                DROP TABLE "{table}" CASCADE;
+                DROP FUNCTION IF EXISTS "{old_trigger}" CASCADE;
+                ALTER TABLE "{shadow_table}" RENAME TO "{table}";
+                "#,
+                table = self.table,
+                shadow_table = shadow_table,
+                old_trigger = self.old_trigger_name(ctx),
+                new_trigger = self.new_trigger_name(ctx),
+            );
+            transaction
+                .run(&query)
+                .context("failed to replace table with shadow table")?;
+
+            // Objects created together with the shadow table are named after it, for example
+            // the primary key constraint and default partition. These are given the names they
+            // would have had if they had been created for the table directly.
+            let constraints: Vec<String> = transaction
+                .query_with_params(
+                    "
+                    SELECT pg_constraint.conname AS name
+                    FROM pg_catalog.pg_constraint
+                    JOIN pg_catalog.pg_class ON pg_class.oid = pg_constraint.conrelid
+                    JOIN pg_catalog.pg_namespace ON pg_namespace.oid = pg_class.relnamespace
+                    WHERE pg_namespace.nspname = current_schema()
+                    AND pg_class.relname = $1
+                    AND left(pg_constraint.conname, length($2)) = $2
+                    ",
+                    &[&self.table, &shadow_table],
+                )
+                .context("failed to get constraints for shadow table")?
+                .iter()
+                .map(|row| row.get("name"))
+                .collect();
+            for constraint in constraints {
+                transaction
+                    .run(&format!(
+                        r#"
+                        ALTER TABLE "{table}" RENAME CONSTRAINT "{constraint}" TO "{new_name}"
+                        "#,
+                        table = self.table,
+                        constraint = constraint,
+                        new_name = constraint.replacen(&shadow_table, &self.table, 1),
+                    ))
+                    .context("failed to rename constraint")?;
+            }
+
+            let relations: Vec<(String, String)> = transaction
+                .query_with_params(
+                    "
+                    SELECT pg_class.relname AS name, pg_class.relkind::TEXT AS kind
+                    FROM pg_catalog.pg_class
+                    JOIN pg_catalog.pg_namespace ON pg_namespace.oid = pg_class.relnamespace
+                    WHERE pg_namespace.nspname = current_schema()
+                    AND left(pg_class.relname, length($1)) = $1
+                    ",
+                    &[&shadow_table],
+                )
+                .context("failed to get relations for shadow table")?
+                .iter()
+                .map(|row| (row.get("name"), row.get("kind")))
+                .collect();
+            for (name, kind) in relations {
+                let relation_type = match kind.as_str() {
+                    "i" | "I" => "INDEX",
+                    "S" => "SEQUENCE",
+                    _ => "TABLE",
+                };
+                transaction
+                    .run(&format!(
+                        r#"
+                        ALTER {relation_type} "{name}" RENAME TO "{new_name}"
+                        "#,
+                        relation_type = relation_type,
+                        name = name,
+                        new_name = name.replacen(&shadow_table, &self.table, 1),
+                    ))
+                    .context("failed to rename relation")?;
+            }
+
+            for InheritedSequence {
+                column,
+                sequence,
+                identity,
+            } in &sequences
+            {
+                let query = match identity {
+                    Some(identity) => format!(
+                        r#"
+                        ALTER TABLE "{table}"
+                        ALTER COLUMN "{column}" DROP DEFAULT,
+                        ALTER COLUMN "{column}" ADD GENERATED {generated} AS IDENTITY (START WITH {start})
+                        "#,
+                        table = self.table,
+                        column = column,
+                        generated = if identity == "a" {
+                            "ALWAYS"
+                        } else {
+                            "BY DEFAULT"
+                        },
+                        start = next_identity_values
+                            .get(column.as_str())
+                            .copied()
+                            .unwrap_or(1),
+                    ),
+                    None => format!(
+                        r#"
+                        ALTER SEQUENCE {sequence} OWNED BY "{table}"."{column}"
+                        "#,
+                        sequence = sequence,
+                        table = self.table,
+                        column = column,
+                    ),
+                };
+                transaction
+                    .run(&query)
+                    .context("failed to move sequence to new table")?;
+            }
+
+            // The copies of the indices take the place of the ones dropped with the old table
+            for index in &indices {
+                let shadow_index = self.shadow_index_name(ctx, index.oid);
+                let query = match &index.constraint {
+                    Some((constraint_name, constraint_type)) if constraint_type == "u" => format!(
+                        r#"
+                        ALTER TABLE "{table}" ADD CONSTRAINT "{constraint_name}" UNIQUE USING INDEX "{index}"
+                        "#,
+                        table = self.table,
+                        constraint_name = constraint_name,
+                        index = shadow_index,
+                    ),
+                    _ => format!(
+                        r#"
+                        ALTER INDEX "{index}" RENAME TO "{name}"
+                        "#,
+                        index = shadow_index,
+                        name = index.name,
+                    ),
+                };
+                transaction
+                    .run(&query)
+                    .with_context(|| format!("failed to restore index {}", index.name))?;
+            }
+
+            // Recreate foreign keys referencing the table. They are validated once the
+            // table has been swapped to avoid holding the lock while scanning.
+            for (name, table, definition) in &foreign_keys {
+                transaction
+                    .run(&format!(
+                        r#"
+                        ALTER TABLE "{table}" ADD CONSTRAINT "{name}" {definition} NOT VALID
+                        "#,
+                        table = table,
+                        name = name,
+                        definition = definition,
+                    ))
+                    .with_context(|| format!("failed to recreate foreign key {}", name))?;
+            }
+
+            // Recreate the views which were dropped together with the old table, which now
+            // refer to the new table by the same name
+            for view in &views {
+                for statement in view.create_statements() {
+                    transaction.run(&statement).with_context(|| {
+                        format!("failed to restore view {}.{}", view.schema, view.name)
+                    })?;
+                }
+            }
+
+            Ok(())
+        })
+        .context("failed to swap tables")?;
+
+        for (name, table, _) in &foreign_keys {
+            db.run(&format!(
+                r#"
+                ALTER TABLE "{table}" VALIDATE CONSTRAINT "{name}"
+                "#,
+                table = table,
+                name = name,
+            ))
+            .with_context(|| format!("failed to validate foreign key {}", name))?;
+        }
+
+        Ok(None)
+    }
+
+    fn update_schema(&self, ctx: &MigrationContext, schema: &mut Schema) {
+        schema.change_table(&self.table, |table_changes| {
+            table_changes.set_table(&self.shadow_table_name(ctx));
+        });
+    }
+
+    fn abort(&self, ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
+        let query = format!(
+            r#"
+            DROP FUNCTION IF EXISTS "{old_trigger}" CASCADE;
+            DROP FUNCTION IF EXISTS "{new_trigger}" CASCADE;
+            DROP TABLE IF EXISTS "{shadow_table}" CASCADE;
+            "#,
+            old_trigger = self.old_trigger_name(ctx),
+            new_trigger = self.new_trigger_name(ctx),
+            shadow_table = self.shadow_table_name(ctx),
+        );
+        db.run(&query)
+            .context("failed to drop shadow table and triggers")?;
+
+        Ok(())
+    }
+}
+
+struct InheritedSequence {
+    column: String,
+    sequence: String,
+    // Identity kind if the sequence belongs to an identity column
+    identity: Option<String>,
+}
+
+// An index on the old table other than the primary key, possibly backing a unique or
+// exclusion constraint. `columns` are the real names of the columns it uses.
+struct SecondaryIndex {
+    oid: i64,
+    name: String,
+    definition: String,
+    constraint: Option<(String, String)>,
+    columns: Vec<String>,
+}
+
+impl SecondaryIndex {
+    fn is_exclusion_constraint(&self) -> bool {
+        matches!(&self.constraint, Some((_, constraint_type)) if constraint_type == "x")
+    }
+
+    // Rewrites the definition from `pg_get_indexdef` to create the index on the shadow table.
+    // Returns the parts before and after the index name so that CONCURRENTLY can be added.
+    fn shadow_definition(
+        &self,
+        shadow_table: &str,
+        renames: &HashMap<String, String>,
+    ) -> anyhow::Result<(String, String)> {
+        if let Some(column) = self
+            .columns
+            .iter()
+            .find(|column| !renames.contains_key(*column))
+        {
+            return Err(anyhow!(
+                "index {} uses column {} which isn't in the new table, please drop it first",
+                self.name,
+                column
+            ));
+        }
+
+        let parse_error = || anyhow!("failed to parse definition of index {}", self.name);
+        let (create, rest) = self
+            .definition
+            .split_once(" INDEX ")
+            .ok_or_else(parse_error)?;
+        let (_, rest) = rest.split_once(" USING ").ok_or_else(parse_error)?;
+
+        // Only rewrite from the column list onwards to avoid touching the method name
+        let column_list_start = rest.find('(').ok_or_else(parse_error)?;
+        let (method, columns) = rest.split_at(column_list_start);
+        let columns = common::rewrite_column_references(columns, |name| renames.get(name).cloned());
+
+        Ok((
+            format!("{} INDEX", create),
+            format!("ON \"{}\" USING {}{}", shadow_table, method, columns),
+        ))
+    }
+}
+
+struct CheckConstraint {
+    name: String,
+    definition: String,
+    columns: Vec<String>,
+}
+
+impl CheckConstraint {
+    // Rewrites the definition to use the names of the new columns. Also returns whether the
+    // constraint had been validated on the old table.
+    fn shadow_definition(
+        &self,
+        renames: &HashMap<String, String>,
+    ) -> anyhow::Result<(String, bool)> {
+        if let Some(column) = self
+            .columns
+            .iter()
+            .find(|column| !renames.contains_key(*column))
+        {
+            return Err(anyhow!(
+                "constraint {} uses column {} which isn't in the new table, please drop it first",
+                self.name,
+                column
+            ));
+        }
+
+        let definition =
+            common::rewrite_column_references(&self.definition, |name| renames.get(name).cloned());
+        Ok(match definition.strip_suffix(" NOT VALID") {
+            Some(definition) => (definition.to_string(), false),
+            None => (definition, true),
+        })
+    }
+}
+
+fn get_secondary_indices(db: &mut dyn Conn, table: &str) -> anyhow::Result<Vec<SecondaryIndex>> {
+    let indices = db
+        .query_with_params(
+            "
+            SELECT
+                index_class.oid::INT8 AS oid,
+                index_class.relname AS name,
+                pg_get_indexdef(index_class.oid) AS definition,
+                pg_constraint.conname AS constraint_name,
+                pg_constraint.contype::TEXT AS constraint_type,
+                ARRAY(
+                    SELECT pg_attribute.attname::TEXT
+                    FROM pg_catalog.pg_attribute
+                    WHERE pg_attribute.attrelid = table_class.oid
+                    AND pg_attribute.attnum > 0
+                    AND (
+                        pg_attribute.attnum = ANY(pg_index.indkey)
+                        OR EXISTS (
+                            SELECT 1
+                            FROM pg_catalog.pg_depend
+                            WHERE pg_depend.classid = 'pg_class'::regclass
+                            AND pg_depend.objid = index_class.oid
+                            AND pg_depend.refclassid = 'pg_class'::regclass
+                            AND pg_depend.refobjid = table_class.oid
+                            AND pg_depend.refobjsubid = pg_attribute.attnum
+                        )
+                    )
+                ) AS columns
+            FROM pg_catalog.pg_index
+            JOIN pg_catalog.pg_class index_class ON index_class.oid = pg_index.indexrelid
+            JOIN pg_catalog.pg_class table_class ON table_class.oid = pg_index.indrelid
+            LEFT JOIN pg_catalog.pg_constraint ON pg_constraint.conindid = index_class.oid
+                AND pg_constraint.conrelid = table_class.oid
+                AND pg_constraint.contype IN ('u', 'x')
+            WHERE table_class.relname = $1
+            AND table_class.relnamespace = current_schema()::regnamespace
+            AND NOT pg_index.indisprimary
+            AND pg_index.indisvalid
+            ORDER BY index_class.oid
+            ",
+            &[&table],
+        )?
+        .iter()
+        .map(|row| {
+            let constraint_name: Option<String> = row.get("constraint_name");
+            let constraint_type: Option<String> = row.get("constraint_type");
+
+            SecondaryIndex {
+                oid: row.get("oid"),
+                name: row.get("name"),
+                definition: row.get("definition"),
+                constraint: constraint_name.zip(constraint_type),
+                columns: row.get("columns"),
+            }
+        })
+        .collect();
+
+    Ok(indices)
+}
+
+fn get_check_constraints(db: &mut dyn Conn, table: &str) -> anyhow::Result<Vec<CheckConstraint>> {
+    let constraints = db
+        .query_with_params(
+            "
+            SELECT
+                pg_constraint.conname AS name,
+                pg_get_constraintdef(pg_constraint.oid) AS definition,
+                ARRAY(
+                    SELECT pg_attribute.attname::TEXT
+                    FROM pg_catalog.pg_attribute
+                    WHERE pg_attribute.attrelid = table_class.oid
+                    AND pg_attribute.attnum = ANY(pg_constraint.conkey)
+                ) AS columns
+            FROM pg_catalog.pg_constraint
+            JOIN pg_catalog.pg_class table_class ON table_class.oid = pg_constraint.conrelid
+            WHERE table_class.relname = $1
+            AND table_class.relnamespace = current_schema()::regnamespace
+            AND pg_constraint.contype = 'c'
+            ORDER BY pg_constraint.oid
+            ",
+            &[&table],
+        )?
+        .iter()
+        .map(|row| CheckConstraint {
+            name: row.get("name"),
+            definition: row.get("definition"),
+            columns: row.get("columns"),
+        })
+        .collect();
+
+    Ok(constraints)
+}
+
+// Build a trigger function which copies every write to a table over to `target_table`,
+// which must be schema qualified as triggers run with the search path of the client.
+// `source_columns` are the (real name, alias) pairs of the source table and `columns` maps
+// the target columns to expressions over the aliases. Rows are matched on the primary key.
+fn sync_function(
+    name: &str,
+    condition: &str,
+    source_columns: &[(String, String)],
+    target_table: &str,
+    primary_key: &[String],
+    columns: &[(String, String)],
+) -> anyhow::Result<String> {
+    let expressions: HashMap<&str, &str> = columns
+        .iter()
+        .map(|(column, expression)| (column.as_str(), expression.as_str()))
+        .collect();
+
+    let primary_key_columns = primary_key
+        .iter()
+        .map(|column| format!("\"{}\"", column))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let primary_key_expressions = primary_key
+        .iter()
+        .map(|column| {
+            expressions
+                .get(column.as_str())
+                .map(|expression| expression.to_string())
+                .ok_or_else(|| anyhow!("no value given for primary key column {}", column))
+        })
+        .collect::<anyhow::Result<Vec<String>>>()?
+        .join(", ");
+
+    let row = |record: &str| {
+        source_columns
+            .iter()
+            .map(|(real_name, alias)| format!("{}.\"{}\" AS \"{}\"", record, real_name, alias))
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+
+    let target_columns = columns
+        .iter()
+        .map(|(column, _)| format!("\"{}\"", column))
+        .collect::<Vec<String>>()
+        .join(", ");
+    let target_values = columns
+        .iter()
+        .map(|(_, expression)| expression.to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    let updates: Vec<String> = columns
+        .iter()
+        .filter(|(column, _)| !primary_key.contains(column))
+        .map(|(column, _)| format!("\"{column}\" = EXCLUDED.\"{column}\"", column = column))
+        .collect();
+    let on_conflict = if updates.is_empty() {
+        "DO NOTHING".to_string()
+    } else {
+        format!("DO UPDATE SET {}", updates.join(", "))
+    };
+
+    Ok(format!(
+        r#"
+        CREATE OR REPLACE FUNCTION {name}()
+        RETURNS TRIGGER AS $$
+        BEGIN
+            IF {condition} THEN
+                IF TG_OP = 'DELETE' THEN
+                    DELETE FROM {target_table}
+                    WHERE ({primary_key_columns}) IN (
+                        SELECT {primary_key_expressions} FROM (SELECT {old_row}) AS row
+                    );
+                ELSE
+                    -- Remove the previous row if the primary key was changed
+                    IF TG_OP = 'UPDATE' THEN
+                        DELETE FROM {target_table}
+                        WHERE ({primary_key_columns}) IN (
+                            SELECT {primary_key_expressions} FROM (SELECT {old_row}) AS row
+                            EXCEPT
+                            SELECT {primary_key_expressions} FROM (SELECT {new_row}) AS row
+                        );
+                    END IF;
+
+                    INSERT INTO {target_table} ({target_columns})
+                    SELECT {target_values} FROM (SELECT {new_row}) AS row
+                    ON CONFLICT ({primary_key_columns}) {on_conflict};
+                END IF;
+            END IF;
+            RETURN NULL;
+        END
+        $$ language 'plpgsql';
+        "#,
+        name = name,
+        condition = condition,
+        target_table = target_table,
+        primary_key_columns = primary_key_columns,
+        primary_key_expressions = primary_key_expressions,
+        old_row = row("OLD"),
+        new_row = row("NEW"),
+        target_columns = target_columns,
+        target_values = target_values,
+        on_conflict = on_conflict,
+    ))
+}
@@ -0,0 +1,428 @@
+use super::{backfill, common, Action, MigrationContext};
+use crate::{
+    db::{Conn, Transaction},
+    schema::Schema,
+};
+use anyhow::{anyhow, Context};
+use postgres::error::SqlState;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SplitTable {
+    pub table: String,
+    pub new_table: String,
+    pub columns: Vec<String>,
+    pub reference_column: String,
+
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+impl SplitTable {
+    fn source_trigger_name(&self, ctx: &MigrationContext) -> String {
+        format!("{}_split_table_source_trigger", ctx.prefix())
+    }
+
+    fn new_table_trigger_name(&self, ctx: &MigrationContext) -> String {
+        format!("{}_split_table_new_trigger", ctx.prefix())
+    }
+}
+
+#[typetag::serde(name = "split_table")]
+impl Action for SplitTable {
+    fn describe(&self) -> String {
+        format!(
+            "Splitting columns from \"{}\" into \"{}\"",
+            self.table, self.new_table
+        )
+    }
+
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    fn run(
+        &self,
+        ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        schema: &Schema,
+    ) -> anyhow::Result<()> {
+        let table = schema.get_table(db, &self.table)?;
+
+        let primary_key = common::get_primary_key_columns_for_table(
+            db,
+            &format!("\"{}\".\"{}\"", table.schema, table.real_name),
+        )
+        .context("failed to get primary key")?;
+        let primary_key = match primary_key.as_slice() {
+            [primary_key] => primary_key.to_string(),
+            _ => {
+                return Err(anyhow!(
+                    "table {} must have a primary key with a single column to be split",
+                    self.table
+                ))
+            }
+        };
+
+        let moved_columns = self
+            .columns
+            .iter()
+            .map(|name| {
+                table
+                    .columns
+                    .iter()
+                    .find(|column| &column.name == name)
+                    .ok_or_else(|| anyhow!("no such column {} exists on {}", name, self.table))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // The new table gets a copy of every moved column with the exact same type
+        let types: Vec<(String, String)> = db
+            .query_with_params(
+                "
+                SELECT pg_attribute.attname AS name, format_type(pg_attribute.atttypid, pg_attribute.atttypmod) AS data_type
+                FROM pg_catalog.pg_attribute
+                JOIN pg_catalog.pg_class ON pg_class.oid = pg_attribute.attrelid
+                JOIN pg_catalog.pg_namespace ON pg_namespace.oid = pg_class.relnamespace
+                WHERE pg_namespace.nspname = current_schema()
+                AND pg_class.relname = $1
+                AND pg_attribute.attnum > 0
+                AND NOT pg_attribute.attisdropped
+                ",
+                &[&table.real_name],
+            )
+            .context("failed to get column types")?
+            .iter()
+            .map(|row| (row.get("name"), row.get("data_type")))
+            .collect();
+        let get_type = |real_name: &str| -> anyhow::Result<String> {
+            types
+                .iter()
+                .find(|(name, _)| name == real_name)
+                .map(|(_, data_type)| data_type.to_string())
+                .ok_or_else(|| anyhow!("failed to get type of column {}", real_name))
+        };
+
+        let mut definition_rows = vec![format!(
+            r#""{reference_column}" {data_type} PRIMARY KEY REFERENCES "{table}" ("{primary_key}") ON DELETE CASCADE ON UPDATE CASCADE"#,
+            reference_column = self.reference_column,
+            data_type = get_type(&primary_key)?,
+            table = table.real_name,
+            primary_key = primary_key,
+        )];
+        for column in &moved_columns {
+            let mut parts = vec![format!("\"{}\"", column.name), get_type(&column.real_name)?];
+
+            if let Some(default) = &column.default {
+                parts.push("DEFAULT".to_string());
+                parts.push(default.to_string());
+            }
+
+            if !column.nullable {
+                parts.push("NOT NULL".to_string());
+            }
+
+            definition_rows.push(parts.join(" "));
+        }
+
+        let query = format!(
+            r#"
+            CREATE TABLE IF NOT EXISTS "{new_table}" (
+                {definition}
+            )
+            "#,
+            new_table = self.new_table,
+            definition = definition_rows.join(",\n"),
+        );
+        db.run(&query).context("failed to create new table")?;
+
+        // The moved columns are hidden from the new schema, which means it can't
+        // set any values for them. The columns will be dropped on completion anyway.
+        for column in moved_columns.iter().filter(|column| !column.nullable) {
+            let query = format!(
+                r#"
+                ALTER TABLE "{table}"
+                ALTER COLUMN "{column}" DROP NOT NULL
+                "#,
+                table = table.real_name,
+                column = column.real_name,
+            );
+            db.run(&query)
+                .context("failed to drop NOT NULL from moved column")?;
+        }
+
+        let new_table_columns: Vec<String> = moved_columns
+            .iter()
+            .map(|column| format!("\"{}\"", column.name))
+            .collect();
+        let source_values: Vec<String> = moved_columns
+            .iter()
+            .map(|column| format!("NEW.\"{}\"", column.real_name))
+            .collect();
+        let new_table_updates: Vec<String> = moved_columns
+            .iter()
+            .map(|column| format!("\"{column}\" = EXCLUDED.\"{column}\"", column = column.name))
+            .collect();
+        let source_updates: Vec<String> = moved_columns
+            .iter()
+            .map(|column| format!("\"{}\" = NEW.\"{}\"", column.real_name, column.name))
+            .collect();
+        let source_clears: Vec<String> = moved_columns
+            .iter()
+            .map(|column| format!("\"{}\" = NULL", column.real_name))
+            .collect();
+        let source_all_null: Vec<String> = moved_columns
+            .iter()
+            .map(|column| format!("NEW.\"{}\" IS NULL", column.real_name))
+            .collect();
+
+        // Writes to the source table from the old schema are copied to the new table,
+        // and writes to the new table from the new schema are copied back to the source table.
+        // Rows without a value for any moved column have no row in the new table, for example
+        // rows inserted from the new schema without adding a row to the new table.
+        let query = format!(
+            r#"
+            CREATE OR REPLACE FUNCTION {source_trigger}()
+            RETURNS TRIGGER AS $$
+            BEGIN
+                IF reshape.is_old_schema() THEN
+                    IF {source_all_null} THEN
+                        DELETE FROM "{schema}"."{new_table}" WHERE "{reference_column}" = NEW."{primary_key}";
+                    ELSE
+                        INSERT INTO "{schema}"."{new_table}" ("{reference_column}", {new_table_columns})
+                        VALUES (NEW."{primary_key}", {source_values})
+                        ON CONFLICT ("{reference_column}") DO UPDATE SET {new_table_updates};
+                    END IF;
+                END IF;
+                RETURN NULL;
+            END
+            $$ language 'plpgsql';
+
+            DROP TRIGGER IF EXISTS "{source_trigger}" ON "{table}";
+            CREATE TRIGGER "{source_trigger}" AFTER INSERT OR UPDATE ON "{table}" FOR EACH ROW EXECUTE PROCEDURE {source_trigger}();
+
+            CREATE OR REPLACE FUNCTION {new_table_trigger}()
+            RETURNS TRIGGER AS $$
+            BEGIN
+                IF NOT reshape.is_old_schema() THEN
+                    IF TG_OP = 'DELETE' THEN
+                        UPDATE "{schema}"."{table}" SET {source_clears} WHERE "{primary_key}" = OLD."{reference_column}";
+                    ELSE
+                        UPDATE "{schema}"."{table}" SET {source_updates} WHERE "{primary_key}" = NEW."{reference_column}";
+                    END IF;
+                END IF;
+                RETURN NULL;
+            END
+            $$ language 'plpgsql';
+
+            DROP TRIGGER IF EXISTS "{new_table_trigger}" ON "{new_table}";
+            CREATE TRIGGER "{new_table_trigger}" AFTER INSERT OR UPDATE OR DELETE ON "{new_table}" FOR EACH ROW EXECUTE PROCEDURE {new_table_trigger}();
+            "#,
+            source_trigger = self.source_trigger_name(ctx),
+            new_table_trigger = self.new_table_trigger_name(ctx),
+            schema = table.schema,
+            table = table.real_name,
+            new_table = self.new_table,
+            reference_column = self.reference_column,
+            primary_key = primary_key,
+            new_table_columns = new_table_columns.join(", "),
+            source_values = source_values.join(", "),
+            new_table_updates = new_table_updates.join(", "),
+            source_updates = source_updates.join(", "),
+            source_clears = source_clears.join(", "),
+            source_all_null = source_all_null.join(" AND "),
+        );
+        db.run(&query).context("failed to create sync triggers")?;
+
+        // Backfill the new table with the existing rows
+        let source_columns: Vec<(String, String)> = table
+            .columns
+            .iter()
+            .map(|column| (column.real_name.to_string(), column.name.to_string()))
+            .collect();
+        let primary_key_alias = table
+            .columns
+            .iter()
+            .find(|column| column.real_name == primary_key)
+            .map(|column| column.name.to_string())
+            .unwrap_or_else(|| primary_key.to_string());
+        let mut copy_columns = vec![(
+            self.reference_column.to_string(),
+            format!("\"{}\"", primary_key_alias),
+        )];
+        copy_columns.extend(
+            moved_columns
+                .iter()
+                .map(|column| (column.name.to_string(), format!("\"{}\"", column.name))),
+        );
+        let copy_all_null: Vec<String> = moved_columns
+            .iter()
+            .map(|column| format!("\"{}\" IS NULL", column.name))
+            .collect();
+        backfill::copy_rows(
+            ctx,
+            db,
+            &table.real_name,
+            &source_columns,
+            &self.new_table,
+            &copy_columns,
+            Some(&format!("NOT ({})", copy_all_null.join(" AND "))),
+        )
+        .context("failed to copy existing rows to new table")?;
+
+        Ok(())
+    }
+
+    fn complete<'a>(
+        &self,
+        ctx: &MigrationContext,
+        db: &'a mut dyn Conn,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        let mut transaction = db.transaction().context("failed to create transaction")?;
+
+        let drop_columns: Vec<String> = self
+            .columns
+            .iter()
+            .map(|column| format!("DROP COLUMN IF EXISTS \"{}\"", column))
+            .collect();
+        let query = format!(
+            r#"
+            DROP FUNCTION IF EXISTS "{source_trigger}" CASCADE;
+            DROP FUNCTION IF EXISTS "{new_table_trigger}" CASCADE;
+            ALTER TABLE "{table}" {drop_columns};
+            "#,
+            source_trigger = self.source_trigger_name(ctx),
+            new_table_trigger = self.new_table_trigger_name(ctx),
+            table = self.table,
+            drop_columns = drop_columns.join(", "),
+        );
+        transaction
+            .run(&query)
+            .context("failed to drop moved columns")?;
+
+        Ok(Some(transaction))
+    }
+
+    fn update_schema(&self, _ctx: &MigrationContext, schema: &mut Schema) {
+        schema.change_table(&self.table, |table_changes| {
+            for column in &self.columns {
+                table_changes.change_column(column, |column_changes| {
+                    column_changes.set_moved(&self.new_table);
+                });
+            }
+        });
+    }
+
+    fn abort(&self, ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
+        let query = format!(
+            r#"
+            DROP FUNCTION IF EXISTS "{source_trigger}" CASCADE;
+            DROP FUNCTION IF EXISTS "{new_table_trigger}" CASCADE;
+            "#,
+            source_trigger = self.source_trigger_name(ctx),
+            new_table_trigger = self.new_table_trigger_name(ctx),
+        );
+        ctx.run_ddl(db, &query)
+            .context("failed to drop sync triggers")?;
+
+        // The new table has kept the original nullability of the moved columns,
+        // which is used to restore NOT NULL on the source table
+        let not_null_columns: Vec<String> = db
+            .query_with_params(
+                "
+                SELECT column_name
+                FROM information_schema.columns
+                WHERE table_schema = current_schema() AND table_name = $1 AND is_nullable = 'NO'
+                ",
+                &[&self.new_table],
+            )
+            .context("failed to get columns of new table")?
+            .iter()
+            .map(|row| row.get("column_name"))
+            .filter(|column: &String| self.columns.contains(column))
+            .collect();
+        for column in not_null_columns {
+            // Rows inserted from the new schema without a row in the new table have no value
+            // for the moved columns. NOT NULL is first added as a check constraint which isn't
+            // validated, so it applies to new writes even when such rows exist.
+            let constraint_name = format!("{}_{}_not_null", self.table, column);
+            let query = format!(
+                r#"
+                ALTER TABLE "{table}"
+                DROP CONSTRAINT IF EXISTS "{constraint_name}",
+                ADD CONSTRAINT "{constraint_name}" CHECK ("{column}" IS NOT NULL) NOT VALID
+                "#,
+                table = self.table,
+                constraint_name = constraint_name,
+                column = column,
+            );
+            ctx.run_ddl(db, &query)
+                .context("failed to add NOT NULL constraint to moved column")?;
+
+            // Validating doesn't take an exclusive lock. If rows without a value remain,
+            // the check constraint is kept in place of NOT NULL.
+            let query = format!(
+                r#"
+                ALTER TABLE "{table}"
+                VALIDATE CONSTRAINT "{constraint_name}"
+                "#,
+                table = self.table,
+                constraint_name = constraint_name,
+            );
+            match db.run(&query) {
+                Ok(()) => {}
+                Err(err) if is_check_violation(&err) => {
+                    println!();
+                    println!(
+                        "    Column {} has rows without a value, keeping constraint {} which isn't validated instead of NOT NULL",
+                        column, constraint_name
+                    );
+                    continue;
+                }
+                Err(err) => {
+                    return Err(err).context("failed to validate NOT NULL constraint");
+                }
+            }
+
+            // With the validated constraint in place, setting NOT NULL doesn't scan the table
+            let query = format!(
+                r#"
+                ALTER TABLE "{table}"
+                ALTER COLUMN "{column}" SET NOT NULL
+                "#,
+                table = self.table,
+                column = column,
+            );
+            ctx.run_ddl(db, &query)
+                .context("failed to restore NOT NULL on moved column")?;
+
+            let query = format!(
+                r#"
+                ALTER TABLE "{table}"
+                DROP CONSTRAINT "{constraint_name}"
+                "#,
+                table = self.table,
+                constraint_name = constraint_name,
+            );
+            ctx.run_ddl(db, &query)
+                .context("failed to drop NOT NULL constraint")?;
+        }
+
+        let query = format!(
+            r#"
+            DROP TABLE IF EXISTS "{new_table}"
+            "#,
+            new_table = self.new_table,
+        );
+        ctx.run_ddl(db, &query)
+            .context("failed to drop new table")?;
+
+        Ok(())
+    }
+}
+
+fn is_check_violation(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<postgres::Error>()
+        .and_then(postgres::Error::code)
+        == Some(&SqlState::CHECK_VIOLATION)
+}
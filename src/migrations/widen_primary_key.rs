@@ -0,0 +1,217 @@
+use super::{common, Action, AlterColumn, ColumnChanges, MigrationContext};
+use crate::{
+    db::{Conn, Transaction},
+    schema::Schema,
+};
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WidenPrimaryKey {
+    pub table: String,
+    pub column: String,
+
+    #[serde(default = "default_data_type")]
+    pub data_type: String,
+
+    #[serde(default)]
+    pub schema: Option<String>,
+}
+
+fn default_data_type() -> String {
+    "BIGINT".to_string()
+}
+
+impl WidenPrimaryKey {
+    // The column is replaced using a regular `AlterColumn` which takes care of syncing,
+    // backfilling and swapping the column while preserving the primary key, indices
+    // and any foreign keys referencing the column
+    fn alter_column(&self, default: Option<String>) -> AlterColumn {
+        AlterColumn {
+            table: self.table.to_string(),
+            column: self.column.to_string(),
+            up: Some(self.column.to_string()),
+            down: Some(self.column.to_string()),
+            changes: ColumnChanges {
+                name: None,
+                data_type: Some(self.data_type.to_string()),
+                nullable: None,
+                default,
+            },
+            dependencies: vec![],
+            down_dependencies: vec![],
+            schema: self.schema.clone(),
+        }
+    }
+}
+
+#[typetag::serde(name = "widen_primary_key")]
+impl Action for WidenPrimaryKey {
+    fn describe(&self) -> String {
+        format!(
+            "Widening primary key column \"{}\" on \"{}\" to {}",
+            self.column, self.table, self.data_type
+        )
+    }
+
+    fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    fn run(
+        &self,
+        ctx: &MigrationContext,
+        db: &mut dyn Conn,
+        schema: &Schema,
+    ) -> anyhow::Result<()> {
+        let table = schema.get_table(db, &self.table)?;
+        let column = table
+            .columns
+            .iter()
+            .find(|column| column.name == self.column)
+            .ok_or_else(|| anyhow!("no such column {} exists", self.column))?;
+
+        let is_primary_key = !db
+            .query_with_params(
+                "
+                SELECT pg_attribute.attname
+                FROM pg_catalog.pg_index
+                JOIN pg_catalog.pg_class ON pg_class.oid = pg_index.indrelid
+                JOIN pg_catalog.pg_namespace ON pg_namespace.oid = pg_class.relnamespace
+                JOIN pg_catalog.pg_attribute ON pg_attribute.attrelid = pg_class.oid
+                    AND pg_attribute.attnum = ANY(pg_index.indkey)
+                WHERE pg_index.indisprimary
+                AND pg_namespace.nspname = current_schema()
+                AND pg_class.relname = $1
+                AND pg_attribute.attname = $2
+                ",
+                &[&table.real_name, &column.real_name],
+            )
+            .context("failed to get primary key")?
+            .is_empty();
+        if !is_primary_key {
+            return Err(anyhow!(
+                "column {} is not part of the primary key of {}",
+                self.column,
+                self.table
+            ));
+        }
+
+        // The new column should get its values from the same sequence as the existing one.
+        // Identity columns don't have a regular default so we use their sequence directly.
+        let default = common::get_sequence(db, &table.real_name, &column.real_name)
+            .context("failed to get sequence for column")?
+            .map(|sequence| format!("nextval('{}'::regclass)", sequence));
+
+        self.alter_column(default).run(ctx, db, schema)
+    }
+
+    fn complete<'a>(
+        &self,
+        ctx: &MigrationContext,
+        db: &'a mut dyn Conn,
+    ) -> anyhow::Result<Option<Transaction<'a>>> {
+        let sequence = common::get_sequence(db, &self.table, &self.column)
+            .context("failed to get sequence for column")?;
+        let identity = common::get_identity(db, &self.table, &self.column)
+            .context("failed to get identity of column")?;
+
+        // An identity sequence is always dropped together with its column, so a new identity
+        // is created for the new column. Other sequences are moved over to the new column.
+        // Both happen in the transaction which swaps the columns, so the new column is never
+        // without a default.
+        let next_identity_value: Option<i64> = match (&sequence, &identity) {
+            (Some(sequence), Some(_)) => Some(
+                common::get_next_sequence_value(db, sequence)
+                    .context("failed to get current value of sequence")?,
+            ),
+            _ => None,
+        };
+
+        let start_swap = |transaction: &mut dyn Conn| -> anyhow::Result<()> {
+            // Detach the sequence from the old column so it isn't dropped along with it
+            if let (Some(sequence), None) = (&sequence, &identity) {
+                transaction
+                    .run(&format!(
+                        "ALTER SEQUENCE {sequence} OWNED BY NONE",
+                        sequence = sequence,
+                    ))
+                    .context("failed to detach sequence from column")?;
+            }
+
+            Ok(())
+        };
+
+        let finish_swap = |transaction: &mut dyn Conn| -> anyhow::Result<()> {
+            match (&sequence, &identity, next_identity_value) {
+                (_, Some(identity), Some(next_identity_value)) => {
+                    // Rows may have been inserted since the sequence was read, but they can't
+                    // be anymore as the swap holds an exclusive lock on the table. The identity
+                    // starts after whichever is largest of the sequence and the existing ids.
+                    let max_value: Option<i64> = transaction
+                        .query(&format!(
+                            r#"
+                            SELECT MAX("{column}")::BIGINT AS max_value FROM "{table}"
+                            "#,
+                            table = self.table,
+                            column = self.column,
+                        ))
+                        .context("failed to get largest value of column")?
+                        .first()
+                        .and_then(|row| row.get("max_value"));
+                    let start = max_value
+                        .map_or(next_identity_value, |max| next_identity_value.max(max + 1));
+
+                    let generated = if identity == "a" {
+                        "ALWAYS"
+                    } else {
+                        "BY DEFAULT"
+                    };
+                    let query = format!(
+                        r#"
+                        ALTER TABLE "{table}"
+                        ALTER COLUMN "{column}" DROP DEFAULT,
+                        ALTER COLUMN "{column}" ADD GENERATED {generated} AS IDENTITY (START WITH {start})
+                        "#,
+                        table = self.table,
+                        column = self.column,
+                        generated = generated,
+                        start = start,
+                    );
+                    transaction
+                        .run(&query)
+                        .context("failed to add identity to new column")?;
+                }
+                (Some(sequence), _, _) => {
+                    let query = format!(
+                        r#"
+                        ALTER SEQUENCE {sequence} OWNED BY "{table}"."{column}";
+                        ALTER SEQUENCE {sequence} AS {data_type};
+                        "#,
+                        sequence = sequence,
+                        table = self.table,
+                        column = self.column,
+                        data_type = self.data_type,
+                    );
+                    transaction
+                        .run(&query)
+                        .context("failed to move sequence to new column")?;
+                }
+                _ => {}
+            }
+
+            Ok(())
+        };
+
+        self.alter_column(None)
+            .complete_with(ctx, db, start_swap, finish_swap)
+    }
+
+    fn update_schema(&self, ctx: &MigrationContext, schema: &mut Schema) {
+        self.alter_column(None).update_schema(ctx, schema);
+    }
+
+    fn abort(&self, ctx: &MigrationContext, db: &mut dyn Conn) -> anyhow::Result<()> {
+        self.alter_column(None).abort(ctx, db)
+    }
+}
@@ -0,0 +1,113 @@
+use std::fmt;
+
+use crate::db::RecordingConn;
+
+// The SQL a migration would run, recorded by `Reshape::plan` without changing the database
+pub struct Plan {
+    // Helper functions set up before the actions are run
+    pub setup: Vec<String>,
+    pub actions: Vec<ActionPlan>,
+    // Schemas and views created for the new migration once all actions have run
+    pub views: Vec<String>,
+}
+
+// The SQL run by an action when a migration is started, completed and aborted
+pub struct ActionPlan {
+    pub migration: String,
+    pub description: String,
+    pub start: Vec<String>,
+    pub complete: Vec<String>,
+    pub abort: Vec<String>,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+}
+
+// A standalone SQL script for one phase of a migration, as exported by `Reshape::export_sql`
+pub struct SqlScript {
+    // File name of the script, prefixed by its position in the order of execution
+    pub name: String,
+    pub statements: Vec<String>,
+}
+
+impl fmt::Display for SqlScript {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for statement in &self.statements {
+            writeln!(f, "{}\n", format_statement(statement))?;
+        }
+        Ok(())
+    }
+}
+
+// Take the statements recorded for a phase. If planning the phase failed, for example
+// because it depends on a table which is created earlier in the same plan, the error
+// is noted after the statements which were recorded up to that point.
+pub(crate) fn take_statements(db: &mut RecordingConn, result: anyhow::Result<()>) -> Vec<String> {
+    let mut statements = db.take_statements();
+    if let Err(err) = result {
+        statements.push(format!("-- failed to plan: {:#}", err));
+    }
+    statements
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "-- Set up helpers")?;
+        write_statements(f, &self.setup)?;
+
+        for action in &self.actions {
+            writeln!(
+                f,
+                "-- Migration '{}': {}",
+                action.migration, action.description
+            )?;
+            writeln!(f, "-- Start")?;
+            write_statements(f, &action.start)?;
+            writeln!(f, "-- Complete")?;
+            write_statements(f, &action.complete)?;
+            writeln!(f, "-- Abort")?;
+            write_statements(f, &action.abort)?;
+        }
+
+        writeln!(f, "-- Create schemas and views for the new migration")?;
+        write_statements(f, &self.views)
+    }
+}
+
+fn write_statements(f: &mut fmt::Formatter<'_>, statements: &[String]) -> fmt::Result {
+    for statement in statements {
+        writeln!(f, "{}", format_statement(statement))?;
+    }
+    writeln!(f)
+}
+
+// Statements are built from indented multi-line strings, so remove the common indentation
+// and make sure each statement is terminated
+pub(crate) fn format_statement(statement: &str) -> String {
+    let lines: Vec<&str> = statement
+        .lines()
+        .skip_while(|line| line.trim().is_empty())
+        .collect();
+    let indentation = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+
+    let formatted = lines
+        .iter()
+        .map(|line| line.get(indentation..).unwrap_or_else(|| line.trim_start()))
+        .collect::<Vec<&str>>()
+        .join("\n");
+    let formatted = formatted.trim_end();
+
+    if formatted.starts_with("--") || formatted.ends_with(';') {
+        formatted.to_string()
+    } else {
+        format!("{};", formatted)
+    }
+}
@@ -9,6 +9,9 @@ use std::collections::{HashMap, HashSet};
 // The changes to a table are tracked by a `TableChanges` struct. The possible
 // changes are:
 //   - Changing the name which updates `current_name`.
+//   - Changing the backing table which will add the new table to the end of
+//     `backing_tables`. This is used when a table is rewritten into a shadow
+//     table which will eventually replace the current table.
 //   - Removing which sets the `removed` flag.
 //
 // Changes to a column are tracked by a `ColumnChanges` struct which reside in
@@ -17,22 +20,42 @@ use std::collections::{HashMap, HashSet};
 //   - Changing the backing column which will add the new column to the end of
 //     `intermediate_columns`. This is used when temporary columns are
 //     introduced which will eventually replace the current column.
+//   - Changing the default value which updates `default`. This is used when
+//     the default is changed in place and only applies to the new schema.
+//   - Moving to another table which sets `moved_to`. The column is then hidden
+//     from its current table and is instead backed by a column in `moved_to`.
 //   - Removing which sets the `removed` flag.
 //
+// Types which are replaced by a new type during a migration are tracked in
+// `type_changes`. The new type is exposed under the original name in the
+// migration's schema until the migration is completed.
+//
 // Schema provides some schema introspection methods, `get_tables` and `get_table`,
 // which will retrieve the current schema from the database and apply the changes.
+//
+// Tables are looked up and changed within the Postgres schema set with
+// `set_current_schema`, which is the schema of the action being run. Table changes
+// are tracked per Postgres schema as tables in different schemas can share names.
 #[derive(Debug)]
 pub struct Schema {
+    current_schema: String,
     table_changes: Vec<TableChanges>,
+    type_changes: Vec<TypeChanges>,
 }
 
 impl Schema {
     pub fn new() -> Schema {
         Schema {
+            current_schema: "public".to_string(),
             table_changes: Vec::new(),
+            type_changes: Vec::new(),
         }
     }
 
+    pub fn set_current_schema(&mut self, schema: &str) {
+        self.current_schema = schema.to_string();
+    }
+
     pub fn change_table<F>(&mut self, current_name: &str, f: F)
     where
         F: FnOnce(&mut TableChanges),
@@ -40,9 +63,12 @@ impl Schema {
         let table_change_index = self
             .table_changes
             .iter()
-            .position(|table| table.current_name == current_name)
+            .position(|table| {
+                table.schema == self.current_schema && table.current_name == current_name
+            })
             .unwrap_or_else(|| {
-                let new_changes = TableChanges::new(current_name.to_string());
+                let new_changes =
+                    TableChanges::new(self.current_schema.to_string(), current_name.to_string());
                 self.table_changes.push(new_changes);
                 self.table_changes.len() - 1
             });
@@ -50,6 +76,26 @@ impl Schema {
         let table_changes = &mut self.table_changes[table_change_index];
         f(table_changes)
     }
+
+    // Back a type by another type, for example when an enum is replaced
+    pub fn set_type(&mut self, name: &str, real_name: &str) {
+        self.type_changes
+            .retain(|changes| changes.schema != self.current_schema || changes.name != name);
+        self.type_changes.push(TypeChanges {
+            schema: self.current_schema.to_string(),
+            name: name.to_string(),
+            real_name: real_name.to_string(),
+        });
+    }
+
+    // All changed types in the current schema as (name, real name) pairs
+    pub fn get_types(&self) -> Vec<(&str, &str)> {
+        self.type_changes
+            .iter()
+            .filter(|changes| changes.schema == self.current_schema)
+            .map(|changes| (changes.name.as_ref(), changes.real_name.as_ref()))
+            .collect()
+    }
 }
 
 impl Default for Schema {
@@ -58,19 +104,28 @@ impl Default for Schema {
     }
 }
 
+#[derive(Debug)]
+struct TypeChanges {
+    schema: String,
+    name: String,
+    real_name: String,
+}
+
 #[derive(Debug)]
 pub struct TableChanges {
+    schema: String,
     current_name: String,
-    real_name: String,
+    backing_tables: Vec<String>,
     column_changes: Vec<ColumnChanges>,
     removed: bool,
 }
 
 impl TableChanges {
-    fn new(name: String) -> Self {
+    fn new(schema: String, name: String) -> Self {
         Self {
+            schema,
             current_name: name.to_string(),
-            real_name: name,
+            backing_tables: vec![name],
             column_changes: Vec::new(),
             removed: false,
         }
@@ -80,6 +135,19 @@ impl TableChanges {
         self.current_name = name.to_string();
     }
 
+    // Point the table to a new backing table. The columns of the new table
+    // are used as they are, so any earlier column changes no longer apply.
+    pub fn set_table(&mut self, table_name: &str) {
+        self.backing_tables.push(table_name.to_string());
+        self.column_changes.clear();
+    }
+
+    fn real_name(&self) -> &str {
+        self.backing_tables
+            .last()
+            .expect("backing_tables should never be empty")
+    }
+
     pub fn change_column<F>(&mut self, current_name: &str, f: F)
     where
         F: FnOnce(&mut ColumnChanges),
@@ -107,6 +175,8 @@ impl TableChanges {
 pub struct ColumnChanges {
     current_name: String,
     backing_columns: Vec<String>,
+    default: Option<String>,
+    moved_to: Option<String>,
     removed: bool,
 }
 
@@ -115,6 +185,8 @@ impl ColumnChanges {
         Self {
             current_name: name.to_string(),
             backing_columns: vec![name],
+            default: None,
+            moved_to: None,
             removed: false,
         }
     }
@@ -127,6 +199,14 @@ impl ColumnChanges {
         self.backing_columns.push(column_name.to_string())
     }
 
+    pub fn set_default(&mut self, default: &str) {
+        self.default = Some(default.to_string());
+    }
+
+    pub fn set_moved(&mut self, table_name: &str) {
+        self.moved_to = Some(table_name.to_string());
+    }
+
     pub fn set_removed(&mut self) {
         self.removed = true;
     }
@@ -140,6 +220,7 @@ impl ColumnChanges {
 
 #[derive(Debug)]
 pub struct Table {
+    pub schema: String,
     pub name: String,
     pub real_name: String,
     pub columns: Vec<Column>,
@@ -155,21 +236,43 @@ pub struct Column {
 }
 
 impl Schema {
+    fn current_table_changes(&self) -> impl Iterator<Item = &TableChanges> {
+        self.table_changes
+            .iter()
+            .filter(|changes| changes.schema == self.current_schema)
+    }
+
     pub fn get_tables(&self, db: &mut dyn Conn) -> anyhow::Result<Vec<Table>> {
-        db.query(
+        // Partitions are left out as they are accessed through their parent table
+        db.query_with_params(
             "
-            SELECT table_name
-            FROM information_schema.tables
-            WHERE table_schema = 'public'
+            SELECT pg_class.relname AS table_name
+            FROM pg_catalog.pg_class
+            JOIN pg_catalog.pg_namespace ON pg_namespace.oid = pg_class.relnamespace
+            WHERE pg_namespace.nspname = $1
+            AND pg_class.relkind IN ('r', 'p')
+            AND NOT pg_class.relispartition
             ",
+            &[&self.current_schema],
         )?
         .iter()
         .map(|row| row.get::<'_, _, String>("table_name"))
         .filter_map(|real_name| {
+            // Skip table if it has been replaced by another backing table
+            let is_replaced = self.current_table_changes().any(|changes| {
+                let (_, rest) = changes
+                    .backing_tables
+                    .split_last()
+                    .expect("backing_tables should never be empty");
+                rest.contains(&real_name)
+            });
+            if is_replaced {
+                return None;
+            }
+
             let table_changes = self
-                .table_changes
-                .iter()
-                .find(|changes| changes.real_name == real_name);
+                .current_table_changes()
+                .find(|changes| changes.real_name() == real_name);
 
             // Skip table if it has been removed
             if let Some(changes) = table_changes {
@@ -185,12 +288,11 @@ impl Schema {
 
     pub fn get_table(&self, db: &mut dyn Conn, table_name: &str) -> anyhow::Result<Table> {
         let table_changes = self
-            .table_changes
-            .iter()
+            .current_table_changes()
             .find(|changes| changes.current_name == table_name);
 
         let real_table_name = table_changes
-            .map(|changes| changes.real_name.to_string())
+            .map(|changes| changes.real_name().to_string())
             .unwrap_or_else(|| table_name.to_string());
 
         self.get_table_by_real_name(db, &real_table_name)
@@ -202,20 +304,19 @@ impl Schema {
         real_table_name: &str,
     ) -> anyhow::Result<Table> {
         let table_changes = self
-            .table_changes
-            .iter()
-            .find(|changes| changes.real_name == real_table_name);
+            .current_table_changes()
+            .find(|changes| changes.real_name() == real_table_name);
 
         let real_columns: Vec<(String, String, bool, Option<String>)> = db
-            .query(&format!(
+            .query_with_params(
                 "
                 SELECT column_name, data_type, is_nullable, column_default
                 FROM information_schema.columns
-                WHERE table_name = '{table}' AND table_schema = 'public'
+                WHERE table_name = $1 AND table_schema = $2
                 ORDER BY ordinal_position
                 ",
-                table = real_table_name,
-            ))?
+                &[&real_table_name, &self.current_schema],
+            )?
             .iter()
             .map(|row| {
                 (
@@ -229,10 +330,11 @@ impl Schema {
 
         let mut ignore_columns: HashSet<String> = HashSet::new();
         let mut aliases: HashMap<String, &str> = HashMap::new();
+        let mut defaults: HashMap<String, &str> = HashMap::new();
 
         if let Some(changes) = table_changes {
             for column_changes in &changes.column_changes {
-                if column_changes.removed {
+                if column_changes.removed || column_changes.moved_to.is_some() {
                     ignore_columns.insert(column_changes.real_name().to_string());
                 } else {
                     aliases.insert(
@@ -241,6 +343,10 @@ impl Schema {
                     );
                 }
 
+                if let Some(default) = &column_changes.default {
+                    defaults.insert(column_changes.real_name().to_string(), default);
+                }
+
                 let (_, rest) = column_changes
                     .backing_columns
                     .split_last()
@@ -263,6 +369,10 @@ impl Schema {
                 .get(&real_name)
                 .map(|alias| alias.to_string())
                 .unwrap_or_else(|| real_name.to_string());
+            let default = defaults
+                .get(&real_name)
+                .map(|default| default.to_string())
+                .or(default);
 
             columns.push(Column {
                 name,
@@ -278,6 +388,7 @@ impl Schema {
             .unwrap_or_else(|| real_table_name);
 
         let table = Table {
+            schema: self.current_schema.to_string(),
             name: current_table_name.to_string(),
             real_name: real_table_name.to_string(),
             columns,
@@ -4,12 +4,17 @@ use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 use version::version;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct State {
     pub version: String,
     pub status: Status,
     pub current_migration: Option<String>,
     pub migrations: Vec<Migration>,
+
+    // Tenant schemas which actions without a schema are applied to.
+    // Empty unless the migration was started with a tenant selector.
+    #[serde(default)]
+    pub tenants: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -19,7 +24,17 @@ pub enum Status {
     Idle,
 
     #[serde(rename = "applying")]
-    Applying { migrations: Vec<Migration> },
+    Applying {
+        migrations: Vec<Migration>,
+
+        // The next migration, action and tenant to apply, used to resume an interrupted run
+        #[serde(default)]
+        current_migration_index: usize,
+        #[serde(default)]
+        current_action_index: usize,
+        #[serde(default)]
+        current_tenant_index: usize,
+    },
 
     #[serde(rename = "in_progress")]
     InProgress { migrations: Vec<Migration> },
@@ -29,6 +44,8 @@ pub enum Status {
         migrations: Vec<Migration>,
         current_migration_index: usize,
         current_action_index: usize,
+        #[serde(default)]
+        current_tenant_index: usize,
     },
 
     #[serde(rename = "aborting")]
@@ -36,9 +53,28 @@ pub enum Status {
         migrations: Vec<Migration>,
         last_migration_index: usize,
         last_action_index: usize,
+        #[serde(default = "all_tenants")]
+        last_tenant_index: usize,
     },
 }
 
+impl Status {
+    // Short name of the status, matching its serialized form
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Idle => "idle",
+            Status::Applying { .. } => "applying",
+            Status::InProgress { .. } => "in_progress",
+            Status::Completing { .. } => "completing",
+            Status::Aborting { .. } => "aborting",
+        }
+    }
+}
+
+fn all_tenants() -> usize {
+    usize::MAX
+}
+
 impl State {
     pub fn load(db: &mut impl Conn) -> State {
         Self::ensure_schema_and_table(db);
@@ -67,6 +103,60 @@ impl State {
         Ok(())
     }
 
+    // The SQL which saving the state runs, with the state inlined as a literal.
+    // This is used when exporting migrations as SQL scripts to be run without Reshape.
+    pub fn save_query(&self) -> anyhow::Result<String> {
+        let json = serde_json::to_string(self)?;
+        Ok(format!(
+            "
+            CREATE SCHEMA IF NOT EXISTS reshape;
+            CREATE TABLE IF NOT EXISTS reshape.data (key TEXT PRIMARY KEY, value JSONB);
+            INSERT INTO reshape.data (key, value) VALUES ('state', '{json}')
+            ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value
+            ",
+            json = json.replace('\'', "''"),
+        ))
+    }
+
+    // Backfills save a cursor after every batch so that an interrupted migration can pick up
+    // where it left off. The cursors are stored next to the state, one row per backfill,
+    // and are only valid until the migration has been fully applied or aborted.
+    pub(crate) fn load_backfill_cursor(
+        db: &mut dyn Conn,
+        backfill: &str,
+    ) -> anyhow::Result<Option<Vec<String>>> {
+        let key = format!("backfill:{}", backfill);
+        let rows =
+            db.query_with_params("SELECT value FROM reshape.data WHERE key = $1", &[&key])?;
+
+        match rows.first() {
+            Some(row) => {
+                let json: serde_json::Value = row.get(0);
+                Ok(Some(serde_json::from_value(json)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn save_backfill_cursor(
+        db: &mut dyn Conn,
+        backfill: &str,
+        cursor: &[String],
+    ) -> anyhow::Result<()> {
+        let key = format!("backfill:{}", backfill);
+        let json = serde_json::to_value(cursor)?;
+        db.query_with_params(
+            "INSERT INTO reshape.data (key, value) VALUES ($1, $2) ON CONFLICT (key) DO UPDATE SET value = $2",
+            &[&key, &json],
+        )?;
+        Ok(())
+    }
+
+    pub(crate) fn clear_backfill_cursors(db: &mut dyn Conn) -> anyhow::Result<()> {
+        db.run("DELETE FROM reshape.data WHERE key LIKE 'backfill:%'")?;
+        Ok(())
+    }
+
     pub fn clear(&mut self, db: &mut impl Conn) -> anyhow::Result<()> {
         db.run("DROP SCHEMA reshape CASCADE")?;
 
@@ -74,6 +164,7 @@ impl State {
         self.status = default.status;
         self.current_migration = default.current_migration;
         self.migrations = default.migrations;
+        self.tenants = default.tenants;
 
         Ok(())
     }
@@ -100,9 +191,18 @@ impl State {
         Ok(())
     }
 
-    pub fn applying(&mut self, new_migrations: Vec<Migration>) {
+    pub fn applying(
+        &mut self,
+        new_migrations: Vec<Migration>,
+        current_migration_index: usize,
+        current_action_index: usize,
+        current_tenant_index: usize,
+    ) {
         self.status = Status::Applying {
             migrations: new_migrations,
+            current_migration_index,
+            current_action_index,
+            current_tenant_index,
         };
     }
 
@@ -117,11 +217,13 @@ impl State {
         migrations: Vec<Migration>,
         current_migration_index: usize,
         current_action_index: usize,
+        current_tenant_index: usize,
     ) {
         self.status = Status::Completing {
             migrations,
             current_migration_index,
             current_action_index,
+            current_tenant_index,
         }
     }
 
@@ -130,11 +232,13 @@ impl State {
         migrations: Vec<Migration>,
         last_migration_index: usize,
         last_action_index: usize,
+        last_tenant_index: usize,
     ) {
         self.status = Status::Aborting {
             migrations,
             last_migration_index,
             last_action_index,
+            last_tenant_index,
         }
     }
 
@@ -177,6 +281,7 @@ impl Default for State {
             status: Status::Idle,
             current_migration: None,
             migrations: vec![],
+            tenants: vec![],
         }
     }
 }
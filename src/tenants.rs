@@ -0,0 +1,54 @@
+use anyhow::{anyhow, Context};
+
+use crate::db::Conn;
+
+// Selects the tenant schemas a migration should be applied to when using
+// schema-per-tenant, where the same tables are repeated across many Postgres schemas.
+// Actions which don't specify a schema are applied to every selected tenant schema.
+#[derive(Clone, Debug)]
+pub enum TenantSelector {
+    // A LIKE pattern matched against schema names, for example `tenant\_%`
+    Pattern(String),
+
+    // A query returning the tenant schema names in its first column
+    Query(String),
+}
+
+impl TenantSelector {
+    pub(crate) fn resolve(&self, db: &mut impl Conn) -> anyhow::Result<Vec<String>> {
+        let tenants: Vec<String> = match self {
+            TenantSelector::Pattern(pattern) => db
+                .query_with_params(
+                    r#"
+                    SELECT nspname
+                    FROM pg_catalog.pg_namespace
+                    WHERE nspname LIKE $1
+                    AND nspname NOT LIKE 'pg\_%'
+                    AND nspname NOT LIKE 'migration\_%'
+                    AND nspname NOT IN ('information_schema', 'reshape')
+                    ORDER BY nspname
+                    "#,
+                    &[pattern],
+                )
+                .with_context(|| format!("failed to find tenant schemas matching {}", pattern))?
+                .iter()
+                .map(|row| row.get("nspname"))
+                .collect(),
+            TenantSelector::Query(query) => db
+                .query(&format!(
+                    "SELECT name::TEXT FROM ({}) AS tenants(name)",
+                    query
+                ))
+                .with_context(|| format!("failed to run tenant query {}", query))?
+                .iter()
+                .map(|row| row.get(0))
+                .collect(),
+        };
+
+        if tenants.is_empty() {
+            return Err(anyhow!("no tenant schemas were selected by {:?}", self));
+        }
+
+        Ok(tenants)
+    }
+}
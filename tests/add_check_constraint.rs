@@ -0,0 +1,100 @@
+use reshape::migrations::{
+    AddCheckConstraint, AlterColumn, ColumnBuilder, ColumnChanges, CreateTableBuilder, Migration,
+};
+
+mod common;
+
+#[test]
+fn add_check_constraint() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let create_table_migration = Migration::new("create_products_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("products")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![
+                ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("price")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap(),
+            ])
+            .build()
+            .unwrap(),
+    );
+    let add_check_migration = Migration::new("add_positive_cost_check", None)
+        .with_action(AlterColumn {
+            table: "products".to_string(),
+            column: "price".to_string(),
+            up: None,
+            down: None,
+            changes: ColumnChanges {
+                name: Some("cost".to_string()),
+                data_type: None,
+                nullable: None,
+                default: None,
+            },
+            dependencies: vec![],
+            down_dependencies: vec![],
+            schema: None,
+        })
+        .with_action(AddCheckConstraint {
+            table: "products".to_string(),
+            name: "positive_cost".to_string(),
+            check: "cost >= 0".to_string(),
+            schema: None,
+        });
+
+    let first_migrations = vec![create_table_migration.clone()];
+    let second_migrations = vec![create_table_migration.clone(), add_check_migration.clone()];
+
+    // Run first migration and insert some valid data
+    reshape.migrate(first_migrations.clone()).unwrap();
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &first_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+    old_db
+        .simple_query("INSERT INTO products (id, price) VALUES (1, 10), (2, 20)")
+        .unwrap();
+
+    // Run second migration
+    reshape.migrate(second_migrations.clone()).unwrap();
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &second_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+
+    // Ensure the check applies to new rows using the renamed column
+    new_db
+        .simple_query("INSERT INTO products (id, cost) VALUES (3, 30)")
+        .unwrap();
+    let result = new_db.simple_query("INSERT INTO products (id, cost) VALUES (4, -1)");
+    assert!(result.is_err(), "expected insert to violate check");
+
+    reshape.complete_migration().unwrap();
+
+    // Ensure the check has been validated and given its final name
+    let (validated, definition): (bool, String) = new_db
+        .query_one(
+            "
+            SELECT convalidated, pg_get_constraintdef(oid) AS definition
+            FROM pg_catalog.pg_constraint
+            WHERE conrelid = 'public.products'::regclass AND conname = 'positive_cost'
+            ",
+            &[],
+        )
+        .map(|row| (row.get("convalidated"), row.get("definition")))
+        .unwrap();
+    assert!(validated, "expected check to be validated");
+    assert_eq!("CHECK ((cost >= 0))", definition);
+
+    common::assert_cleaned_up(&mut new_db);
+}
@@ -1,4 +1,8 @@
-use reshape::migrations::{AddColumn, Column, ColumnBuilder, CreateTableBuilder, Migration};
+use std::time::Duration;
+
+use reshape::migrations::{
+    AddColumn, Column, ColumnBuilder, CreateTableBuilder, Dependency, LockRetryPolicy, Migration,
+};
 use reshape::Status;
 
 mod common;
@@ -38,6 +42,8 @@ fn add_column() {
                 generated: None,
             },
             up: Some("(STRING_TO_ARRAY(name, ' '))[1]".to_string()),
+            dependencies: vec![],
+            schema: None,
         })
         .with_action(AddColumn {
             table: "users".to_string(),
@@ -49,6 +55,8 @@ fn add_column() {
                 generated: None,
             },
             up: Some("(STRING_TO_ARRAY(name, ' '))[2]".to_string()),
+            dependencies: vec![],
+            schema: None,
         });
 
     let first_migrations = vec![create_users_table.clone()];
@@ -148,6 +156,8 @@ fn add_column_nullable() {
             generated: None,
         },
         up: None,
+        dependencies: vec![],
+        schema: None,
     });
 
     let first_migrations = vec![create_users_table.clone()];
@@ -207,7 +217,7 @@ fn add_column_nullable() {
         .unwrap();
     let name: Option<String> = new_db
         .query_one("SELECT name from users WHERE id = 3", &[])
-        .map(|row| (row.get("name")))
+        .map(|row| row.get("name"))
         .unwrap();
     assert_eq!(None, name);
 
@@ -247,6 +257,8 @@ fn add_column_with_default() {
                 generated: None,
             },
             up: None,
+            dependencies: vec![],
+            schema: None,
         });
 
     let first_migrations = vec![create_users_table.clone()];
@@ -307,3 +319,230 @@ fn add_column_with_default() {
     reshape.complete_migration().unwrap();
     common::assert_cleaned_up(&mut new_db);
 }
+
+fn dependency_migrations() -> (Migration, Migration) {
+    let create_tables_migration = Migration::new("create_tables", None)
+        .with_action(
+            CreateTableBuilder::default()
+                .name("customers")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("email")
+                        .data_type("TEXT")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        )
+        .with_action(
+            CreateTableBuilder::default()
+                .name("orders")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("customer_id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        );
+    let add_column_migration = Migration::new("add_customer_email", None).with_action(AddColumn {
+        table: "orders".to_string(),
+        column: ColumnBuilder::default()
+            .name("customer_email")
+            .data_type("TEXT")
+            .build()
+            .unwrap(),
+        up: Some("(SELECT email FROM customers WHERE customers.id = customer_id)".to_string()),
+        dependencies: vec![Dependency {
+            table: "customers".to_string(),
+            on: "customers.id = orders.customer_id".to_string(),
+        }],
+        schema: None,
+    });
+
+    (create_tables_migration, add_column_migration)
+}
+
+#[test]
+fn add_column_with_dependency() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let (create_tables_migration, add_column_migration) = dependency_migrations();
+
+    let first_migrations = vec![create_tables_migration.clone()];
+    let second_migrations = vec![
+        create_tables_migration.clone(),
+        add_column_migration.clone(),
+    ];
+
+    // Run first migration and insert some data
+    reshape.migrate(first_migrations.clone()).unwrap();
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &first_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+    old_db
+        .simple_query(
+            "
+            INSERT INTO customers (id, email) VALUES (1, 'a@example.com'), (2, 'b@example.com');
+            INSERT INTO orders (id, customer_id) VALUES (1, 1), (2, 2);
+            ",
+        )
+        .unwrap();
+
+    // Run second migration
+    reshape.migrate(second_migrations.clone()).unwrap();
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &second_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+
+    let get_emails = |db: &mut postgres::Client| -> Vec<Option<String>> {
+        db.query("SELECT customer_email FROM orders ORDER BY id", &[])
+            .unwrap()
+            .iter()
+            .map(|row| row.get("customer_email"))
+            .collect()
+    };
+
+    // Ensure existing rows were backfilled using the other table
+    assert_eq!(
+        vec![
+            Some("a@example.com".to_string()),
+            Some("b@example.com".to_string())
+        ],
+        get_emails(&mut new_db)
+    );
+
+    // Ensure the value is refreshed when the other table changes through either schema
+    old_db
+        .simple_query("UPDATE customers SET email = 'c@example.com' WHERE id = 1")
+        .unwrap();
+    new_db
+        .simple_query("UPDATE customers SET email = 'd@example.com' WHERE id = 2")
+        .unwrap();
+    assert_eq!(
+        vec![
+            Some("c@example.com".to_string()),
+            Some("d@example.com".to_string())
+        ],
+        get_emails(&mut new_db)
+    );
+
+    reshape.complete_migration().unwrap();
+    common::assert_cleaned_up(&mut new_db);
+}
+
+#[test]
+fn add_column_with_dependency_retried_complete() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let (create_tables_migration, add_column_migration) = dependency_migrations();
+    reshape
+        .migrate(vec![create_tables_migration.clone()])
+        .unwrap();
+    reshape
+        .migrate(vec![
+            create_tables_migration.clone(),
+            add_column_migration.clone(),
+        ])
+        .unwrap();
+
+    // Fail completing once by holding a lock on the table with the dependency trigger
+    db.simple_query("BEGIN; LOCK TABLE public.customers IN ACCESS SHARE MODE")
+        .unwrap();
+    reshape.set_lock_retry_policy(LockRetryPolicy {
+        lock_timeout: Duration::from_millis(50),
+        statement_timeout: None,
+        max_retries: 0,
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(10),
+    });
+    assert!(reshape.complete_migration().is_err());
+
+    db.simple_query("COMMIT").unwrap();
+    reshape.complete_migration().unwrap();
+
+    db.simple_query("UPDATE public.customers SET email = 'a@example.com'")
+        .unwrap();
+    common::assert_cleaned_up(&mut db);
+}
+
+#[test]
+fn add_column_with_dependency_retried_abort() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let (create_tables_migration, add_column_migration) = dependency_migrations();
+    reshape
+        .migrate(vec![create_tables_migration.clone()])
+        .unwrap();
+    reshape
+        .migrate(vec![
+            create_tables_migration.clone(),
+            add_column_migration.clone(),
+        ])
+        .unwrap();
+
+    // Fail aborting once by holding a lock on the table with the dependency trigger
+    db.simple_query("BEGIN; LOCK TABLE public.customers IN ACCESS SHARE MODE")
+        .unwrap();
+    reshape.set_lock_retry_policy(LockRetryPolicy {
+        lock_timeout: Duration::from_millis(50),
+        statement_timeout: None,
+        max_retries: 0,
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(10),
+    });
+    assert!(reshape.abort().is_err());
+
+    // The column is kept along with the triggers as the abort was rolled back as a whole
+    db.simple_query("COMMIT").unwrap();
+    let columns: i64 = db
+        .query_one(
+            "
+            SELECT COUNT(*)
+            FROM information_schema.columns
+            WHERE table_schema = 'public' AND table_name = 'orders'
+            ",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(3, columns);
+
+    reshape.abort().unwrap();
+
+    let columns: Vec<String> = db
+        .query(
+            "
+            SELECT column_name
+            FROM information_schema.columns
+            WHERE table_schema = 'public' AND table_name = 'orders'
+            ORDER BY ordinal_position
+            ",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(vec!["id", "customer_id"], columns);
+    common::assert_cleaned_up(&mut db);
+}
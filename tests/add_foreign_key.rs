@@ -0,0 +1,194 @@
+use reshape::migrations::{
+    AddForeignKey, ColumnBuilder, CreateTableBuilder, ForeignKey, Migration,
+};
+
+mod common;
+
+#[test]
+fn add_foreign_key() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let create_tables_migration = Migration::new("create_tables", None)
+        .with_action(
+            CreateTableBuilder::default()
+                .name("users")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap()])
+                .build()
+                .unwrap(),
+        )
+        .with_action(
+            CreateTableBuilder::default()
+                .name("items")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("user_id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        );
+    let add_foreign_key_migration =
+        Migration::new("add_foreign_key", None).with_action(AddForeignKey {
+            table: "items".to_string(),
+            foreign_key: ForeignKey {
+                columns: vec!["user_id".to_string()],
+                referenced_table: "users".to_string(),
+                referenced_columns: vec!["id".to_string()],
+            },
+            schema: None,
+        });
+
+    let first_migrations = vec![create_tables_migration.clone()];
+    let second_migrations = vec![
+        create_tables_migration.clone(),
+        add_foreign_key_migration.clone(),
+    ];
+
+    // Run first migration and insert some valid data
+    reshape.migrate(first_migrations.clone()).unwrap();
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &first_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+    old_db
+        .simple_query(
+            "
+            INSERT INTO users (id) VALUES (1), (2);
+            INSERT INTO items (id, user_id) VALUES (1, 1), (2, 2);
+            ",
+        )
+        .unwrap();
+
+    // Run second migration
+    reshape.migrate(second_migrations.clone()).unwrap();
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &second_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+
+    // Ensure the foreign key is enforced for new rows but not yet validated
+    let validated: bool = new_db
+        .query_one(
+            "
+            SELECT convalidated
+            FROM pg_catalog.pg_constraint
+            WHERE conrelid = 'public.items'::regclass AND contype = 'f'
+            ",
+            &[],
+        )
+        .unwrap()
+        .get("convalidated");
+    assert!(!validated, "expected foreign key to not yet be validated");
+
+    let result = new_db.simple_query("INSERT INTO items (id, user_id) VALUES (3, 3)");
+    assert!(result.is_err(), "expected insert to violate foreign key");
+
+    reshape.complete_migration().unwrap();
+
+    // Ensure the foreign key has been validated and given its final name
+    let (name, validated): (String, bool) = new_db
+        .query_one(
+            "
+            SELECT conname, convalidated
+            FROM pg_catalog.pg_constraint
+            WHERE conrelid = 'public.items'::regclass AND contype = 'f'
+            ",
+            &[],
+        )
+        .map(|row| (row.get("conname"), row.get("convalidated")))
+        .unwrap();
+    assert_eq!("items_user_id_fkey", name);
+    assert!(validated, "expected foreign key to be validated");
+
+    common::assert_cleaned_up(&mut new_db);
+}
+
+#[test]
+fn add_foreign_key_abort() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_tables_migration = Migration::new("create_tables", None)
+        .with_action(
+            CreateTableBuilder::default()
+                .name("users")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap()])
+                .build()
+                .unwrap(),
+        )
+        .with_action(
+            CreateTableBuilder::default()
+                .name("items")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("user_id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        );
+    let add_foreign_key_migration =
+        Migration::new("add_foreign_key", None).with_action(AddForeignKey {
+            table: "items".to_string(),
+            foreign_key: ForeignKey {
+                columns: vec!["user_id".to_string()],
+                referenced_table: "users".to_string(),
+                referenced_columns: vec!["id".to_string()],
+            },
+            schema: None,
+        });
+
+    reshape
+        .migrate(vec![create_tables_migration.clone()])
+        .unwrap();
+    reshape
+        .migrate(vec![
+            create_tables_migration.clone(),
+            add_foreign_key_migration.clone(),
+        ])
+        .unwrap();
+    reshape.abort().unwrap();
+
+    // Ensure the foreign key was removed
+    let count: i64 = db
+        .query_one(
+            "
+            SELECT COUNT(*)
+            FROM pg_catalog.pg_constraint
+            WHERE conrelid = 'public.items'::regclass AND contype = 'f'
+            ",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(0, count, "expected foreign key to not exist");
+
+    common::assert_cleaned_up(&mut db);
+}
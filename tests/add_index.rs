@@ -1,4 +1,6 @@
-use reshape::migrations::{AddIndex, ColumnBuilder, CreateTableBuilder, Migration};
+use reshape::migrations::{
+    AddIndex, AlterColumn, ColumnBuilder, ColumnChanges, CreateTableBuilder, Migration,
+};
 
 mod common;
 
@@ -29,6 +31,11 @@ fn add_index() {
         table: "users".to_string(),
         name: "name_idx".to_string(),
         columns: vec!["name".to_string()],
+        unique: false,
+        method: None,
+        predicate: None,
+        include: vec![],
+        schema: None,
     });
 
     let first_migrations = vec![create_table_migration.clone()];
@@ -67,3 +74,90 @@ fn add_index() {
     reshape.complete_migration().unwrap();
     common::assert_cleaned_up(&mut new_db);
 }
+
+#[test]
+fn add_index_with_options() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_table_migration = Migration::new("create_user_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![
+                ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("name")
+                    .data_type("TEXT")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("email")
+                    .data_type("TEXT")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("deleted_at")
+                    .data_type("TIMESTAMP")
+                    .build()
+                    .unwrap(),
+            ])
+            .build()
+            .unwrap(),
+    );
+    let add_index_migration = Migration::new("add_email_index", None)
+        .with_action(AlterColumn {
+            table: "users".to_string(),
+            column: "email".to_string(),
+            up: None,
+            down: None,
+            changes: ColumnChanges {
+                name: Some("mail".to_string()),
+                data_type: None,
+                nullable: None,
+                default: None,
+            },
+            dependencies: vec![],
+            down_dependencies: vec![],
+            schema: None,
+        })
+        .with_action(AddIndex {
+            table: "users".to_string(),
+            name: "mail_idx".to_string(),
+            columns: vec![
+                "LOWER(mail)".to_string(),
+                "name DESC NULLS LAST".to_string(),
+            ],
+            unique: true,
+            method: Some("btree".to_string()),
+            predicate: Some("deleted_at IS NULL".to_string()),
+            include: vec!["id".to_string()],
+            schema: None,
+        });
+
+    reshape
+        .migrate(vec![create_table_migration.clone()])
+        .unwrap();
+    reshape
+        .migrate(vec![
+            create_table_migration.clone(),
+            add_index_migration.clone(),
+        ])
+        .unwrap();
+
+    // Ensure the index was created with all options using the real column names
+    let definition: String = db
+        .query_one("SELECT pg_get_indexdef('public.mail_idx'::regclass)", &[])
+        .unwrap()
+        .get(0);
+    assert_eq!(
+        "CREATE UNIQUE INDEX mail_idx ON public.users USING btree (lower(email), name DESC NULLS LAST) INCLUDE (id) WHERE (deleted_at IS NULL)",
+        definition
+    );
+
+    reshape.complete_migration().unwrap();
+    common::assert_cleaned_up(&mut db);
+}
@@ -0,0 +1,140 @@
+use reshape::migrations::{AddUniqueConstraint, ColumnBuilder, CreateTableBuilder, Migration};
+
+mod common;
+
+#[test]
+fn add_unique_constraint() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let create_table_migration = Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![
+                ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("email")
+                    .data_type("TEXT")
+                    .build()
+                    .unwrap(),
+            ])
+            .build()
+            .unwrap(),
+    );
+    let add_unique_migration =
+        Migration::new("add_unique_email", None).with_action(AddUniqueConstraint {
+            table: "users".to_string(),
+            name: "unique_email".to_string(),
+            columns: vec!["email".to_string()],
+            schema: None,
+        });
+
+    let first_migrations = vec![create_table_migration.clone()];
+    let second_migrations = vec![create_table_migration.clone(), add_unique_migration.clone()];
+
+    reshape.migrate(first_migrations.clone()).unwrap();
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &first_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+    old_db
+        .simple_query("INSERT INTO users (id, email) VALUES (1, 'a@example.com')")
+        .unwrap();
+
+    // Run second migration and ensure uniqueness is already enforced through the index
+    reshape.migrate(second_migrations.clone()).unwrap();
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &second_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+    let result = new_db.simple_query("INSERT INTO users (id, email) VALUES (2, 'a@example.com')");
+    assert!(result.is_err(), "expected insert to violate unique index");
+
+    reshape.complete_migration().unwrap();
+
+    // Ensure the constraint exists and is backed by an index with the same name
+    let index_name: String = new_db
+        .query_one(
+            "
+            SELECT pg_class.relname
+            FROM pg_catalog.pg_constraint
+            JOIN pg_catalog.pg_class ON pg_constraint.conindid = pg_class.oid
+            WHERE pg_constraint.conname = 'unique_email' AND pg_constraint.contype = 'u'
+            ",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!("unique_email", index_name);
+
+    common::assert_cleaned_up(&mut new_db);
+}
+
+#[test]
+fn add_unique_constraint_with_duplicates() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_table_migration = Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![
+                ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("email")
+                    .data_type("TEXT")
+                    .build()
+                    .unwrap(),
+            ])
+            .build()
+            .unwrap(),
+    );
+    let add_unique_migration =
+        Migration::new("add_unique_email", None).with_action(AddUniqueConstraint {
+            table: "users".to_string(),
+            name: "unique_email".to_string(),
+            columns: vec!["email".to_string()],
+            schema: None,
+        });
+
+    reshape
+        .migrate(vec![create_table_migration.clone()])
+        .unwrap();
+    db.simple_query(
+        "INSERT INTO public.users (id, email) VALUES (1, 'a@example.com'), (2, 'a@example.com')",
+    )
+    .unwrap();
+
+    // Building the index fails which should leave no invalid index behind
+    assert!(reshape
+        .migrate(vec![
+            create_table_migration.clone(),
+            add_unique_migration.clone()
+        ])
+        .is_err());
+
+    let count: i64 = db
+        .query_one(
+            "
+            SELECT COUNT(*)
+            FROM pg_catalog.pg_index
+            WHERE indrelid = 'public.users'::regclass AND NOT indisprimary
+            ",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(0, count, "expected no index to remain");
+
+    common::assert_cleaned_up(&mut db);
+}
@@ -1,5 +1,5 @@
 use reshape::migrations::{
-    AlterColumn, ColumnBuilder, ColumnChanges, CreateTableBuilder, Migration,
+    AlterColumn, ColumnBuilder, ColumnChanges, CreateTableBuilder, Dependency, Migration,
 };
 use reshape::Status;
 
@@ -39,6 +39,9 @@ fn alter_column_data() {
             name: None,
             default: None,
         },
+        dependencies: vec![],
+        down_dependencies: vec![],
+        schema: None,
     });
 
     let first_migrations = vec![create_users_table.clone()];
@@ -143,6 +146,9 @@ fn alter_column_set_not_null() {
             name: None,
             default: None,
         },
+        dependencies: vec![],
+        down_dependencies: vec![],
+        schema: None,
     });
 
     let first_migrations = vec![create_users_table.clone()];
@@ -248,6 +254,9 @@ fn alter_column_rename() {
                 name: Some("full_name".to_string()),
                 default: None,
             },
+            dependencies: vec![],
+            down_dependencies: vec![],
+            schema: None,
         });
 
     let first_migrations = vec![create_users_table.clone()];
@@ -336,6 +345,9 @@ fn alter_column_multiple() {
                 name: None,
                 default: None,
             },
+            dependencies: vec![],
+            down_dependencies: vec![],
+            schema: None,
         })
         .with_action(AlterColumn {
             table: "users".to_string(),
@@ -348,6 +360,9 @@ fn alter_column_multiple() {
                 name: None,
                 default: None,
             },
+            dependencies: vec![],
+            down_dependencies: vec![],
+            schema: None,
         });
 
     let first_migrations = vec![create_users_table.clone()];
@@ -406,7 +421,7 @@ fn alter_column_multiple() {
         .unwrap()
         .iter()
         .map(|row| row.get("counter"))
-        .nth(0)
+        .next()
         .unwrap();
     assert_eq!(52, result);
 
@@ -419,7 +434,7 @@ fn alter_column_multiple() {
         .unwrap()
         .iter()
         .map(|row| row.get("counter"))
-        .nth(0)
+        .next()
         .unwrap();
     assert_eq!(48, result);
 
@@ -464,6 +479,9 @@ fn alter_column_default() {
                 name: None,
                 default: Some("'NEW DEFAULT'".to_string()),
             },
+            dependencies: vec![],
+            down_dependencies: vec![],
+            schema: None,
         });
 
     let first_migrations = vec![create_users_table.clone()];
@@ -531,3 +549,451 @@ fn alter_column_default() {
     reshape.complete_migration().unwrap();
     common::assert_cleaned_up(&mut new_db);
 }
+
+#[test]
+fn alter_column_with_indices_and_constraints() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let create_tables = Migration::new("create_tables", None)
+        .with_action(
+            CreateTableBuilder::default()
+                .name("users")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("email")
+                        .data_type("TEXT")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        )
+        .with_action(
+            CreateTableBuilder::default()
+                .name("items")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("user_email")
+                        .data_type("TEXT")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        );
+    let change_email_type = Migration::new("change_email_type", None).with_action(AlterColumn {
+        table: "users".to_string(),
+        column: "email".to_string(),
+        up: Some("email".to_string()),
+        down: Some("email".to_string()),
+        changes: ColumnChanges {
+            data_type: Some("VARCHAR(255)".to_string()),
+            nullable: None,
+            name: None,
+            default: None,
+        },
+        dependencies: vec![],
+        down_dependencies: vec![],
+        schema: None,
+    });
+
+    let first_migrations = vec![create_tables.clone()];
+    let second_migrations = vec![create_tables.clone(), change_email_type.clone()];
+
+    // Run first migration and add an index, a unique constraint and a foreign key
+    reshape.migrate(first_migrations.clone()).unwrap();
+    old_db
+        .simple_query(
+            "
+            CREATE INDEX users_email_idx ON users (email);
+            ALTER TABLE users ADD CONSTRAINT users_email_key UNIQUE (email);
+            ALTER TABLE items ADD CONSTRAINT items_user_email_fkey
+                FOREIGN KEY (user_email) REFERENCES users (email);
+            INSERT INTO users (id, email) VALUES (1, 'a@example.com');
+            INSERT INTO items (id, user_email) VALUES (1, 'a@example.com');
+            CREATE VIEW user_emails AS SELECT id, email FROM users;
+            CREATE VIEW user_domains AS SELECT split_part(email, '@', 2) AS domain FROM user_emails;
+            GRANT SELECT ON user_emails TO PUBLIC;
+            ",
+        )
+        .unwrap();
+
+    // Run second migration
+    reshape.migrate(second_migrations.clone()).unwrap();
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &second_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+
+    // Ensure the unique index is already enforced for the new column
+    let result = new_db.simple_query("INSERT INTO users (id, email) VALUES (2, 'a@example.com')");
+    assert!(result.is_err(), "expected insert to violate unique index");
+
+    reshape.complete_migration().unwrap();
+
+    // Ensure all indices and constraints remain with their original names
+    let indices: Vec<String> = new_db
+        .query(
+            "
+            SELECT pg_class.relname
+            FROM pg_catalog.pg_index
+            JOIN pg_catalog.pg_class ON pg_class.oid = pg_index.indexrelid
+            WHERE pg_index.indrelid = 'public.users'::regclass
+            ORDER BY pg_class.relname
+            ",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(
+        vec!["users_email_idx", "users_email_key", "users_pkey"],
+        indices
+    );
+
+    let constraints: Vec<(String, bool)> = new_db
+        .query(
+            "
+            SELECT conname, convalidated
+            FROM pg_catalog.pg_constraint
+            WHERE conrelid IN ('public.users'::regclass, 'public.items'::regclass)
+            ORDER BY conname
+            ",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| (row.get("conname"), row.get("convalidated")))
+        .collect();
+    assert_eq!(
+        vec![
+            ("items_pkey".to_string(), true),
+            ("items_user_email_fkey".to_string(), true),
+            ("users_email_key".to_string(), true),
+            ("users_pkey".to_string(), true),
+        ],
+        constraints
+    );
+
+    // Ensure the foreign key is still enforced
+    let result =
+        new_db.simple_query("INSERT INTO items (id, user_email) VALUES (2, 'b@example.com')");
+    assert!(result.is_err(), "expected insert to violate foreign key");
+
+    // Ensure views on the column, and views on those views, were recreated with their privileges
+    let domain: String = new_db
+        .query_one("SELECT domain FROM public.user_domains", &[])
+        .unwrap()
+        .get(0);
+    assert_eq!("example.com", domain);
+
+    let has_privilege: bool = new_db
+        .query_one(
+            "SELECT has_table_privilege('public', 'public.user_emails', 'SELECT')",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert!(has_privilege);
+
+    common::assert_cleaned_up(&mut new_db);
+}
+
+#[test]
+fn alter_column_set_not_null_in_place() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let create_users_table = Migration::new("create_user_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![
+                ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("name")
+                    .data_type("TEXT")
+                    .build()
+                    .unwrap(),
+            ])
+            .build()
+            .unwrap(),
+    );
+    let set_name_not_null = Migration::new("set_name_not_null", None).with_action(AlterColumn {
+        table: "users".to_string(),
+        column: "name".to_string(),
+        up: None,
+        down: None,
+        changes: ColumnChanges {
+            data_type: None,
+            nullable: Some(false),
+            name: None,
+            default: None,
+        },
+        dependencies: vec![],
+        down_dependencies: vec![],
+        schema: None,
+    });
+
+    let first_migrations = vec![create_users_table.clone()];
+    let second_migrations = vec![create_users_table.clone(), set_name_not_null.clone()];
+
+    reshape.migrate(first_migrations.clone()).unwrap();
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &first_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+    old_db
+        .simple_query("INSERT INTO users (id, name) VALUES (1, 'John Doe')")
+        .unwrap();
+
+    // Run second migration and ensure no temporary column was added
+    reshape.migrate(second_migrations.clone()).unwrap();
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &second_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+
+    let column_count: i64 = new_db
+        .query_one(
+            "
+            SELECT COUNT(*)
+            FROM information_schema.columns
+            WHERE table_schema = 'public' AND table_name = 'users'
+            ",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(2, column_count, "expected no temporary column");
+
+    // Ensure NULL values are rejected right away
+    let result = new_db.simple_query("INSERT INTO users (id, name) VALUES (2, NULL)");
+    assert!(result.is_err(), "expected insert to violate NOT NULL");
+
+    reshape.complete_migration().unwrap();
+
+    let is_nullable: String = new_db
+        .query_one(
+            "
+            SELECT is_nullable
+            FROM information_schema.columns
+            WHERE table_schema = 'public' AND table_name = 'users' AND column_name = 'name'
+            ",
+            &[],
+        )
+        .unwrap()
+        .get("is_nullable");
+    assert_eq!("NO", is_nullable);
+
+    common::assert_cleaned_up(&mut new_db);
+}
+
+#[test]
+fn alter_column_drop_not_null_in_place() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let create_users_table = Migration::new("create_user_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![
+                ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("name")
+                    .data_type("TEXT")
+                    .nullable(false)
+                    .build()
+                    .unwrap(),
+            ])
+            .build()
+            .unwrap(),
+    );
+    let drop_name_not_null = Migration::new("drop_name_not_null", None).with_action(AlterColumn {
+        table: "users".to_string(),
+        column: "name".to_string(),
+        up: None,
+        down: None,
+        changes: ColumnChanges {
+            data_type: None,
+            nullable: Some(true),
+            name: None,
+            default: None,
+        },
+        dependencies: vec![],
+        down_dependencies: vec![],
+        schema: None,
+    });
+
+    let first_migrations = vec![create_users_table.clone()];
+    let second_migrations = vec![create_users_table.clone(), drop_name_not_null.clone()];
+
+    reshape.migrate(first_migrations.clone()).unwrap();
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &first_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+
+    reshape.migrate(second_migrations.clone()).unwrap();
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &second_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+
+    // Ensure only the new schema can insert NULL values
+    new_db
+        .simple_query("INSERT INTO users (id, name) VALUES (1, NULL)")
+        .unwrap();
+    let result = old_db.simple_query("INSERT INTO users (id, name) VALUES (2, NULL)");
+    assert!(result.is_err(), "expected insert to violate NOT NULL");
+
+    reshape.complete_migration().unwrap();
+
+    new_db
+        .simple_query("INSERT INTO users (id, name) VALUES (3, NULL)")
+        .unwrap();
+
+    common::assert_cleaned_up(&mut new_db);
+}
+
+#[test]
+fn alter_column_with_dependencies() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let create_tables = Migration::new("create_tables", None)
+        .with_action(
+            CreateTableBuilder::default()
+                .name("customers")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("email")
+                        .data_type("TEXT")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        )
+        .with_action(
+            CreateTableBuilder::default()
+                .name("orders")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("customer")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        );
+
+    // Change orders to refer to customers by email instead of id
+    let customer_by_email = Migration::new("customer_by_email", None).with_action(AlterColumn {
+        table: "orders".to_string(),
+        column: "customer".to_string(),
+        up: Some("(SELECT email FROM customers WHERE customers.id = customer)".to_string()),
+        down: Some("(SELECT id FROM customers WHERE customers.email = customer)".to_string()),
+        changes: ColumnChanges {
+            data_type: Some("TEXT".to_string()),
+            nullable: None,
+            name: None,
+            default: None,
+        },
+        dependencies: vec![Dependency {
+            table: "customers".to_string(),
+            on: "customers.id = orders.customer".to_string(),
+        }],
+        down_dependencies: vec![Dependency {
+            table: "customers".to_string(),
+            on: "customers.email = orders.customer".to_string(),
+        }],
+        schema: None,
+    });
+
+    let first_migrations = vec![create_tables.clone()];
+    let second_migrations = vec![create_tables.clone(), customer_by_email.clone()];
+
+    reshape.migrate(first_migrations.clone()).unwrap();
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(&create_tables.name))
+        .unwrap();
+    old_db
+        .simple_query(
+            "
+            INSERT INTO customers (id, email) VALUES (1, 'a@example.com');
+            INSERT INTO orders (id, customer) VALUES (1, 1);
+            ",
+        )
+        .unwrap();
+
+    reshape.migrate(second_migrations.clone()).unwrap();
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &customer_by_email.name,
+        ))
+        .unwrap();
+
+    // Ensure `up` is re-evaluated when the customer changes
+    old_db
+        .simple_query("UPDATE customers SET email = 'b@example.com' WHERE id = 1")
+        .unwrap();
+    let customer: String = new_db
+        .query_one("SELECT customer FROM orders WHERE id = 1", &[])
+        .unwrap()
+        .get(0);
+    assert_eq!("b@example.com", customer);
+
+    // Ensure `down` is re-evaluated when a customer matching the new column is added
+    new_db
+        .simple_query("INSERT INTO orders (id, customer) VALUES (2, 'c@example.com')")
+        .unwrap();
+    new_db
+        .simple_query("INSERT INTO customers (id, email) VALUES (2, 'c@example.com')")
+        .unwrap();
+    let customer: Option<i32> = old_db
+        .query_one("SELECT customer FROM orders WHERE id = 2", &[])
+        .unwrap()
+        .get(0);
+    assert_eq!(Some(2), customer);
+
+    reshape.complete_migration().unwrap();
+    common::assert_cleaned_up(&mut new_db);
+}
@@ -21,7 +21,7 @@ pub fn assert_cleaned_up(db: &mut Client) {
             "
             SELECT column_name
             FROM information_schema.columns
-            WHERE table_schema = 'public'
+            WHERE table_schema NOT IN ('pg_catalog', 'information_schema')
             AND column_name LIKE '__reshape%'
             ",
             &[],
@@ -43,7 +43,7 @@ pub fn assert_cleaned_up(db: &mut Client) {
             "
             SELECT trigger_name
             FROM information_schema.triggers
-            WHERE trigger_schema = 'public'
+            WHERE trigger_schema NOT IN ('pg_catalog', 'information_schema')
             AND trigger_name LIKE '__reshape%'
             ",
             &[],
@@ -65,7 +65,7 @@ pub fn assert_cleaned_up(db: &mut Client) {
             "
             SELECT routine_name
             FROM information_schema.routines
-            WHERE routine_schema = 'public'
+            WHERE routine_schema NOT IN ('pg_catalog', 'information_schema')
             AND routine_name LIKE '__reshape%'
             ",
             &[],
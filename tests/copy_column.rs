@@ -0,0 +1,147 @@
+use reshape::migrations::{ColumnBuilder, CopyColumn, CreateTableBuilder, Migration};
+
+mod common;
+
+#[test]
+fn copy_column() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let create_tables_migration = Migration::new("create_tables", None)
+        .with_action(
+            CreateTableBuilder::default()
+                .name("customers")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("email")
+                        .data_type("TEXT")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        )
+        .with_action(
+            CreateTableBuilder::default()
+                .name("orders")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("customer_id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        );
+    let copy_column_migration =
+        Migration::new("copy_customer_email", None).with_action(CopyColumn {
+            table: "orders".to_string(),
+            column: ColumnBuilder::default()
+                .name("customer_email")
+                .data_type("TEXT")
+                .build()
+                .unwrap(),
+            from: "customers".to_string(),
+            on: "customers.id = orders.customer_id".to_string(),
+            up: "customers.email".to_string(),
+            schema: None,
+        });
+
+    let first_migrations = vec![create_tables_migration.clone()];
+    let second_migrations = vec![
+        create_tables_migration.clone(),
+        copy_column_migration.clone(),
+    ];
+
+    // Run first migration and insert some data
+    reshape.migrate(first_migrations.clone()).unwrap();
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &first_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+    old_db
+        .simple_query(
+            "
+            INSERT INTO customers (id, email) VALUES (1, 'a@example.com'), (2, 'b@example.com');
+            INSERT INTO orders (id, customer_id) VALUES (1, 1), (2, 2);
+            ",
+        )
+        .unwrap();
+
+    // Run second migration
+    reshape.migrate(second_migrations.clone()).unwrap();
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &second_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+
+    let get_emails = |db: &mut postgres::Client| -> Vec<Option<String>> {
+        db.query("SELECT customer_email FROM orders ORDER BY id", &[])
+            .unwrap()
+            .iter()
+            .map(|row| row.get("customer_email"))
+            .collect()
+    };
+
+    // Ensure existing rows were backfilled
+    assert_eq!(
+        vec![
+            Some("a@example.com".to_string()),
+            Some("b@example.com".to_string())
+        ],
+        get_emails(&mut new_db)
+    );
+
+    // Ensure the copy is kept up to date when either table changes
+    old_db
+        .simple_query(
+            "
+            INSERT INTO orders (id, customer_id) VALUES (3, 1);
+            UPDATE customers SET email = 'c@example.com' WHERE id = 2;
+            ",
+        )
+        .unwrap();
+    new_db
+        .simple_query("UPDATE orders SET customer_id = 2 WHERE id = 1")
+        .unwrap();
+    assert_eq!(
+        vec![
+            Some("c@example.com".to_string()),
+            Some("c@example.com".to_string()),
+            Some("a@example.com".to_string())
+        ],
+        get_emails(&mut new_db)
+    );
+
+    old_db
+        .simple_query("DELETE FROM customers WHERE id = 2")
+        .unwrap();
+    assert_eq!(
+        vec![None, None, Some("a@example.com".to_string())],
+        get_emails(&mut new_db)
+    );
+
+    reshape.complete_migration().unwrap();
+
+    // Ensure the column has been given its final name and triggers are gone
+    assert_eq!(
+        vec![None, None, Some("a@example.com".to_string())],
+        get_emails(&mut new_db)
+    );
+
+    common::assert_cleaned_up(&mut new_db);
+}
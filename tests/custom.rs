@@ -0,0 +1,100 @@
+use reshape::migrations::{ColumnBuilder, CreateTableBuilder, Custom, Migration};
+
+mod common;
+
+#[test]
+fn custom() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_table_migration = Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![ColumnBuilder::default()
+                .name("id")
+                .data_type("INTEGER")
+                .build()
+                .unwrap()])
+            .build()
+            .unwrap(),
+    );
+    let custom_migration = Migration::new("custom_migration", None).with_action(Custom {
+        start: Some("INSERT INTO users (id) VALUES (1)".to_string()),
+        complete: Some("INSERT INTO users (id) VALUES (2)".to_string()),
+        abort: Some("DELETE FROM users WHERE id = 1".to_string()),
+        complete_in_transaction: true,
+        schema: None,
+    });
+
+    let get_ids = |db: &mut postgres::Client| -> Vec<i32> {
+        db.query("SELECT id FROM public.users ORDER BY id", &[])
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect()
+    };
+
+    reshape
+        .migrate(vec![create_table_migration.clone()])
+        .unwrap();
+
+    // Ensure the start query runs when the migration starts
+    reshape
+        .migrate(vec![
+            create_table_migration.clone(),
+            custom_migration.clone(),
+        ])
+        .unwrap();
+    assert_eq!(vec![1], get_ids(&mut db));
+
+    // Ensure the complete query runs when the migration completes
+    reshape.complete_migration().unwrap();
+    assert_eq!(vec![1, 2], get_ids(&mut db));
+
+    common::assert_cleaned_up(&mut db);
+}
+
+#[test]
+fn custom_abort() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_table_migration = Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![ColumnBuilder::default()
+                .name("id")
+                .data_type("INTEGER")
+                .build()
+                .unwrap()])
+            .build()
+            .unwrap(),
+    );
+    let custom_migration = Migration::new("custom_migration", None).with_action(Custom {
+        start: Some("INSERT INTO users (id) VALUES (1)".to_string()),
+        complete: None,
+        abort: Some("DELETE FROM users WHERE id = 1".to_string()),
+        complete_in_transaction: false,
+        schema: None,
+    });
+
+    reshape
+        .migrate(vec![create_table_migration.clone()])
+        .unwrap();
+    reshape
+        .migrate(vec![
+            create_table_migration.clone(),
+            custom_migration.clone(),
+        ])
+        .unwrap();
+    reshape.abort().unwrap();
+
+    // Ensure the abort query has undone the start query
+    let count: i64 = db
+        .query_one("SELECT COUNT(*) FROM public.users", &[])
+        .unwrap()
+        .get(0);
+    assert_eq!(0, count);
+
+    common::assert_cleaned_up(&mut db);
+}
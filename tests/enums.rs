@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+
+use reshape::migrations::{
+    AddEnumValue, ColumnBuilder, CreateEnum, CreateTableBuilder, EnumColumn, Migration, RemoveEnum,
+    RenameEnumValue, ReplaceEnum,
+};
+
+mod common;
+
+fn get_enum_values(db: &mut postgres::Client, name: &str) -> Vec<String> {
+    db.query(
+        "
+        SELECT enumlabel
+        FROM pg_catalog.pg_enum
+        JOIN pg_catalog.pg_type ON pg_type.oid = pg_enum.enumtypid
+        JOIN pg_catalog.pg_namespace ON pg_namespace.oid = pg_type.typnamespace
+        WHERE pg_namespace.nspname = 'public' AND pg_type.typname = $1
+        ORDER BY enumsortorder
+        ",
+        &[&name],
+    )
+    .unwrap()
+    .iter()
+    .map(|row| row.get(0))
+    .collect()
+}
+
+fn create_users_table_migration() -> Migration {
+    Migration::new("create_users_table", None)
+        .with_action(CreateEnum {
+            name: "status".to_string(),
+            values: vec![
+                "active".to_string(),
+                "disabled".to_string(),
+                "banned".to_string(),
+            ],
+            schema: None,
+        })
+        .with_action(
+            CreateTableBuilder::default()
+                .name("users")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("status")
+                        .data_type("status")
+                        .default_value("'disabled'")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        )
+}
+
+fn replace_status_migration() -> Migration {
+    Migration::new("replace_status_enum", None).with_action(ReplaceEnum {
+        enum_name: "status".to_string(),
+        values: vec!["active".to_string(), "inactive".to_string()],
+        columns: vec![EnumColumn {
+            table: "users".to_string(),
+            column: "status".to_string(),
+        }],
+        renames: HashMap::from([("disabled".to_string(), "inactive".to_string())]),
+        schema: None,
+    })
+}
+
+fn get_statuses(db: &mut postgres::Client) -> Vec<String> {
+    db.query("SELECT status::TEXT FROM users ORDER BY id", &[])
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect()
+}
+
+#[test]
+fn create_and_remove_enum() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_enum_migration =
+        Migration::new("create_status_enum", None).with_action(CreateEnum {
+            name: "status".to_string(),
+            values: vec!["active".to_string(), "inactive".to_string()],
+            schema: None,
+        });
+    let add_value_migration = Migration::new("add_status_value", None).with_action(AddEnumValue {
+        enum_name: "status".to_string(),
+        value: "pending".to_string(),
+        before: Some("active".to_string()),
+        after: None,
+        schema: None,
+    });
+    let remove_enum_migration =
+        Migration::new("remove_status_enum", None).with_action(RemoveEnum {
+            enum_name: "status".to_string(),
+            schema: None,
+        });
+
+    let get_values = |db: &mut postgres::Client| -> Vec<String> {
+        db.query(
+            "
+            SELECT enumlabel
+            FROM pg_catalog.pg_enum
+            WHERE enumtypid = 'public.status'::regtype
+            ORDER BY enumsortorder
+            ",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect()
+    };
+
+    reshape
+        .migrate(vec![create_enum_migration.clone()])
+        .unwrap();
+    assert_eq!(vec!["active", "inactive"], get_values(&mut db));
+
+    reshape
+        .migrate(vec![
+            create_enum_migration.clone(),
+            add_value_migration.clone(),
+        ])
+        .unwrap();
+    assert_eq!(vec!["pending", "active", "inactive"], get_values(&mut db));
+    reshape.complete_migration().unwrap();
+
+    reshape
+        .migrate(vec![
+            create_enum_migration.clone(),
+            add_value_migration.clone(),
+            remove_enum_migration.clone(),
+        ])
+        .unwrap();
+    reshape.complete_migration().unwrap();
+
+    let count: i64 = db
+        .query_one(
+            "SELECT COUNT(*) FROM pg_catalog.pg_type WHERE typname = 'status'",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(0, count, "expected enum to be removed");
+
+    common::assert_cleaned_up(&mut db);
+}
+
+#[test]
+fn rename_enum_value() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let create_table_migration = Migration::new("create_users_table", None)
+        .with_action(CreateEnum {
+            name: "status".to_string(),
+            values: vec!["active".to_string(), "disabled".to_string()],
+            schema: None,
+        })
+        .with_action(
+            CreateTableBuilder::default()
+                .name("users")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("status")
+                        .data_type("status")
+                        .default_value("'disabled'")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        );
+    let rename_value_migration =
+        Migration::new("rename_status_value", None).with_action(RenameEnumValue {
+            enum_name: "status".to_string(),
+            from: "disabled".to_string(),
+            to: "inactive".to_string(),
+            columns: vec![EnumColumn {
+                table: "users".to_string(),
+                column: "status".to_string(),
+            }],
+            schema: None,
+        });
+
+    let first_migrations = vec![create_table_migration.clone()];
+    let second_migrations = vec![
+        create_table_migration.clone(),
+        rename_value_migration.clone(),
+    ];
+
+    reshape.migrate(first_migrations.clone()).unwrap();
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &first_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+    old_db
+        .simple_query("INSERT INTO users (id, status) VALUES (1, 'active'), (2, 'disabled')")
+        .unwrap();
+
+    reshape.migrate(second_migrations.clone()).unwrap();
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &second_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+
+    // Ensure each schema sees its own version of the values
+    let get_statuses = |db: &mut postgres::Client| -> Vec<String> {
+        db.query("SELECT status::TEXT FROM users ORDER BY id", &[])
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect()
+    };
+    assert_eq!(vec!["active", "disabled"], get_statuses(&mut old_db));
+    assert_eq!(vec!["active", "inactive"], get_statuses(&mut new_db));
+
+    // Ensure values and defaults are translated between the schemas
+    old_db
+        .simple_query("INSERT INTO users (id) VALUES (3)")
+        .unwrap();
+    new_db
+        .simple_query("INSERT INTO users (id, status) VALUES (4, 'inactive')")
+        .unwrap();
+    new_db
+        .simple_query("INSERT INTO users (id) VALUES (5)")
+        .unwrap();
+    assert_eq!(
+        vec!["active", "disabled", "disabled", "disabled", "disabled"],
+        get_statuses(&mut old_db)
+    );
+    assert_eq!(
+        vec!["active", "inactive", "inactive", "inactive", "inactive"],
+        get_statuses(&mut new_db)
+    );
+
+    reshape.complete_migration().unwrap();
+
+    let values: Vec<String> = new_db
+        .query(
+            "
+            SELECT enumlabel
+            FROM pg_catalog.pg_enum
+            WHERE enumtypid = 'public.status'::regtype
+            ORDER BY enumsortorder
+            ",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(vec!["active", "inactive"], values);
+
+    common::assert_cleaned_up(&mut new_db);
+}
+
+#[test]
+fn add_enum_value_after() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_enum_migration =
+        Migration::new("create_status_enum", None).with_action(CreateEnum {
+            name: "status".to_string(),
+            values: vec!["active".to_string(), "inactive".to_string()],
+            schema: None,
+        });
+    let add_values_migration = Migration::new("add_status_values", None)
+        .with_action(AddEnumValue {
+            enum_name: "status".to_string(),
+            value: "pending".to_string(),
+            before: None,
+            after: Some("active".to_string()),
+            schema: None,
+        })
+        .with_action(AddEnumValue {
+            enum_name: "status".to_string(),
+            value: "new".to_string(),
+            before: Some("pending".to_string()),
+            after: None,
+            schema: None,
+        })
+        .with_action(AddEnumValue {
+            enum_name: "status".to_string(),
+            value: "archived".to_string(),
+            before: None,
+            after: Some("inactive".to_string()),
+            schema: None,
+        });
+
+    reshape
+        .migrate(vec![create_enum_migration.clone()])
+        .unwrap();
+    reshape
+        .migrate(vec![
+            create_enum_migration.clone(),
+            add_values_migration.clone(),
+        ])
+        .unwrap();
+    reshape.complete_migration().unwrap();
+
+    assert_eq!(
+        vec!["active", "new", "pending", "inactive", "archived"],
+        get_enum_values(&mut db, "status")
+    );
+
+    common::assert_cleaned_up(&mut db);
+}
+
+#[test]
+fn replace_enum() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let first_migrations = vec![create_users_table_migration()];
+    let second_migrations = vec![create_users_table_migration(), replace_status_migration()];
+
+    reshape.migrate(first_migrations.clone()).unwrap();
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &first_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+    old_db
+        .simple_query("INSERT INTO users (id, status) VALUES (1, 'active'), (2, 'disabled')")
+        .unwrap();
+
+    reshape.migrate(second_migrations.clone()).unwrap();
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &second_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+
+    // Ensure each schema sees its own version of the values
+    assert_eq!(vec!["active", "disabled"], get_statuses(&mut old_db));
+    assert_eq!(vec!["active", "inactive"], get_statuses(&mut new_db));
+
+    // Ensure values and defaults are translated between the schemas
+    old_db
+        .simple_query("INSERT INTO users (id, status) VALUES (3, 'disabled')")
+        .unwrap();
+    new_db
+        .simple_query("INSERT INTO users (id) VALUES (4)")
+        .unwrap();
+    assert_eq!(
+        vec!["active", "disabled", "disabled", "disabled"],
+        get_statuses(&mut old_db)
+    );
+    assert_eq!(
+        vec!["active", "inactive", "inactive", "inactive"],
+        get_statuses(&mut new_db)
+    );
+
+    // Ensure the new schema refers to the new enum by its original name
+    new_db
+        .simple_query("INSERT INTO users (id, status) VALUES (5, 'inactive'::status)")
+        .unwrap();
+    let result = new_db.simple_query("INSERT INTO users (id, status) VALUES (6, 'banned'::status)");
+    assert!(result.is_err(), "expected removed value to be rejected");
+
+    reshape.complete_migration().unwrap();
+
+    assert_eq!(
+        vec!["active", "inactive"],
+        get_enum_values(&mut new_db, "status")
+    );
+    assert_eq!(
+        vec!["active", "inactive", "inactive", "inactive", "inactive"],
+        get_statuses(&mut new_db)
+    );
+    let data_type: String = new_db
+        .query_one(
+            "
+            SELECT udt_name::TEXT
+            FROM information_schema.columns
+            WHERE table_schema = 'public' AND table_name = 'users' AND column_name = 'status'
+            ",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!("status", data_type);
+
+    common::assert_cleaned_up(&mut new_db);
+}
+
+#[test]
+fn replace_enum_with_removed_value_in_use() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let first_migrations = vec![create_users_table_migration()];
+    let second_migrations = vec![create_users_table_migration(), replace_status_migration()];
+
+    reshape.migrate(first_migrations.clone()).unwrap();
+    db.simple_query("INSERT INTO public.users (id, status) VALUES (1, 'active'), (2, 'banned')")
+        .unwrap();
+
+    // The migration can't be applied as a row uses a value which is removed
+    assert!(reshape.migrate(second_migrations.clone()).is_err());
+
+    assert_eq!(
+        vec!["active", "disabled", "banned"],
+        get_enum_values(&mut db, "status")
+    );
+    db.simple_query(&reshape::schema_query_for_migration(
+        &first_migrations.last().unwrap().name,
+    ))
+    .unwrap();
+    assert_eq!(vec!["active", "banned"], get_statuses(&mut db));
+
+    common::assert_cleaned_up(&mut db);
+}
+
+#[test]
+fn replace_enum_abort() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let first_migrations = vec![create_users_table_migration()];
+    let second_migrations = vec![create_users_table_migration(), replace_status_migration()];
+
+    reshape.migrate(first_migrations.clone()).unwrap();
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &first_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+    old_db
+        .simple_query("INSERT INTO users (id, status) VALUES (1, 'active'), (2, 'disabled')")
+        .unwrap();
+
+    reshape.migrate(second_migrations.clone()).unwrap();
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &second_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+    new_db
+        .simple_query("INSERT INTO users (id, status) VALUES (3, 'inactive')")
+        .unwrap();
+
+    reshape.abort().unwrap();
+
+    // Ensure the old enum is kept along with the values written during the migration
+    assert_eq!(
+        vec!["active", "disabled", "banned"],
+        get_enum_values(&mut old_db, "status")
+    );
+    assert_eq!(
+        vec!["active", "disabled", "disabled"],
+        get_statuses(&mut old_db)
+    );
+    let count: i64 = old_db
+        .query_one(
+            "SELECT COUNT(*) FROM pg_catalog.pg_type WHERE typname LIKE '\\_\\_reshape%'",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(0, count, "expected new enum to be removed");
+
+    common::assert_cleaned_up(&mut old_db);
+}
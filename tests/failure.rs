@@ -27,6 +27,7 @@ fn invalid_migration() {
                 generated: None,
             },
         ],
+        schema: None,
     });
     let add_first_column = Migration::new("add_first_column", None).with_action(AddColumn {
         table: "users".to_string(),
@@ -38,6 +39,8 @@ fn invalid_migration() {
             generated: None,
         },
         up: Some("INVALID SQL".to_string()),
+        dependencies: vec![],
+        schema: None,
     });
 
     let first_migrations = vec![create_users_table.clone()];
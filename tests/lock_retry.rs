@@ -0,0 +1,171 @@
+use std::{thread, time::Duration};
+
+use reshape::migrations::{
+    AddColumn, AlterColumn, Column, ColumnBuilder, ColumnChanges, CreateTableBuilder,
+    LockRetryPolicy, Migration,
+};
+use reshape::Status;
+
+mod common;
+
+fn migrations() -> (Migration, Migration) {
+    let create_users_table = Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![ColumnBuilder::default()
+                .name("id")
+                .data_type("INTEGER")
+                .build()
+                .unwrap()])
+            .build()
+            .unwrap(),
+    );
+    let add_name_column = Migration::new("add_name_column", None).with_action(AddColumn {
+        table: "users".to_string(),
+        column: Column {
+            name: "name".to_string(),
+            data_type: "TEXT".to_string(),
+            nullable: true,
+            default: None,
+            generated: None,
+        },
+        up: None,
+        dependencies: vec![],
+        schema: None,
+    });
+
+    (create_users_table, add_name_column)
+}
+
+#[test]
+fn lock_retry_succeeds_once_lock_is_released() {
+    let (mut reshape, mut old_db, _) = common::setup();
+    let (create_users_table, add_name_column) = migrations();
+
+    reshape.migrate(vec![create_users_table.clone()]).unwrap();
+
+    // Hold a lock on the table which conflicts with ALTER TABLE, like a long-running query
+    old_db
+        .simple_query("BEGIN; LOCK TABLE users IN ACCESS SHARE MODE")
+        .unwrap();
+    let holder = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(800));
+        old_db.simple_query("COMMIT").unwrap();
+        old_db
+    });
+
+    reshape.set_lock_retry_policy(LockRetryPolicy {
+        lock_timeout: Duration::from_millis(100),
+        statement_timeout: None,
+        max_retries: 20,
+        initial_backoff: Duration::from_millis(50),
+        max_backoff: Duration::from_millis(200),
+    });
+    reshape
+        .migrate(vec![create_users_table, add_name_column])
+        .unwrap();
+    assert!(matches!(reshape.state.status, Status::InProgress { .. }));
+
+    reshape.complete_migration().unwrap();
+
+    let mut old_db = holder.join().unwrap();
+    common::assert_cleaned_up(&mut old_db);
+}
+
+#[test]
+fn lock_retry_gives_up() {
+    let (mut reshape, mut old_db, _) = common::setup();
+    let (create_users_table, add_name_column) = migrations();
+
+    reshape.migrate(vec![create_users_table.clone()]).unwrap();
+
+    old_db
+        .simple_query("BEGIN; LOCK TABLE users IN ACCESS SHARE MODE")
+        .unwrap();
+
+    reshape.set_lock_retry_policy(LockRetryPolicy {
+        lock_timeout: Duration::from_millis(50),
+        statement_timeout: None,
+        max_retries: 2,
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(20),
+    });
+    let error = reshape
+        .migrate(vec![create_users_table, add_name_column])
+        .unwrap_err();
+    assert!(
+        format!("{:#}", error).contains("lock timeout"),
+        "unexpected error: {:#}",
+        error
+    );
+
+    // The abort which follows the failure is also blocked, so it's finished
+    // with `reshape abort` once the lock has been released
+    old_db.simple_query("COMMIT").unwrap();
+    reshape.abort().unwrap();
+    assert!(matches!(reshape.state.status, Status::Idle));
+
+    common::assert_cleaned_up(&mut old_db);
+}
+
+#[test]
+fn lock_retry_retries_column_swap() {
+    let (mut reshape, mut old_db, _) = common::setup();
+    let (create_users_table, _) = migrations();
+    let alter_id_column = Migration::new("alter_id_column", None).with_action(AlterColumn {
+        table: "users".to_string(),
+        column: "id".to_string(),
+        up: Some("id::BIGINT".to_string()),
+        down: Some("id::INTEGER".to_string()),
+        changes: ColumnChanges {
+            data_type: Some("BIGINT".to_string()),
+            nullable: None,
+            name: None,
+            default: None,
+        },
+        dependencies: vec![],
+        down_dependencies: vec![],
+        schema: None,
+    });
+
+    reshape
+        .migrate(vec![create_users_table.clone(), alter_id_column])
+        .unwrap();
+
+    // The swap on completion is retried as a whole, without holding on to any locks in between
+    old_db
+        .simple_query("BEGIN; LOCK TABLE users IN ACCESS SHARE MODE")
+        .unwrap();
+    let holder = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(800));
+        old_db.simple_query("COMMIT").unwrap();
+        old_db
+    });
+
+    reshape.set_lock_retry_policy(LockRetryPolicy {
+        lock_timeout: Duration::from_millis(100),
+        statement_timeout: None,
+        max_retries: 20,
+        initial_backoff: Duration::from_millis(50),
+        max_backoff: Duration::from_millis(200),
+    });
+    reshape.complete_migration().unwrap();
+    assert!(matches!(reshape.state.status, Status::Idle));
+
+    let mut old_db = holder.join().unwrap();
+    let data_type: String = old_db
+        .query_one(
+            "
+            SELECT data_type
+            FROM information_schema.columns
+            WHERE table_schema = 'public' AND table_name = 'users' AND column_name = 'id'
+            ",
+            &[],
+        )
+        .unwrap()
+        .get("data_type");
+    assert_eq!("bigint", data_type);
+
+    common::assert_cleaned_up(&mut old_db);
+}
@@ -0,0 +1,174 @@
+use reshape::migrations::{
+    AddColumn, AddIndex, AttachPartition, ColumnBuilder, CreatePartitionedTableBuilder,
+    DetachPartition, Migration,
+};
+
+mod common;
+
+#[test]
+fn partitioned_table() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let create_table_migration = Migration::new("create_events_table", None)
+        .with_action(
+            CreatePartitionedTableBuilder::default()
+                .name("events")
+                .primary_key(vec!["id".to_string(), "created_at".to_string()])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("created_at")
+                        .data_type("DATE")
+                        .nullable(false)
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("name")
+                        .data_type("TEXT")
+                        .build()
+                        .unwrap(),
+                ])
+                .partition_by("RANGE (created_at)")
+                .build()
+                .unwrap(),
+        )
+        .with_action(AttachPartition {
+            table: "events".to_string(),
+            partition: "events_2024_01".to_string(),
+            bound: "FOR VALUES FROM ('2024-01-01') TO ('2024-02-01')".to_string(),
+            create: true,
+            schema: None,
+        })
+        .with_action(AttachPartition {
+            table: "events".to_string(),
+            partition: "events_2024_02".to_string(),
+            bound: "FOR VALUES FROM ('2024-02-01') TO ('2024-03-01')".to_string(),
+            create: true,
+            schema: None,
+        });
+    let second_migration = Migration::new("index_and_detach", None)
+        .with_action(AddIndex {
+            table: "events".to_string(),
+            name: "events_name_idx".to_string(),
+            columns: vec!["name".to_string()],
+            unique: false,
+            method: None,
+            predicate: None,
+            include: vec![],
+            schema: None,
+        })
+        .with_action(AddColumn {
+            table: "events".to_string(),
+            column: ColumnBuilder::default()
+                .name("upper_name")
+                .data_type("TEXT")
+                .build()
+                .unwrap(),
+            up: Some("UPPER(name)".to_string()),
+            dependencies: vec![],
+            schema: None,
+        })
+        .with_action(DetachPartition {
+            table: "events".to_string(),
+            partition: "events_2024_01".to_string(),
+            schema: None,
+        });
+
+    let first_migrations = vec![create_table_migration.clone()];
+    let second_migrations = vec![create_table_migration.clone(), second_migration.clone()];
+
+    // Run first migration and insert rows into both partitions through the parent
+    reshape.migrate(first_migrations.clone()).unwrap();
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &first_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+    old_db
+        .simple_query(
+            "
+            INSERT INTO events (id, created_at, name) VALUES
+                (1, '2024-01-15', 'first'),
+                (2, '2024-02-15', 'second');
+            ",
+        )
+        .unwrap();
+
+    // Ensure only the parent table is exposed in the schema
+    let views: Vec<String> = old_db
+        .query(
+            "
+            SELECT table_name
+            FROM information_schema.views
+            WHERE table_schema = current_schema()
+            ",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| row.get("table_name"))
+        .collect();
+    assert_eq!(vec!["events"], views);
+
+    reshape.migrate(second_migrations.clone()).unwrap();
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &second_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+
+    // Ensure the index was built for each partition and is valid on the parent
+    let indices: Vec<(String, bool)> = new_db
+        .query(
+            "
+            SELECT pg_class.relname, pg_index.indisvalid
+            FROM pg_catalog.pg_index
+            JOIN pg_catalog.pg_class ON pg_class.oid = pg_index.indexrelid
+            WHERE pg_class.relname LIKE 'events_name_idx%'
+            ORDER BY pg_class.relname
+            ",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| (row.get("relname"), row.get("indisvalid")))
+        .collect();
+    assert_eq!(
+        vec![
+            ("events_name_idx".to_string(), true),
+            ("events_name_idx_events_2024_01".to_string(), true),
+            ("events_name_idx_events_2024_02".to_string(), true),
+        ],
+        indices
+    );
+
+    // Ensure rows in all partitions were backfilled
+    let upper_names: Vec<String> = new_db
+        .query("SELECT upper_name FROM events ORDER BY id", &[])
+        .unwrap()
+        .iter()
+        .map(|row| row.get("upper_name"))
+        .collect();
+    assert_eq!(vec!["FIRST", "SECOND"], upper_names);
+
+    reshape.complete_migration().unwrap();
+
+    // Ensure the partition was detached
+    let count: i64 = new_db
+        .query_one("SELECT COUNT(*) FROM public.events", &[])
+        .unwrap()
+        .get(0);
+    assert_eq!(1, count);
+
+    let count: i64 = new_db
+        .query_one("SELECT COUNT(*) FROM public.events_2024_01", &[])
+        .unwrap()
+        .get(0);
+    assert_eq!(1, count);
+
+    common::assert_cleaned_up(&mut new_db);
+}
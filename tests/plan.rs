@@ -0,0 +1,195 @@
+use reshape::{
+    migrations::{
+        AddColumn, AddForeignKey, Column, ColumnBuilder, CreateTableBuilder, ForeignKey, Migration,
+    },
+    Status,
+};
+
+mod common;
+
+#[test]
+fn plan() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_users_table = Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![ColumnBuilder::default()
+                .name("id")
+                .data_type("INTEGER")
+                .build()
+                .unwrap()])
+            .build()
+            .unwrap(),
+    );
+    let add_name_column = Migration::new("add_name_column", None).with_action(AddColumn {
+        table: "users".to_string(),
+        column: Column {
+            name: "name".to_string(),
+            data_type: "TEXT".to_string(),
+            nullable: false,
+            default: None,
+            generated: None,
+        },
+        up: Some("'unknown'".to_string()),
+        dependencies: vec![],
+        schema: None,
+    });
+
+    reshape.migrate(vec![create_users_table.clone()]).unwrap();
+    db.simple_query("INSERT INTO users (id) VALUES (1)")
+        .unwrap();
+
+    let plan = reshape
+        .plan(vec![create_users_table, add_name_column])
+        .unwrap();
+
+    // The statements for every phase of the action are recorded
+    assert_eq!(1, plan.actions.len());
+    let action = &plan.actions[0];
+    assert_eq!("add_name_column", action.migration);
+    assert!(action
+        .start
+        .iter()
+        .any(|statement| statement.contains("ADD COLUMN IF NOT EXISTS")));
+    assert!(action
+        .start
+        .iter()
+        .any(|statement| statement.contains("UPDATE \"users\"")));
+    assert!(action
+        .complete
+        .iter()
+        .any(|statement| statement.contains("RENAME COLUMN")));
+    assert!(action
+        .abort
+        .iter()
+        .any(|statement| statement.contains("DROP COLUMN IF EXISTS")));
+    assert!(plan
+        .views
+        .iter()
+        .any(|statement| statement.contains("CREATE OR REPLACE VIEW")));
+
+    let output = plan.to_string();
+    assert!(output.contains("-- Migration 'add_name_column': "));
+    assert!(output.contains("-- Start"));
+
+    // Nothing has been changed in the database
+    assert!(matches!(reshape.state.status, Status::Idle));
+    let columns: Vec<String> = db
+        .query(
+            "
+            SELECT column_name
+            FROM information_schema.columns
+            WHERE table_schema = 'public' AND table_name = 'users'
+            ",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(vec!["id"], columns);
+    assert!(db
+        .query(
+            "SELECT 1 FROM pg_namespace WHERE nspname = 'migration_add_name_column'",
+            &[]
+        )
+        .unwrap()
+        .is_empty());
+    common::assert_cleaned_up(&mut db);
+}
+
+#[test]
+fn plan_add_foreign_key() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_tables = Migration::new("create_tables", None)
+        .with_action(
+            CreateTableBuilder::default()
+                .name("users")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap()])
+                .build()
+                .unwrap(),
+        )
+        .with_action(
+            CreateTableBuilder::default()
+                .name("items")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("user_id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        );
+    let add_foreign_key = Migration::new("add_foreign_key", None).with_action(AddForeignKey {
+        table: "items".to_string(),
+        foreign_key: ForeignKey {
+            columns: vec!["user_id".to_string()],
+            referenced_table: "users".to_string(),
+            referenced_columns: vec!["id".to_string()],
+        },
+        schema: None,
+    });
+
+    reshape.migrate(vec![create_tables.clone()]).unwrap();
+
+    let plan = reshape.plan(vec![create_tables, add_foreign_key]).unwrap();
+
+    // Completing and aborting are planned against the constraint added when starting
+    assert_eq!(1, plan.actions.len());
+    let action = &plan.actions[0];
+    assert!(action
+        .start
+        .iter()
+        .any(|statement| statement.contains("ADD CONSTRAINT")));
+    assert!(action
+        .complete
+        .iter()
+        .any(|statement| statement.contains("RENAME CONSTRAINT")));
+    assert!(!action.abort.is_empty());
+    assert!(action
+        .abort
+        .iter()
+        .any(|statement| statement.contains("DROP CONSTRAINT")));
+
+    // Catalog queries aren't part of the plan
+    let statements = plan
+        .setup
+        .iter()
+        .chain(&action.start)
+        .chain(&action.complete)
+        .chain(&action.abort)
+        .chain(&plan.views);
+    for statement in statements {
+        assert!(
+            !statement.trim_start().to_uppercase().starts_with("SELECT"),
+            "unexpected query in plan: {}",
+            statement
+        );
+    }
+
+    // Nothing has been changed in the database
+    assert!(db
+        .query(
+            "SELECT 1 FROM pg_catalog.pg_constraint WHERE conrelid = 'public.items'::regclass AND contype = 'f'",
+            &[]
+        )
+        .unwrap()
+        .is_empty());
+    common::assert_cleaned_up(&mut db);
+}
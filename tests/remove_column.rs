@@ -31,6 +31,7 @@ fn remove_column() {
             table: "users".to_string(),
             column: "name".to_string(),
             down: Some("'TEST_DOWN_VALUE'".to_string()),
+            schema: None,
         });
 
     let first_migrations = vec![create_table_migration.clone()];
@@ -0,0 +1,127 @@
+use reshape::migrations::{
+    ColumnBuilder, CreateTableBuilder, ForeignKey, Migration, RemoveConstraint, RemoveForeignKey,
+};
+
+mod common;
+
+#[test]
+fn remove_foreign_key() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_tables_migration = Migration::new("create_tables", None)
+        .with_action(
+            CreateTableBuilder::default()
+                .name("users")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap()])
+                .build()
+                .unwrap(),
+        )
+        .with_action(
+            CreateTableBuilder::default()
+                .name("items")
+                .primary_key(vec!["id".to_string()])
+                .foreign_keys(vec![ForeignKey {
+                    columns: vec!["user_id".to_string()],
+                    referenced_table: "users".to_string(),
+                    referenced_columns: vec!["id".to_string()],
+                }])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("user_id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        );
+    let remove_foreign_key_migration =
+        Migration::new("remove_foreign_key", None).with_action(RemoveForeignKey {
+            table: "items".to_string(),
+            foreign_key: "items_user_id_fkey".to_string(),
+            schema: None,
+        });
+
+    let first_migrations = vec![create_tables_migration.clone()];
+    let second_migrations = vec![
+        create_tables_migration.clone(),
+        remove_foreign_key_migration.clone(),
+    ];
+
+    let count_foreign_keys = |db: &mut postgres::Client| -> i64 {
+        db.query_one(
+            "
+            SELECT COUNT(*)
+            FROM pg_catalog.pg_constraint
+            WHERE conrelid = 'public.items'::regclass AND contype = 'f'
+            ",
+            &[],
+        )
+        .unwrap()
+        .get(0)
+    };
+
+    reshape.migrate(first_migrations.clone()).unwrap();
+    assert_eq!(1, count_foreign_keys(&mut db));
+
+    // Ensure the foreign key is kept while the old schema is still in use
+    reshape.migrate(second_migrations.clone()).unwrap();
+    assert_eq!(1, count_foreign_keys(&mut db));
+
+    // Ensure the foreign key is removed once the migration is complete
+    reshape.complete_migration().unwrap();
+    assert_eq!(0, count_foreign_keys(&mut db));
+
+    common::assert_cleaned_up(&mut db);
+}
+
+#[test]
+fn remove_constraint_missing() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_table_migration = Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![ColumnBuilder::default()
+                .name("id")
+                .data_type("INTEGER")
+                .build()
+                .unwrap()])
+            .build()
+            .unwrap(),
+    );
+    let remove_constraint_migration =
+        Migration::new("remove_constraint", None).with_action(RemoveConstraint {
+            table: "users".to_string(),
+            constraint: "does_not_exist".to_string(),
+            schema: None,
+        });
+
+    reshape
+        .migrate(vec![create_table_migration.clone()])
+        .unwrap();
+
+    // Ensure the migration fails early as the constraint doesn't exist
+    assert!(
+        reshape
+            .migrate(vec![
+                create_table_migration.clone(),
+                remove_constraint_migration.clone(),
+            ])
+            .is_err(),
+        "expected migration to fail"
+    );
+
+    common::assert_cleaned_up(&mut db);
+}
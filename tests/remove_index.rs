@@ -30,11 +30,17 @@ fn remove_index() {
             table: "users".to_string(),
             name: "name_idx".to_string(),
             columns: vec!["name".to_string()],
+            unique: false,
+            method: None,
+            predicate: None,
+            include: vec![],
+            schema: None,
         });
 
     let remove_index_migration =
         Migration::new("remove_name_index", None).with_action(RemoveIndex {
             index: "name_idx".to_string(),
+            schema: None,
         });
 
     let first_migrations = vec![create_table_migration.clone()];
@@ -21,6 +21,7 @@ fn remove_table() {
     let remove_table_migration =
         Migration::new("remove_users_table", None).with_action(RemoveTable {
             table: "users".to_string(),
+            schema: None,
         });
 
     let first_migrations = vec![create_table_migration.clone()];
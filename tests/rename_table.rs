@@ -22,6 +22,7 @@ fn rename_table() {
         .with_action(RenameTable {
             table: "users".to_string(),
             new_name: "customers".to_string(),
+            schema: None,
         });
 
     let first_migrations = vec![create_table_migration.clone()];
@@ -0,0 +1,677 @@
+use std::{collections::HashMap, thread, time::Duration};
+
+use reshape::migrations::{
+    BackfillPolicy, ColumnBuilder, CreateTableBuilder, ForeignKey, Migration, RewriteTable,
+};
+
+mod common;
+
+#[test]
+fn rewrite_table() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let create_tables_migration = Migration::new("create_tables", None)
+        .with_action(
+            CreateTableBuilder::default()
+                .name("users")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("SERIAL")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("first_name")
+                        .data_type("TEXT")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("last_name")
+                        .data_type("TEXT")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        )
+        .with_action(
+            CreateTableBuilder::default()
+                .name("items")
+                .primary_key(vec!["id".to_string()])
+                .foreign_keys(vec![ForeignKey {
+                    columns: vec!["user_id".to_string()],
+                    referenced_table: "users".to_string(),
+                    referenced_columns: vec!["id".to_string()],
+                }])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("user_id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        );
+    let rewrite_migration = Migration::new("rewrite_users", None).with_action(RewriteTable {
+        table: "users".to_string(),
+        columns: vec![
+            ColumnBuilder::default()
+                .name("id")
+                .data_type("INTEGER")
+                .build()
+                .unwrap(),
+            ColumnBuilder::default()
+                .name("name")
+                .data_type("TEXT")
+                .build()
+                .unwrap(),
+        ],
+        primary_key: vec!["id".to_string()],
+        foreign_keys: vec![],
+        partition_by: None,
+        up: HashMap::from([(
+            "name".to_string(),
+            "first_name || ' ' || last_name".to_string(),
+        )]),
+        down: HashMap::from([
+            (
+                "first_name".to_string(),
+                "split_part(name, ' ', 1)".to_string(),
+            ),
+            (
+                "last_name".to_string(),
+                "split_part(name, ' ', 2)".to_string(),
+            ),
+        ]),
+        schema: None,
+    });
+
+    let first_migrations = vec![create_tables_migration.clone()];
+    let second_migrations = vec![create_tables_migration.clone(), rewrite_migration.clone()];
+
+    reshape.migrate(first_migrations.clone()).unwrap();
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &first_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+    old_db
+        .simple_query(
+            "
+            INSERT INTO users (first_name, last_name) VALUES ('John', 'Doe'), ('Jane', 'Doe');
+            INSERT INTO items (id, user_id) VALUES (1, 1);
+            ",
+        )
+        .unwrap();
+
+    reshape.migrate(second_migrations.clone()).unwrap();
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &second_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+
+    // Ensure existing rows have been copied
+    let get_names = |db: &mut postgres::Client| -> Vec<(i32, String)> {
+        db.query("SELECT id, name FROM users ORDER BY id", &[])
+            .unwrap()
+            .iter()
+            .map(|row| (row.get("id"), row.get("name")))
+            .collect()
+    };
+    assert_eq!(
+        vec![(1, "John Doe".to_string()), (2, "Jane Doe".to_string())],
+        get_names(&mut new_db)
+    );
+
+    // Ensure writes from the old schema are synced to the new one
+    old_db
+        .simple_query(
+            "
+            INSERT INTO users (first_name, last_name) VALUES ('Alice', 'Smith');
+            UPDATE users SET last_name = 'Roe' WHERE id = 2;
+            ",
+        )
+        .unwrap();
+    assert_eq!(
+        vec![
+            (1, "John Doe".to_string()),
+            (2, "Jane Roe".to_string()),
+            (3, "Alice Smith".to_string())
+        ],
+        get_names(&mut new_db)
+    );
+
+    // Ensure writes from the new schema are synced to the old one
+    new_db
+        .simple_query(
+            "
+            INSERT INTO users (name) VALUES ('Bob Brown');
+            DELETE FROM users WHERE id = 3;
+            ",
+        )
+        .unwrap();
+    let old_names: Vec<(i32, String, String)> = old_db
+        .query(
+            "SELECT id, first_name, last_name FROM users ORDER BY id",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| (row.get(0), row.get(1), row.get(2)))
+        .collect();
+    assert_eq!(
+        vec![
+            (1, "John".to_string(), "Doe".to_string()),
+            (2, "Jane".to_string(), "Roe".to_string()),
+            (4, "Bob".to_string(), "Brown".to_string()),
+        ],
+        old_names
+    );
+
+    reshape.complete_migration().unwrap();
+
+    // Ensure the table has been replaced, keeping its sequence and foreign keys
+    new_db
+        .simple_query("INSERT INTO users (name) VALUES ('Eve Black')")
+        .unwrap();
+    assert_eq!(
+        vec![
+            (1, "John Doe".to_string()),
+            (2, "Jane Roe".to_string()),
+            (4, "Bob Brown".to_string()),
+            (5, "Eve Black".to_string())
+        ],
+        get_names(&mut new_db)
+    );
+
+    let constraints: Vec<(String, bool)> = new_db
+        .query(
+            "
+            SELECT conname, convalidated
+            FROM pg_catalog.pg_constraint
+            WHERE conrelid IN ('public.users'::regclass, 'public.items'::regclass)
+            ORDER BY conname
+            ",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| (row.get("conname"), row.get("convalidated")))
+        .collect();
+    assert_eq!(
+        vec![
+            ("items_pkey".to_string(), true),
+            ("items_user_id_fkey".to_string(), true),
+            ("users_pkey".to_string(), true),
+        ],
+        constraints
+    );
+
+    common::assert_cleaned_up(&mut new_db);
+}
+
+#[test]
+fn rewrite_table_partitioned() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_table_migration = Migration::new("create_events_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("events")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![
+                ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("payload")
+                    .data_type("TEXT")
+                    .build()
+                    .unwrap(),
+            ])
+            .build()
+            .unwrap(),
+    );
+    let partition_migration = Migration::new("partition_events", None).with_action(RewriteTable {
+        table: "events".to_string(),
+        columns: vec![
+            ColumnBuilder::default()
+                .name("id")
+                .data_type("INTEGER")
+                .build()
+                .unwrap(),
+            ColumnBuilder::default()
+                .name("payload")
+                .data_type("TEXT")
+                .build()
+                .unwrap(),
+        ],
+        primary_key: vec!["id".to_string()],
+        foreign_keys: vec![],
+        partition_by: Some("RANGE (id)".to_string()),
+        up: HashMap::new(),
+        down: HashMap::new(),
+        schema: None,
+    });
+
+    reshape
+        .migrate(vec![create_table_migration.clone()])
+        .unwrap();
+    db.simple_query("INSERT INTO public.events (id, payload) VALUES (1, 'a'), (2, 'b')")
+        .unwrap();
+
+    reshape
+        .migrate(vec![
+            create_table_migration.clone(),
+            partition_migration.clone(),
+        ])
+        .unwrap();
+    reshape.complete_migration().unwrap();
+
+    // Ensure the table is now partitioned with all rows in the default partition
+    let kind: String = db
+        .query_one(
+            "SELECT relkind::TEXT FROM pg_catalog.pg_class WHERE oid = 'public.events'::regclass",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!("p", kind);
+
+    let count: i64 = db
+        .query_one("SELECT COUNT(*) FROM public.events_default", &[])
+        .unwrap()
+        .get(0);
+    assert_eq!(2, count);
+
+    common::assert_cleaned_up(&mut db);
+}
+
+#[test]
+fn rewrite_table_with_indices_and_constraints() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_users_table = Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![
+                ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .generated("ALWAYS AS IDENTITY")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("email")
+                    .data_type("TEXT")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("age")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap(),
+            ])
+            .build()
+            .unwrap(),
+    );
+
+    // Reorder the columns
+    let reorder_columns = Migration::new("reorder_columns", None).with_action(RewriteTable {
+        table: "users".to_string(),
+        columns: vec![
+            ColumnBuilder::default()
+                .name("id")
+                .data_type("INTEGER")
+                .build()
+                .unwrap(),
+            ColumnBuilder::default()
+                .name("age")
+                .data_type("INTEGER")
+                .build()
+                .unwrap(),
+            ColumnBuilder::default()
+                .name("email")
+                .data_type("TEXT")
+                .build()
+                .unwrap(),
+        ],
+        primary_key: vec!["id".to_string()],
+        foreign_keys: vec![],
+        partition_by: None,
+        up: HashMap::new(),
+        down: HashMap::new(),
+        schema: None,
+    });
+
+    reshape.migrate(vec![create_users_table.clone()]).unwrap();
+    db.simple_query(
+        "
+        CREATE INDEX users_lower_email_idx ON users (lower(email));
+        ALTER TABLE users ADD CONSTRAINT users_email_key UNIQUE (email);
+        ALTER TABLE users ADD CONSTRAINT users_age_check CHECK (age >= 0);
+        INSERT INTO users (email, age) VALUES ('a@example.com', 30), ('b@example.com', 40);
+        ",
+    )
+    .unwrap();
+
+    reshape
+        .migrate(vec![create_users_table.clone(), reorder_columns.clone()])
+        .unwrap();
+
+    // Rows written while the migration is in progress take ids from the old table's identity
+    db.simple_query(&reshape::schema_query_for_migration(
+        &create_users_table.name,
+    ))
+    .unwrap();
+    db.simple_query("INSERT INTO users (email, age) VALUES ('c@example.com', 50)")
+        .unwrap();
+    reshape.complete_migration().unwrap();
+
+    // Ensure indices and constraints were recreated with their original names
+    let indices: Vec<String> = db
+        .query(
+            "
+            SELECT pg_class.relname
+            FROM pg_catalog.pg_index
+            JOIN pg_catalog.pg_class ON pg_class.oid = pg_index.indexrelid
+            WHERE pg_index.indrelid = 'public.users'::regclass
+            ORDER BY pg_class.relname
+            ",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(
+        vec!["users_email_key", "users_lower_email_idx", "users_pkey"],
+        indices
+    );
+
+    let result =
+        db.simple_query("INSERT INTO public.users (email, age) VALUES ('d@example.com', -1)");
+    assert!(
+        result.is_err(),
+        "expected insert to violate check constraint"
+    );
+    let result =
+        db.simple_query("INSERT INTO public.users (email, age) VALUES ('a@example.com', 1)");
+    assert!(
+        result.is_err(),
+        "expected insert to violate unique constraint"
+    );
+
+    // Ensure the identity continues after the ids used while the migration was in progress
+    let id: i32 = db
+        .query_one(
+            "INSERT INTO public.users (email, age) VALUES ('e@example.com', 60) RETURNING id",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert!(id > 3, "expected new id after existing ones, got {}", id);
+
+    common::assert_cleaned_up(&mut db);
+}
+
+#[test]
+fn rewrite_table_with_concurrent_delete() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let create_table_migration = Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![
+                ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("name")
+                    .data_type("TEXT")
+                    .build()
+                    .unwrap(),
+            ])
+            .build()
+            .unwrap(),
+    );
+    let rewrite_migration = Migration::new("rewrite_users", None).with_action(RewriteTable {
+        table: "users".to_string(),
+        columns: vec![
+            ColumnBuilder::default()
+                .name("id")
+                .data_type("BIGINT")
+                .build()
+                .unwrap(),
+            ColumnBuilder::default()
+                .name("name")
+                .data_type("TEXT")
+                .build()
+                .unwrap(),
+        ],
+        primary_key: vec!["id".to_string()],
+        foreign_keys: vec![],
+        partition_by: None,
+        up: HashMap::new(),
+        down: HashMap::new(),
+        schema: None,
+    });
+
+    reshape
+        .migrate(vec![create_table_migration.clone()])
+        .unwrap();
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &create_table_migration.name,
+        ))
+        .unwrap();
+    old_db
+        .simple_query("INSERT INTO users (id, name) VALUES (1, 'John'), (2, 'Jane')")
+        .unwrap();
+
+    // Delete a row from the old schema once the sync triggers exist, but only commit the
+    // delete while the row is being copied
+    let deleter = thread::spawn(move || {
+        while old_db
+            .query(
+                "SELECT 1 FROM pg_catalog.pg_trigger WHERE tgname LIKE '%_rewrite_old_trigger'",
+                &[],
+            )
+            .unwrap()
+            .is_empty()
+        {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        old_db
+            .simple_query("BEGIN; DELETE FROM users WHERE id = 2")
+            .unwrap();
+        thread::sleep(Duration::from_millis(1000));
+        old_db.simple_query("COMMIT").unwrap();
+    });
+
+    reshape.set_backfill_policy(BackfillPolicy {
+        batch_size: 1,
+        sleep_between_batches: Duration::from_millis(500),
+        ..BackfillPolicy::default()
+    });
+    reshape
+        .migrate(vec![create_table_migration, rewrite_migration.clone()])
+        .unwrap();
+    deleter.join().unwrap();
+
+    // Ensure the deleted row wasn't copied to the new table
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &rewrite_migration.name,
+        ))
+        .unwrap();
+    let ids: Vec<i64> = new_db
+        .query("SELECT id FROM users ORDER BY id", &[])
+        .unwrap()
+        .iter()
+        .map(|row| row.get("id"))
+        .collect();
+    assert_eq!(vec![1], ids);
+
+    reshape.complete_migration().unwrap();
+    common::assert_cleaned_up(&mut new_db);
+}
+
+#[test]
+fn rewrite_table_with_dependent_views() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_table_migration = Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![
+                ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("name")
+                    .data_type("TEXT")
+                    .build()
+                    .unwrap(),
+            ])
+            .build()
+            .unwrap(),
+    );
+    let rewrite_migration = Migration::new("rewrite_users", None).with_action(RewriteTable {
+        table: "users".to_string(),
+        columns: vec![
+            ColumnBuilder::default()
+                .name("id")
+                .data_type("BIGINT")
+                .build()
+                .unwrap(),
+            ColumnBuilder::default()
+                .name("name")
+                .data_type("TEXT")
+                .build()
+                .unwrap(),
+        ],
+        primary_key: vec!["id".to_string()],
+        foreign_keys: vec![],
+        partition_by: None,
+        up: HashMap::new(),
+        down: HashMap::new(),
+        schema: None,
+    });
+
+    reshape
+        .migrate(vec![create_table_migration.clone()])
+        .unwrap();
+    db.simple_query(
+        "
+        INSERT INTO public.users (id, name) VALUES (1, 'John');
+        CREATE VIEW public.user_names AS SELECT name FROM public.users;
+        CREATE VIEW public.first_user_name AS SELECT name FROM public.user_names LIMIT 1;
+        GRANT SELECT ON public.user_names TO PUBLIC;
+        ",
+    )
+    .unwrap();
+
+    reshape
+        .migrate(vec![create_table_migration, rewrite_migration])
+        .unwrap();
+    reshape.complete_migration().unwrap();
+
+    // Ensure the views were recreated on top of the new table, keeping their privileges
+    let name: String = db
+        .query_one("SELECT name FROM public.first_user_name", &[])
+        .unwrap()
+        .get("name");
+    assert_eq!("John", name);
+
+    let has_privilege: bool = db
+        .query_one(
+            "SELECT has_table_privilege('public', 'public.user_names', 'SELECT')",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert!(has_privilege);
+
+    db.simple_query("DROP VIEW public.first_user_name, public.user_names")
+        .unwrap();
+    common::assert_cleaned_up(&mut db);
+}
+
+#[test]
+fn rewrite_table_with_materialized_view() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_table_migration = Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![ColumnBuilder::default()
+                .name("id")
+                .data_type("INTEGER")
+                .build()
+                .unwrap()])
+            .build()
+            .unwrap(),
+    );
+    let rewrite_migration = Migration::new("rewrite_users", None).with_action(RewriteTable {
+        table: "users".to_string(),
+        columns: vec![ColumnBuilder::default()
+            .name("id")
+            .data_type("BIGINT")
+            .build()
+            .unwrap()],
+        primary_key: vec!["id".to_string()],
+        foreign_keys: vec![],
+        partition_by: None,
+        up: HashMap::new(),
+        down: HashMap::new(),
+        schema: None,
+    });
+
+    reshape
+        .migrate(vec![create_table_migration.clone()])
+        .unwrap();
+    db.simple_query("CREATE MATERIALIZED VIEW public.user_ids AS SELECT id FROM public.users")
+        .unwrap();
+
+    reshape
+        .migrate(vec![create_table_migration, rewrite_migration])
+        .unwrap();
+
+    // Ensure the table isn't replaced, which would drop the materialized view
+    let error = reshape.complete_migration().unwrap_err();
+    assert!(
+        format!("{:#}", error).contains("materialized view public.user_ids"),
+        "unexpected error: {:#}",
+        error
+    );
+    let count: i64 = db
+        .query_one("SELECT COUNT(*) FROM public.user_ids", &[])
+        .unwrap()
+        .get(0);
+    assert_eq!(0, count);
+
+    // Once the view has been dropped, the migration can be completed
+    db.simple_query("DROP MATERIALIZED VIEW public.user_ids")
+        .unwrap();
+    reshape.complete_migration().unwrap();
+    common::assert_cleaned_up(&mut db);
+}
@@ -0,0 +1,184 @@
+use reshape::migrations::{
+    AddColumn, AlterColumn, Column, ColumnBuilder, ColumnChanges, CreateTableBuilder, Migration,
+};
+
+mod common;
+
+#[test]
+fn non_public_schema() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+    old_db
+        .simple_query("DROP SCHEMA IF EXISTS billing CASCADE")
+        .unwrap();
+
+    let create_tables = Migration::new("create_tables", None)
+        .with_action(
+            CreateTableBuilder::default()
+                .name("users")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap()])
+                .build()
+                .unwrap(),
+        )
+        .with_action(
+            CreateTableBuilder::default()
+                .name("invoices")
+                .schema("billing")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("amount")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        );
+    let change_invoices = Migration::new("change_invoices", None)
+        .with_action(AddColumn {
+            table: "invoices".to_string(),
+            column: Column {
+                name: "currency".to_string(),
+                data_type: "TEXT".to_string(),
+                nullable: false,
+                default: None,
+                generated: None,
+            },
+            up: Some("'USD'".to_string()),
+            dependencies: vec![],
+            schema: Some("billing".to_string()),
+        })
+        .with_action(AlterColumn {
+            table: "invoices".to_string(),
+            column: "amount".to_string(),
+            up: None,
+            down: None,
+            changes: ColumnChanges {
+                data_type: None,
+                nullable: None,
+                name: Some("total".to_string()),
+                default: None,
+            },
+            dependencies: vec![],
+            down_dependencies: vec![],
+            schema: Some("billing".to_string()),
+        });
+
+    let first_migrations = vec![create_tables.clone()];
+    let second_migrations = vec![create_tables.clone(), change_invoices.clone()];
+
+    // The tables are created in their own schemas
+    reshape.migrate(first_migrations.clone()).unwrap();
+    old_db
+        .simple_query("INSERT INTO billing.invoices (id, amount) VALUES (1, 100)")
+        .unwrap();
+
+    reshape.migrate(second_migrations.clone()).unwrap();
+
+    // Each schema with tables gets its own schema of views
+    let view_schemas: Vec<String> = old_db
+        .query(
+            "
+            SELECT table_schema
+            FROM information_schema.views
+            WHERE table_name IN ('users', 'invoices')
+            ORDER BY table_schema
+            ",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(
+        vec![
+            "migration_change_invoices",
+            "migration_change_invoices__billing",
+            "migration_create_tables",
+            "migration_create_tables__billing",
+        ],
+        view_schemas
+    );
+
+    let schemas = reshape::schemas_for_migrations(&second_migrations);
+    assert_eq!(vec!["public", "billing"], schemas);
+
+    let old_schema_query = reshape::schema_query_for_migration_with_schemas(
+        &first_migrations.last().unwrap().name,
+        &schemas,
+    );
+    let new_schema_query = reshape::schema_query_for_migration_with_schemas(
+        &second_migrations.last().unwrap().name,
+        &schemas,
+    );
+    assert_eq!(
+        "SET search_path TO migration_change_invoices, migration_change_invoices__billing",
+        new_schema_query
+    );
+    old_db.simple_query(&old_schema_query).unwrap();
+    new_db.simple_query(&new_schema_query).unwrap();
+
+    // Tables in both schemas can be used through the views
+    old_db
+        .simple_query("INSERT INTO users (id) VALUES (1)")
+        .unwrap();
+    assert_eq!(1, new_db.query("SELECT id FROM users", &[]).unwrap().len());
+
+    // Writes from the old schema are visible in the new schema
+    old_db
+        .simple_query("INSERT INTO invoices (id, amount) VALUES (2, 200)")
+        .unwrap();
+    let invoices: Vec<(i32, i32, String)> = new_db
+        .query("SELECT id, total, currency FROM invoices ORDER BY id", &[])
+        .unwrap()
+        .iter()
+        .map(|row| (row.get("id"), row.get("total"), row.get("currency")))
+        .collect();
+    assert_eq!(
+        vec![(1, 100, "USD".to_string()), (2, 200, "USD".to_string())],
+        invoices
+    );
+
+    // Writes from the new schema are visible in the old schema
+    new_db
+        .simple_query("INSERT INTO invoices (id, total, currency) VALUES (3, 300, 'EUR')")
+        .unwrap();
+    let amounts: Vec<i32> = old_db
+        .query("SELECT amount FROM invoices ORDER BY id", &[])
+        .unwrap()
+        .iter()
+        .map(|row| row.get("amount"))
+        .collect();
+    assert_eq!(vec![100, 200, 300], amounts);
+
+    reshape.complete_migration().unwrap();
+    common::assert_cleaned_up(&mut new_db);
+
+    // Only the views for the latest migration remain
+    let remaining: Vec<String> = new_db
+        .query(
+            "SELECT nspname FROM pg_namespace WHERE nspname LIKE 'migration\\_%' ORDER BY nspname",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(
+        vec![
+            "migration_change_invoices",
+            "migration_change_invoices__billing"
+        ],
+        remaining
+    );
+}
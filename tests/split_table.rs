@@ -0,0 +1,405 @@
+use std::{thread, time::Duration};
+
+use reshape::migrations::{
+    BackfillPolicy, ColumnBuilder, CreateTableBuilder, Migration, SplitTable,
+};
+
+mod common;
+
+#[test]
+fn split_table() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let create_table_migration = Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![
+                ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("name")
+                    .data_type("TEXT")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("city")
+                    .data_type("TEXT")
+                    .nullable(false)
+                    .build()
+                    .unwrap(),
+            ])
+            .build()
+            .unwrap(),
+    );
+    let split_table_migration = Migration::new("split_addresses", None).with_action(SplitTable {
+        table: "users".to_string(),
+        new_table: "addresses".to_string(),
+        columns: vec!["city".to_string()],
+        reference_column: "user_id".to_string(),
+        schema: None,
+    });
+
+    let first_migrations = vec![create_table_migration.clone()];
+    let second_migrations = vec![
+        create_table_migration.clone(),
+        split_table_migration.clone(),
+    ];
+
+    // Run first migration and insert some data
+    reshape.migrate(first_migrations.clone()).unwrap();
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &first_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+    old_db
+        .simple_query("INSERT INTO users (id, name, city) VALUES (1, 'Alice', 'Stockholm')")
+        .unwrap();
+
+    // Run second migration
+    reshape.migrate(second_migrations.clone()).unwrap();
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &second_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+
+    // Ensure existing rows were copied to the new table
+    let city: String = new_db
+        .query_one("SELECT city FROM addresses WHERE user_id = 1", &[])
+        .unwrap()
+        .get("city");
+    assert_eq!("Stockholm", city);
+
+    // Ensure the moved column is no longer part of the source table in the new schema
+    assert!(new_db.simple_query("SELECT city FROM users").is_err());
+
+    // Ensure writes through the old schema are synced to the new table
+    old_db
+        .simple_query(
+            "
+            INSERT INTO users (id, name, city) VALUES (2, 'Bob', 'Oslo');
+            UPDATE users SET city = 'Gothenburg' WHERE id = 1;
+            ",
+        )
+        .unwrap();
+    let cities: Vec<String> = new_db
+        .query("SELECT city FROM addresses ORDER BY user_id", &[])
+        .unwrap()
+        .iter()
+        .map(|row| row.get("city"))
+        .collect();
+    assert_eq!(vec!["Gothenburg", "Oslo"], cities);
+
+    // Ensure writes through the new schema are synced back to the source table
+    new_db
+        .simple_query(
+            "
+            INSERT INTO users (id, name) VALUES (3, 'Carol');
+            INSERT INTO addresses (user_id, city) VALUES (3, 'Helsinki');
+            UPDATE addresses SET city = 'Bergen' WHERE user_id = 2;
+            ",
+        )
+        .unwrap();
+    let cities: Vec<String> = old_db
+        .query("SELECT city FROM users ORDER BY id", &[])
+        .unwrap()
+        .iter()
+        .map(|row| row.get("city"))
+        .collect();
+    assert_eq!(vec!["Gothenburg", "Bergen", "Helsinki"], cities);
+
+    // Ensure rows without a value for the moved column can still be updated from the old schema,
+    // even though the column is NOT NULL in the new table
+    new_db
+        .simple_query("INSERT INTO users (id, name) VALUES (4, 'Dave')")
+        .unwrap();
+    old_db
+        .simple_query("UPDATE users SET name = 'David' WHERE id = 4")
+        .unwrap();
+    let count: i64 = new_db
+        .query_one("SELECT COUNT(*) FROM addresses WHERE user_id = 4", &[])
+        .unwrap()
+        .get(0);
+    assert_eq!(0, count, "expected no address for user without a city");
+
+    reshape.complete_migration().unwrap();
+
+    // Ensure the moved column has been dropped from the source table
+    let count: i64 = new_db
+        .query_one(
+            "
+            SELECT COUNT(*)
+            FROM information_schema.columns
+            WHERE table_schema = 'public' AND table_name = 'users' AND column_name = 'city'
+            ",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(0, count, "expected moved column to be dropped");
+
+    common::assert_cleaned_up(&mut new_db);
+}
+
+#[test]
+fn split_table_abort() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_table_migration = Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![
+                ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("city")
+                    .data_type("TEXT")
+                    .nullable(false)
+                    .build()
+                    .unwrap(),
+            ])
+            .build()
+            .unwrap(),
+    );
+    let split_table_migration = Migration::new("split_addresses", None).with_action(SplitTable {
+        table: "users".to_string(),
+        new_table: "addresses".to_string(),
+        columns: vec!["city".to_string()],
+        reference_column: "user_id".to_string(),
+        schema: None,
+    });
+
+    reshape
+        .migrate(vec![create_table_migration.clone()])
+        .unwrap();
+    reshape
+        .migrate(vec![
+            create_table_migration.clone(),
+            split_table_migration.clone(),
+        ])
+        .unwrap();
+    reshape.abort().unwrap();
+
+    // Ensure the new table was removed and the source column is NOT NULL again
+    let count: i64 = db
+        .query_one(
+            "
+            SELECT COUNT(*)
+            FROM information_schema.tables
+            WHERE table_schema = 'public' AND table_name = 'addresses'
+            ",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(0, count, "expected new table to not exist");
+
+    let nullable: String = db
+        .query_one(
+            "
+            SELECT is_nullable
+            FROM information_schema.columns
+            WHERE table_schema = 'public' AND table_name = 'users' AND column_name = 'city'
+            ",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!("NO", nullable);
+
+    common::assert_cleaned_up(&mut db);
+}
+
+#[test]
+fn split_table_with_concurrent_delete() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let create_table_migration = Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![
+                ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("city")
+                    .data_type("TEXT")
+                    .build()
+                    .unwrap(),
+            ])
+            .build()
+            .unwrap(),
+    );
+    let split_table_migration = Migration::new("split_addresses", None).with_action(SplitTable {
+        table: "users".to_string(),
+        new_table: "addresses".to_string(),
+        columns: vec!["city".to_string()],
+        reference_column: "user_id".to_string(),
+        schema: None,
+    });
+
+    reshape
+        .migrate(vec![create_table_migration.clone()])
+        .unwrap();
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &create_table_migration.name,
+        ))
+        .unwrap();
+    old_db
+        .simple_query("INSERT INTO users (id, city) VALUES (1, 'Stockholm'), (2, 'Oslo')")
+        .unwrap();
+
+    // Delete a row from the old schema once the sync triggers exist, but only commit the
+    // delete while the row is being copied
+    let deleter = thread::spawn(move || {
+        while old_db
+            .query(
+                "SELECT 1 FROM pg_catalog.pg_trigger WHERE tgname LIKE '%_split_table_source_trigger'",
+                &[],
+            )
+            .unwrap()
+            .is_empty()
+        {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        old_db
+            .simple_query("BEGIN; DELETE FROM users WHERE id = 2")
+            .unwrap();
+        thread::sleep(Duration::from_millis(1000));
+        old_db.simple_query("COMMIT").unwrap();
+    });
+
+    reshape.set_backfill_policy(BackfillPolicy {
+        batch_size: 1,
+        sleep_between_batches: Duration::from_millis(500),
+        ..BackfillPolicy::default()
+    });
+    reshape
+        .migrate(vec![create_table_migration, split_table_migration.clone()])
+        .unwrap();
+    deleter.join().unwrap();
+
+    // Ensure the deleted row wasn't copied to the new table
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &split_table_migration.name,
+        ))
+        .unwrap();
+    let user_ids: Vec<i32> = new_db
+        .query("SELECT user_id FROM addresses ORDER BY user_id", &[])
+        .unwrap()
+        .iter()
+        .map(|row| row.get("user_id"))
+        .collect();
+    assert_eq!(vec![1], user_ids);
+
+    reshape.complete_migration().unwrap();
+    common::assert_cleaned_up(&mut new_db);
+}
+
+#[test]
+fn split_table_abort_after_new_schema_insert() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let create_table_migration = Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![
+                ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("city")
+                    .data_type("TEXT")
+                    .nullable(false)
+                    .build()
+                    .unwrap(),
+            ])
+            .build()
+            .unwrap(),
+    );
+    let split_table_migration = Migration::new("split_addresses", None).with_action(SplitTable {
+        table: "users".to_string(),
+        new_table: "addresses".to_string(),
+        columns: vec!["city".to_string()],
+        reference_column: "user_id".to_string(),
+        schema: None,
+    });
+
+    reshape
+        .migrate(vec![create_table_migration.clone()])
+        .unwrap();
+    reshape
+        .migrate(vec![
+            create_table_migration.clone(),
+            split_table_migration.clone(),
+        ])
+        .unwrap();
+
+    // Insert one user with and one without an address from the new schema
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &split_table_migration.name,
+        ))
+        .unwrap();
+    new_db
+        .simple_query(
+            "
+            INSERT INTO users (id) VALUES (1), (2);
+            INSERT INTO addresses (user_id, city) VALUES (1, 'Stockholm');
+            ",
+        )
+        .unwrap();
+
+    reshape.abort().unwrap();
+
+    // Ensure the address was copied back
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &create_table_migration.name,
+        ))
+        .unwrap();
+    let cities: Vec<Option<String>> = old_db
+        .query("SELECT city FROM users ORDER BY id", &[])
+        .unwrap()
+        .iter()
+        .map(|row| row.get("city"))
+        .collect();
+    assert_eq!(vec![Some("Stockholm".to_string()), None], cities);
+
+    // Ensure NOT NULL is still enforced for new rows, using a constraint which isn't validated
+    let constraint: (String, bool) = old_db
+        .query_one(
+            "
+            SELECT conname, convalidated
+            FROM pg_catalog.pg_constraint
+            WHERE conrelid = 'public.users'::regclass AND contype = 'c'
+            ",
+            &[],
+        )
+        .map(|row| (row.get("conname"), row.get("convalidated")))
+        .unwrap();
+    assert_eq!(("users_city_not_null".to_string(), false), constraint);
+
+    let result = old_db.simple_query("INSERT INTO users (id) VALUES (3)");
+    assert!(result.is_err(), "expected insert to violate NOT NULL");
+
+    common::assert_cleaned_up(&mut old_db);
+}
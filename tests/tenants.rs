@@ -0,0 +1,209 @@
+use reshape::{
+    migrations::{
+        AddColumn, AlterColumn, Column, ColumnBuilder, ColumnChanges, CreateTableBuilder, Custom,
+        Migration,
+    },
+    Status, TenantSelector,
+};
+
+mod common;
+
+fn create_tenants(db: &mut postgres::Client) {
+    db.simple_query(
+        "
+        DROP SCHEMA IF EXISTS tenant_a CASCADE;
+        DROP SCHEMA IF EXISTS tenant_b CASCADE;
+        CREATE SCHEMA tenant_a;
+        CREATE SCHEMA tenant_b;
+        ",
+    )
+    .unwrap();
+}
+
+fn create_users_table() -> Migration {
+    Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![
+                ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("name")
+                    .data_type("TEXT")
+                    .build()
+                    .unwrap(),
+            ])
+            .build()
+            .unwrap(),
+    )
+}
+
+#[test]
+fn migrate_tenants() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+    create_tenants(&mut old_db);
+
+    let tenants = TenantSelector::Pattern("tenant\\_%".to_string());
+    let create_users_table = create_users_table();
+    let rename_name = Migration::new("rename_name", None).with_action(AlterColumn {
+        table: "users".to_string(),
+        column: "name".to_string(),
+        up: None,
+        down: None,
+        changes: ColumnChanges {
+            data_type: None,
+            nullable: None,
+            name: Some("full_name".to_string()),
+            default: None,
+        },
+        dependencies: vec![],
+        down_dependencies: vec![],
+        schema: None,
+    });
+
+    let first_migrations = vec![create_users_table.clone()];
+    let second_migrations = vec![create_users_table.clone(), rename_name.clone()];
+
+    // The table is created in every tenant schema
+    reshape
+        .migrate_tenants(first_migrations.clone(), &tenants)
+        .unwrap();
+    assert_eq!(vec!["tenant_a", "tenant_b"], reshape.state.tenants);
+    old_db
+        .simple_query(
+            "
+            INSERT INTO tenant_a.users (id, name) VALUES (1, 'Alice');
+            INSERT INTO tenant_b.users (id, name) VALUES (1, 'Bob');
+            ",
+        )
+        .unwrap();
+
+    reshape
+        .migrate_tenants(second_migrations.clone(), &tenants)
+        .unwrap();
+
+    // Each tenant gets its own schemas of views
+    let old_schema_query = reshape::schema_query_for_migration_with_schemas(
+        &first_migrations.last().unwrap().name,
+        &["tenant_a".to_string()],
+    );
+    let new_schema_query = reshape::schema_query_for_migration_with_schemas(
+        &second_migrations.last().unwrap().name,
+        &["tenant_a".to_string()],
+    );
+    old_db.simple_query(&old_schema_query).unwrap();
+    new_db.simple_query(&new_schema_query).unwrap();
+
+    // Writes through the old schema are visible in the new schema and vice versa
+    old_db
+        .simple_query("INSERT INTO users (id, name) VALUES (2, 'Anna')")
+        .unwrap();
+    new_db
+        .simple_query("INSERT INTO users (id, full_name) VALUES (3, 'Adam')")
+        .unwrap();
+
+    let expected = vec!["Alice", "Anna", "Adam"];
+    let old_names: Vec<String> = old_db
+        .query("SELECT name FROM users ORDER BY id", &[])
+        .unwrap()
+        .iter()
+        .map(|row| row.get("name"))
+        .collect();
+    let new_names: Vec<String> = new_db
+        .query("SELECT full_name FROM users ORDER BY id", &[])
+        .unwrap()
+        .iter()
+        .map(|row| row.get("full_name"))
+        .collect();
+    assert_eq!(expected, old_names);
+    assert_eq!(expected, new_names);
+
+    reshape.complete_migration().unwrap();
+    common::assert_cleaned_up(&mut new_db);
+
+    // The column has been renamed for every tenant
+    let renamed: Vec<String> = new_db
+        .query(
+            "
+            SELECT table_schema
+            FROM information_schema.columns
+            WHERE table_name = 'users' AND column_name = 'full_name'
+            AND table_schema LIKE 'tenant\\_%'
+            ORDER BY table_schema
+            ",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert_eq!(vec!["tenant_a", "tenant_b"], renamed);
+}
+
+#[test]
+fn migrate_tenants_failure_aborts_applied_tenants() {
+    let (mut reshape, mut old_db, _) = common::setup();
+    create_tenants(&mut old_db);
+
+    let tenants = TenantSelector::Query(
+        "SELECT nspname FROM pg_namespace WHERE nspname IN ('tenant_a', 'tenant_b') ORDER BY nspname"
+            .to_string(),
+    );
+    let create_users_table = create_users_table();
+    let add_email = Migration::new("add_email", None)
+        .with_action(AddColumn {
+            table: "users".to_string(),
+            column: Column {
+                name: "email".to_string(),
+                data_type: "TEXT".to_string(),
+                nullable: true,
+                default: None,
+                generated: None,
+            },
+            up: None,
+            dependencies: vec![],
+            schema: None,
+        })
+        // Fails for tenant_b which has no users
+        .with_action(Custom {
+            start: Some("SELECT 1 / (SELECT COUNT(*) FROM users)::INTEGER".to_string()),
+            complete: None,
+            abort: None,
+            complete_in_transaction: false,
+            schema: None,
+        });
+
+    reshape
+        .migrate_tenants(vec![create_users_table.clone()], &tenants)
+        .unwrap();
+    old_db
+        .simple_query("INSERT INTO tenant_a.users (id, name) VALUES (1, 'Alice')")
+        .unwrap();
+
+    let result = reshape.migrate_tenants(vec![create_users_table, add_email], &tenants);
+    assert!(result.is_err());
+    assert!(matches!(reshape.state.status, Status::Idle));
+
+    // The column which had been added for both tenants has been removed again
+    let columns: Vec<String> = old_db
+        .query(
+            "
+            SELECT table_schema || '.' || column_name
+            FROM information_schema.columns
+            WHERE table_name = 'users' AND column_name NOT IN ('id', 'name')
+            AND table_schema LIKE 'tenant\\_%'
+            ",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+    assert!(columns.is_empty(), "found columns: {}", columns.join(", "));
+
+    common::assert_cleaned_up(&mut old_db);
+}
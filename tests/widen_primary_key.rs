@@ -0,0 +1,310 @@
+use std::time::Duration;
+
+use reshape::migrations::{
+    ColumnBuilder, CreateTableBuilder, ForeignKey, LockRetryPolicy, Migration, WidenPrimaryKey,
+};
+
+mod common;
+
+fn get_column_type(db: &mut postgres::Client, table: &str, column: &str) -> String {
+    db.query_one(
+        "
+        SELECT data_type
+        FROM information_schema.columns
+        WHERE table_schema = 'public' AND table_name = $1 AND column_name = $2
+        ",
+        &[&table, &column],
+    )
+    .unwrap()
+    .get("data_type")
+}
+
+#[test]
+fn widen_primary_key() {
+    let (mut reshape, mut old_db, mut new_db) = common::setup();
+
+    let create_tables_migration = Migration::new("create_tables", None)
+        .with_action(
+            CreateTableBuilder::default()
+                .name("users")
+                .primary_key(vec!["id".to_string()])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("SERIAL")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("name")
+                        .data_type("TEXT")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        )
+        .with_action(
+            CreateTableBuilder::default()
+                .name("items")
+                .primary_key(vec!["id".to_string()])
+                .foreign_keys(vec![ForeignKey {
+                    columns: vec!["user_id".to_string()],
+                    referenced_table: "users".to_string(),
+                    referenced_columns: vec!["id".to_string()],
+                }])
+                .columns(vec![
+                    ColumnBuilder::default()
+                        .name("id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                    ColumnBuilder::default()
+                        .name("user_id")
+                        .data_type("INTEGER")
+                        .build()
+                        .unwrap(),
+                ])
+                .build()
+                .unwrap(),
+        );
+    let widen_migration = Migration::new("widen_users_id", None).with_action(WidenPrimaryKey {
+        table: "users".to_string(),
+        column: "id".to_string(),
+        data_type: "BIGINT".to_string(),
+        schema: None,
+    });
+
+    let first_migrations = vec![create_tables_migration.clone()];
+    let second_migrations = vec![create_tables_migration.clone(), widen_migration.clone()];
+
+    reshape.migrate(first_migrations.clone()).unwrap();
+    old_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &first_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+    old_db
+        .simple_query(
+            "
+            INSERT INTO users (name) VALUES ('John'), ('Jane');
+            INSERT INTO items (id, user_id) VALUES (1, 1), (2, 2);
+            ",
+        )
+        .unwrap();
+
+    reshape.migrate(second_migrations.clone()).unwrap();
+    new_db
+        .simple_query(&reshape::schema_query_for_migration(
+            &second_migrations.last().unwrap().name,
+        ))
+        .unwrap();
+
+    // Ensure both schemas get ids from the same sequence
+    old_db
+        .simple_query("INSERT INTO users (name) VALUES ('Alice')")
+        .unwrap();
+    new_db
+        .simple_query("INSERT INTO users (name) VALUES ('Bob')")
+        .unwrap();
+
+    let get_users = |db: &mut postgres::Client| -> Vec<(i64, String)> {
+        db.query("SELECT id::BIGINT, name FROM users ORDER BY id", &[])
+            .unwrap()
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect()
+    };
+    assert_eq!(get_users(&mut old_db), get_users(&mut new_db));
+
+    reshape.complete_migration().unwrap();
+
+    // Ensure the column has been widened and the sequence moved over
+    assert_eq!("bigint", get_column_type(&mut new_db, "users", "id"));
+    let (sequence, sequence_type): (String, String) = new_db
+        .query_one(
+            "
+            SELECT pg_get_serial_sequence('public.users', 'id'), pg_sequences.data_type::TEXT
+            FROM pg_catalog.pg_sequences
+            WHERE sequencename = 'users_id_seq'
+            ",
+            &[],
+        )
+        .map(|row| (row.get(0), row.get(1)))
+        .unwrap();
+    assert_eq!("public.users_id_seq", sequence);
+    assert_eq!("bigint", sequence_type);
+
+    // Ensure the primary key and foreign key have been kept
+    let constraints: Vec<(String, bool)> = new_db
+        .query(
+            "
+            SELECT conname, convalidated
+            FROM pg_catalog.pg_constraint
+            WHERE conrelid IN ('public.users'::regclass, 'public.items'::regclass)
+            ORDER BY conname
+            ",
+            &[],
+        )
+        .unwrap()
+        .iter()
+        .map(|row| (row.get("conname"), row.get("convalidated")))
+        .collect();
+    assert_eq!(
+        vec![
+            ("items_pkey".to_string(), true),
+            ("items_user_id_fkey".to_string(), true),
+            ("users_pkey".to_string(), true),
+        ],
+        constraints
+    );
+
+    new_db
+        .simple_query("INSERT INTO users (name) VALUES ('Eve')")
+        .unwrap();
+    let result = new_db.simple_query("INSERT INTO items (id, user_id) VALUES (3, 100)");
+    assert!(result.is_err(), "expected insert to violate foreign key");
+
+    common::assert_cleaned_up(&mut new_db);
+}
+
+#[test]
+fn widen_primary_key_identity() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_table_migration = Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![
+                ColumnBuilder::default()
+                    .name("id")
+                    .data_type("INTEGER")
+                    .generated("ALWAYS AS IDENTITY")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("name")
+                    .data_type("TEXT")
+                    .build()
+                    .unwrap(),
+            ])
+            .build()
+            .unwrap(),
+    );
+    let widen_migration = Migration::new("widen_users_id", None).with_action(WidenPrimaryKey {
+        table: "users".to_string(),
+        column: "id".to_string(),
+        data_type: "BIGINT".to_string(),
+        schema: None,
+    });
+
+    reshape
+        .migrate(vec![create_table_migration.clone()])
+        .unwrap();
+    db.simple_query("INSERT INTO public.users (name) VALUES ('John'), ('Jane')")
+        .unwrap();
+
+    reshape
+        .migrate(vec![
+            create_table_migration.clone(),
+            widen_migration.clone(),
+        ])
+        .unwrap();
+
+    // Ids which the sequence hasn't handed out must not be reused by the new identity
+    db.simple_query(&reshape::schema_query_for_migration(
+        &create_table_migration.name,
+    ))
+    .unwrap();
+    db.simple_query(
+        "INSERT INTO public.users (id, name) OVERRIDING SYSTEM VALUE VALUES (10, 'Bob')",
+    )
+    .unwrap();
+    reshape.complete_migration().unwrap();
+
+    // Ensure the column is still an identity which continues after the existing ids
+    assert_eq!("bigint", get_column_type(&mut db, "users", "id"));
+    let id: i64 = db
+        .query_one(
+            "INSERT INTO public.users (name) VALUES ('Alice') RETURNING id",
+            &[],
+        )
+        .unwrap()
+        .get(0);
+    assert_eq!(11, id);
+
+    common::assert_cleaned_up(&mut db);
+}
+
+#[test]
+fn widen_primary_key_retried_swap() {
+    let (mut reshape, mut db, _) = common::setup();
+
+    let create_table_migration = Migration::new("create_users_table", None).with_action(
+        CreateTableBuilder::default()
+            .name("users")
+            .primary_key(vec!["id".to_string()])
+            .columns(vec![
+                ColumnBuilder::default()
+                    .name("id")
+                    .data_type("SERIAL")
+                    .build()
+                    .unwrap(),
+                ColumnBuilder::default()
+                    .name("name")
+                    .data_type("TEXT")
+                    .build()
+                    .unwrap(),
+            ])
+            .build()
+            .unwrap(),
+    );
+    let widen_migration = Migration::new("widen_users_id", None).with_action(WidenPrimaryKey {
+        table: "users".to_string(),
+        column: "id".to_string(),
+        data_type: "BIGINT".to_string(),
+        schema: None,
+    });
+
+    reshape
+        .migrate(vec![create_table_migration.clone()])
+        .unwrap();
+    reshape
+        .migrate(vec![
+            create_table_migration.clone(),
+            widen_migration.clone(),
+        ])
+        .unwrap();
+
+    // Fail the swap once by holding a lock on the table
+    db.simple_query("BEGIN; LOCK TABLE public.users IN ACCESS SHARE MODE")
+        .unwrap();
+    reshape.set_lock_retry_policy(LockRetryPolicy {
+        lock_timeout: Duration::from_millis(50),
+        statement_timeout: None,
+        max_retries: 0,
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(10),
+    });
+    assert!(reshape.complete_migration().is_err());
+
+    db.simple_query("COMMIT").unwrap();
+    reshape.complete_migration().unwrap();
+
+    // Ensure the sequence was still moved over to the new column
+    let (sequence, sequence_type): (Option<String>, String) = db
+        .query_one(
+            "
+            SELECT pg_get_serial_sequence('public.users', 'id'), pg_sequences.data_type::TEXT
+            FROM pg_catalog.pg_sequences
+            WHERE sequencename = 'users_id_seq'
+            ",
+            &[],
+        )
+        .map(|row| (row.get(0), row.get(1)))
+        .unwrap();
+    assert_eq!(Some("public.users_id_seq".to_string()), sequence);
+    assert_eq!("bigint", sequence_type);
+
+    common::assert_cleaned_up(&mut db);
+}